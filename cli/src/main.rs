@@ -33,10 +33,10 @@ fn main() {
             buffer
         });
         let server_cmd = async_process::Command::new(opts.server);
-        let server = sim.spawn_machine(server_cmd, None).await;
+        let server = sim.spawn_machine(server_cmd, None).await.unwrap();
         let mut client_cmd = async_process::Command::new(opts.client);
         client_cmd.arg(server_addr.to_string());
-        let client = sim.spawn_machine(client_cmd, delay).await;
+        let client = sim.spawn_machine(client_cmd, delay).await.unwrap();
         sim.plug(server, public, Some(server_addr)).await;
         match opts.topology.as_str() {
             "m2" => {
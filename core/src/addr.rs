@@ -19,6 +19,7 @@ pub enum Ipv4AddrClass {
 }
 
 /// Extension methods for IPv4 addresses
+#[allow(dead_code)]
 pub trait Ipv4AddrExt {
     /// Get a random, global IPv4 address.
     fn random_global() -> Ipv4Addr;
@@ -0,0 +1,543 @@
+use crate::packet::Protocol;
+use libpacket::icmp::{self, IcmpPacket, MutableIcmpPacket};
+use libpacket::icmpv6::{self, Icmpv6Packet, MutableIcmpv6Packet};
+use libpacket::ip::IpNextHeaderProtocols;
+use libpacket::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use libpacket::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use libpacket::MutablePacket;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// ICMP errors must not be longer than this (RFC 1812 section 4.3.2.3).
+const MAX_ICMP_ERROR_LEN: usize = 576;
+
+/// ICMPv6 errors must not be longer than the minimum IPv6 MTU (RFC 4443 section 2.4).
+const MAX_ICMPV6_ERROR_LEN: usize = 1280;
+
+/// Reason of a destination unreachable error, the reasons which both ICMP and ICMPv6 can
+/// express (RFC 7915 sections 4.2 and 5.2).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unreachable {
+    /// There is no route to the destination host or network.
+    NoRoute,
+    /// Communication with the destination is administratively prohibited.
+    Prohibited,
+    /// There is no socket bound to the destination port.
+    Port,
+}
+
+/// The start of the UDP or TCP packet which caused an ICMP error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Original {
+    pub protocol: Protocol,
+    pub source: SocketAddr,
+    pub dest: SocketAddr,
+    pub ttl: u8,
+    /// Start of the UDP or TCP header after the ports, usually truncated.
+    pub rest: Vec<u8>,
+}
+
+impl Original {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let (protocol, source, dest, ttl, segment) = match bytes.first()? >> 4 {
+            4 => {
+                let packet = Ipv4Packet::new(bytes)?;
+                let header_len = usize::from(packet.get_header_length()) * 4;
+                (
+                    packet.get_next_level_protocol(),
+                    packet.get_source().into(),
+                    packet.get_destination().into(),
+                    packet.get_ttl(),
+                    bytes.get(header_len..)?,
+                )
+            }
+            6 => {
+                let packet = Ipv6Packet::new(bytes)?;
+                (
+                    packet.get_next_header(),
+                    packet.get_source().into(),
+                    packet.get_destination().into(),
+                    packet.get_hop_limit(),
+                    &bytes[40..],
+                )
+            }
+            _ => return None,
+        };
+        let protocol = match protocol {
+            IpNextHeaderProtocols::Udp => Protocol::Udp,
+            IpNextHeaderProtocols::Tcp => Protocol::Tcp,
+            _ => return None,
+        };
+        if segment.len() < 4 {
+            return None;
+        }
+        let port = |i: usize| u16::from_be_bytes([segment[i], segment[i + 1]]);
+        Some(Self {
+            protocol,
+            source: SocketAddr::new(source, port(0)),
+            dest: SocketAddr::new(dest, port(2)),
+            ttl,
+            rest: segment[4..].to_vec(),
+        })
+    }
+
+    /// Returns the packet with the addresses of the original, at most `max_len` bytes long.
+    ///
+    /// # Panics
+    ///
+    /// If source and destination are of different address families.
+    fn to_bytes(&self, max_len: usize) -> Vec<u8> {
+        let protocol = match self.protocol {
+            Protocol::Udp => IpNextHeaderProtocols::Udp,
+            Protocol::Tcp => IpNextHeaderProtocols::Tcp,
+        };
+        let mut segment = Vec::with_capacity(4 + self.rest.len());
+        segment.extend_from_slice(&self.source.port().to_be_bytes());
+        segment.extend_from_slice(&self.dest.port().to_be_bytes());
+        segment.extend_from_slice(&self.rest);
+        let (mut bytes, header_len) = match (self.source, self.dest) {
+            (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
+                let mut bytes = vec![0; 20 + segment.len()];
+                let mut packet = MutableIpv4Packet::new(&mut bytes).unwrap();
+                packet.set_version(4);
+                packet.set_header_length(5);
+                packet.set_total_length((20 + segment.len()) as u16);
+                packet.set_ttl(self.ttl);
+                packet.set_next_level_protocol(protocol);
+                packet.set_source(*source.ip());
+                packet.set_destination(*dest.ip());
+                packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+                (bytes, 20)
+            }
+            (SocketAddr::V6(source), SocketAddr::V6(dest)) => {
+                let mut bytes = vec![0; 40 + segment.len()];
+                let mut packet = MutableIpv6Packet::new(&mut bytes).unwrap();
+                packet.set_version(6);
+                packet.set_payload_length(segment.len() as u16);
+                packet.set_hop_limit(self.ttl);
+                packet.set_next_header(protocol);
+                packet.set_source(*source.ip());
+                packet.set_destination(*dest.ip());
+                (bytes, 40)
+            }
+            _ => panic!("original packet with addresses of different families"),
+        };
+        bytes[header_len..].copy_from_slice(&segment);
+        bytes.truncate(max_len);
+        bytes
+    }
+}
+
+/// An ICMP or ICMPv6 message, limited to the messages which can be translated between the two
+/// (RFC 7915).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IcmpMessage {
+    EchoRequest {
+        id: u16,
+        seq: u16,
+        data: Vec<u8>,
+    },
+    EchoReply {
+        id: u16,
+        seq: u16,
+        data: Vec<u8>,
+    },
+    Unreachable {
+        reason: Unreachable,
+        original: Original,
+    },
+    /// The packet exceeded the MTU of the next hop, as reported in the message.
+    PacketTooBig {
+        mtu: u16,
+        original: Original,
+    },
+    TimeExceeded {
+        code: u8,
+        original: Original,
+    },
+}
+
+impl IcmpMessage {
+    /// Returns the UDP or TCP packet which caused an error, `None` for echo messages.
+    pub fn original(&self) -> Option<&Original> {
+        match self {
+            Self::EchoRequest { .. } | Self::EchoReply { .. } => None,
+            Self::Unreachable { original, .. }
+            | Self::PacketTooBig { original, .. }
+            | Self::TimeExceeded { original, .. } => Some(original),
+        }
+    }
+
+    /// Returns the UDP or TCP packet which caused an error, `None` for echo messages.
+    pub fn original_mut(&mut self) -> Option<&mut Original> {
+        match self {
+            Self::EchoRequest { .. } | Self::EchoReply { .. } => None,
+            Self::Unreachable { original, .. }
+            | Self::PacketTooBig { original, .. }
+            | Self::TimeExceeded { original, .. } => Some(original),
+        }
+    }
+
+    fn parse_echo(body: &[u8]) -> (u16, u16, Vec<u8>) {
+        let id = u16::from_be_bytes([body[4], body[5]]);
+        let seq = u16::from_be_bytes([body[6], body[7]]);
+        (id, seq, body[8..].to_vec())
+    }
+
+    /// Returns the type, code, the rest of the header and the data of the ICMP message.
+    fn encode(&self, icmpv6: bool, max_error_len: usize) -> (u8, u8, [u8; 4], Vec<u8>) {
+        let echo = |ty, id: &u16, seq: &u16, data: &Vec<u8>| {
+            let [a, b] = id.to_be_bytes();
+            let [c, d] = seq.to_be_bytes();
+            (ty, 0, [a, b, c, d], data.clone())
+        };
+        let original = |original: &Original| original.to_bytes(max_error_len - 8);
+        match (self, icmpv6) {
+            (Self::EchoRequest { id, seq, data }, false) => echo(8, id, seq, data),
+            (Self::EchoRequest { id, seq, data }, true) => echo(128, id, seq, data),
+            (Self::EchoReply { id, seq, data }, false) => echo(0, id, seq, data),
+            (Self::EchoReply { id, seq, data }, true) => echo(129, id, seq, data),
+            (
+                Self::Unreachable {
+                    reason,
+                    original: o,
+                },
+                false,
+            ) => {
+                let code = match reason {
+                    Unreachable::NoRoute => 1,
+                    Unreachable::Prohibited => 10,
+                    Unreachable::Port => 3,
+                };
+                (3, code, [0; 4], original(o))
+            }
+            (
+                Self::Unreachable {
+                    reason,
+                    original: o,
+                },
+                true,
+            ) => {
+                let code = match reason {
+                    Unreachable::NoRoute => 0,
+                    Unreachable::Prohibited => 1,
+                    Unreachable::Port => 4,
+                };
+                (1, code, [0; 4], original(o))
+            }
+            (Self::PacketTooBig { mtu, original: o }, false) => {
+                let [a, b] = mtu.to_be_bytes();
+                (3, 4, [0, 0, a, b], original(o))
+            }
+            (Self::PacketTooBig { mtu, original: o }, true) => {
+                let [a, b] = mtu.to_be_bytes();
+                (2, 0, [0, 0, a, b], original(o))
+            }
+            (Self::TimeExceeded { code, original: o }, false) => (11, *code, [0; 4], original(o)),
+            (Self::TimeExceeded { code, original: o }, true) => (3, *code, [0; 4], original(o)),
+        }
+    }
+}
+
+/// An ICMP message carried in an IPv4 packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Icmp {
+    pub source: Ipv4Addr,
+    pub dest: Ipv4Addr,
+    pub ttl: u8,
+    pub message: IcmpMessage,
+}
+
+impl Icmp {
+    /// Parses an IPv4 packet, returns `None` if it doesn't carry an ICMP message listed in
+    /// `IcmpMessage`.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let packet = Ipv4Packet::new(bytes)?;
+        if packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+            return None;
+        }
+        let start = usize::from(packet.get_header_length()) * 4;
+        let end = usize::from(packet.get_total_length()).min(bytes.len());
+        let body = bytes.get(start..end)?;
+        if body.len() < 8 {
+            return None;
+        }
+        let original = || Original::parse(&body[8..]);
+        let message = match (body[0], body[1]) {
+            (8, 0) | (0, 0) => {
+                let (id, seq, data) = IcmpMessage::parse_echo(body);
+                if body[0] == 8 {
+                    IcmpMessage::EchoRequest { id, seq, data }
+                } else {
+                    IcmpMessage::EchoReply { id, seq, data }
+                }
+            }
+            (3, 4) => IcmpMessage::PacketTooBig {
+                mtu: u16::from_be_bytes([body[6], body[7]]),
+                original: original()?,
+            },
+            (3, code) => {
+                let reason = match code {
+                    0 | 1 | 5..=8 | 11 | 12 => Unreachable::NoRoute,
+                    9 | 10 | 13 | 15 => Unreachable::Prohibited,
+                    3 => Unreachable::Port,
+                    _ => return None,
+                };
+                IcmpMessage::Unreachable {
+                    reason,
+                    original: original()?,
+                }
+            }
+            (11, code) => IcmpMessage::TimeExceeded {
+                code,
+                original: original()?,
+            },
+            _ => return None,
+        };
+        Some(Self {
+            source: packet.get_source(),
+            dest: packet.get_destination(),
+            ttl: packet.get_ttl(),
+            message,
+        })
+    }
+
+    /// Builds the IPv4 packet, errors are truncated to 576 bytes.
+    ///
+    /// # Panics
+    ///
+    /// If the original packet of an error isn't an IPv4 packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (ty, code, header, data) = self.message.encode(false, MAX_ICMP_ERROR_LEN - 20);
+        let icmp_len = 8 + data.len();
+        let mut bytes = vec![0; 20 + icmp_len];
+        let mut packet = MutableIpv4Packet::new(&mut bytes).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length((20 + icmp_len) as u16);
+        packet.set_ttl(self.ttl);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        packet.set_source(self.source);
+        packet.set_destination(self.dest);
+        packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+        {
+            let body = packet.payload_mut();
+            body[0] = ty;
+            body[1] = code;
+            body[4..8].copy_from_slice(&header);
+            body[8..].copy_from_slice(&data);
+            let checksum = icmp::checksum(&IcmpPacket::new(body).unwrap());
+            MutableIcmpPacket::new(body).unwrap().set_checksum(checksum);
+        }
+        bytes
+    }
+}
+
+/// An ICMPv6 message carried in an IPv6 packet without extension headers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Icmp6 {
+    pub source: Ipv6Addr,
+    pub dest: Ipv6Addr,
+    pub hop_limit: u8,
+    pub message: IcmpMessage,
+}
+
+impl Icmp6 {
+    /// Returns `true` if the packet is an IPv6 packet carrying an ICMPv6 message of any type.
+    pub fn is_icmpv6(bytes: &[u8]) -> bool {
+        Ipv6Packet::new(bytes)
+            .map(|packet| packet.get_next_header() == IpNextHeaderProtocols::Icmpv6)
+            .unwrap_or_default()
+    }
+
+    /// Parses an IPv6 packet, returns `None` if it doesn't carry an ICMPv6 message listed in
+    /// `IcmpMessage`.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if !Self::is_icmpv6(bytes) {
+            return None;
+        }
+        let packet = Ipv6Packet::new(bytes)?;
+        let end = (40 + usize::from(packet.get_payload_length())).min(bytes.len());
+        let body = &bytes[40..end];
+        if body.len() < 8 {
+            return None;
+        }
+        let original = || Original::parse(&body[8..]);
+        let message = match (body[0], body[1]) {
+            (128, 0) | (129, 0) => {
+                let (id, seq, data) = IcmpMessage::parse_echo(body);
+                if body[0] == 128 {
+                    IcmpMessage::EchoRequest { id, seq, data }
+                } else {
+                    IcmpMessage::EchoReply { id, seq, data }
+                }
+            }
+            (1, code) => {
+                let reason = match code {
+                    0 | 2 | 3 => Unreachable::NoRoute,
+                    1 => Unreachable::Prohibited,
+                    4 => Unreachable::Port,
+                    _ => return None,
+                };
+                IcmpMessage::Unreachable {
+                    reason,
+                    original: original()?,
+                }
+            }
+            (2, 0) => {
+                let mtu = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                IcmpMessage::PacketTooBig {
+                    mtu: mtu.min(u32::from(u16::MAX)) as u16,
+                    original: original()?,
+                }
+            }
+            (3, code) => IcmpMessage::TimeExceeded {
+                code,
+                original: original()?,
+            },
+            _ => return None,
+        };
+        Some(Self {
+            source: packet.get_source(),
+            dest: packet.get_destination(),
+            hop_limit: packet.get_hop_limit(),
+            message,
+        })
+    }
+
+    /// Builds the IPv6 packet, errors are truncated to 1280 bytes.
+    ///
+    /// # Panics
+    ///
+    /// If the original packet of an error isn't an IPv6 packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (ty, code, header, data) = self.message.encode(true, MAX_ICMPV6_ERROR_LEN - 40);
+        let icmp_len = 8 + data.len();
+        let mut bytes = vec![0; 40 + icmp_len];
+        let mut packet = MutableIpv6Packet::new(&mut bytes).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length(icmp_len as u16);
+        packet.set_hop_limit(self.hop_limit);
+        packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        packet.set_source(self.source);
+        packet.set_destination(self.dest);
+        {
+            let body = packet.payload_mut();
+            body[0] = ty;
+            body[1] = code;
+            body[4..8].copy_from_slice(&header);
+            body[8..].copy_from_slice(&data);
+            let checksum =
+                icmpv6::checksum(&Icmpv6Packet::new(body).unwrap(), &self.source, &self.dest);
+            MutableIcmpv6Packet::new(body)
+                .unwrap()
+                .set_checksum(checksum);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Packet, Packet6};
+    use std::net::{SocketAddrV4, SocketAddrV6};
+
+    #[test]
+    fn roundtrips_echo_messages() {
+        let icmp = Icmp {
+            source: Ipv4Addr::new(10, 0, 0, 1),
+            dest: Ipv4Addr::new(8, 8, 8, 8),
+            ttl: 64,
+            message: IcmpMessage::EchoRequest {
+                id: 7,
+                seq: 1,
+                data: b"ping".to_vec(),
+            },
+        };
+        let bytes = icmp.to_bytes();
+        let packet = IcmpPacket::new(&bytes[20..]).unwrap();
+        assert_eq!(packet.get_checksum(), icmp::checksum(&packet));
+        assert_eq!(Icmp::parse(&bytes), Some(icmp));
+        assert!(Icmp6::parse(&bytes).is_none());
+
+        let icmp6 = Icmp6 {
+            source: "fd00::2".parse().unwrap(),
+            dest: "64:ff9b::808:808".parse().unwrap(),
+            hop_limit: 64,
+            message: IcmpMessage::EchoReply {
+                id: 7,
+                seq: 1,
+                data: b"pong".to_vec(),
+            },
+        };
+        let bytes = icmp6.to_bytes();
+        assert_eq!(bytes[40], 129);
+        assert!(Icmp6::is_icmpv6(&bytes));
+        assert_eq!(Icmp6::parse(&bytes), Some(icmp6));
+    }
+
+    #[test]
+    fn parses_originals_of_errors() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000);
+        let dest = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+        let mut udp = Packet::build_udp(source, dest, &[0; 2000]);
+        // routers include as much of the original as fits
+        udp.truncate(MAX_ICMP_ERROR_LEN - 28);
+        let mut bytes = Icmp {
+            source: Ipv4Addr::new(1, 1, 1, 1),
+            dest: *source.ip(),
+            ttl: 64,
+            message: IcmpMessage::EchoReply {
+                id: 0,
+                seq: 0,
+                data: udp,
+            },
+        }
+        .to_bytes();
+        // turn the reply into a fragmentation needed error with next-hop MTU 1400
+        bytes[20..28].copy_from_slice(&[3, 4, 0, 0, 0, 0, 0x05, 0x78]);
+        let icmp = Icmp::parse(&bytes).unwrap();
+        let original = match &icmp.message {
+            IcmpMessage::PacketTooBig {
+                mtu: 1400,
+                original,
+            } => original,
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert_eq!(original.protocol, Protocol::Udp);
+        assert_eq!(original.source, source.into());
+        assert_eq!(original.dest, dest.into());
+        assert_eq!(icmp.to_bytes().len(), MAX_ICMP_ERROR_LEN);
+
+        let source = SocketAddrV6::new("fd00::2".parse().unwrap(), 5000, 0, 0);
+        let dest = SocketAddrV6::new("2001:db8::1".parse().unwrap(), 53, 0, 0);
+        let mut original = Original {
+            source: source.into(),
+            dest: dest.into(),
+            ..original.clone()
+        };
+        original.rest.resize(2000, 0);
+        let icmp6 = Icmp6 {
+            source: "2001:db8::ff".parse().unwrap(),
+            dest: *source.ip(),
+            hop_limit: 64,
+            message: IcmpMessage::Unreachable {
+                reason: Unreachable::Port,
+                original,
+            },
+        };
+        let bytes = icmp6.to_bytes();
+        assert_eq!(bytes.len(), MAX_ICMPV6_ERROR_LEN);
+        assert_eq!(&bytes[40..42], &[1, 4]);
+        let parsed = Icmp6::parse(&bytes).unwrap();
+        let original = parsed.message.original().unwrap();
+        assert_eq!(original.source, source.into());
+        assert_eq!(original.dest, dest.into());
+
+        // the original is a regular packet apart from being truncated
+        let mut inner = bytes[48..].to_vec();
+        let packet = Packet6::new(&mut inner).unwrap();
+        assert_eq!(packet.get_source(), source);
+        assert_eq!(packet.get_destination(), dest);
+    }
+}
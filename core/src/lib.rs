@@ -3,17 +3,19 @@ use futures::channel::mpsc;
 use futures::future::FutureExt;
 use futures::stream::{Stream, StreamExt};
 use std::collections::VecDeque;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
 mod addr;
+mod icmp;
 mod packet;
 mod range;
 
-pub use packet::{Packet, Protocol};
-pub use range::Ipv4Range;
+pub use icmp::{Icmp, Icmp6, IcmpMessage, Original, Unreachable};
+pub use packet::{Packet, Packet6, Protocol};
+pub use range::{embed_ipv4_addr, extract_ipv4_addr, is_nat64_prefix, Ipv4Range, Ipv6Range};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Ipv4Route {
@@ -50,6 +52,41 @@ impl From<Ipv4Addr> for Ipv4Route {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct Ipv6Route {
+    dest: Ipv6Range,
+    gateway: Option<Ipv6Addr>,
+}
+
+impl Ipv6Route {
+    /// Create a new route with the given destination and gateway.
+    pub fn new(dest: Ipv6Range, gateway: Option<Ipv6Addr>) -> Self {
+        Self { dest, gateway }
+    }
+
+    /// Returns the destination IP range of the route.
+    pub fn dest(&self) -> Ipv6Range {
+        self.dest
+    }
+
+    /// Returns the route's gateway (if any).
+    pub fn gateway(&self) -> Option<Ipv6Addr> {
+        self.gateway
+    }
+}
+
+impl From<Ipv6Range> for Ipv6Route {
+    fn from(range: Ipv6Range) -> Self {
+        Self::new(range, None)
+    }
+}
+
+impl From<Ipv6Addr> for Ipv6Route {
+    fn from(addr: Ipv6Addr) -> Self {
+        Self::new(addr.into(), None)
+    }
+}
+
 #[derive(Debug)]
 pub struct Plug {
     tx: mpsc::UnboundedSender<Vec<u8>>,
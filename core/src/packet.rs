@@ -1,11 +1,12 @@
 use libpacket::ip::IpNextHeaderProtocols;
 use libpacket::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use libpacket::ipv6::{Ipv6Packet, MutableIpv6Packet};
 use libpacket::tcp::{self, MutableTcpPacket, TcpPacket};
 use libpacket::udp::{self, MutableUdpPacket, UdpPacket};
 use libpacket::{MutablePacket, Packet as _};
-use std::net::SocketAddrV4;
+use std::net::{SocketAddrV4, SocketAddrV6};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Protocol {
     Udp,
     Tcp,
@@ -54,6 +55,20 @@ impl<'a> Packet<'a> {
         SocketAddrV4::new(ip, port)
     }
 
+    /// Returns the payload of the UDP datagram or TCP segment.
+    pub fn payload(&self) -> &[u8] {
+        let packet = Ipv4Packet::new(self.bytes).unwrap();
+        let header_len = match self.protocol {
+            Protocol::Udp => 8,
+            Protocol::Tcp => {
+                TcpPacket::new(packet.payload()).unwrap().get_data_offset() as usize * 4
+            }
+        };
+        let start = packet.get_header_length() as usize * 4 + header_len;
+        let end = (packet.get_total_length() as usize).min(self.bytes.len());
+        &self.bytes[start.min(end)..end]
+    }
+
     pub fn get_ttl(&self) -> u8 {
         Ipv4Packet::new(self.bytes).unwrap().get_ttl()
     }
@@ -96,6 +111,49 @@ impl<'a> Packet<'a> {
         MutableIpv4Packet::new(self.bytes).unwrap().set_ttl(ttl)
     }
 
+    /// Builds an IPv4 UDP packet carrying `payload`.
+    pub fn build_udp(source: SocketAddrV4, dest: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let udp_len = 8 + payload.len();
+        let mut bytes = vec![0; 20 + udp_len];
+        {
+            let mut packet = MutableIpv4Packet::new(&mut bytes).unwrap();
+            packet.set_version(4);
+            packet.set_header_length(5);
+            packet.set_total_length((20 + udp_len) as u16);
+            packet.set_ttl(64);
+            packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            let mut udp = MutableUdpPacket::new(packet.payload_mut()).unwrap();
+            udp.set_length(udp_len as u16);
+            udp.set_payload(payload);
+        }
+        let mut packet = Packet::new(&mut bytes).unwrap();
+        packet.set_source(source);
+        packet.set_destination(dest);
+        packet.set_checksum();
+        bytes
+    }
+
+    /// Translates the packet to an IPv6 packet between `source` and `dest` carrying the same
+    /// UDP datagram or TCP segment (RFC 7915). The hop limit is copied from the TTL.
+    pub fn to_ipv6(&self, source: SocketAddrV6, dest: SocketAddrV6) -> Vec<u8> {
+        let packet = Ipv4Packet::new(self.bytes).unwrap();
+        let segment = packet.payload();
+        let mut bytes = vec![0; 40 + segment.len()];
+        {
+            let mut packet6 = MutableIpv6Packet::new(&mut bytes).unwrap();
+            packet6.set_version(6);
+            packet6.set_payload_length(segment.len() as u16);
+            packet6.set_hop_limit(packet.get_ttl());
+            packet6.set_next_header(packet.get_next_level_protocol());
+            packet6.set_payload(segment);
+        }
+        let mut packet6 = Packet6::new(&mut bytes).unwrap();
+        packet6.set_source(source);
+        packet6.set_destination(dest);
+        packet6.set_checksum();
+        bytes
+    }
+
     pub fn set_checksum(&mut self) {
         let mut packet = MutableIpv4Packet::new(self.bytes).unwrap();
         let source = packet.get_source();
@@ -113,3 +171,166 @@ impl<'a> Packet<'a> {
         }
     }
 }
+
+/// A UDP or TCP packet carried in an IPv6 packet without extension headers.
+#[derive(Debug)]
+pub struct Packet6<'a> {
+    protocol: Protocol,
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Packet6<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Option<Self> {
+        let packet = Ipv6Packet::new(bytes)?;
+        let protocol = match packet.get_next_header() {
+            IpNextHeaderProtocols::Udp => {
+                UdpPacket::new(packet.payload())?;
+                Protocol::Udp
+            }
+            IpNextHeaderProtocols::Tcp => {
+                TcpPacket::new(packet.payload())?;
+                Protocol::Tcp
+            }
+            _ => return None,
+        };
+        Some(Self { protocol, bytes })
+    }
+
+    pub fn get_source(&self) -> SocketAddrV6 {
+        let packet = Ipv6Packet::new(self.bytes).unwrap();
+        let ip = packet.get_source();
+        let port = match self.protocol {
+            Protocol::Udp => UdpPacket::new(packet.payload()).unwrap().get_source(),
+            Protocol::Tcp => TcpPacket::new(packet.payload()).unwrap().get_source(),
+        };
+        SocketAddrV6::new(ip, port, 0, 0)
+    }
+
+    pub fn get_destination(&self) -> SocketAddrV6 {
+        let packet = Ipv6Packet::new(self.bytes).unwrap();
+        let ip = packet.get_destination();
+        let port = match self.protocol {
+            Protocol::Udp => UdpPacket::new(packet.payload()).unwrap().get_destination(),
+            Protocol::Tcp => TcpPacket::new(packet.payload()).unwrap().get_destination(),
+        };
+        SocketAddrV6::new(ip, port, 0, 0)
+    }
+
+    /// Returns the payload of the UDP datagram or TCP segment.
+    pub fn payload(&self) -> &[u8] {
+        let packet = Ipv6Packet::new(self.bytes).unwrap();
+        let header_len = match self.protocol {
+            Protocol::Udp => 8,
+            Protocol::Tcp => {
+                TcpPacket::new(packet.payload()).unwrap().get_data_offset() as usize * 4
+            }
+        };
+        let start = 40 + header_len;
+        let end = (40 + packet.get_payload_length() as usize).min(self.bytes.len());
+        &self.bytes[start.min(end)..end]
+    }
+
+    pub fn get_hop_limit(&self) -> u8 {
+        Ipv6Packet::new(self.bytes).unwrap().get_hop_limit()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_source(&mut self, addr: SocketAddrV6) {
+        let mut packet = MutableIpv6Packet::new(self.bytes).unwrap();
+        packet.set_source(*addr.ip());
+        match self.protocol {
+            Protocol::Udp => {
+                let mut udp = MutableUdpPacket::new(packet.payload_mut()).unwrap();
+                udp.set_source(addr.port());
+            }
+            Protocol::Tcp => {
+                let mut tcp = MutableTcpPacket::new(packet.payload_mut()).unwrap();
+                tcp.set_source(addr.port());
+            }
+        }
+    }
+
+    pub fn set_destination(&mut self, addr: SocketAddrV6) {
+        let mut packet = MutableIpv6Packet::new(self.bytes).unwrap();
+        packet.set_destination(*addr.ip());
+        match self.protocol {
+            Protocol::Udp => {
+                let mut udp = MutableUdpPacket::new(packet.payload_mut()).unwrap();
+                udp.set_destination(addr.port());
+            }
+            Protocol::Tcp => {
+                let mut tcp = MutableTcpPacket::new(packet.payload_mut()).unwrap();
+                tcp.set_destination(addr.port());
+            }
+        }
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        MutableIpv6Packet::new(self.bytes)
+            .unwrap()
+            .set_hop_limit(hop_limit)
+    }
+
+    /// Builds an IPv6 UDP packet carrying `payload`.
+    pub fn build_udp(source: SocketAddrV6, dest: SocketAddrV6, payload: &[u8]) -> Vec<u8> {
+        let udp_len = 8 + payload.len();
+        let mut bytes = vec![0; 40 + udp_len];
+        {
+            let mut packet = MutableIpv6Packet::new(&mut bytes).unwrap();
+            packet.set_version(6);
+            packet.set_payload_length(udp_len as u16);
+            packet.set_hop_limit(64);
+            packet.set_next_header(IpNextHeaderProtocols::Udp);
+            let mut udp = MutableUdpPacket::new(packet.payload_mut()).unwrap();
+            udp.set_length(udp_len as u16);
+            udp.set_payload(payload);
+        }
+        let mut packet = Packet6::new(&mut bytes).unwrap();
+        packet.set_source(source);
+        packet.set_destination(dest);
+        packet.set_checksum();
+        bytes
+    }
+
+    /// Translates the packet to an IPv4 packet between `source` and `dest` carrying the same
+    /// UDP datagram or TCP segment (RFC 7915). The TTL is copied from the hop limit.
+    pub fn to_ipv4(&self, source: SocketAddrV4, dest: SocketAddrV4) -> Vec<u8> {
+        let packet6 = Ipv6Packet::new(self.bytes).unwrap();
+        let segment = packet6.payload();
+        let mut bytes = vec![0; 20 + segment.len()];
+        {
+            let mut packet = MutableIpv4Packet::new(&mut bytes).unwrap();
+            packet.set_version(4);
+            packet.set_header_length(5);
+            packet.set_total_length((20 + segment.len()) as u16);
+            packet.set_ttl(packet6.get_hop_limit());
+            packet.set_next_level_protocol(packet6.get_next_header());
+            packet.set_payload(segment);
+        }
+        let mut packet = Packet::new(&mut bytes).unwrap();
+        packet.set_source(source);
+        packet.set_destination(dest);
+        packet.set_checksum();
+        bytes
+    }
+
+    /// Sets the UDP or TCP checksum, IPv6 headers don't have a checksum.
+    pub fn set_checksum(&mut self) {
+        let mut packet = MutableIpv6Packet::new(self.bytes).unwrap();
+        let source = packet.get_source();
+        let dest = packet.get_destination();
+        match self.protocol {
+            Protocol::Udp => {
+                let mut udp = MutableUdpPacket::new(packet.payload_mut()).unwrap();
+                udp.set_checksum(udp::ipv6_checksum(&udp.to_immutable(), &source, &dest));
+            }
+            Protocol::Tcp => {
+                let mut tcp = MutableTcpPacket::new(packet.payload_mut()).unwrap();
+                tcp.set_checksum(tcp::ipv6_checksum(&tcp.to_immutable(), &source, &dest));
+            }
+        }
+    }
+}
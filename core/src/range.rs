@@ -1,5 +1,5 @@
 use crate::addr::{Ipv4AddrClass, Ipv4AddrExt};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use thiserror::Error;
 
@@ -187,6 +187,191 @@ impl Ipv4Range {
     }
 }
 
+/// A range of IPv6 addresses with a common prefix
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Range {
+    addr: Ipv6Addr,
+    bits: u8,
+}
+
+impl std::fmt::Debug for Ipv6Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.bits)
+    }
+}
+
+impl Ipv6Range {
+    /// Create an IPv6 range with the given base address and netmask prefix length.
+    ///
+    /// # Example
+    ///
+    /// Create the subnet fd00::/64 with `Ipv6Range::new("fd00::".parse().unwrap(), 64)`
+    pub fn new(addr: Ipv6Addr, bits: u8) -> Self {
+        let mask = !((!0u128).checked_shr(u32::from(bits)).unwrap_or(0));
+        Ipv6Range {
+            addr: Ipv6Addr::from(u128::from(addr) & mask),
+            bits,
+        }
+    }
+
+    /// Return the entire IPv6 range, eg. ::/0
+    pub fn global() -> Self {
+        Ipv6Range {
+            addr: Ipv6Addr::UNSPECIFIED,
+            bits: 0,
+        }
+    }
+
+    /// Returns the global unicast range 2000::/3
+    pub fn global_unicast() -> Self {
+        Ipv6Range {
+            addr: Ipv6Addr::new(0x2000, 0, 0, 0, 0, 0, 0, 0),
+            bits: 3,
+        }
+    }
+
+    /// Returns the NAT64 well-known prefix 64:ff9b::/96
+    pub fn nat64_well_known_prefix() -> Self {
+        Ipv6Range {
+            addr: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+            bits: 96,
+        }
+    }
+
+    /// Returns the unique local subnet fd00:0:0:x::/64 where x is given by `subnet`.
+    pub fn unique_local_subnet(subnet: u16) -> Self {
+        Ipv6Range {
+            addr: Ipv6Addr::new(0xfd00, 0, 0, subnet, 0, 0, 0, 0),
+            bits: 64,
+        }
+    }
+
+    /// Returns a random unique local subnet from the range fd00::/48
+    pub fn random_unique_local_subnet() -> Self {
+        Ipv6Range::unique_local_subnet(rand::random())
+    }
+
+    /// Get the netmask as an IP address
+    pub fn netmask(&self) -> Ipv6Addr {
+        Ipv6Addr::from(!((!0u128).checked_shr(u32::from(self.bits)).unwrap_or(0)))
+    }
+
+    /// Get the number of netmask prefix bits
+    pub fn netmask_prefix_length(&self) -> u8 {
+        self.bits
+    }
+
+    /// Get the base address of the range, ie. the lowest IP address which is part of the range.
+    pub fn base_addr(&self) -> Ipv6Addr {
+        self.addr
+    }
+
+    /// Get a default IP address for the range's gateway. This is one higher than the base address
+    /// of the range. eg. for fd00::/64, the default address for the gateway will be fd00::1
+    pub fn gateway_addr(&self) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.addr) | 1)
+    }
+
+    /// Get a random IP address from the range which is not the base address or the default
+    /// for the gateway address.
+    pub fn random_client_addr(&self) -> Ipv6Addr {
+        let mask = (!0u128).checked_shr(u32::from(self.bits)).unwrap_or(0);
+        assert!(mask > 1);
+        loop {
+            let x = rand::random::<u128>() & mask;
+            if x < 2 {
+                continue;
+            }
+            return Ipv6Addr::from(u128::from(self.addr) | x);
+        }
+    }
+
+    /// Generate an IP address for a device.
+    pub fn address_for(&self, device: u32) -> Ipv6Addr {
+        let mask = (!0u128).checked_shr(u32::from(self.bits)).unwrap_or(0);
+        assert!(mask > 1);
+        Ipv6Addr::from(u128::from(self.addr) | ((u128::from(device) + 2) & mask))
+    }
+
+    /// Check whether this range contains the given IP address
+    pub fn contains(&self, ip: Ipv6Addr) -> bool {
+        let base_addr = u128::from(self.addr);
+        let test_addr = u128::from(ip);
+        (base_addr ^ test_addr).leading_zeros() >= u32::from(self.bits)
+    }
+
+    /// Split a range into `num` sub-ranges
+    ///
+    /// # Panics
+    ///
+    /// If the range is too small to be split up that much.
+    pub fn split(self, num: u32) -> Vec<Self> {
+        assert!(num > 0);
+        let extra_bits = (32 - (num - 1).leading_zeros()) as u8;
+        let bits = self.bits + extra_bits;
+        assert!(bits <= 128);
+        (0..num)
+            .map(|n| {
+                let offset = u128::from(n)
+                    .checked_shl(128 - u32::from(bits))
+                    .unwrap_or(0);
+                Ipv6Range {
+                    addr: Ipv6Addr::from(u128::from(self.addr) | offset),
+                    bits,
+                }
+            })
+            .collect()
+    }
+}
+
+const NAT64_PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+/// Returns `true` if the prefix length is one of 32, 40, 48, 56, 64 or 96, which RFC 6052
+/// allows for embedding IPv4 addresses.
+pub fn is_nat64_prefix(prefix: Ipv6Range) -> bool {
+    NAT64_PREFIX_LENGTHS.contains(&prefix.netmask_prefix_length())
+}
+
+/// Embeds an IPv4 address in an IPv6 prefix (RFC 6052 section 2.2), eg. the address a DNS64
+/// server synthesizes for an IPv4-only host.
+///
+/// # Panics
+///
+/// If the prefix length is not one of 32, 40, 48, 56, 64 or 96.
+pub fn embed_ipv4_addr(prefix: Ipv6Range, addr: Ipv4Addr) -> Ipv6Addr {
+    assert!(is_nat64_prefix(prefix));
+    let mut octets = prefix.base_addr().octets();
+    let mut pos = usize::from(prefix.netmask_prefix_length() / 8);
+    for octet in addr.octets() {
+        // bits 64 to 71 are reserved
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = octet;
+        pos += 1;
+    }
+    Ipv6Addr::from(octets)
+}
+
+/// Extracts the IPv4 address embedded in an IPv6 address, returns `None` if the address is not
+/// part of the prefix.
+pub fn extract_ipv4_addr(prefix: Ipv6Range, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !prefix.contains(addr) || !is_nat64_prefix(prefix) {
+        return None;
+    }
+    let octets = addr.octets();
+    let mut pos = usize::from(prefix.netmask_prefix_length() / 8);
+    let mut addr = [0; 4];
+    for octet in &mut addr {
+        if pos == 8 {
+            pos += 1;
+        }
+        *octet = octets[pos];
+        pos += 1;
+    }
+    Some(Ipv4Addr::from(addr))
+}
+
 /// Errors returned by `SubnetV*::from_str`
 #[derive(Debug, Error)]
 pub enum IpRangeParseError {
@@ -235,6 +420,37 @@ impl From<Ipv4Addr> for Ipv4Range {
     }
 }
 
+impl FromStr for Ipv6Range {
+    type Err = IpRangeParseError;
+
+    fn from_str(s: &str) -> Result<Ipv6Range, IpRangeParseError> {
+        let mut split = s.split('/');
+        let addr = split.next().unwrap();
+        let bits = match split.next() {
+            Some(bits) => bits,
+            None => return Err(IpRangeParseError::MissingDelimiter),
+        };
+        if split.next().is_some() {
+            return Err(IpRangeParseError::ExtraDelimiter);
+        }
+        let addr = match Ipv6Addr::from_str(addr) {
+            Ok(addr) => addr,
+            Err(e) => return Err(IpRangeParseError::ParseAddr(e)),
+        };
+        let bits = match u8::from_str(bits) {
+            Ok(bits) => bits,
+            Err(e) => return Err(IpRangeParseError::ParseNetmaskPrefixLength(e)),
+        };
+        Ok(Ipv6Range::new(addr, bits))
+    }
+}
+
+impl From<Ipv6Addr> for Ipv6Range {
+    fn from(addr: Ipv6Addr) -> Self {
+        Self::new(addr, 128)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,4 +463,41 @@ mod tests {
         assert!(addrs.contains("1.2.3.255".parse().unwrap()));
         assert!(!addrs.contains("1.2.4.5".parse().unwrap()));
     }
+
+    #[test]
+    fn it_creates_ipv6_address_range() {
+        let addrs: Ipv6Range = "2001:db8::/32".parse().unwrap();
+
+        assert!(addrs.contains("2001:db8::5".parse().unwrap()));
+        assert!(addrs.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!addrs.contains("2001:db9::5".parse().unwrap()));
+        assert_eq!(
+            addrs.address_for(0),
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let halves = addrs.split(2);
+        assert_eq!(
+            halves[1].base_addr(),
+            "2001:db8:8000::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(halves[1].netmask_prefix_length(), 33);
+    }
+
+    #[test]
+    fn embeds_ipv4_addrs() {
+        let addr = Ipv4Addr::new(192, 0, 2, 33);
+        let prefix = Ipv6Range::nat64_well_known_prefix();
+        let embedded = embed_ipv4_addr(prefix, addr);
+        assert_eq!(embedded, "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(extract_ipv4_addr(prefix, embedded), Some(addr));
+
+        let prefix = "2001:db8:122::/48".parse().unwrap();
+        let embedded = embed_ipv4_addr(prefix, addr);
+        assert_eq!(
+            embedded,
+            "2001:db8:122:c000:2:2100::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(extract_ipv4_addr(prefix, embedded), Some(addr));
+    }
 }
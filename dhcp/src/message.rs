@@ -0,0 +1,208 @@
+//! Encoding and decoding of DHCP messages (RFC 2131, RFC 2132).
+use std::net::Ipv4Addr;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+
+pub const MAGIC_COOKIE: u32 = 0x6382_5363;
+
+/// Flag asking the server to broadcast its replies.
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER: u8 = 2;
+pub const DHCPREQUEST: u8 = 3;
+pub const DHCPDECLINE: u8 = 4;
+pub const DHCPACK: u8 = 5;
+pub const DHCPNAK: u8 = 6;
+pub const DHCPRELEASE: u8 = 7;
+pub const DHCPINFORM: u8 = 8;
+
+pub const PAD: u8 = 0;
+pub const SUBNET_MASK: u8 = 1;
+pub const ROUTER: u8 = 3;
+pub const DOMAIN_NAME_SERVER: u8 = 6;
+pub const REQUESTED_IP_ADDRESS: u8 = 50;
+pub const LEASE_TIME: u8 = 51;
+pub const MESSAGE_TYPE: u8 = 53;
+pub const SERVER_IDENTIFIER: u8 = 54;
+pub const RENEWAL_TIME: u8 = 58;
+pub const REBINDING_TIME: u8 = 59;
+pub const END: u8 = 255;
+
+const HTYPE_ETHERNET: u8 = 1;
+
+/// Length of the fixed part of a message up to and including the magic cookie.
+const HEADER_LEN: usize = 240;
+
+/// BOOTP relays drop shorter messages.
+const MIN_LEN: usize = 300;
+
+/// A DHCP message with an ethernet client hardware address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; 6],
+    options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Message {
+    /// Creates a message of type `ty` without further options.
+    pub fn new(op: u8, ty: u8, xid: u32, chaddr: [u8; 6]) -> Self {
+        Self {
+            op,
+            xid,
+            flags: 0,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            options: vec![(MESSAGE_TYPE, vec![ty])],
+        }
+    }
+
+    /// Parses a message, returns `None` if `bytes` is not a DHCP message of an ethernet client.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[1] != HTYPE_ETHERNET || bytes[2] != 6 {
+            return None;
+        }
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if u32_at(236) != MAGIC_COOKIE {
+            return None;
+        }
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&bytes[28..34]);
+        let mut options = Vec::new();
+        let mut rest = &bytes[HEADER_LEN..];
+        loop {
+            match rest.first() {
+                None | Some(&END) => break,
+                Some(&PAD) => rest = &rest[1..],
+                Some(&code) => {
+                    let len = *rest.get(1)? as usize;
+                    if rest.len() < 2 + len {
+                        return None;
+                    }
+                    options.push((code, rest[2..2 + len].to_vec()));
+                    rest = &rest[2 + len..];
+                }
+            }
+        }
+        let message = Self {
+            op: bytes[0],
+            xid: u32_at(4),
+            flags: u16::from_be_bytes([bytes[10], bytes[11]]),
+            ciaddr: u32_at(12).into(),
+            yiaddr: u32_at(16).into(),
+            siaddr: u32_at(20).into(),
+            giaddr: u32_at(24).into(),
+            chaddr,
+            options,
+        };
+        message.ty()?;
+        Some(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_LEN];
+        bytes[0] = self.op;
+        bytes[1] = HTYPE_ETHERNET;
+        bytes[2] = 6;
+        bytes[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.ciaddr.octets());
+        bytes[16..20].copy_from_slice(&self.yiaddr.octets());
+        bytes[20..24].copy_from_slice(&self.siaddr.octets());
+        bytes[24..28].copy_from_slice(&self.giaddr.octets());
+        bytes[28..34].copy_from_slice(&self.chaddr);
+        bytes[236..240].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        for (code, value) in &self.options {
+            bytes.push(*code);
+            bytes.push(value.len() as u8);
+            bytes.extend_from_slice(value);
+        }
+        bytes.push(END);
+        bytes.resize(bytes.len().max(MIN_LEN), PAD);
+        bytes
+    }
+
+    /// Returns the DHCP message type, `None` for BOOTP messages.
+    pub fn ty(&self) -> Option<u8> {
+        self.option(MESSAGE_TYPE)?.first().copied()
+    }
+
+    /// Returns the value of the first option with `code`.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == code)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Returns the value of an option carrying a single address.
+    pub fn addr_option(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code)? {
+            [a, b, c, d] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a 32-bit integer option.
+    pub fn u32_option(&self, code: u8) -> Option<u32> {
+        match self.option(code)? {
+            [a, b, c, d] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+            _ => None,
+        }
+    }
+
+    /// Appends an option, values longer than 255 bytes are truncated.
+    pub fn add_option(&mut self, code: u8, mut value: Vec<u8>) {
+        value.truncate(u8::MAX as usize);
+        self.options.push((code, value));
+    }
+
+    pub fn add_addr_option(&mut self, code: u8, addrs: &[Ipv4Addr]) {
+        let value = addrs.iter().flat_map(|addr| addr.octets()).collect();
+        self.add_option(code, value);
+    }
+
+    pub fn add_u32_option(&mut self, code: u8, value: u32) {
+        self.add_option(code, value.to_be_bytes().to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_parses_messages() {
+        let mut message = Message::new(BOOTREPLY, DHCPOFFER, 0x1234_5678, [2, 0, 0, 0, 0, 1]);
+        message.flags = FLAG_BROADCAST;
+        message.yiaddr = Ipv4Addr::new(10, 0, 0, 5);
+        message.add_addr_option(SERVER_IDENTIFIER, &[Ipv4Addr::new(10, 0, 0, 2)]);
+        message.add_u32_option(LEASE_TIME, 3600);
+        let bytes = message.encode();
+        assert_eq!(bytes.len(), MIN_LEN);
+        let parsed = Message::parse(&bytes).unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.ty(), Some(DHCPOFFER));
+        assert_eq!(
+            parsed.addr_option(SERVER_IDENTIFIER),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        assert_eq!(parsed.u32_option(LEASE_TIME), Some(3600));
+        assert!(Message::parse(&bytes[..HEADER_LEN - 1]).is_none());
+    }
+}
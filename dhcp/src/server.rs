@@ -0,0 +1,555 @@
+use crate::message::*;
+use futures::channel::{mpsc, oneshot};
+use futures::future::Future;
+use futures::stream::Stream;
+use netsim_embed_core::{Ipv4Range, Packet, Plug, Protocol};
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+const ETH_HDR_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(3600);
+/// How long an offered address is reserved for the client it was offered to.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An address bound to a client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DhcpLease {
+    pub mac: [u8; 6],
+    pub addr: Ipv4Addr,
+    pub expires: Instant,
+}
+
+#[derive(Debug)]
+struct Binding {
+    addr: Ipv4Addr,
+    expires: Instant,
+    /// Whether the address was acknowledged or only offered.
+    bound: bool,
+}
+
+#[derive(Debug)]
+enum DhcpCtrl {
+    Leases(oneshot::Sender<Vec<DhcpLease>>),
+    Revoke([u8; 6]),
+    SetLeaseTime(Duration),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    offered: AtomicUsize,
+    acked: AtomicUsize,
+    nacked: AtomicUsize,
+    released: AtomicUsize,
+    invalid: AtomicUsize,
+}
+
+fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// An in-process DHCPv4 server (RFC 2131) attached to a switch port.
+///
+/// Addresses are leased from a fixed pool. A client is offered the address it was bound to
+/// before as long as it wasn't handed to another client, addresses of expired leases are only
+/// reclaimed once the pool is exhausted. The server answers ARP requests for its own address so
+/// clients can unicast renewals to it.
+#[derive(Debug)]
+pub struct DhcpServer {
+    plug: Plug,
+    addr: Ipv4Addr,
+    mac: [u8; 6],
+    range: Ipv4Range,
+    free: VecDeque<Ipv4Addr>,
+    bindings: HashMap<[u8; 6], Binding>,
+    lease_time: Duration,
+    router: Option<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    ctrl_tx: mpsc::UnboundedSender<DhcpCtrl>,
+    ctrl_rx: mpsc::UnboundedReceiver<DhcpCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl DhcpServer {
+    /// Creates a server with address `addr` and hardware address `mac` in `range`, which leases
+    /// the addresses of `pool`.
+    pub fn new(
+        plug: Plug,
+        addr: Ipv4Addr,
+        mac: [u8; 6],
+        range: Ipv4Range,
+        pool: Vec<Ipv4Addr>,
+    ) -> Self {
+        let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
+        Self {
+            plug,
+            addr,
+            mac,
+            range,
+            free: pool.into(),
+            bindings: Default::default(),
+            lease_time: DEFAULT_LEASE_TIME,
+            router: None,
+            dns_servers: vec![],
+            ctrl_tx,
+            ctrl_rx,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns a handle to inspect and reconfigure the server after it was spawned.
+    pub fn handle(&self) -> DhcpHandle {
+        DhcpHandle {
+            ctrl: self.ctrl_tx.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Sets the lease time of new leases and renewals, clients renew after half of it.
+    pub fn set_lease_time(&mut self, lease_time: Duration) {
+        self.lease_time = lease_time;
+    }
+
+    /// Sets the default gateway announced to clients.
+    pub fn set_router(&mut self, router: Option<Ipv4Addr>) {
+        self.router = router;
+    }
+
+    /// Sets the DNS servers announced to clients.
+    pub fn set_dns_servers(&mut self, dns_servers: Vec<Ipv4Addr>) {
+        self.dns_servers = dns_servers;
+    }
+
+    /// Returns the active leases.
+    pub fn leases(&self) -> Vec<DhcpLease> {
+        let now = Instant::now();
+        let mut leases = self
+            .bindings
+            .iter()
+            .filter(|(_, binding)| binding.bound && binding.expires > now)
+            .map(|(mac, binding)| DhcpLease {
+                mac: *mac,
+                addr: binding.addr,
+                expires: binding.expires,
+            })
+            .collect::<Vec<_>>();
+        leases.sort_by_key(|lease| lease.addr);
+        leases
+    }
+
+    /// Forgets the lease of a client, its next renewal is refused and it is offered a different
+    /// address if the pool has one.
+    pub fn revoke(&mut self, mac: [u8; 6]) {
+        if let Some(binding) = self.bindings.remove(&mac) {
+            self.free.push_back(binding.addr);
+        }
+    }
+
+    /// Returns the address the client is bound to or an address from the pool, reserving it for
+    /// the client.
+    fn allocate(&mut self, mac: [u8; 6], now: Instant) -> Option<Ipv4Addr> {
+        if let Some(binding) = self.bindings.get_mut(&mac) {
+            if !binding.bound || binding.expires <= now {
+                binding.bound = false;
+                binding.expires = now + OFFER_TIMEOUT;
+            }
+            return Some(binding.addr);
+        }
+        let addr = match self.free.pop_front() {
+            Some(addr) => addr,
+            None => {
+                let expired = self
+                    .bindings
+                    .iter()
+                    .filter(|(_, binding)| binding.expires <= now)
+                    .min_by_key(|(_, binding)| binding.expires)
+                    .map(|(mac, _)| *mac)?;
+                self.bindings.remove(&expired).unwrap().addr
+            }
+        };
+        self.bindings.insert(
+            mac,
+            Binding {
+                addr,
+                expires: now + OFFER_TIMEOUT,
+                bound: false,
+            },
+        );
+        Some(addr)
+    }
+
+    fn reply(&self, request: &Message, ty: u8) -> Message {
+        let mut reply = Message::new(BOOTREPLY, ty, request.xid, request.chaddr);
+        reply.flags = request.flags;
+        reply.giaddr = request.giaddr;
+        reply.add_addr_option(SERVER_IDENTIFIER, &[self.addr]);
+        reply
+    }
+
+    /// Builds an offer or acknowledgement of `addr`, without lease for `DHCPINFORM`.
+    fn configure(&self, request: &Message, ty: u8, addr: Option<Ipv4Addr>) -> Message {
+        let mut reply = self.reply(request, ty);
+        reply.siaddr = self.addr;
+        if ty == DHCPACK {
+            reply.ciaddr = request.ciaddr;
+        }
+        if let Some(addr) = addr {
+            let secs = self.lease_time.as_secs().min(u32::MAX as u64) as u32;
+            reply.yiaddr = addr;
+            reply.add_u32_option(LEASE_TIME, secs);
+            reply.add_u32_option(RENEWAL_TIME, secs / 2);
+            reply.add_u32_option(REBINDING_TIME, secs / 8 * 7);
+        }
+        reply.add_addr_option(SUBNET_MASK, &[self.range.netmask()]);
+        if let Some(router) = self.router {
+            reply.add_addr_option(ROUTER, &[router]);
+        }
+        if !self.dns_servers.is_empty() {
+            reply.add_addr_option(DOMAIN_NAME_SERVER, &self.dns_servers);
+        }
+        reply
+    }
+
+    fn nak(&self, request: &Message) -> Message {
+        inc(&self.counters.nacked);
+        self.reply(request, DHCPNAK)
+    }
+
+    fn handle_request(&mut self, request: &Message) -> Option<Message> {
+        let now = Instant::now();
+        let server_id = request.addr_option(SERVER_IDENTIFIER);
+        match request.ty()? {
+            DHCPDISCOVER => {
+                let addr = self.allocate(request.chaddr, now);
+                if addr.is_none() {
+                    log::info!("dhcp {}: pool exhausted", self.addr);
+                }
+                inc(&self.counters.offered);
+                Some(self.configure(request, DHCPOFFER, Some(addr?)))
+            }
+            DHCPREQUEST => {
+                if server_id.is_some() && server_id != Some(self.addr) {
+                    // the client accepted another server's offer
+                    if matches!(self.bindings.get(&request.chaddr), Some(binding) if !binding.bound)
+                    {
+                        self.revoke(request.chaddr);
+                    }
+                    return None;
+                }
+                let requested = request
+                    .addr_option(REQUESTED_IP_ADDRESS)
+                    .unwrap_or(request.ciaddr);
+                let lease_time = self.lease_time;
+                let taken = self.is_bound(requested, now);
+                match self.bindings.get_mut(&request.chaddr) {
+                    Some(binding) if binding.addr == requested => {
+                        binding.bound = true;
+                        binding.expires = now + lease_time;
+                        inc(&self.counters.acked);
+                        Some(self.configure(request, DHCPACK, Some(requested)))
+                    }
+                    // a rebooting client we don't know of may be served by another server
+                    None if server_id.is_none()
+                        && request.ciaddr.is_unspecified()
+                        && self.range.contains(requested)
+                        && !taken =>
+                    {
+                        None
+                    }
+                    _ => Some(self.nak(request)),
+                }
+            }
+            DHCPDECLINE => {
+                // the address is in use, it is taken out of the pool
+                if server_id == Some(self.addr) {
+                    if let Some(binding) = self.bindings.remove(&request.chaddr) {
+                        log::info!("dhcp {}: {} was declined", self.addr, binding.addr);
+                    }
+                }
+                None
+            }
+            DHCPRELEASE => {
+                if matches!(self.bindings.get(&request.chaddr), Some(binding) if binding.addr == request.ciaddr)
+                {
+                    inc(&self.counters.released);
+                    self.revoke(request.chaddr);
+                }
+                None
+            }
+            DHCPINFORM => {
+                inc(&self.counters.acked);
+                Some(self.configure(request, DHCPACK, None))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_bound(&self, addr: Ipv4Addr, now: Instant) -> bool {
+        self.bindings
+            .values()
+            .any(|binding| binding.addr == addr && binding.expires > now)
+    }
+
+    /// Sends a reply to a client as described in section 4.1 of RFC 2131, `source` is the
+    /// hardware address the request was received from.
+    fn send_reply(&mut self, source: [u8; 6], request: &Message, reply: &Message) {
+        let (dest, mac) = if !request.giaddr.is_unspecified() {
+            (SocketAddrV4::new(request.giaddr, SERVER_PORT), source)
+        } else if reply.ty() == Some(DHCPNAK) {
+            (
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT),
+                BROADCAST_MAC,
+            )
+        } else if !request.ciaddr.is_unspecified() {
+            (SocketAddrV4::new(request.ciaddr, CLIENT_PORT), source)
+        } else if request.flags & FLAG_BROADCAST != 0 {
+            (
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT),
+                BROADCAST_MAC,
+            )
+        } else {
+            (SocketAddrV4::new(reply.yiaddr, CLIENT_PORT), request.chaddr)
+        };
+        let source = SocketAddrV4::new(self.addr, SERVER_PORT);
+        let packet = Packet::build_udp(source, dest, &reply.encode());
+        self.send_frame(mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    fn send_frame(&mut self, dest: [u8; 6], ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETH_HDR_LEN + payload.len());
+        frame.extend_from_slice(&dest);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.plug.unbounded_send(frame);
+    }
+
+    fn process_arp(&mut self, arp: &[u8]) {
+        if arp.len() < ARP_LEN
+            || u16::from_be_bytes([arp[6], arp[7]]) != ARP_REQUEST
+            || arp[24..28] != self.addr.octets()
+        {
+            return;
+        }
+        let mut reply = arp[..ARP_LEN].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&self.mac);
+        reply[14..18].copy_from_slice(&self.addr.octets());
+        reply[18..28].copy_from_slice(&arp[8..18]);
+        let mut dest = [0; 6];
+        dest.copy_from_slice(&arp[8..14]);
+        self.send_frame(dest, ETHERTYPE_ARP, &reply);
+    }
+
+    fn process_ipv4(&mut self, source: [u8; 6], mut bytes: Vec<u8>) {
+        let packet = match Packet::new(&mut bytes) {
+            Some(packet) if packet.protocol() == Protocol::Udp => packet,
+            _ => return,
+        };
+        let dest = packet.get_destination();
+        if dest.port() != SERVER_PORT || !(dest.ip().is_broadcast() || *dest.ip() == self.addr) {
+            return;
+        }
+        let request = match Message::parse(packet.payload()) {
+            Some(request) if request.op == BOOTREQUEST => request,
+            _ => {
+                log::debug!("dhcp {}: dropping invalid request", self.addr);
+                inc(&self.counters.invalid);
+                return;
+            }
+        };
+        log::trace!(
+            "dhcp {}: request {:?} from {:02x?}",
+            self.addr,
+            request.ty(),
+            request.chaddr
+        );
+        if let Some(reply) = self.handle_request(&request) {
+            self.send_reply(source, &request, &reply);
+        }
+    }
+
+    fn process_ctrl(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(ctrl)) = Pin::new(&mut self.ctrl_rx).poll_next(cx) {
+            log::debug!("dhcp {}: CTRL {:?}", self.addr, ctrl);
+            match ctrl {
+                DhcpCtrl::Leases(tx) => {
+                    tx.send(self.leases()).ok();
+                }
+                DhcpCtrl::Revoke(mac) => self.revoke(mac),
+                DhcpCtrl::SetLeaseTime(lease_time) => self.set_lease_time(lease_time),
+            }
+        }
+    }
+}
+
+impl Future for DhcpServer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            // reconfigurations apply to the frames sent after them
+            self.process_ctrl(cx);
+            let frame = match self.plug.poll_incoming(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Ready(Some(frame)) => frame,
+            };
+            if frame.len() < ETH_HDR_LEN || !(frame[..6] == self.mac || frame[..6] == BROADCAST_MAC)
+            {
+                continue;
+            }
+            let mut source = [0; 6];
+            source.copy_from_slice(&frame[6..12]);
+            match u16::from_be_bytes([frame[12], frame[13]]) {
+                ETHERTYPE_ARP => self.process_arp(&frame[ETH_HDR_LEN..]),
+                ETHERTYPE_IPV4 => self.process_ipv4(source, frame[ETH_HDR_LEN..].to_vec()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Handle to inspect and reconfigure a running `DhcpServer`.
+#[derive(Clone, Debug)]
+pub struct DhcpHandle {
+    ctrl: mpsc::UnboundedSender<DhcpCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl DhcpHandle {
+    /// Returns the active leases.
+    pub async fn leases(&self) -> Vec<DhcpLease> {
+        let (tx, rx) = oneshot::channel();
+        self.send(DhcpCtrl::Leases(tx));
+        rx.await.unwrap_or_default()
+    }
+
+    /// Forgets the lease of a client, its next renewal is refused.
+    pub fn revoke(&self, mac: [u8; 6]) {
+        self.send(DhcpCtrl::Revoke(mac));
+    }
+
+    /// Sets the lease time of new leases and renewals.
+    pub fn set_lease_time(&self, lease_time: Duration) {
+        self.send(DhcpCtrl::SetLeaseTime(lease_time));
+    }
+
+    fn send(&self, ctrl: DhcpCtrl) {
+        self.ctrl.unbounded_send(ctrl).ok();
+    }
+
+    /// Number of offers sent in response to discovers.
+    pub fn offered(&self) -> usize {
+        self.counters.offered.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that were acknowledged, including renewals.
+    pub fn acked(&self) -> usize {
+        self.counters.acked.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that were refused.
+    pub fn nacked(&self) -> usize {
+        self.counters.nacked.load(Ordering::Relaxed)
+    }
+
+    /// Number of leases released by their clients.
+    pub fn released(&self) -> usize {
+        self.counters.released.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets to the server port dropped because they could not be parsed.
+    pub fn invalid(&self) -> usize {
+        self.counters.invalid.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::wire;
+
+    const CLIENT_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+    fn request(ty: u8, ciaddr: Ipv4Addr, requested: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut message = Message::new(BOOTREQUEST, ty, 42, CLIENT_MAC);
+        message.ciaddr = ciaddr;
+        if let Some(requested) = requested {
+            message.add_addr_option(REQUESTED_IP_ADDRESS, &[requested]);
+        }
+        let packet = Packet::build_udp(
+            SocketAddrV4::new(ciaddr, CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT),
+            &message.encode(),
+        );
+        let mut frame = BROADCAST_MAC.to_vec();
+        frame.extend_from_slice(&CLIENT_MAC);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&packet);
+        frame
+    }
+
+    async fn reply(plug: &mut Plug) -> Message {
+        let mut frame = plug.incoming().await.unwrap();
+        let packet = Packet::new(&mut frame[ETH_HDR_LEN..]).unwrap();
+        Message::parse(packet.payload()).unwrap()
+    }
+
+    #[async_std::test]
+    async fn leases_and_revokes_addresses() {
+        let (mut client, server_plug) = wire();
+        let range = Ipv4Range::local_subnet_10();
+        let server_addr = range.address_for(0);
+        let pool = vec![range.address_for(1), range.address_for(2)];
+        let server = DhcpServer::new(server_plug, server_addr, [2, 0xff, 0, 0, 0, 0], range, pool);
+        let handle = server.handle();
+        async_std::task::spawn(server);
+
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        client.unbounded_send(request(DHCPDISCOVER, unspecified, None));
+        let offer = reply(&mut client).await;
+        assert_eq!(offer.ty(), Some(DHCPOFFER));
+        assert_eq!(offer.yiaddr, range.address_for(1));
+        assert_eq!(offer.addr_option(SERVER_IDENTIFIER), Some(server_addr));
+        assert_eq!(offer.u32_option(LEASE_TIME), Some(3600));
+
+        client.unbounded_send(request(DHCPREQUEST, unspecified, Some(offer.yiaddr)));
+        let ack = reply(&mut client).await;
+        assert_eq!(ack.ty(), Some(DHCPACK));
+        assert_eq!(ack.yiaddr, offer.yiaddr);
+        let leases = handle.leases().await;
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].mac, CLIENT_MAC);
+        assert_eq!(leases[0].addr, offer.yiaddr);
+
+        handle.set_lease_time(Duration::from_secs(60));
+        client.unbounded_send(request(DHCPREQUEST, offer.yiaddr, None));
+        let ack = reply(&mut client).await;
+        assert_eq!(ack.ty(), Some(DHCPACK));
+        assert_eq!(ack.u32_option(LEASE_TIME), Some(60));
+        assert_eq!(ack.u32_option(RENEWAL_TIME), Some(30));
+
+        handle.revoke(CLIENT_MAC);
+        client.unbounded_send(request(DHCPREQUEST, offer.yiaddr, None));
+        assert_eq!(reply(&mut client).await.ty(), Some(DHCPNAK));
+        client.unbounded_send(request(DHCPDISCOVER, unspecified, None));
+        assert_eq!(reply(&mut client).await.yiaddr, range.address_for(2));
+        assert!(handle.leases().await.is_empty());
+        assert_eq!(handle.offered(), 2);
+        assert_eq!(handle.acked(), 2);
+        assert_eq!(handle.nacked(), 1);
+    }
+}
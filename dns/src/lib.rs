@@ -0,0 +1,4 @@
+pub mod message;
+mod server;
+
+pub use server::{DnsHandle, DnsServer};
@@ -0,0 +1,185 @@
+//! Decoding of DNS queries and encoding of responses (RFC 1035, RFC 3596).
+use std::net::IpAddr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_ANY: u16 = 255;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+
+const HEADER_LEN: usize = 12;
+
+/// Responses over UDP are truncated to this size.
+const MAX_UDP_LEN: usize = 512;
+
+/// The question of a query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Question {
+    /// Lower case name without trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A DNS query, queries with more than one question are not supported.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    flags: u16,
+    /// Encoded question, echoed in the response.
+    raw_question: Vec<u8>,
+    /// `None` if the query is not a standard query.
+    pub question: Option<Question>,
+}
+
+impl Query {
+    /// Parses a query, returns `None` if `bytes` is not a DNS query.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let flags = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if flags & FLAG_QR != 0 {
+            return None;
+        }
+        let qdcount = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let mut query = Self {
+            id,
+            flags,
+            raw_question: vec![],
+            question: None,
+        };
+        if qdcount != 1 {
+            return None;
+        }
+        if flags & OPCODE_MASK != 0 {
+            return Some(query);
+        }
+        let mut labels = Vec::new();
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *bytes.get(pos)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // queries don't use compression
+            if len > 63 {
+                return None;
+            }
+            let label = bytes.get(pos..pos + len)?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            pos += len;
+        }
+        let fixed = bytes.get(pos..pos + 4)?;
+        query.question = Some(Question {
+            name: labels.join("."),
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        });
+        query.raw_question = bytes[HEADER_LEN..pos + 4].to_vec();
+        Some(query)
+    }
+
+    /// Encodes an authoritative response answering the question with `addrs`, which are
+    /// omitted if they don't fit into a UDP response.
+    pub fn response(&self, rcode: u8, addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+        let mut flags = FLAG_QR | FLAG_AA | (self.flags & (OPCODE_MASK | FLAG_RD)) | rcode as u16;
+        let mut answers = Vec::new();
+        let mut ancount = 0u16;
+        for addr in addrs {
+            let (ty, data) = match addr {
+                IpAddr::V4(addr) => (TYPE_A, addr.octets().to_vec()),
+                IpAddr::V6(addr) => (TYPE_AAAA, addr.octets().to_vec()),
+            };
+            let len = HEADER_LEN + self.raw_question.len() + answers.len() + 12 + data.len();
+            if len > MAX_UDP_LEN {
+                flags |= FLAG_TC;
+                break;
+            }
+            // pointer to the name of the question
+            answers.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            answers.extend_from_slice(&ty.to_be_bytes());
+            answers.extend_from_slice(&CLASS_IN.to_be_bytes());
+            answers.extend_from_slice(&ttl.to_be_bytes());
+            answers.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            answers.extend_from_slice(&data);
+            ancount += 1;
+        }
+        let qdcount = u16::from(!self.raw_question.is_empty());
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.raw_question.len() + answers.len());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.extend_from_slice(&qdcount.to_be_bytes());
+        bytes.extend_from_slice(&ancount.to_be_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.raw_question);
+        bytes.extend_from_slice(&answers);
+        bytes
+    }
+}
+
+/// Encodes a standard query, used by tests and simulated clients.
+pub fn encode_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend_from_slice(&FLAG_RD.to_be_bytes());
+    bytes.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    bytes.extend_from_slice(&qtype.to_be_bytes());
+    bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn answers_queries() {
+        let bytes = encode_query(7, "Machine-1.Netsim.", TYPE_A);
+        let query = Query::parse(&bytes).unwrap();
+        let question = query.question.clone().unwrap();
+        assert_eq!(question.name, "machine-1.netsim");
+        assert_eq!(question.qtype, TYPE_A);
+        assert_eq!(question.qclass, CLASS_IN);
+
+        let addrs = [
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        let response = query.response(RCODE_NOERROR, &addrs, 60);
+        assert_eq!(&response[..2], &7u16.to_be_bytes());
+        assert_eq!(response[2] & 0x84, 0x84);
+        assert_eq!(response[3] & 0x0f, RCODE_NOERROR);
+        assert_eq!(&response[6..8], &2u16.to_be_bytes());
+        assert_eq!(&response[12..bytes.len()], &bytes[12..]);
+        let answer = &response[bytes.len()..];
+        assert_eq!(&answer[..2], &[0xc0, 12]);
+        assert_eq!(&answer[10..12], &4u16.to_be_bytes());
+        assert_eq!(&answer[12..16], &[10, 0, 0, 2]);
+        assert_eq!(answer.len(), 16 + 28);
+
+        let many = vec![IpAddr::V6(Ipv6Addr::LOCALHOST); 32];
+        let response = query.response(RCODE_NOERROR, &many, 60);
+        assert!(response.len() <= MAX_UDP_LEN);
+        assert_eq!(response[2] & 0x02, 0x02);
+    }
+}
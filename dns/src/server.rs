@@ -0,0 +1,388 @@
+use crate::message::*;
+use futures::channel::{mpsc, oneshot};
+use futures::future::Future;
+use futures::stream::Stream;
+use netsim_embed_core::{embed_ipv4_addr, is_nat64_prefix, Ipv6Range, Packet, Plug, Protocol};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// TTL of the answered records, short so that changed records are picked up by caching
+/// resolvers.
+const RECORD_TTL: u32 = 5;
+
+#[derive(Debug)]
+enum DnsCtrl {
+    AddRecord(String, IpAddr),
+    RemoveRecords(String),
+    SetDns64Prefix(Option<Ipv6Range>),
+    Records(oneshot::Sender<Vec<(String, IpAddr)>>),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queries: AtomicUsize,
+    nxdomain: AtomicUsize,
+    synthesized: AtomicUsize,
+    invalid: AtomicUsize,
+}
+
+fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Normalizes a name like the names of questions.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// An in-process DNS server answering `A` and `AAAA` queries over UDP authoritatively from
+/// registered records. Queries for other names are answered with `NXDOMAIN`. With a DNS64
+/// prefix, `AAAA` records are synthesized for names with only `A` records (RFC 6147).
+#[derive(Debug)]
+pub struct DnsServer {
+    plug: Plug,
+    addr: SocketAddrV4,
+    records: HashMap<String, Vec<IpAddr>>,
+    dns64_prefix: Option<Ipv6Range>,
+    ctrl_tx: mpsc::UnboundedSender<DnsCtrl>,
+    ctrl_rx: mpsc::UnboundedReceiver<DnsCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl DnsServer {
+    pub fn new(plug: Plug, addr: SocketAddrV4) -> Self {
+        let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
+        Self {
+            plug,
+            addr,
+            records: Default::default(),
+            dns64_prefix: None,
+            ctrl_tx,
+            ctrl_rx,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns a handle to inspect and change the records after the server was spawned.
+    pub fn handle(&self) -> DnsHandle {
+        DnsHandle {
+            ctrl: self.ctrl_tx.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Adds an address record, names are case insensitive.
+    pub fn add_record(&mut self, name: &str, addr: IpAddr) {
+        let addrs = self.records.entry(normalize(name)).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// Removes all records of a name.
+    pub fn remove_records(&mut self, name: &str) {
+        self.records.remove(&normalize(name));
+    }
+
+    /// Sets the prefix of a NAT64 to synthesize `AAAA` records in, eg.
+    /// `Ipv6Range::nat64_well_known_prefix()`. `None` disables DNS64.
+    ///
+    /// # Panics
+    ///
+    /// If the prefix length is not one of 32, 40, 48, 56, 64 or 96.
+    pub fn set_dns64_prefix(&mut self, prefix: Option<Ipv6Range>) {
+        if let Some(prefix) = prefix {
+            assert!(is_nat64_prefix(prefix));
+        }
+        self.dns64_prefix = prefix;
+    }
+
+    /// Returns all records sorted by name.
+    pub fn records(&self) -> Vec<(String, IpAddr)> {
+        let mut records = self
+            .records
+            .iter()
+            .flat_map(|(name, addrs)| addrs.iter().map(move |addr| (name.clone(), *addr)))
+            .collect::<Vec<_>>();
+        records.sort();
+        records
+    }
+
+    fn handle_query(&self, query: &Query) -> Vec<u8> {
+        let question = match &query.question {
+            Some(question) => question,
+            None => return query.response(RCODE_NOTIMP, &[], RECORD_TTL),
+        };
+        if !matches!(question.qclass, CLASS_IN | CLASS_ANY) {
+            return query.response(RCODE_NOTIMP, &[], RECORD_TTL);
+        }
+        let addrs = match self.records.get(&question.name) {
+            Some(addrs) => addrs,
+            None => {
+                inc(&self.counters.nxdomain);
+                return query.response(RCODE_NXDOMAIN, &[], RECORD_TTL);
+            }
+        };
+        let addrs = addrs
+            .iter()
+            .filter(|addr| match question.qtype {
+                TYPE_A => addr.is_ipv4(),
+                TYPE_AAAA => addr.is_ipv6(),
+                TYPE_ANY => true,
+                _ => false,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        if let (TYPE_AAAA, true, Some(prefix)) =
+            (question.qtype, addrs.is_empty(), self.dns64_prefix)
+        {
+            let synthesized = self.records[&question.name]
+                .iter()
+                .filter_map(|addr| match addr {
+                    IpAddr::V4(addr) => Some(IpAddr::V6(embed_ipv4_addr(prefix, *addr))),
+                    IpAddr::V6(_) => None,
+                })
+                .collect::<Vec<_>>();
+            if !synthesized.is_empty() {
+                inc(&self.counters.synthesized);
+            }
+            return query.response(RCODE_NOERROR, &synthesized, RECORD_TTL);
+        }
+        query.response(RCODE_NOERROR, &addrs, RECORD_TTL)
+    }
+
+    fn process_ctrl(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(ctrl)) = Pin::new(&mut self.ctrl_rx).poll_next(cx) {
+            log::debug!("dns {}: CTRL {:?}", self.addr, ctrl);
+            match ctrl {
+                DnsCtrl::AddRecord(name, addr) => self.add_record(&name, addr),
+                DnsCtrl::RemoveRecords(name) => self.remove_records(&name),
+                DnsCtrl::SetDns64Prefix(prefix) => self.set_dns64_prefix(prefix),
+                DnsCtrl::Records(tx) => {
+                    tx.send(self.records()).ok();
+                }
+            }
+        }
+    }
+}
+
+impl Future for DnsServer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            // record changes apply to the queries received after them
+            self.process_ctrl(cx);
+            let mut bytes = match self.plug.poll_incoming(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Ready(Some(bytes)) => bytes,
+            };
+            let packet = match Packet::new(&mut bytes) {
+                Some(packet) if packet.protocol() == Protocol::Udp => packet,
+                _ => {
+                    log::debug!("dns {}: dropping non-udp packet", self.addr);
+                    continue;
+                }
+            };
+            let source = packet.get_source();
+            let dest = packet.get_destination();
+            if dest != self.addr {
+                log::debug!("dns {}: dropping packet to {}", self.addr, dest);
+                continue;
+            }
+            let query = match Query::parse(packet.payload()) {
+                Some(query) => query,
+                None => {
+                    log::debug!("dns {}: dropping invalid query from {}", self.addr, source);
+                    inc(&self.counters.invalid);
+                    continue;
+                }
+            };
+            inc(&self.counters.queries);
+            log::trace!("dns {}: {:?} from {}", self.addr, query.question, source);
+            let response = self.handle_query(&query);
+            let response = Packet::build_udp(self.addr, source, &response);
+            self.plug.unbounded_send(response);
+        }
+    }
+}
+
+/// Handle to inspect and change the records of a running `DnsServer`.
+#[derive(Clone, Debug)]
+pub struct DnsHandle {
+    ctrl: mpsc::UnboundedSender<DnsCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl DnsHandle {
+    /// Adds an address record, names are case insensitive.
+    pub fn add_record(&self, name: &str, addr: IpAddr) {
+        self.send(DnsCtrl::AddRecord(name.into(), addr));
+    }
+
+    /// Removes all records of a name.
+    pub fn remove_records(&self, name: &str) {
+        self.send(DnsCtrl::RemoveRecords(name.into()));
+    }
+
+    /// Sets the prefix of a NAT64 to synthesize `AAAA` records in, `None` disables DNS64.
+    ///
+    /// # Panics
+    ///
+    /// If the prefix length is not one of 32, 40, 48, 56, 64 or 96.
+    pub fn set_dns64_prefix(&self, prefix: Option<Ipv6Range>) {
+        if let Some(prefix) = prefix {
+            assert!(is_nat64_prefix(prefix));
+        }
+        self.send(DnsCtrl::SetDns64Prefix(prefix));
+    }
+
+    /// Returns all records sorted by name.
+    pub async fn records(&self) -> Vec<(String, IpAddr)> {
+        let (tx, rx) = oneshot::channel();
+        self.send(DnsCtrl::Records(tx));
+        rx.await.unwrap_or_default()
+    }
+
+    fn send(&self, ctrl: DnsCtrl) {
+        self.ctrl.unbounded_send(ctrl).ok();
+    }
+
+    /// Number of queries that were answered.
+    pub fn queries(&self) -> usize {
+        self.counters.queries.load(Ordering::Relaxed)
+    }
+
+    /// Number of queries for names without records.
+    pub fn nxdomain(&self) -> usize {
+        self.counters.nxdomain.load(Ordering::Relaxed)
+    }
+
+    /// Number of `AAAA` answers synthesized from `A` records.
+    pub fn synthesized(&self) -> usize {
+        self.counters.synthesized.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because they could not be parsed.
+    pub fn invalid(&self) -> usize {
+        self.counters.invalid.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::wire;
+    use std::convert::TryFrom;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+
+    /// Sends a UDP packet from a client and returns the payload of the response.
+    async fn exchange(plug: &mut Plug, server: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        plug.unbounded_send(Packet::build_udp(CLIENT, server, payload));
+        let mut bytes = plug.incoming().await.unwrap();
+        let packet = Packet::new(&mut bytes).unwrap();
+        assert_eq!(packet.get_source(), server);
+        assert_eq!(packet.get_destination(), CLIENT);
+        packet.payload().to_vec()
+    }
+
+    /// Sends a query from a client and returns the answered addresses.
+    async fn query(plug: &mut Plug, server: SocketAddrV4, name: &str, qtype: u16) -> Vec<IpAddr> {
+        let query = encode_query(1, name, qtype);
+        let response = exchange(plug, server, &query).await;
+        assert_eq!(response[3] & 0x0f, RCODE_NOERROR);
+        let mut answers = &response[query.len()..];
+        let mut addrs = vec![];
+        while !answers.is_empty() {
+            let len = usize::from(u16::from_be_bytes([answers[10], answers[11]]));
+            let data = &answers[12..12 + len];
+            addrs.push(match len {
+                4 => Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap()).into(),
+                _ => Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap()).into(),
+            });
+            answers = &answers[12 + len..];
+        }
+        addrs
+    }
+
+    #[async_std::test]
+    async fn answers_queries() {
+        let (mut plug, server_plug) = wire();
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 53), 53);
+        let mut server = DnsServer::new(server_plug, addr);
+        let ipv4: IpAddr = Ipv4Addr::new(192, 0, 2, 1).into();
+        let ipv6: IpAddr = "2001:db8::1".parse::<Ipv6Addr>().unwrap().into();
+        server.add_record("Host.Example", ipv4);
+        server.add_record("host.example", ipv6);
+        let handle = server.handle();
+        async_std::task::spawn(server);
+
+        assert_eq!(
+            query(&mut plug, addr, "host.example", TYPE_A).await,
+            vec![ipv4]
+        );
+        assert_eq!(
+            query(&mut plug, addr, "HOST.example.", TYPE_AAAA).await,
+            vec![ipv6]
+        );
+        let response = exchange(&mut plug, addr, &encode_query(2, "other", TYPE_A)).await;
+        assert_eq!(response[..2], 2u16.to_be_bytes());
+        assert_eq!(response[3] & 0x0f, RCODE_NXDOMAIN);
+        assert_eq!(handle.nxdomain(), 1);
+
+        // packets to other addresses and invalid queries are dropped without a response
+        let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 54), 53);
+        plug.unbounded_send(Packet::build_udp(
+            CLIENT,
+            other,
+            &encode_query(3, "host", TYPE_A),
+        ));
+        plug.unbounded_send(Packet::build_udp(CLIENT, addr, &[0; 5]));
+
+        // record changes apply to later queries
+        handle.add_record("other", ipv4);
+        handle.remove_records("host.example");
+        assert_eq!(query(&mut plug, addr, "other", TYPE_A).await, vec![ipv4]);
+        let response = exchange(&mut plug, addr, &encode_query(4, "host.example", TYPE_A)).await;
+        assert_eq!(response[3] & 0x0f, RCODE_NXDOMAIN);
+        assert_eq!(handle.records().await, vec![("other".into(), ipv4)]);
+        assert_eq!(handle.queries(), 5);
+        assert_eq!(handle.invalid(), 1);
+    }
+
+    #[async_std::test]
+    async fn synthesizes_aaaa_records() {
+        let (mut plug, server_plug) = wire();
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 53), 53);
+        let mut server = DnsServer::new(server_plug, addr);
+        let ipv4: IpAddr = Ipv4Addr::new(192, 0, 2, 33).into();
+        let ipv6: IpAddr = "2001:db8::34".parse::<Ipv6Addr>().unwrap().into();
+        server.add_record("ipv4-only", ipv4);
+        server.add_record("dual-stack", ipv4);
+        server.add_record("dual-stack", ipv6);
+        let handle = server.handle();
+        handle.set_dns64_prefix(Some(Ipv6Range::nat64_well_known_prefix()));
+        async_std::task::spawn(server);
+
+        let synthesized: IpAddr = "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap().into();
+        let answers = query(&mut plug, addr, "ipv4-only", TYPE_AAAA).await;
+        assert_eq!(answers, vec![synthesized]);
+        let answers = query(&mut plug, addr, "ipv4-only", TYPE_A).await;
+        assert_eq!(answers, vec![ipv4]);
+        let answers = query(&mut plug, addr, "dual-stack", TYPE_AAAA).await;
+        assert_eq!(answers, vec![ipv6]);
+        assert_eq!(handle.synthesized(), 1);
+
+        handle.set_dns64_prefix(None);
+        let answers = query(&mut plug, addr, "ipv4-only", TYPE_AAAA).await;
+        assert!(answers.is_empty());
+        assert_eq!(handle.queries(), 4);
+    }
+}
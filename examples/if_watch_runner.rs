@@ -34,12 +34,8 @@ async fn recv(machine: &mut Machine<String, String>, mut n: usize) -> BTreeSet<S
     while n > 0 {
         match timeout(Duration::from_secs(3), machine.recv()).await {
             Ok(Some(s)) => {
-                if s.ends_with("/32") {
-                    // ignore: intermediate state between setting addr and netmask
-                } else {
-                    n -= 1;
-                    result.insert(s);
-                }
+                n -= 1;
+                result.insert(s);
             }
             Ok(None) => panic!("machine exited"),
             Err(e) => panic!("error: {}", e),
@@ -56,14 +52,17 @@ fn main() {
         let addr1 = sim.network_mut(net).unique_addr();
         let addr2 = sim.network_mut(net).unique_addr();
         let addr3 = sim.network_mut(net).unique_addr();
+        let addr4 = sim.network_mut(net).unique_addr();
         let if_watch_bin = exe("if_watch");
         let wait_for_exit_bin = exe("wait_for_exit");
         let watcher = sim
             .spawn_machine(Command::new(if_watch_bin.clone()), None)
-            .await;
+            .await
+            .unwrap();
         let pinger = sim
             .spawn_machine(Command::new(wait_for_exit_bin), None)
-            .await;
+            .await
+            .unwrap();
         sim.plug(watcher, net, Some(addr1)).await;
         sim.plug(pinger, net, None).await;
         assert_eq!(
@@ -90,5 +89,21 @@ fn main() {
             recv(sim.machine(watcher), 1).await,
             BTreeSet::from_iter([format!("<up {addr3}/0"),])
         );
+        sim.add_addr(watcher, 0, addr4.into()).await.unwrap();
+        assert_eq!(
+            recv(sim.machine(watcher), 1).await,
+            BTreeSet::from_iter([format!("<up {addr4}/0"),])
+        );
+        assert_eq!(
+            sim.machine(watcher).addrs(),
+            vec![(addr3.into(), 0), (addr4.into(), 0)]
+        );
+        ping(sim.machine(pinger).namespace(), addr4).await;
+        sim.remove_addr(watcher, 0, addr4.into()).await.unwrap();
+        assert_eq!(
+            recv(sim.machine(watcher), 1).await,
+            BTreeSet::from_iter([format!("<down {addr4}/0"),])
+        );
+        assert_eq!(sim.machine(watcher).addrs(), vec![(addr3.into(), 0)]);
     });
 }
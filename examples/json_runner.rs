@@ -0,0 +1,64 @@
+use async_process::Command as Process;
+use async_std::future::timeout;
+use netsim_embed::{run, JsonChannel, Machine, Netsim};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Command {
+    Upper { text: String },
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+enum Event {
+    Started,
+    Upper { text: String, lines: usize },
+}
+
+/// Runs in the machine's process, started by the runner with the `machine` argument.
+fn machine() {
+    let mut channel = JsonChannel::<Command, Event>::from_env().unwrap();
+    // output that looks like an event is just printed by the runner
+    println!("<started");
+    println!("\"Started\"");
+    channel.send(&Event::Started).unwrap();
+    while let Some(Command::Upper { text }) = channel.recv().unwrap() {
+        let lines = text.lines().count();
+        let text = text.to_uppercase();
+        channel.send(&Event::Upper { text, lines }).unwrap();
+    }
+}
+
+async fn recv(machine: &mut Machine<Command, Event>) -> Event {
+    match timeout(Duration::from_secs(3), machine.recv()).await {
+        Ok(Some(ev)) => ev,
+        Ok(None) => panic!("machine exited"),
+        Err(e) => panic!("error: {}", e),
+    }
+}
+
+fn main() {
+    if std::env::args().nth(1).as_deref() == Some("machine") {
+        machine();
+        return;
+    }
+    env_logger::init();
+    run(async {
+        let mut sim = Netsim::<Command, Event>::new();
+        let mut process = Process::new(std::env::current_exe().unwrap());
+        process.arg("machine");
+        let machine = sim.spawn_json_machine(process, None).await.unwrap();
+        assert_eq!(recv(sim.machine(machine)).await, Event::Started);
+
+        let text = "first line\nsecond line\n".to_string();
+        sim.machine(machine).send(Command::Upper { text });
+        assert_eq!(
+            recv(sim.machine(machine)).await,
+            Event::Upper {
+                text: "FIRST LINE\nSECOND LINE\n".into(),
+                lines: 2,
+            }
+        );
+        assert!(sim.machine(machine).drain().is_empty());
+    });
+}
@@ -10,7 +10,7 @@ fn main() {
         let mut server = Command::new("ncat");
         server.args(["-l", "-4", "-p", "4242", "-c", "echo '<Hello World'"]);
 
-        let server = netsim.spawn_machine(server, None).await;
+        let server = netsim.spawn_machine(server, None).await.unwrap();
         netsim.plug(server, net1, None).await;
         let server_addr = netsim.machine(server).addr();
         println!("Server Addr {server_addr}:4242");
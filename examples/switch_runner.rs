@@ -0,0 +1,86 @@
+use async_process::Command;
+use netsim_embed::{run, IfaceMode, Ipv4Range, MachineConfig, Namespace, Netsim};
+use std::{net::Ipv4Addr, path::PathBuf};
+
+async fn ping(ns: Namespace, addr: Ipv4Addr) {
+    let status = Command::new("nsenter")
+        .args([
+            format!("--net={ns}"),
+            "ping".to_owned(),
+            "-c".to_owned(),
+            4.to_string(),
+            "-i".to_owned(),
+            "0.1".to_string(),
+            addr.to_string(),
+        ])
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success(), "pinging {} failed", addr);
+}
+
+/// Returns the MAC address the neighbour table of a namespace holds for `addr`.
+async fn neighbour(ns: Namespace, addr: Ipv4Addr) -> Option<String> {
+    let output = Command::new("nsenter")
+        .args([
+            format!("--net={ns}"),
+            "ip".to_owned(),
+            "neigh".to_owned(),
+            "show".to_owned(),
+            addr.to_string(),
+        ])
+        .output()
+        .await
+        .unwrap();
+    let output = String::from_utf8(output.stdout).unwrap();
+    let mut words = output.split_whitespace();
+    words.find(|word| *word == "lladdr")?;
+    words.next().map(|mac| mac.to_owned())
+}
+
+fn format_mac(mac: [u8; 6]) -> String {
+    let octets: Vec<_> = mac.iter().map(|b| format!("{b:02x}")).collect();
+    octets.join(":")
+}
+
+fn exe(name: &str) -> PathBuf {
+    std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join(name)
+}
+
+fn main() {
+    env_logger::init();
+    run(async {
+        let mut sim = Netsim::<String, String>::new();
+        let net = sim.spawn_switch(Ipv4Range::random_local_subnet());
+        let config = MachineConfig {
+            iface_mode: IfaceMode::Tap,
+            ..Default::default()
+        };
+        let wait_for_exit_bin = exe("wait_for_exit");
+        let a = sim
+            .spawn_machine_with_config(Command::new(&wait_for_exit_bin), None, config.clone())
+            .await
+            .unwrap();
+        let b = sim
+            .spawn_machine_with_config(Command::new(&wait_for_exit_bin), None, config)
+            .await
+            .unwrap();
+        sim.plug(a, net, None).await;
+        sim.plug(b, net, None).await;
+        let (ns_a, addr_a) = (sim.machine(a).namespace(), sim.machine(a).addr());
+        let (ns_b, addr_b) = (sim.machine(b).namespace(), sim.machine(b).addr());
+        let mac_a = format_mac(sim.machine(a).iface_mac_addr(0).unwrap());
+        let mac_b = format_mac(sim.machine(b).iface_mac_addr(0).unwrap());
+        assert_eq!(neighbour(ns_a, addr_b).await, None);
+
+        // the ARP request and reply teach both machines the MAC address of the other one
+        ping(ns_a, addr_b).await;
+        assert_eq!(neighbour(ns_a, addr_b).await, Some(mac_b));
+        assert_eq!(neighbour(ns_b, addr_a).await, Some(mac_a));
+        ping(ns_b, addr_a).await;
+    });
+}
@@ -0,0 +1,82 @@
+use async_io::Async;
+use async_std::future::timeout;
+use futures::prelude::*;
+use netsim_embed::{run, Ipv4Range, Machine, Netsim};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+/// Commands and events are passed to task machines as they are, they don't implement `Display`
+/// or `FromStr`.
+#[derive(Debug)]
+enum Command {
+    Ping(SocketAddrV4, Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Bound(u16),
+    Pong(SocketAddrV4, Vec<u8>),
+}
+
+async fn recv(machine: &mut Machine<Command, Event>) -> Event {
+    match timeout(Duration::from_secs(3), machine.recv()).await {
+        Ok(Some(ev)) => ev,
+        Ok(None) => panic!("machine exited"),
+        Err(e) => panic!("error: {}", e),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    run(async {
+        let mut sim = Netsim::<Command, Event>::new();
+        let net = sim.spawn_network(Ipv4Range::global());
+        let server = sim
+            .spawn_task(
+                |_, events| async move {
+                    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000);
+                    let socket = Async::<UdpSocket>::bind(addr).unwrap();
+                    events.unbounded_send(Event::Bound(3000)).unwrap();
+                    let mut buf = [0; 1500];
+                    loop {
+                        let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+                        socket.send_to(&buf[..len], addr).await.unwrap();
+                    }
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let client = sim
+            .spawn_task(
+                |mut commands, events| async move {
+                    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+                    let socket = Async::<UdpSocket>::bind(addr).unwrap();
+                    while let Some(Command::Ping(addr, payload)) = commands.next().await {
+                        socket.send_to(&payload, addr).await.unwrap();
+                        let mut buf = [0; 1500];
+                        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                        let from = match from {
+                            std::net::SocketAddr::V4(from) => from,
+                            from => panic!("unexpected peer {}", from),
+                        };
+                        events
+                            .unbounded_send(Event::Pong(from, buf[..len].to_vec()))
+                            .unwrap();
+                    }
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        sim.plug(server, net, None).await;
+        sim.plug(client, net, None).await;
+        assert_eq!(recv(sim.machine(server)).await, Event::Bound(3000));
+
+        let addr = SocketAddrV4::new(sim.machine(server).addr(), 3000);
+        let payload = b"multi\nline".to_vec();
+        sim.machine(client)
+            .send(Command::Ping(addr, payload.clone()));
+        assert_eq!(recv(sim.machine(client)).await, Event::Pong(addr, payload));
+    });
+}
@@ -0,0 +1,203 @@
+//! Segmentation of the GSO packets a TUN interface with offloads enabled hands out.
+//!
+//! See `linux/virtio_net.h`.
+use netsim_embed_core::{Packet, Packet6};
+
+/// Length of the `virtio_net_hdr` prefixed to every packet.
+pub const VNET_HDR_LEN: usize = 10;
+
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_UDP_L4: u8 = 5;
+const GSO_ECN: u8 = 0x80;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const VLAN_TAG_LEN: usize = 4;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_CWR: u8 = 0x80;
+
+/// Splits a packet read from the interface into packets of at most `gso_size` payload bytes and
+/// fills in their checksums. `l2_len` is the length of the ethernet header of TAP interfaces,
+/// frames which don't carry IP are passed through. Returns `None` if the packet can't be
+/// segmented.
+pub fn segment(bytes: &[u8], mut l2_len: usize) -> Option<Vec<Vec<u8>>> {
+    if bytes.len() < VNET_HDR_LEN + l2_len {
+        return None;
+    }
+    let (hdr, packet) = bytes.split_at(VNET_HDR_LEN);
+    let gso_type = hdr[1] & !GSO_ECN;
+    let gso_size = u16::from_ne_bytes([hdr[4], hdr[5]]) as usize;
+    let mut packet = packet.to_vec();
+    if l2_len > 0 {
+        let mut ethertype = u16::from_be_bytes([packet[12], packet[13]]);
+        // frames of VLAN interfaces are tagged
+        if ethertype == ETHERTYPE_VLAN && packet.len() >= l2_len + VLAN_TAG_LEN {
+            l2_len += VLAN_TAG_LEN;
+            ethertype = u16::from_be_bytes([packet[16], packet[17]]);
+        }
+        if !matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6) {
+            return if gso_type == GSO_NONE {
+                Some(vec![packet])
+            } else {
+                None
+            };
+        }
+    }
+    let version = packet.get(l2_len)? >> 4;
+    let l3_len = match version {
+        4 => usize::from(packet[l2_len] & 0x0f) * 4,
+        6 => 40,
+        _ => return None,
+    };
+    if gso_type == GSO_NONE {
+        set_checksum(&mut packet[l2_len..]);
+        return Some(vec![packet]);
+    }
+    // GSO_UDP is UDP fragmentation offload, which is never enabled
+    if !matches!(gso_type, GSO_TCPV4 | GSO_TCPV6 | GSO_UDP_L4) || gso_size == 0 {
+        return None;
+    }
+    let tcp = gso_type != GSO_UDP_L4;
+    let l4_len = if tcp {
+        usize::from(packet.get(l2_len + l3_len + 12)? >> 4) * 4
+    } else {
+        8
+    };
+    let hdr_len = l2_len + l3_len + l4_len;
+    if packet.len() < hdr_len {
+        return None;
+    }
+    let (headers, payload) = packet.split_at(hdr_len);
+    let num = payload.len().div_ceil(gso_size);
+    let mut segments = Vec::with_capacity(num);
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let mut segment = Vec::with_capacity(hdr_len + chunk.len());
+        segment.extend_from_slice(headers);
+        segment.extend_from_slice(chunk);
+        let ip = &mut segment[l2_len..];
+        let len = ip.len();
+        if version == 4 {
+            ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            let id = u16::from_be_bytes([ip[4], ip[5]]).wrapping_add(i as u16);
+            ip[4..6].copy_from_slice(&id.to_be_bytes());
+        } else {
+            ip[4..6].copy_from_slice(&((len - l3_len) as u16).to_be_bytes());
+        }
+        let l4 = &mut ip[l3_len..];
+        if tcp {
+            let seq = u32::from_be_bytes([l4[4], l4[5], l4[6], l4[7]])
+                .wrapping_add((i * gso_size) as u32);
+            l4[4..8].copy_from_slice(&seq.to_be_bytes());
+            if i + 1 < num {
+                l4[13] &= !(TCP_FIN | TCP_PSH);
+            }
+            if i > 0 {
+                l4[13] &= !TCP_CWR;
+            }
+        } else {
+            l4[4..6].copy_from_slice(&((8 + chunk.len()) as u16).to_be_bytes());
+        }
+        set_checksum(ip);
+        segments.push(segment);
+    }
+    Some(segments)
+}
+
+/// Prefixes a packet with a `virtio_net_hdr` without offloads.
+pub fn encapsulate(packet: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(VNET_HDR_LEN + packet.len());
+    bytes.extend_from_slice(&[0; VNET_HDR_LEN]);
+    bytes.extend_from_slice(packet);
+    bytes
+}
+
+fn set_checksum(bytes: &mut [u8]) {
+    match bytes.first().map(|b| b >> 4) {
+        Some(4) => {
+            if let Some(mut packet) = Packet::new(bytes) {
+                packet.set_checksum();
+            }
+        }
+        Some(6) => {
+            if let Some(mut packet) = Packet6::new(bytes) {
+                packet.set_checksum();
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn segments_udp_gso_packets() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1000);
+        let dest = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 2000);
+        let payload = (0..250u8).collect::<Vec<_>>();
+        let mut bytes = vec![0, GSO_UDP_L4, 0, 0];
+        bytes.extend_from_slice(&100u16.to_ne_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&Packet::build_udp(source, dest, &payload));
+        let segments = segment(&bytes, 0).unwrap();
+        assert_eq!(segments.len(), 3);
+        for (i, mut segment) in segments.into_iter().enumerate() {
+            let expected =
+                Packet::build_udp(source, dest, &payload[i * 100..(i * 100 + 100).min(250)]);
+            let packet = Packet::new(&mut segment).unwrap();
+            assert_eq!(packet.get_source(), source);
+            assert_eq!(packet.payload(), &expected[28..]);
+            assert_eq!(segment[28..], expected[28..]);
+            assert_eq!(segment[20..28], expected[20..28]);
+        }
+    }
+
+    #[test]
+    fn segments_tcp_gso_packets() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1000);
+        let dest = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 2000);
+        let payload = (0..250u8).collect::<Vec<_>>();
+        let mut bytes = vec![0, GSO_TCPV4, 0, 0];
+        bytes.extend_from_slice(&100u16.to_ne_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&[0x45, 0, 0, 0, 0, 7, 0, 0, 64, 6, 0, 0]);
+        bytes.extend_from_slice(&source.ip().octets());
+        bytes.extend_from_slice(&dest.ip().octets());
+        bytes.extend_from_slice(&source.port().to_be_bytes());
+        bytes.extend_from_slice(&dest.port().to_be_bytes());
+        bytes.extend_from_slice(&0xffff_ff80u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0, 5 << 4, TCP_FIN | TCP_PSH | TCP_CWR | 0x10]);
+        bytes.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&payload);
+        let segments = segment(&bytes, 0).unwrap();
+        assert_eq!(segments.len(), 3);
+        for (i, mut segment) in segments.into_iter().enumerate() {
+            let chunk = &payload[i * 100..(i * 100 + 100).min(250)];
+            assert_eq!(segment.len(), 40 + chunk.len());
+            assert_eq!(segment[2..4], (segment.len() as u16).to_be_bytes());
+            assert_eq!(segment[4..6], (7 + i as u16).to_be_bytes());
+            // sequence numbers wrap around
+            let seq = 0xffff_ff80u32.wrapping_add(i as u32 * 100);
+            assert_eq!(segment[24..28], seq.to_be_bytes());
+            let flags = segment[33];
+            assert_eq!(flags & 0x10, 0x10);
+            assert_eq!(flags & TCP_CWR != 0, i == 0);
+            assert_eq!(
+                flags & (TCP_FIN | TCP_PSH),
+                if i == 2 { TCP_FIN | TCP_PSH } else { 0 }
+            );
+            assert_eq!(&segment[40..], chunk);
+            let packet = Packet::new(&mut segment).unwrap();
+            assert_eq!(packet.get_source(), source);
+            assert_eq!(packet.get_destination(), dest);
+        }
+    }
+}
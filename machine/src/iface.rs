@@ -1,15 +1,14 @@
-use netsim_embed_core::Ipv4Route;
+use crate::netlink;
+use netsim_embed_core::{Ipv4Route, Ipv6Route};
 use std::ffi::{CStr, CString};
 use std::io::{self, Read, Write};
 use std::mem;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 mod ioctl {
     use ioctl_sys::*;
     use libc::*;
-    use std::ffi::CStr;
-    use std::net::Ipv4Addr;
 
     #[repr(C)]
     #[derive(Clone, Copy)]
@@ -18,30 +17,6 @@ mod ioctl {
         pub ifr_ifru: __ifreq_ifr_ifru,
     }
 
-    impl ifreq {
-        pub fn new(name: &CStr) -> Self {
-            unsafe {
-                let mut req: Self = std::mem::zeroed();
-                std::ptr::copy_nonoverlapping(
-                    name.as_ptr(),
-                    req.ifr_ifrn.ifrn_name.as_mut_ptr() as *mut _,
-                    name.to_bytes().len(),
-                );
-                req
-            }
-        }
-
-        pub fn set_ifru_addr(&mut self, ipv4_addr: Ipv4Addr) {
-            unsafe {
-                let addr = &mut self.ifr_ifru.ifru_addr as *mut libc::sockaddr;
-                let addr = &mut *(addr as *mut libc::sockaddr_in);
-                addr.sin_family = libc::AF_INET as libc::sa_family_t;
-                addr.sin_port = 0;
-                addr.sin_addr.s_addr = u32::from(ipv4_addr).to_be();
-            }
-        }
-    }
-
     #[repr(C)]
     #[derive(Clone, Copy)]
     pub union __ifreq_ifr_ifrn {
@@ -52,42 +27,66 @@ mod ioctl {
     #[derive(Clone, Copy)]
     pub union __ifreq_ifr_ifru {
         pub ifru_addr: sockaddr,
-        pub ifru_dstaddr: sockaddr,
-        pub ifru_broadaddr: sockaddr,
-        pub ifru_netmask: sockaddr,
-        pub ifru_hwaddr: sockaddr,
         pub ifru_flags: c_short,
         pub ifru_ivalue: c_int,
-        pub ifru_mtu: c_int,
-        pub ifru: ifmap,
-        pub ifru_slave: [c_char; IFNAMSIZ],
-        pub ifru_newname: [c_char; IFNAMSIZ],
-        pub ifru_data: *mut c_void,
+        pub ifru_map: [c_ulong; 3],
     }
 
-    #[repr(C)]
-    #[derive(Debug, Clone, Copy)]
-    pub struct ifmap {
-        pub mem_start: c_ulong,
-        pub mem_end: c_ulong,
-        pub base_addr: c_ushort,
-        pub irq: c_uchar,
-        pub dma: c_uchar,
-        pub port: c_uchar,
-    }
-
-    ioctl!(bad read siocgifflags with 0x8913; ifreq);
-    ioctl!(bad write siocsifflags with 0x8914; ifreq);
-    ioctl!(bad write siocsifaddr with 0x8916; ifreq);
-    ioctl!(bad write siocsifnetmask with 0x891c; ifreq);
     ioctl!(write tunsetiff with b'T', 202; libc::c_int);
     ioctl!(write tunsetoffload with b'T', 208; libc::c_int);
 }
 
+/// Length of an ethernet header without VLAN tags.
+pub const ETH_HDR_LEN: usize = 14;
+
+// Offload flags passed to `TUNSETOFFLOAD`, see `linux/if_tun.h`.
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+const TUN_F_USO4: libc::c_uint = 0x20;
+const TUN_F_USO6: libc::c_uint = 0x40;
+
+/// Segmentation offloads of an interface. Packets the machine sends with offloads enabled are
+/// segmented when they leave the machine, like a NIC would.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Offload {
+    /// TCP segmentation offload.
+    pub tso: bool,
+    /// UDP segmentation offload, requires Linux 6.2.
+    pub uso: bool,
+}
+
+impl Offload {
+    fn flags(&self) -> libc::c_uint {
+        let mut flags = TUN_F_CSUM;
+        if self.tso {
+            flags |= TUN_F_TSO4 | TUN_F_TSO6;
+        }
+        if self.uso {
+            flags |= TUN_F_USO4 | TUN_F_USO6;
+        }
+        flags
+    }
+}
+
+/// Whether an interface exchanges IP packets or ethernet frames.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IfaceMode {
+    /// A TUN interface which is plugged into routed networks.
+    #[default]
+    Tun,
+    /// A TAP interface which is plugged into switched networks, the machine's kernel does ARP
+    /// and neighbour discovery.
+    Tap,
+}
+
 /// See: https://www.kernel.org/doc/Documentation/networking/tuntap.txt
+///
+/// Packets are prefixed with a `virtio_net_hdr` describing offloads.
 pub struct Iface {
     name: CString,
     fd: RawFd,
+    mode: IfaceMode,
 }
 
 impl AsRawFd for Iface {
@@ -121,27 +120,16 @@ impl Write for Iface {
 impl Iface {
     /// Creates a new virtual network interface.
     pub fn new() -> Result<Self, io::Error> {
-        // create loopback interface
-        unsafe {
-            let fd = errno!(libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0))?;
-            let lo = CString::new("lo")?;
-
-            let mut req = ioctl::ifreq::new(&lo);
-            req.set_ifru_addr(Ipv4Addr::LOCALHOST);
-
-            if let Err(err) = errno!(ioctl::siocsifaddr(fd, &req)) {
-                let _ = libc::close(fd);
-                return Err(err);
-            }
-
-            let mut req = ioctl::ifreq::new(&lo);
-            req.ifr_ifru.ifru_flags |=
-                libc::IFF_UP as i16 | libc::IFF_LOOPBACK as i16 | libc::IFF_RUNNING as i16;
+        Self::with_mode(IfaceMode::Tun)
+    }
 
-            let res = errno!(ioctl::siocsifflags(fd, &req));
-            let _ = libc::close(fd);
-            res?;
-        }
+    /// Creates a new TUN or TAP interface.
+    pub fn with_mode(mode: IfaceMode) -> Result<Self, io::Error> {
+        // put the loopback interface up, which assigns its addresses
+        let lo = CString::new("lo")?;
+        let index = name_to_index(&lo)?;
+        let flags = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        netlink::set_link_flags(index, flags, flags)?;
         // create tun interface
         unsafe {
             let fd = loop {
@@ -156,18 +144,33 @@ impl Iface {
             };
 
             let mut req: ioctl::ifreq = mem::zeroed();
-            req.ifr_ifru.ifru_flags = libc::IFF_TUN as i16 | libc::IFF_NO_PI as i16;
+            let kind = match mode {
+                IfaceMode::Tun => libc::IFF_TUN,
+                IfaceMode::Tap => libc::IFF_TAP,
+            };
+            req.ifr_ifru.ifru_flags = (kind | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as i16;
 
-            errno!(ioctl::tunsetiff(fd, &mut req as *mut _ as *mut _))?;
+            if let Err(err) = errno!(ioctl::tunsetiff(fd, &mut req as *mut _ as *mut _)) {
+                let _ = libc::close(fd);
+                return Err(err);
+            }
 
             let name = CStr::from_ptr(&req.ifr_ifrn.ifrn_name as *const _).to_owned();
+            // closes the fd on error
+            let iface = Self { name, fd, mode };
 
-            errno!(ioctl::tunsetoffload(
-                fd,
-                0x01 as *const libc::c_void as *const _
-            ))?;
+            iface.set_offload(Offload::default())?;
+
+            // keep secondary addresses when the primary address is removed
+            std::fs::write(
+                format!(
+                    "/proc/sys/net/ipv4/conf/{}/promote_secondaries",
+                    iface.name.to_string_lossy()
+                ),
+                "1",
+            )?;
 
-            Ok(Self { name, fd })
+            Ok(iface)
         }
     }
 
@@ -176,6 +179,18 @@ impl Iface {
         &self.name
     }
 
+    pub fn mode(&self) -> IfaceMode {
+        self.mode
+    }
+
+    /// Length of the link layer header preceding IP packets.
+    pub fn l2_len(&self) -> usize {
+        match self.mode {
+            IfaceMode::Tun => 0,
+            IfaceMode::Tap => ETH_HDR_LEN,
+        }
+    }
+
     /// Receives a packet.
     pub fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
         Ok(unsafe {
@@ -198,112 +213,139 @@ impl Iface {
         })
     }
 
-    /// Set an interface IPv4 address and netmask.
-    pub fn set_ipv4_addr(&self, ipv4_addr: Ipv4Addr, netmask_bits: u8) -> Result<(), io::Error> {
-        unsafe {
-            let fd = errno!(libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0))?;
-            let mut req = ioctl::ifreq::new(self.name());
-            req.set_ifru_addr(ipv4_addr);
+    /// Returns the interface index.
+    pub fn index(&self) -> Result<u32, io::Error> {
+        name_to_index(&self.name)
+    }
 
-            if let Err(err) = errno!(ioctl::siocsifaddr(fd, &req)) {
-                let _ = libc::close(fd);
-                return Err(err);
-            }
+    /// Adds an IPv4 address with a prefix length, the interface may have several addresses.
+    pub fn add_ipv4_addr(&self, ipv4_addr: Ipv4Addr, prefix_len: u8) -> Result<(), io::Error> {
+        netlink::add_addr(self.index()?, ipv4_addr.into(), prefix_len)
+    }
 
-            let netmask = Ipv4Addr::from(!((!0u32) >> netmask_bits));
-            req.set_ifru_addr(netmask);
+    /// Removes an IPv4 address.
+    pub fn remove_ipv4_addr(&self, ipv4_addr: Ipv4Addr, prefix_len: u8) -> Result<(), io::Error> {
+        netlink::remove_addr(self.index()?, ipv4_addr.into(), prefix_len)
+    }
 
-            let res = errno!(ioctl::siocsifnetmask(fd, &req));
-            let _ = libc::close(fd);
-            res?;
-            Ok(())
-        }
+    /// Adds an IPv6 address with a prefix length, the interface may have several addresses.
+    pub fn add_ipv6_addr(&self, ipv6_addr: Ipv6Addr, prefix_len: u8) -> Result<(), io::Error> {
+        netlink::add_addr(self.index()?, ipv6_addr.into(), prefix_len)
+    }
+
+    /// Removes an IPv6 address.
+    pub fn remove_ipv6_addr(&self, ipv6_addr: Ipv6Addr, prefix_len: u8) -> Result<(), io::Error> {
+        netlink::remove_addr(self.index()?, ipv6_addr.into(), prefix_len)
     }
 
     /// Put an interface up.
     pub fn put_up(&self) -> Result<(), io::Error> {
-        unsafe {
-            let fd = errno!(libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0))?;
-            let mut req = ioctl::ifreq::new(self.name());
-
-            if let Err(err) = errno!(ioctl::siocgifflags(fd, &mut req)) {
-                let _ = libc::close(fd);
-                return Err(err);
-            }
-
-            req.ifr_ifru.ifru_flags |= libc::IFF_UP as i16 | libc::IFF_RUNNING as i16;
-
-            let res = errno!(ioctl::siocsifflags(fd, &req));
-            let _ = libc::close(fd);
-            res?;
-            Ok(())
-        }
+        let flags = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        netlink::set_link_flags(self.index()?, flags, flags)
     }
 
     /// Put an interface down.
     pub fn put_down(&self) -> Result<(), io::Error> {
-        unsafe {
-            let fd = errno!(libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0))?;
-            let mut req = ioctl::ifreq::new(self.name());
-
-            if let Err(err) = errno!(ioctl::siocgifflags(fd, &mut req)) {
-                let _ = libc::close(fd);
-                return Err(err);
-            }
-
-            req.ifr_ifru.ifru_flags &= !(libc::IFF_UP as i16);
-
-            let res = errno!(ioctl::siocsifflags(fd, &req));
-            let _ = libc::close(fd);
-            res?;
-            Ok(())
-        }
+        netlink::set_link_flags(self.index()?, 0, libc::IFF_UP as u32)
     }
 
-    /// Adds an ipv4 route.
-    pub fn add_ipv4_route(&self, route: Ipv4Route) -> Result<(), io::Error> {
+    /// Enables/disables segmentation offloads.
+    pub fn set_offload(&self, offload: Offload) -> Result<(), io::Error> {
         unsafe {
-            let fd = errno!(libc::socket(
-                libc::PF_INET,
-                libc::SOCK_DGRAM,
-                libc::IPPROTO_IP,
+            errno!(ioctl::tunsetoffload(
+                self.fd,
+                offload.flags() as usize as *const _
             ))?;
+        }
+        Ok(())
+    }
 
-            let mut rtentry: libc::rtentry = mem::zeroed();
-
-            let rt_dst = &mut *(&mut rtentry.rt_dst as *mut _ as *mut libc::sockaddr_in);
-            rt_dst.sin_family = libc::AF_INET as u16;
-            rt_dst.sin_addr = libc::in_addr {
-                s_addr: u32::from(route.dest().base_addr()).to_be(),
-            };
-
-            let rt_genmask = &mut *(&mut rtentry.rt_genmask as *mut _ as *mut libc::sockaddr_in);
-            rt_genmask.sin_family = libc::AF_INET as u16;
-            rt_genmask.sin_addr = libc::in_addr {
-                s_addr: u32::from(route.dest().netmask()).to_be(),
-            };
+    /// Sets the MAC address of a TAP interface.
+    pub fn set_mac_addr(&self, addr: [u8; 6]) -> Result<(), io::Error> {
+        netlink::set_link_addr(self.index()?, addr)
+    }
 
-            rtentry.rt_flags = libc::RTF_UP;
+    /// Sets the MTU of the interface.
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), io::Error> {
+        netlink::set_link_mtu(self.index()?, mtu)
+    }
 
-            if let Some(gateway_addr) = route.gateway() {
-                let rt_gateway =
-                    &mut *(&mut rtentry.rt_gateway as *mut _ as *mut libc::sockaddr_in);
-                rt_gateway.sin_family = libc::AF_INET as u16;
-                rt_gateway.sin_addr = libc::in_addr {
-                    s_addr: u32::from(gateway_addr).to_be(),
-                };
+    /// Adds an ipv4 route to a routing table, routes with a lower metric are preferred.
+    pub fn add_ipv4_route(
+        &self,
+        table: u32,
+        route: Ipv4Route,
+        metric: u32,
+    ) -> Result<(), io::Error> {
+        let dest = route.dest();
+        netlink::add_route(
+            table,
+            self.index()?,
+            IpAddr::V4(dest.base_addr()),
+            dest.netmask_prefix_length(),
+            route.gateway().map(IpAddr::V4),
+            metric,
+        )
+    }
 
-                rtentry.rt_flags |= libc::RTF_GATEWAY;
-            }
+    /// Removes an ipv4 route from a routing table.
+    pub fn remove_ipv4_route(
+        &self,
+        table: u32,
+        route: Ipv4Route,
+        metric: u32,
+    ) -> Result<(), io::Error> {
+        let dest = route.dest();
+        netlink::remove_route(
+            table,
+            self.index()?,
+            IpAddr::V4(dest.base_addr()),
+            dest.netmask_prefix_length(),
+            route.gateway().map(IpAddr::V4),
+            metric,
+        )
+    }
 
-            rtentry.rt_dev = self.name.as_ptr() as *mut _;
+    /// Adds an ipv6 route to a routing table, routes with a lower metric are preferred.
+    pub fn add_ipv6_route(
+        &self,
+        table: u32,
+        route: Ipv6Route,
+        metric: u32,
+    ) -> Result<(), io::Error> {
+        let dest = route.dest();
+        netlink::add_route(
+            table,
+            self.index()?,
+            IpAddr::V6(dest.base_addr()),
+            dest.netmask_prefix_length(),
+            route.gateway().map(IpAddr::V6),
+            metric,
+        )
+    }
 
-            if let Err(err) = errno!(libc::ioctl(fd, libc::SIOCADDRT, &rtentry)) {
-                let _ = libc::close(fd);
-                return Err(err);
-            }
+    /// Removes an ipv6 route from a routing table.
+    pub fn remove_ipv6_route(
+        &self,
+        table: u32,
+        route: Ipv6Route,
+        metric: u32,
+    ) -> Result<(), io::Error> {
+        let dest = route.dest();
+        netlink::remove_route(
+            table,
+            self.index()?,
+            IpAddr::V6(dest.base_addr()),
+            dest.netmask_prefix_length(),
+            route.gateway().map(IpAddr::V6),
+            metric,
+        )
+    }
+}
 
-            Ok(())
-        }
+fn name_to_index(name: &CStr) -> Result<u32, io::Error> {
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
     }
 }
@@ -0,0 +1,212 @@
+//! Commands and events as JSON lines over dedicated pipes, which keeps them apart from the
+//! process's stdout.
+use crate::{abbrev, drain_output, Program};
+use async_io::Async;
+use async_process::{unix::CommandExt, Command};
+use futures::{
+    channel::mpsc,
+    future::FutureExt,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    stream::StreamExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Stdio;
+
+/// Environment variable with the file descriptor the process reads commands from.
+pub const COMMAND_FD: &str = "NETSIM_EMBED_COMMAND_FD";
+
+/// Environment variable with the file descriptor the process writes events to.
+pub const EVENT_FD: &str = "NETSIM_EMBED_EVENT_FD";
+
+/// Encodes a message as a single line, newlines in strings are escaped by JSON.
+fn encode<T: Serialize>(msg: &T) -> io::Result<String> {
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn decode<T: DeserializeOwned>(line: &str) -> io::Result<T> {
+    Ok(serde_json::from_str(line)?)
+}
+
+/// Creates a pipe which isn't inherited by spawned processes, returns the read and write end.
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    unsafe {
+        errno!(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC))?;
+        Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    unsafe { errno!(libc::fcntl(fd, libc::F_SETFD, flags)).map(drop) }
+}
+
+/// Runs `bin` with commands and events exchanged over the pipes announced in `COMMAND_FD` and
+/// `EVENT_FD`. Its stdin is closed and its stdout and stderr are printed.
+pub(crate) fn program<C, E>(
+    mut bin: Command,
+    mut cmd: mpsc::UnboundedReceiver<C>,
+    event: mpsc::UnboundedSender<E>,
+) -> Program
+where
+    C: Serialize + Send + 'static,
+    E: DeserializeOwned + Send + 'static,
+{
+    Box::new(move |id, mut stop| {
+        let (command_rx, command_tx) = pipe()?;
+        let (event_rx, event_tx) = pipe()?;
+        let child_fds = [command_rx.as_raw_fd(), event_tx.as_raw_fd()];
+        bin.env(COMMAND_FD, child_fds[0].to_string())
+            .env(EVENT_FD, child_fds[1].to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        unsafe {
+            bin.pre_exec(move || {
+                for fd in child_fds {
+                    set_cloexec(fd, false)?;
+                }
+                Ok(())
+            });
+        }
+        let mut child = bin.spawn().map_err(|e| {
+            log::error!("cannot start machine {:?}: {}", bin, e);
+            e
+        })?;
+        // the events pipe closes when the process exits
+        drop((command_rx, event_tx));
+        let mut commands = Async::new(File::from(command_tx))?;
+        let mut events = BufReader::new(Async::new(File::from(event_rx))?)
+            .lines()
+            .fuse();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines().fuse();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines().fuse();
+
+        Ok(async move {
+            let command_task = async {
+                while let Some(cmd) = cmd.next().await {
+                    let line = encode(&cmd)?;
+                    log::debug!("{} {}", id, abbrev(&line.trim_end(), 2000, 80));
+                    commands.write_all(line.as_bytes()).await?;
+                }
+                log::info!("{} (command): closed", id);
+                io::Result::Ok(())
+            }
+            .fuse();
+            futures::pin_mut!(command_task);
+
+            let event_task = async {
+                while let Some(line) = events.next().await {
+                    let line = line?;
+                    log::debug!("{} {}", id, abbrev(&line, 2000, 80));
+                    if event.unbounded_send(decode(&line)?).is_err() {
+                        break;
+                    }
+                }
+                log::info!("{} (events): closed", id);
+                io::Result::Ok(())
+            }
+            .fuse();
+            futures::pin_mut!(event_task);
+
+            let stdout_task = async {
+                while let Some(line) = stdout.next().await {
+                    let line = line?;
+                    println!("{id} (stdout): {line}");
+                }
+                log::info!("{} (stdout): closed", id);
+                io::Result::Ok(())
+            }
+            .fuse();
+            futures::pin_mut!(stdout_task);
+
+            let stderr_task = async {
+                while let Some(line) = stderr.next().await {
+                    let line = line?;
+                    println!("{id} (stderr): {line}");
+                }
+                log::info!("{} (stderr): closed", id);
+                io::Result::Ok(())
+            }
+            .fuse();
+            futures::pin_mut!(stderr_task);
+
+            futures::select! {
+                res = command_task => res?,
+                res = event_task => res?,
+                res = stdout_task => res?,
+                res = stderr_task => res?,
+                _ = stop => {}
+            };
+            log::info!("{} killing", id);
+            child.kill()?;
+            drain_output(id, vec![event_task, stdout_task, stderr_task]).await
+        }
+        .boxed_local())
+    })
+}
+
+/// The end of the JSON protocol in the process of a machine spawned with `Machine::with_json`,
+/// which receives commands of type `C` and sends events of type `E`.
+#[derive(Debug)]
+pub struct JsonChannel<C, E> {
+    commands: io::BufReader<File>,
+    events: File,
+    _marker: PhantomData<fn(E) -> C>,
+}
+
+impl<C: DeserializeOwned, E: Serialize> JsonChannel<C, E> {
+    /// Opens the pipes announced in the environment, must be called at most once per process.
+    /// The pipes aren't inherited by processes spawned by the calling process.
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            commands: io::BufReader::new(fd_from_env(COMMAND_FD)?),
+            events: fd_from_env(EVENT_FD)?,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Receives the next command, `None` once the machine stops sending commands.
+    pub fn recv(&mut self) -> io::Result<Option<C>> {
+        let mut line = String::new();
+        if self.commands.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        decode(&line).map(Some)
+    }
+
+    pub fn send(&mut self, event: &E) -> io::Result<()> {
+        self.events.write_all(encode(event)?.as_bytes())
+    }
+}
+
+fn fd_from_env(var: &str) -> io::Result<File> {
+    let fd = std::env::var(var)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", var)))?;
+    // fails if the fd isn't open
+    set_cloexec(fd, true)?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_messages_as_lines() {
+        let msg = ("multi\nline".to_string(), vec![1u8, 2]);
+        let line = encode(&msg).unwrap();
+        assert_eq!(line.matches('\n').count(), 1);
+        assert!(line.ends_with('\n'));
+        assert_eq!(decode::<(String, Vec<u8>)>(&line).unwrap(), msg);
+        assert!(decode::<(String, Vec<u8>)>("not json").is_err());
+    }
+}
@@ -11,42 +11,176 @@ macro_rules! errno {
     }};
 }
 
+mod gso;
 pub mod iface;
+#[cfg(feature = "json")]
+pub mod json;
 mod namespace;
+mod netlink;
+mod resolver;
+mod sysctl;
 
+pub use iface::{IfaceMode, Offload};
+#[cfg(feature = "json")]
+pub use json::JsonChannel;
 pub use namespace::{unshare_user, Namespace};
+pub use resolver::Resolver;
+pub use sysctl::{Sysctls, TcpEcn};
 
 use async_process::Command;
 use async_std::future::timeout;
 use futures::{
     channel::{mpsc, oneshot},
-    future::{FusedFuture, FutureExt},
+    future::{try_join_all, FusedFuture, Future, FutureExt, LocalBoxFuture},
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     sink::SinkExt,
     stream::{FusedStream, StreamExt},
 };
-use netsim_embed_core::{Ipv4Range, Packet, Plug};
+use netsim_embed_core::{Ipv4Range, Ipv4Route, Ipv6Range, Ipv6Route, Plug};
 use std::{
     collections::VecDeque,
     fmt::{self, Display},
-    future::{pending, poll_fn},
     io::{Error, ErrorKind, Result, Write},
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
     process::Stdio,
     str::FromStr,
-    task::Poll,
+    sync::Arc,
     thread,
     time::Duration,
 };
 
 #[derive(Debug)]
 enum IfaceCtrl {
-    Up,
-    Down,
-    SetAddr(Ipv4Addr, u8, oneshot::Sender<()>),
+    Up(usize),
+    Down(usize),
+    SetAddr(usize, Ipv4Addr, u8, oneshot::Sender<()>),
+    SetIpv6Addr(usize, Ipv6Addr, u8, oneshot::Sender<()>),
+    RemoveAddr(usize, Ipv4Addr, u8, oneshot::Sender<()>),
+    RemoveIpv6Addr(usize, Ipv6Addr, u8, oneshot::Sender<()>),
+    AddIface(Plug, oneshot::Sender<(String, Option<[u8; 6]>)>),
+    AddIpAddr(usize, IpAddr, u8, oneshot::Sender<Result<()>>),
+    RemoveIpAddr(usize, IpAddr, u8, oneshot::Sender<Result<()>>),
+    SetMtu(usize, u32, oneshot::Sender<Result<()>>),
+    SetOffload(usize, Offload, oneshot::Sender<Result<()>>),
+    AddRoute(usize, u32, Ipv4Route, u32, oneshot::Sender<Result<()>>),
+    RemoveRoute(usize, u32, Ipv4Route, u32, oneshot::Sender<Result<()>>),
+    AddIpv6Route(usize, u32, Ipv6Route, u32, oneshot::Sender<Result<()>>),
+    RemoveIpv6Route(usize, u32, Ipv6Route, u32, oneshot::Sender<Result<()>>),
+    SetDefaultRoute(usize, bool),
+    AddRule(netlink::Rule, oneshot::Sender<Result<()>>),
+    RemoveRule(netlink::Rule, oneshot::Sender<Result<()>>),
+    SetSysctls(Sysctls, oneshot::Sender<Result<()>>),
+    Sysctls(oneshot::Sender<Result<Sysctls>>),
     Exit,
 }
 
+/// MTU of a newly created TUN interface.
+const DEFAULT_MTU: u32 = 1500;
+
+/// Maximum length of a hostname accepted by `sethostname`.
+const MAX_HOSTNAME_LEN: usize = 64;
+
+/// Largest packet a TUN interface hands out, a GSO packet or a packet with the maximum MTU.
+const MAX_PACKET_LEN: usize = u16::MAX as usize;
+
+/// The routing table `add_route` and `add_ipv6_route` add routes to.
+pub const MAIN_TABLE: u32 = netlink::RT_TABLE_MAIN;
+
+/// A policy routing rule which routes packets from `source` to `dest` with the routes of
+/// `table`. Rules are evaluated in the order of their priority, lower first, until a table has a
+/// route for the packet. The rule for the main table has priority 32766.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipv4Rule {
+    pub source: Ipv4Range,
+    pub dest: Ipv4Range,
+    pub table: u32,
+    pub priority: u32,
+}
+
+impl From<Ipv4Rule> for netlink::Rule {
+    fn from(rule: Ipv4Rule) -> Self {
+        Self {
+            source: (
+                rule.source.base_addr().into(),
+                rule.source.netmask_prefix_length(),
+            ),
+            dest: (
+                rule.dest.base_addr().into(),
+                rule.dest.netmask_prefix_length(),
+            ),
+            table: rule.table,
+            priority: rule.priority,
+        }
+    }
+}
+
+/// An IPv6 policy routing rule, see `Ipv4Rule`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipv6Rule {
+    pub source: Ipv6Range,
+    pub dest: Ipv6Range,
+    pub table: u32,
+    pub priority: u32,
+}
+
+impl From<Ipv6Rule> for netlink::Rule {
+    fn from(rule: Ipv6Rule) -> Self {
+        Self {
+            source: (
+                rule.source.base_addr().into(),
+                rule.source.netmask_prefix_length(),
+            ),
+            dest: (
+                rule.dest.base_addr().into(),
+                rule.dest.netmask_prefix_length(),
+            ),
+            table: rule.table,
+            priority: rule.priority,
+        }
+    }
+}
+
+/// Addresses assigned to an interface of a machine.
+#[derive(Clone, Debug)]
+struct IfaceInfo {
+    name: String,
+    addr: Ipv4Addr,
+    mask: u8,
+    addr6: Ipv6Addr,
+    prefix_len6: u8,
+    secondary_addrs: Vec<(IpAddr, u8)>,
+    default_route: bool,
+    mode: IfaceMode,
+    mac_addr: Option<[u8; 6]>,
+    mtu: u32,
+    offload: Offload,
+}
+
+impl IfaceInfo {
+    fn new(
+        name: String,
+        mode: IfaceMode,
+        mac_addr: Option<[u8; 6]>,
+        mtu: u32,
+        offload: Offload,
+    ) -> Self {
+        Self {
+            name,
+            mode,
+            mac_addr,
+            addr: Ipv4Addr::UNSPECIFIED,
+            mask: 32,
+            addr6: Ipv6Addr::UNSPECIFIED,
+            prefix_len6: 128,
+            secondary_addrs: Vec::new(),
+            default_route: true,
+            mtu,
+            offload,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct MachineId(pub usize);
 
@@ -56,19 +190,79 @@ impl fmt::Display for MachineId {
     }
 }
 
+/// Configuration of a machine applied when it is spawned. Spawning the machine fails with the
+/// error if the configuration can't be applied.
+#[derive(Clone, Debug, Default)]
+pub struct MachineConfig {
+    /// Mode of the machine's interfaces, including interfaces added later on.
+    pub iface_mode: IfaceMode,
+    /// Name resolution configuration, the machine shares the host's if it is `None`.
+    pub resolver: Option<Resolver>,
+    /// Hostname of the machine, defaults to `machine-<id>`. Spawning the machine fails if it is
+    /// longer than 64 bytes.
+    pub hostname: Option<String>,
+    /// Network sysctls applied before the machine's process is started.
+    pub sysctls: Sysctls,
+    /// MTU of the machine's interfaces, defaults to 1500.
+    pub mtu: Option<u32>,
+    /// Segmentation offloads of the machine's interfaces.
+    pub offload: Offload,
+}
+
+impl MachineConfig {
+    /// Checks the parts of the configuration which can be checked before the machine is started.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(hostname) = &self.hostname {
+            if hostname.len() > MAX_HOSTNAME_LEN {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "hostname {:?} is longer than {} bytes",
+                        hostname, MAX_HOSTNAME_LEN
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Namespace of a machine and name and MAC address of its first interface, sent to `start` once
+/// the program was started.
+type Started = (Namespace, String, Option<[u8; 6]>);
+
+/// Starts the program of a machine in its namespace. The returned future resolves when the
+/// program exits, or after `stop` fired and the program was shut down.
+type Program = Box<
+    dyn FnOnce(MachineId, oneshot::Receiver<()>) -> Result<LocalBoxFuture<'static, Result<()>>>
+        + Send,
+>;
+
+/// Locally administered MAC address of a TAP interface derived from the machine and interface.
+fn mac_addr(id: MachineId, iface: usize) -> [u8; 6] {
+    let id = (id.0 as u32).to_be_bytes();
+    [0x02, id[1], id[2], id[3], (iface >> 8) as u8, iface as u8]
+}
+
 /// Spawns a thread in a new network namespace and configures a TUN interface that sends and
 /// receives IP packets from the tx/rx channels and runs some UDP/TCP networking code in task.
+///
+/// Further interfaces can be added with `add_iface`, the methods without an interface index
+/// refer to the first interface.
 #[derive(Debug)]
 pub struct Machine<C, E> {
     id: MachineId,
-    addr: Ipv4Addr,
-    mask: u8,
+    hostname: String,
+    ifaces: Vec<IfaceInfo>,
     ns: Namespace,
     ctrl: mpsc::UnboundedSender<IfaceCtrl>,
     tx: mpsc::UnboundedSender<C>,
     rx: mpsc::UnboundedReceiver<E>,
     join: Option<thread::JoinHandle<Result<()>>>,
     buffer: VecDeque<E>,
+    /// MTU and offloads of interfaces added later on.
+    mtu: u32,
+    offload: Offload,
 }
 
 impl<C, E> Machine<C, E>
@@ -77,24 +271,118 @@ where
     E: FromStr + Display + Send + 'static,
     E::Err: std::fmt::Debug + Display + Send + Sync,
 {
-    pub async fn new(id: MachineId, plug: Plug, cmd: Command) -> Self {
+    pub async fn new(id: MachineId, plug: Plug, cmd: Command) -> Result<Self> {
+        Self::with_config(id, plug, cmd, MachineConfig::default()).await
+    }
+
+    /// Spawns a machine whose interfaces are configured by `config`.
+    pub async fn with_config(
+        id: MachineId,
+        plug: Plug,
+        cmd: Command,
+        config: MachineConfig,
+    ) -> Result<Self> {
+        Self::start(id, plug, config, |cmd_rx, event_tx| {
+            command(cmd, cmd_rx, event_tx)
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "json")]
+impl<C, E> Machine<C, E>
+where
+    C: serde::Serialize + Send + 'static,
+    E: serde::de::DeserializeOwned + Send + 'static,
+{
+    /// Spawns a machine whose process exchanges commands and events as JSON lines over
+    /// dedicated pipes instead of its stdin and stdout, see `JsonChannel`.
+    pub async fn with_json(
+        id: MachineId,
+        plug: Plug,
+        cmd: Command,
+        config: MachineConfig,
+    ) -> Result<Self> {
+        Self::start(id, plug, config, |cmd_rx, event_tx| {
+            json::program(cmd, cmd_rx, event_tx)
+        })
+        .await
+    }
+}
+
+impl<C, E> Machine<C, E>
+where
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawns a machine which runs the future returned by `task` instead of a process. Commands
+    /// and events are passed over the channels without being formatted or parsed. The future
+    /// is polled on the machine's thread, so sockets it creates belong to the machine's network
+    /// namespace, while tasks or blocking calls it hands off to other threads don't.
+    pub async fn with_task<F, T>(
+        id: MachineId,
+        plug: Plug,
+        config: MachineConfig,
+        task: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<C>, mpsc::UnboundedSender<E>) -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        Self::start(id, plug, config, |cmd_rx, event_tx| {
+            Box::new(move |_, stop| {
+                Ok(async move {
+                    futures::select! {
+                        _ = task(cmd_rx, event_tx).boxed_local().fuse() => {}
+                        _ = stop.fuse() => {}
+                    }
+                    Ok(())
+                }
+                .boxed_local())
+            })
+        })
+        .await
+    }
+
+    async fn start<F>(
+        id: MachineId,
+        plug: Plug,
+        mut config: MachineConfig,
+        program: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<C>, mpsc::UnboundedSender<E>) -> Program,
+    {
+        config.validate()?;
+        let hostname = config
+            .hostname
+            .get_or_insert_with(|| format!("machine-{}", id.0))
+            .clone();
         let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
         let (cmd_tx, cmd_rx) = mpsc::unbounded();
         let (event_tx, event_rx) = mpsc::unbounded();
         let (ns_tx, ns_rx) = oneshot::channel();
-        let join = machine(id, plug, cmd, ctrl_rx, ns_tx, cmd_rx, event_tx);
-        let ns = ns_rx.await.unwrap();
-        Self {
+        let mode = config.iface_mode;
+        let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
+        let offload = config.offload;
+        let join = machine(id, plug, config, ctrl_rx, ns_tx, program(cmd_rx, event_tx));
+        let (ns, name, mac_addr) = ns_rx
+            .await
+            .unwrap()
+            .map_err(|err| Error::new(err.kind(), format!("{} failed to start: {}", id, err)))?;
+        Ok(Self {
             id,
-            addr: Ipv4Addr::UNSPECIFIED,
-            mask: 32,
+            hostname,
+            ifaces: vec![IfaceInfo::new(name, mode, mac_addr, mtu, offload)],
             ns,
             ctrl: ctrl_tx,
             tx: cmd_tx,
             rx: event_rx,
             join: Some(join),
             buffer: VecDeque::new(),
-        }
+            mtu,
+            offload,
+        })
     }
 }
 
@@ -103,22 +391,463 @@ impl<C, E> Machine<C, E> {
         self.id
     }
 
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
     pub fn addr(&self) -> Ipv4Addr {
-        self.addr
+        self.iface_addr(0)
     }
 
     pub fn mask(&self) -> u8 {
-        self.mask
+        self.iface_mask(0)
     }
 
     pub async fn set_addr(&mut self, addr: Ipv4Addr, mask: u8) {
+        self.set_iface_addr(0, addr, mask).await
+    }
+
+    pub fn ipv6_addr(&self) -> Ipv6Addr {
+        self.iface_ipv6_addr(0)
+    }
+
+    pub fn ipv6_prefix_len(&self) -> u8 {
+        self.iface_ipv6_prefix_len(0)
+    }
+
+    /// Removes the IPv4 address of the machine.
+    pub async fn clear_addr(&mut self) {
+        self.clear_iface_addr(0).await
+    }
+
+    /// Sets the IPv6 address of the machine, replacing the previous one.
+    pub async fn set_ipv6_addr(&mut self, addr: Ipv6Addr, prefix_len: u8) {
+        self.set_iface_ipv6_addr(0, addr, prefix_len).await
+    }
+
+    /// Removes the IPv6 address of the machine.
+    pub async fn clear_ipv6_addr(&mut self) {
+        self.clear_iface_ipv6_addr(0).await
+    }
+
+    /// Returns all addresses of the first interface with their prefix length.
+    pub fn addrs(&self) -> Vec<(IpAddr, u8)> {
+        self.iface_addrs(0)
+    }
+
+    /// Adds a secondary address to the first interface.
+    pub async fn add_addr(&mut self, addr: IpAddr, prefix_len: u8) -> Result<()> {
+        self.add_iface_addr(0, addr, prefix_len).await
+    }
+
+    /// Removes an address from the first interface.
+    pub async fn remove_addr(&mut self, addr: IpAddr) -> Result<()> {
+        self.remove_iface_addr(0, addr).await
+    }
+
+    /// Adds an interface sending and receiving packets through `plug` and returns its index. The
+    /// interface has the mode the machine was configured with.
+    ///
+    /// The default route of an interface has its index plus one as metric, so the default route
+    /// of the lowest interface which has an address is used.
+    pub async fn add_iface(&mut self, plug: Plug) -> usize {
         let (tx, rx) = oneshot::channel();
         self.ctrl
-            .unbounded_send(IfaceCtrl::SetAddr(addr, mask, tx))
+            .unbounded_send(IfaceCtrl::AddIface(plug, tx))
+            .unwrap();
+        let (name, mac_addr) = rx.await.unwrap();
+        let mode = self.ifaces[0].mode;
+        let info = IfaceInfo::new(name, mode, mac_addr, self.mtu, self.offload);
+        self.ifaces.push(info);
+        self.ifaces.len() - 1
+    }
+
+    pub fn num_ifaces(&self) -> usize {
+        self.ifaces.len()
+    }
+
+    /// Returns the name of an interface inside the machine's namespace, eg. for binding a
+    /// socket to a device.
+    pub fn iface_name(&self, iface: usize) -> &str {
+        &self.ifaces[iface].name
+    }
+
+    pub fn iface_mode(&self, iface: usize) -> IfaceMode {
+        self.ifaces[iface].mode
+    }
+
+    /// Returns the MAC address of a TAP interface.
+    pub fn iface_mac_addr(&self, iface: usize) -> Option<[u8; 6]> {
+        self.ifaces[iface].mac_addr
+    }
+
+    pub fn iface_addr(&self, iface: usize) -> Ipv4Addr {
+        self.ifaces[iface].addr
+    }
+
+    pub fn iface_mask(&self, iface: usize) -> u8 {
+        self.ifaces[iface].mask
+    }
+
+    pub fn iface_ipv6_addr(&self, iface: usize) -> Ipv6Addr {
+        self.ifaces[iface].addr6
+    }
+
+    pub fn iface_ipv6_prefix_len(&self, iface: usize) -> u8 {
+        self.ifaces[iface].prefix_len6
+    }
+
+    /// Returns all addresses of an interface with their prefix length, starting with the
+    /// primary IPv4 and IPv6 addresses followed by secondary addresses in the order they were
+    /// added.
+    pub fn iface_addrs(&self, iface: usize) -> Vec<(IpAddr, u8)> {
+        let info = &self.ifaces[iface];
+        let mut addrs = Vec::with_capacity(info.secondary_addrs.len() + 2);
+        if !info.addr.is_unspecified() {
+            addrs.push((info.addr.into(), info.mask));
+        }
+        if !info.addr6.is_unspecified() {
+            addrs.push((info.addr6.into(), info.prefix_len6));
+        }
+        addrs.extend_from_slice(&info.secondary_addrs);
+        addrs
+    }
+
+    /// Adds a secondary address to an interface, which doesn't change the interface's routes.
+    pub async fn add_iface_addr(
+        &mut self,
+        iface: usize,
+        addr: IpAddr,
+        prefix_len: u8,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::AddIpAddr(iface, addr, prefix_len, tx))
+            .unwrap();
+        rx.await.unwrap()?;
+        self.ifaces[iface].secondary_addrs.push((addr, prefix_len));
+        Ok(())
+    }
+
+    /// Removes an address from an interface, removing a primary address behaves like
+    /// `clear_iface_addr` or `clear_iface_ipv6_addr`.
+    pub async fn remove_iface_addr(&mut self, iface: usize, addr: IpAddr) -> Result<()> {
+        let info = &self.ifaces[iface];
+        if addr == IpAddr::V4(info.addr) {
+            self.clear_iface_addr(iface).await;
+            return Ok(());
+        }
+        if addr == IpAddr::V6(info.addr6) {
+            self.clear_iface_ipv6_addr(iface).await;
+            return Ok(());
+        }
+        let idx = info
+            .secondary_addrs
+            .iter()
+            .position(|(secondary, _)| *secondary == addr)
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))?;
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::RemoveIpAddr(
+                iface,
+                addr,
+                info.secondary_addrs[idx].1,
+                tx,
+            ))
+            .unwrap();
+        rx.await.unwrap()?;
+        self.ifaces[iface].secondary_addrs.remove(idx);
+        Ok(())
+    }
+
+    /// Removes all secondary addresses of an interface.
+    pub async fn clear_secondary_addrs(&mut self, iface: usize) -> Result<()> {
+        while let Some((addr, _)) = self.ifaces[iface].secondary_addrs.last() {
+            self.remove_iface_addr(iface, *addr).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the IPv4 address of an interface, replacing the previous one.
+    pub async fn set_iface_addr(&mut self, iface: usize, addr: Ipv4Addr, mask: u8) {
+        let info = &self.ifaces[iface];
+        if info.addr == addr && info.mask == mask {
+            return;
+        }
+        self.clear_iface_addr(iface).await;
+        // a secondary address becomes the primary address
+        self.remove_iface_addr(iface, addr.into()).await.ok();
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetAddr(iface, addr, mask, tx))
+            .unwrap();
+        rx.await.unwrap();
+        let info = &mut self.ifaces[iface];
+        info.addr = addr;
+        info.mask = mask;
+    }
+
+    /// Removes the IPv4 address of an interface.
+    pub async fn clear_iface_addr(&mut self, iface: usize) {
+        let info = &self.ifaces[iface];
+        if info.addr.is_unspecified() {
+            return;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::RemoveAddr(iface, info.addr, info.mask, tx))
+            .unwrap();
+        rx.await.unwrap();
+        let info = &mut self.ifaces[iface];
+        info.addr = Ipv4Addr::UNSPECIFIED;
+        info.mask = 32;
+    }
+
+    /// Sets the IPv6 address of an interface, replacing the previous one.
+    pub async fn set_iface_ipv6_addr(&mut self, iface: usize, addr: Ipv6Addr, prefix_len: u8) {
+        let info = &self.ifaces[iface];
+        if info.addr6 == addr && info.prefix_len6 == prefix_len {
+            return;
+        }
+        self.clear_iface_ipv6_addr(iface).await;
+        self.remove_iface_addr(iface, addr.into()).await.ok();
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetIpv6Addr(iface, addr, prefix_len, tx))
+            .unwrap();
+        rx.await.unwrap();
+        let info = &mut self.ifaces[iface];
+        info.addr6 = addr;
+        info.prefix_len6 = prefix_len;
+    }
+
+    /// Removes the IPv6 address of an interface.
+    pub async fn clear_iface_ipv6_addr(&mut self, iface: usize) {
+        let info = &self.ifaces[iface];
+        if info.addr6.is_unspecified() {
+            return;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::RemoveIpv6Addr(
+                iface,
+                info.addr6,
+                info.prefix_len6,
+                tx,
+            ))
             .unwrap();
         rx.await.unwrap();
-        self.addr = addr;
-        self.mask = mask;
+        let info = &mut self.ifaces[iface];
+        info.addr6 = Ipv6Addr::UNSPECIFIED;
+        info.prefix_len6 = 128;
+    }
+
+    /// Adds an IPv4 route through an interface, routes with a lower metric are preferred. The
+    /// default route of an interface has its index plus one as metric.
+    pub async fn add_route(&self, iface: usize, route: Ipv4Route, metric: u32) -> Result<()> {
+        self.add_table_route(iface, MAIN_TABLE, route, metric).await
+    }
+
+    /// Removes an IPv4 route added with `add_route`.
+    pub async fn remove_route(&self, iface: usize, route: Ipv4Route, metric: u32) -> Result<()> {
+        self.remove_table_route(iface, MAIN_TABLE, route, metric)
+            .await
+    }
+
+    /// Adds an IPv4 route through an interface to a routing table, which is used for the packets
+    /// matching a rule added with `add_rule`.
+    pub async fn add_table_route(
+        &self,
+        iface: usize,
+        table: u32,
+        route: Ipv4Route,
+        metric: u32,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::AddRoute(iface, table, route, metric, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Removes an IPv4 route added with `add_table_route`.
+    pub async fn remove_table_route(
+        &self,
+        iface: usize,
+        table: u32,
+        route: Ipv4Route,
+        metric: u32,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::RemoveRoute(iface, table, route, metric, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Adds an IPv6 route through an interface, routes with a lower metric are preferred. The
+    /// default route of an interface has its index plus one as metric.
+    pub async fn add_ipv6_route(&self, iface: usize, route: Ipv6Route, metric: u32) -> Result<()> {
+        self.add_ipv6_table_route(iface, MAIN_TABLE, route, metric)
+            .await
+    }
+
+    /// Removes an IPv6 route added with `add_ipv6_route`.
+    pub async fn remove_ipv6_route(
+        &self,
+        iface: usize,
+        route: Ipv6Route,
+        metric: u32,
+    ) -> Result<()> {
+        self.remove_ipv6_table_route(iface, MAIN_TABLE, route, metric)
+            .await
+    }
+
+    /// Adds an IPv6 route through an interface to a routing table, which is used for the packets
+    /// matching a rule added with `add_ipv6_rule`.
+    pub async fn add_ipv6_table_route(
+        &self,
+        iface: usize,
+        table: u32,
+        route: Ipv6Route,
+        metric: u32,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::AddIpv6Route(iface, table, route, metric, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Removes an IPv6 route added with `add_ipv6_table_route`.
+    pub async fn remove_ipv6_table_route(
+        &self,
+        iface: usize,
+        table: u32,
+        route: Ipv6Route,
+        metric: u32,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::RemoveIpv6Route(iface, table, route, metric, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    async fn rule_ctrl(
+        &self,
+        rule: netlink::Rule,
+        ctrl: fn(netlink::Rule, oneshot::Sender<Result<()>>) -> IfaceCtrl,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl.unbounded_send(ctrl(rule, tx)).unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Adds a policy routing rule, eg. to route packets from the address of an interface
+    /// through that interface on a multi-homed machine.
+    pub async fn add_rule(&self, rule: Ipv4Rule) -> Result<()> {
+        self.rule_ctrl(rule.into(), IfaceCtrl::AddRule).await
+    }
+
+    /// Removes a rule added with `add_rule`.
+    pub async fn remove_rule(&self, rule: Ipv4Rule) -> Result<()> {
+        self.rule_ctrl(rule.into(), IfaceCtrl::RemoveRule).await
+    }
+
+    /// Adds an IPv6 policy routing rule.
+    pub async fn add_ipv6_rule(&self, rule: Ipv6Rule) -> Result<()> {
+        self.rule_ctrl(rule.into(), IfaceCtrl::AddRule).await
+    }
+
+    /// Removes a rule added with `add_ipv6_rule`.
+    pub async fn remove_ipv6_rule(&self, rule: Ipv6Rule) -> Result<()> {
+        self.rule_ctrl(rule.into(), IfaceCtrl::RemoveRule).await
+    }
+
+    pub fn iface_mtu(&self, iface: usize) -> u32 {
+        self.ifaces[iface].mtu
+    }
+
+    /// Sets the MTU of an interface, up to 65535 for jumbo frames.
+    pub async fn set_iface_mtu(&mut self, iface: usize, mtu: u32) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetMtu(iface, mtu, tx))
+            .unwrap();
+        rx.await.unwrap()?;
+        self.ifaces[iface].mtu = mtu;
+        Ok(())
+    }
+
+    pub fn iface_offload(&self, iface: usize) -> Offload {
+        self.ifaces[iface].offload
+    }
+
+    /// Enables/disables segmentation offloads of an interface, so the machine's kernel hands
+    /// out GSO packets which are segmented when they leave the machine.
+    pub async fn set_iface_offload(&mut self, iface: usize, offload: Offload) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetOffload(iface, offload, tx))
+            .unwrap();
+        rx.await.unwrap()?;
+        self.ifaces[iface].offload = offload;
+        Ok(())
+    }
+
+    pub fn default_route(&self, iface: usize) -> bool {
+        self.ifaces[iface].default_route
+    }
+
+    /// Enables/disables the default routes installed when an interface gets an address. Without
+    /// a default route only the interface's subnet and routes added with `add_route` are
+    /// reachable.
+    pub async fn set_default_route(&mut self, iface: usize, enabled: bool) -> Result<()> {
+        let info = &self.ifaces[iface];
+        if info.default_route == enabled {
+            return Ok(());
+        }
+        let (addr, addr6) = (info.addr, info.addr6);
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetDefaultRoute(iface, enabled))
+            .unwrap();
+        self.ifaces[iface].default_route = enabled;
+        let metric = iface as u32 + 1;
+        if !addr.is_unspecified() {
+            let route = Ipv4Range::global().into();
+            if enabled {
+                self.add_route(iface, route, metric).await?;
+            } else {
+                self.remove_route(iface, route, metric).await?;
+            }
+        }
+        if !addr6.is_unspecified() {
+            let route = Ipv6Range::global().into();
+            if enabled {
+                self.add_ipv6_route(iface, route, metric).await?;
+            } else {
+                self.remove_ipv6_route(iface, route, metric).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets network sysctls of the machine at runtime, fields which are `None` are left
+    /// unchanged. Sysctls are applied in order and applying stops at the first error.
+    pub async fn set_sysctls(&self, sysctls: Sysctls) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(IfaceCtrl::SetSysctls(sysctls, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Returns the current network sysctls of the machine.
+    pub async fn sysctls(&self) -> Result<Sysctls> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl.unbounded_send(IfaceCtrl::Sysctls(tx)).unwrap();
+        rx.await.unwrap()
     }
 
     pub fn send(&self, cmd: C) {
@@ -136,11 +865,19 @@ impl<C, E> Machine<C, E> {
     }
 
     pub fn up(&self) {
-        self.ctrl.unbounded_send(IfaceCtrl::Up).unwrap();
+        self.iface_up(0);
     }
 
     pub fn down(&self) {
-        self.ctrl.unbounded_send(IfaceCtrl::Down).unwrap();
+        self.iface_down(0);
+    }
+
+    pub fn iface_up(&self, iface: usize) {
+        self.ctrl.unbounded_send(IfaceCtrl::Up(iface)).unwrap();
+    }
+
+    pub fn iface_down(&self, iface: usize) {
+        self.ctrl.unbounded_send(IfaceCtrl::Down(iface)).unwrap();
     }
 
     pub fn namespace(&self) -> Namespace {
@@ -251,41 +988,237 @@ fn abbrev<T: Display>(t: &T, limit: usize, cut_len: usize) -> String {
     result
 }
 
-#[allow(clippy::too_many_arguments)]
-fn machine<C, E>(
+/// Creates an interface of a machine, applies the configured MTU and offloads and assigns a MAC
+/// address to TAP interfaces.
+fn create_iface(
+    id: MachineId,
+    idx: usize,
+    config: &MachineConfig,
+) -> Result<(iface::Iface, Option<[u8; 6]>)> {
+    let mode = config.iface_mode;
+    let iface = iface::Iface::with_mode(mode)?;
+    if let Some(mtu) = config.mtu {
+        iface.set_mtu(mtu)?;
+    }
+    if config.offload != Offload::default() {
+        iface.set_offload(config.offload)?;
+    }
+    let mac_addr = match mode {
+        IfaceMode::Tun => None,
+        IfaceMode::Tap => {
+            let mac_addr = mac_addr(id, idx);
+            iface.set_mac_addr(mac_addr)?;
+            Some(mac_addr)
+        }
+    };
+    Ok((iface, mac_addr))
+}
+
+/// Forwards packets between a TUN/TAP interface and a plug until either side closes.
+async fn forward(id: MachineId, iface: &async_io::Async<iface::Iface>, plug: Plug) -> Result<()> {
+    let (mut tx, mut rx) = plug.split();
+
+    let reader_task = async {
+        let l2_len = iface.get_ref().l2_len();
+        let mut buf = vec![0; gso::VNET_HDR_LEN + l2_len + MAX_PACKET_LEN];
+        'read: loop {
+            let n = iface.read_with(|iface| iface.recv(&mut buf)).await?;
+            if n == 0 {
+                break;
+            }
+            log::trace!("{} (reader): sending packet", id);
+            let packets = if let Some(packets) = gso::segment(&buf[..n], l2_len) {
+                packets
+            } else {
+                log::debug!("{} (reader): dropping unsupported packet", id);
+                continue;
+            };
+            for packet in packets {
+                if tx.send(packet).await.is_err() {
+                    break 'read;
+                }
+            }
+        }
+        log::info!("{} (reader): closed", id);
+        Result::Ok(())
+    }
+    .fuse();
+    futures::pin_mut!(reader_task);
+
+    let writer_task = async {
+        while let Some(packet) = rx.next().await {
+            log::trace!("{} (writer): received packet", id);
+            // can error if the interface is down
+            let packet = gso::encapsulate(&packet);
+            if let Ok(n) = iface.write_with(|iface| iface.send(&packet)).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+        log::info!("{} (writer): closed", id);
+        Result::Ok(())
+    }
+    .fuse();
+    futures::pin_mut!(writer_task);
+
+    futures::select! {
+        res = reader_task => res,
+        res = writer_task => res,
+    }
+}
+
+fn machine(
     id: MachineId,
     plug: Plug,
-    mut bin: Command,
+    config: MachineConfig,
     mut ctrl: mpsc::UnboundedReceiver<IfaceCtrl>,
-    ns_tx: oneshot::Sender<Namespace>,
-    mut cmd: mpsc::UnboundedReceiver<C>,
-    event: mpsc::UnboundedSender<E>,
-) -> thread::JoinHandle<Result<()>>
-where
-    C: Display + Send + 'static,
-    E: FromStr + Display + Send + 'static,
-    E::Err: std::fmt::Debug + Display + Send + Sync,
-{
+    ns_tx: oneshot::Sender<Result<Started>>,
+    program: Program,
+) -> thread::JoinHandle<Result<()>> {
     thread::spawn(move || {
-        let ns = Namespace::unshare()?;
-
         let res = async_global_executor::block_on(async move {
-            let iface = iface::Iface::new()?;
-            let iface = async_io::Async::new(iface)?;
-            let (mut tx, mut rx) = plug.split();
+            let setup = async {
+                let ns = Namespace::unshare()?;
+                let hostname = config.hostname.as_deref().unwrap_or_default();
+                namespace::set_hostname(hostname)?;
+                config.sysctls.apply()?;
+                if let Some(resolver) = &config.resolver {
+                    resolver.mount(hostname)?;
+                }
+                let (iface, mac_addr) = create_iface(id, 0, &config)?;
+                let iface = Arc::new(async_io::Async::new(iface)?);
+                let (stop_tx, stop_rx) = oneshot::channel();
+                let program = program(id, stop_rx)?;
+                Result::Ok((ns, iface, mac_addr, stop_tx, program))
+            };
+            let (ns, iface, mac_addr, stop_tx, program) = match setup.await {
+                Ok(setup) => setup,
+                Err(err) => {
+                    // `start` would only see the channel being dropped otherwise
+                    ns_tx
+                        .send(Err(Error::new(err.kind(), err.to_string())))
+                        .ok();
+                    return Err(err);
+                }
+            };
+            let name = iface.get_ref().name().to_string_lossy().into_owned();
 
             let ctrl_task = async {
+                let mut ifaces = vec![iface.clone()];
+                let mut default_routes = vec![true];
+                let mut tasks = Vec::new();
                 while let Some(ctrl) = ctrl.next().await {
                     log::debug!("{} CTRL {:?}", id, ctrl);
                     match ctrl {
-                        IfaceCtrl::Up => iface.get_ref().put_up()?,
-                        IfaceCtrl::Down => iface.get_ref().put_down()?,
-                        IfaceCtrl::SetAddr(addr, mask, tx) => {
-                            iface.get_ref().set_ipv4_addr(addr, mask)?;
-                            iface.get_ref().put_up()?;
-                            iface.get_ref().add_ipv4_route(Ipv4Range::global().into())?;
+                        IfaceCtrl::Up(idx) => ifaces[idx].get_ref().put_up()?,
+                        IfaceCtrl::Down(idx) => ifaces[idx].get_ref().put_down()?,
+                        IfaceCtrl::SetAddr(idx, addr, mask, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            iface.put_up()?;
+                            iface.add_ipv4_addr(addr, mask)?;
+                            if default_routes[idx] {
+                                let route = Ipv4Range::global().into();
+                                match iface.add_ipv4_route(MAIN_TABLE, route, idx as u32 + 1) {
+                                    Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                                        return Err(err)
+                                    }
+                                    _ => {}
+                                }
+                            }
                             tx.send(()).ok();
                         }
+                        IfaceCtrl::SetIpv6Addr(idx, addr, prefix_len, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            iface.put_up()?;
+                            iface.add_ipv6_addr(addr, prefix_len)?;
+                            // the default route outlives the addresses of a previous network
+                            if default_routes[idx] {
+                                let route = Ipv6Range::global().into();
+                                match iface.add_ipv6_route(MAIN_TABLE, route, idx as u32 + 1) {
+                                    Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                                        return Err(err)
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            tx.send(()).ok();
+                        }
+                        IfaceCtrl::RemoveAddr(idx, addr, mask, tx) => {
+                            ifaces[idx].get_ref().remove_ipv4_addr(addr, mask)?;
+                            tx.send(()).ok();
+                        }
+                        IfaceCtrl::RemoveIpv6Addr(idx, addr, prefix_len, tx) => {
+                            ifaces[idx].get_ref().remove_ipv6_addr(addr, prefix_len)?;
+                            tx.send(()).ok();
+                        }
+                        IfaceCtrl::AddIface(plug, tx) => {
+                            let (iface, mac_addr) = create_iface(id, ifaces.len(), &config)?;
+                            let iface = Arc::new(async_io::Async::new(iface)?);
+                            let name = iface.get_ref().name().to_string_lossy().into_owned();
+                            ifaces.push(iface.clone());
+                            default_routes.push(true);
+                            // dropping the task when the machine exits cancels it
+                            tasks.push(async_global_executor::spawn(async move {
+                                if let Err(err) = forward(id, &iface, plug).await {
+                                    log::error!("{} ({:?}): {}", id, iface.get_ref().name(), err);
+                                }
+                            }));
+                            tx.send((name, mac_addr)).ok();
+                        }
+                        IfaceCtrl::AddIpAddr(idx, addr, prefix_len, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            let res = match addr {
+                                IpAddr::V4(addr) => iface.add_ipv4_addr(addr, prefix_len),
+                                IpAddr::V6(addr) => iface.add_ipv6_addr(addr, prefix_len),
+                            };
+                            tx.send(res).ok();
+                        }
+                        IfaceCtrl::RemoveIpAddr(idx, addr, prefix_len, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            let res = match addr {
+                                IpAddr::V4(addr) => iface.remove_ipv4_addr(addr, prefix_len),
+                                IpAddr::V6(addr) => iface.remove_ipv6_addr(addr, prefix_len),
+                            };
+                            tx.send(res).ok();
+                        }
+                        IfaceCtrl::SetMtu(idx, mtu, tx) => {
+                            tx.send(ifaces[idx].get_ref().set_mtu(mtu)).ok();
+                        }
+                        IfaceCtrl::SetOffload(idx, offload, tx) => {
+                            tx.send(ifaces[idx].get_ref().set_offload(offload)).ok();
+                        }
+                        IfaceCtrl::AddRoute(idx, table, route, metric, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            tx.send(iface.add_ipv4_route(table, route, metric)).ok();
+                        }
+                        IfaceCtrl::RemoveRoute(idx, table, route, metric, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            tx.send(iface.remove_ipv4_route(table, route, metric)).ok();
+                        }
+                        IfaceCtrl::AddIpv6Route(idx, table, route, metric, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            tx.send(iface.add_ipv6_route(table, route, metric)).ok();
+                        }
+                        IfaceCtrl::RemoveIpv6Route(idx, table, route, metric, tx) => {
+                            let iface = ifaces[idx].get_ref();
+                            tx.send(iface.remove_ipv6_route(table, route, metric)).ok();
+                        }
+                        IfaceCtrl::AddRule(rule, tx) => {
+                            tx.send(netlink::add_rule(&rule)).ok();
+                        }
+                        IfaceCtrl::RemoveRule(rule, tx) => {
+                            tx.send(netlink::remove_rule(&rule)).ok();
+                        }
+                        IfaceCtrl::SetDefaultRoute(idx, enabled) => {
+                            default_routes[idx] = enabled;
+                        }
+                        IfaceCtrl::SetSysctls(sysctls, tx) => {
+                            tx.send(sysctls.apply()).ok();
+                        }
+                        IfaceCtrl::Sysctls(tx) => {
+                            tx.send(Sysctls::read()).ok();
+                        }
                         IfaceCtrl::Exit => {
                             break;
                         }
@@ -297,59 +1230,53 @@ where
             .fuse();
             futures::pin_mut!(ctrl_task);
 
-            let reader_task = async {
-                loop {
-                    let mut buf = [0; libc::ETH_FRAME_LEN as usize];
-                    let n = iface.read_with(|iface| iface.recv(&mut buf)).await?;
-                    if n == 0 {
-                        break;
-                    }
-                    // drop ipv6 packets
-                    if buf[0] >> 4 != 4 {
-                        continue;
-                    }
-                    log::trace!("{} (reader): sending packet", id);
-                    let mut bytes = buf[..n].to_vec();
-                    if let Some(mut packet) = Packet::new(&mut bytes) {
-                        packet.set_checksum();
-                    }
-                    if tx.send(bytes).await.is_err() {
-                        break;
-                    }
-                }
-                log::info!("{} (reader): closed", id);
-                Result::Ok(())
-            }
-            .fuse();
-            futures::pin_mut!(reader_task);
-
-            let writer_task = async {
-                while let Some(packet) = rx.next().await {
-                    log::trace!("{} (writer): received packet", id);
-                    // can error if the interface is down
-                    if let Ok(n) = iface.write_with(|iface| iface.send(&packet)).await {
-                        if n == 0 {
-                            break;
-                        }
-                    }
-                }
-                log::info!("{} (writer): closed", id);
-                Result::Ok(())
-            }
-            .fuse();
-            futures::pin_mut!(writer_task);
-
-            bin.stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped());
-            let mut child = bin.spawn().map_err(|e| {
-                log::error!("cannot start machine {:?}: {}", bin, e);
-                e
-            })?;
-            let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines().fuse();
-            let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines().fuse();
-            let mut stdin = child.stdin.take().unwrap();
+            let iface_task = forward(id, &iface, plug).fuse();
+            futures::pin_mut!(iface_task);
+
+            let mut program = program.fuse();
+
+            // unblock here so that possible exec error has a chance to get out
+            let _ = ns_tx.send(Ok((ns, name, mac_addr)));
+
+            futures::select! {
+                res = ctrl_task => res?,
+                res = iface_task => res?,
+                res = program => return res,
+            };
+            log::info!("{} stopping", id);
+            stop_tx.send(()).ok();
+            program.await
+        });
+        log::info!("{}'s event loop yielded with {:?}", id, res);
+        res
+    })
+}
+
+/// Runs `bin` with piped stdio, commands are written to its stdin and lines of its stdout
+/// starting with `<` are parsed as events.
+fn command<C, E>(
+    mut bin: Command,
+    mut cmd: mpsc::UnboundedReceiver<C>,
+    event: mpsc::UnboundedSender<E>,
+) -> Program
+where
+    C: Display + Send + 'static,
+    E: FromStr + Display + Send + 'static,
+    E::Err: std::fmt::Debug + Display + Send + Sync,
+{
+    Box::new(move |id, mut stop| {
+        bin.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = bin.spawn().map_err(|e| {
+            log::error!("cannot start machine {:?}: {}", bin, e);
+            e
+        })?;
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines().fuse();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines().fuse();
+        let mut stdin = child.stdin.take().unwrap();
 
+        Ok(async move {
             let command_task = async {
                 let mut buf = Vec::with_capacity(4096);
                 while let Some(cmd) = cmd.next().await {
@@ -370,7 +1297,7 @@ where
                     if ev.starts_with('<') {
                         let ev = match E::from_str(&ev) {
                             Ok(ev) => ev,
-                            Err(err) => return Err(Error::new(ErrorKind::Other, err.to_string())),
+                            Err(err) => return Err(Error::other(err.to_string())),
                         };
                         log::debug!("{} {}", id, abbrev(&ev, 2000, 80));
                         if event.unbounded_send(ev).is_err() {
@@ -397,56 +1324,32 @@ where
             .fuse();
             futures::pin_mut!(stderr_task);
 
-            // unblock here so that possible exec error has a chance to get out
-            let _ = ns_tx.send(ns);
-
             futures::select! {
-                res = ctrl_task => res?,
-                res = reader_task => res?,
-                res = writer_task => res?,
                 res = command_task => res?,
                 res = event_task => res?,
                 res = stderr_task => res?,
+                _ = stop => {}
             };
             log::info!("{} killing", id);
             child.kill()?;
-            let deadline = timeout(Duration::from_secs(3), pending::<()>());
-            futures::pin_mut!(deadline);
-            let mut event_task = (!event_task.is_terminated()).then_some(event_task);
-            let mut stderr_task = (!stderr_task.is_terminated()).then_some(stderr_task);
-            poll_fn(|cx| {
-                if deadline.poll_unpin(cx).is_ready() {
-                    log::warn!(
-                        "{} ev: {} err: {}",
-                        id,
-                        event_task.is_some(),
-                        stderr_task.is_some()
-                    );
-                    return Poll::Ready(Err(ErrorKind::TimedOut.into()));
-                }
-                match (&mut event_task, &mut stderr_task) {
-                    (None, None) => return Poll::Ready(Ok(())),
-                    (None, Some(err)) => return err.poll_unpin(cx),
-                    (Some(ev), None) => return ev.poll_unpin(cx),
-                    (Some(ev), Some(err)) => {
-                        let ev = ev.poll_unpin(cx);
-                        let err = err.poll_unpin(cx);
-                        match (ev, err) {
-                            (Poll::Ready(Err(e)), _) => return Poll::Ready(Err(e)),
-                            (_, Poll::Ready(Err(e))) => return Poll::Ready(Err(e)),
-                            (Poll::Ready(Ok(_)), Poll::Ready(Ok(_))) => return Poll::Ready(Ok(())),
-                            (Poll::Ready(Ok(_)), _) => event_task = None,
-                            (_, Poll::Ready(Ok(_))) => stderr_task = None,
-                            _ => {}
-                        }
-                    }
-                }
-                Poll::Pending
-            })
-            .await?;
-            Ok(())
-        });
-        log::info!("{}'s event loop yielded with {:?}", id, res);
-        res
+            drain_output(id, vec![event_task, stderr_task]).await
+        }
+        .boxed_local())
     })
 }
+
+/// Waits for the tasks reading the output of a killed process to finish, fails if the output
+/// isn't closed within three seconds.
+async fn drain_output(
+    id: MachineId,
+    tasks: Vec<Pin<&mut dyn FusedFuture<Output = Result<()>>>>,
+) -> Result<()> {
+    let tasks = tasks.into_iter().filter(|task| !task.is_terminated());
+    match timeout(Duration::from_secs(3), try_join_all(tasks)).await {
+        Ok(res) => res.map(drop),
+        Err(_) => {
+            log::warn!("{} output not closed", id);
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
+}
@@ -22,6 +22,17 @@ pub fn unshare_user() -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Sets the hostname of the calling thread's UTS namespace.
+pub(crate) fn set_hostname(hostname: &str) -> Result<(), io::Error> {
+    unsafe {
+        errno!(libc::sethostname(
+            hostname.as_ptr() as *const libc::c_char,
+            hostname.len()
+        ))?;
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Namespace {
     pid: i32,
@@ -0,0 +1,453 @@
+//! Minimal rtnetlink client for configuring links, addresses, routes and routing rules.
+//!
+//! See `linux/netlink.h`, `linux/rtnetlink.h`, `linux/if_addr.h` and `linux/fib_rules.h`.
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+
+const NLMSG_ERROR: u16 = 2;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_MTU: u16 = 4;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_F_NODAD: u8 = 0x02;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_TABLE: u16 = 15;
+
+const FRA_DST: u16 = 1;
+const FRA_SRC: u16 = 2;
+const FRA_PRIORITY: u16 = 6;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+
+/// The main routing table, which routes are added to by default.
+pub const RT_TABLE_MAIN: u32 = 254;
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+/// A netlink request consisting of a fixed size header followed by attributes.
+struct Message {
+    ty: u16,
+    flags: u16,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn new(ty: u16, flags: u16, header: &[u8]) -> Self {
+        Self {
+            ty,
+            flags: flags | NLM_F_REQUEST | NLM_F_ACK,
+            body: header.to_vec(),
+        }
+    }
+
+    fn add_attribute(&mut self, ty: u16, value: &[u8]) {
+        let len = 4 + value.len();
+        self.body.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.body.extend_from_slice(&ty.to_ne_bytes());
+        self.body.extend_from_slice(value);
+        self.body.resize(align(self.body.len()), 0);
+    }
+
+    fn add_addr(&mut self, ty: u16, addr: IpAddr) {
+        match addr {
+            IpAddr::V4(addr) => self.add_attribute(ty, &addr.octets()),
+            IpAddr::V6(addr) => self.add_attribute(ty, &addr.octets()),
+        }
+    }
+
+    fn encode(&self, seq: u32) -> Vec<u8> {
+        let len = 16 + self.body.len();
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&self.ty.to_ne_bytes());
+        buf.extend_from_slice(&self.flags.to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+/// Sends a request and waits for the kernel to acknowledge it.
+fn request(message: &Message) -> io::Result<()> {
+    unsafe {
+        let fd = errno!(libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        ))?;
+        let res = request_fd(fd, message);
+        let _ = libc::close(fd);
+        res
+    }
+}
+
+unsafe fn request_fd(fd: libc::c_int, message: &Message) -> io::Result<()> {
+    let mut addr: libc::sockaddr_nl = mem::zeroed();
+    addr.nl_family = libc::AF_NETLINK as u16;
+    errno!(libc::bind(
+        fd,
+        &addr as *const _ as *const libc::sockaddr,
+        mem::size_of::<libc::sockaddr_nl>() as u32,
+    ))?;
+    let seq = 1;
+    let buf = message.encode(seq);
+    errno!(libc::send(fd, buf.as_ptr() as *const _, buf.len(), 0))?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = errno!(libc::recv(fd, buf.as_mut_ptr() as *mut _, buf.len(), 0))? as usize;
+        let mut pos = 0;
+        while pos + 16 <= len {
+            let msg_len = u32::from_ne_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            let ty = u16::from_ne_bytes(buf[pos + 4..pos + 6].try_into().unwrap());
+            let msg_seq = u32::from_ne_bytes(buf[pos + 8..pos + 12].try_into().unwrap());
+            if msg_len < 16 || pos + msg_len > len {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            if ty == NLMSG_ERROR && msg_seq == seq && msg_len >= 20 {
+                let code = i32::from_ne_bytes(buf[pos + 16..pos + 20].try_into().unwrap());
+                return if code == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(-code))
+                };
+            }
+            pos += align(msg_len);
+        }
+    }
+}
+
+/// Sets the flags selected by `change` of a link.
+pub fn set_link_flags(index: u32, flags: u32, change: u32) -> io::Result<()> {
+    // struct ifinfomsg
+    let mut header = [0u8; 16];
+    header[0] = libc::AF_UNSPEC as u8;
+    header[4..8].copy_from_slice(&(index as i32).to_ne_bytes());
+    header[8..12].copy_from_slice(&flags.to_ne_bytes());
+    header[12..16].copy_from_slice(&change.to_ne_bytes());
+    request(&Message::new(RTM_NEWLINK, 0, &header))
+}
+
+/// Sets the MTU of a link.
+pub fn set_link_mtu(index: u32, mtu: u32) -> io::Result<()> {
+    let mut header = [0u8; 16];
+    header[0] = libc::AF_UNSPEC as u8;
+    header[4..8].copy_from_slice(&(index as i32).to_ne_bytes());
+    let mut message = Message::new(RTM_NEWLINK, 0, &header);
+    message.add_attribute(IFLA_MTU, &mtu.to_ne_bytes());
+    request(&message)
+}
+
+/// Sets the hardware address of a link.
+pub fn set_link_addr(index: u32, addr: [u8; 6]) -> io::Result<()> {
+    let mut header = [0u8; 16];
+    header[0] = libc::AF_UNSPEC as u8;
+    header[4..8].copy_from_slice(&(index as i32).to_ne_bytes());
+    let mut message = Message::new(RTM_NEWLINK, 0, &header);
+    message.add_attribute(IFLA_ADDRESS, &addr);
+    request(&message)
+}
+
+fn addr_message(ty: u16, flags: u16, index: u32, addr: IpAddr, prefix_len: u8) -> Message {
+    // struct ifaddrmsg
+    let mut header = [0u8; 8];
+    header[0] = family(addr);
+    header[1] = prefix_len;
+    header[2] = IFA_F_NODAD;
+    header[4..8].copy_from_slice(&index.to_ne_bytes());
+    let mut message = Message::new(ty, flags, &header);
+    message.add_addr(IFA_LOCAL, addr);
+    message.add_addr(IFA_ADDRESS, addr);
+    message
+}
+
+/// Adds an address together with its prefix length to a link.
+pub fn add_addr(index: u32, addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+    let flags = NLM_F_CREATE | NLM_F_EXCL;
+    request(&addr_message(RTM_NEWADDR, flags, index, addr, prefix_len))
+}
+
+/// Removes an address from a link.
+pub fn remove_addr(index: u32, addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+    request(&addr_message(RTM_DELADDR, 0, index, addr, prefix_len))
+}
+
+/// The `rtm_table` field only fits the ids of the reserved tables, others are passed as an
+/// attribute.
+fn table_id(table: u32) -> u8 {
+    if table < 256 {
+        table as u8
+    } else {
+        0
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route_message(
+    ty: u16,
+    flags: u16,
+    table: u32,
+    index: u32,
+    dest: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+    metric: u32,
+) -> Message {
+    // struct rtmsg
+    let mut header = [0u8; 12];
+    header[0] = family(dest);
+    header[1] = prefix_len;
+    header[4] = table_id(table);
+    header[5] = RTPROT_STATIC;
+    header[6] = if gateway.is_some() {
+        RT_SCOPE_UNIVERSE
+    } else {
+        RT_SCOPE_LINK
+    };
+    header[7] = RTN_UNICAST;
+    let mut message = Message::new(ty, flags, &header);
+    if prefix_len > 0 {
+        message.add_addr(RTA_DST, dest);
+    }
+    if let Some(gateway) = gateway {
+        message.add_addr(RTA_GATEWAY, gateway);
+    }
+    message.add_attribute(RTA_OIF, &index.to_ne_bytes());
+    message.add_attribute(RTA_PRIORITY, &metric.to_ne_bytes());
+    message.add_attribute(RTA_TABLE, &table.to_ne_bytes());
+    message
+}
+
+/// Adds a route through a link to a routing table.
+pub fn add_route(
+    table: u32,
+    index: u32,
+    dest: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+    metric: u32,
+) -> io::Result<()> {
+    let flags = NLM_F_CREATE | NLM_F_EXCL;
+    request(&route_message(
+        RTM_NEWROUTE,
+        flags,
+        table,
+        index,
+        dest,
+        prefix_len,
+        gateway,
+        metric,
+    ))
+}
+
+/// Removes a route from a routing table.
+pub fn remove_route(
+    table: u32,
+    index: u32,
+    dest: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+    metric: u32,
+) -> io::Result<()> {
+    request(&route_message(
+        RTM_DELROUTE,
+        0,
+        table,
+        index,
+        dest,
+        prefix_len,
+        gateway,
+        metric,
+    ))
+}
+
+/// A routing rule which looks up the routes of `table` for packets from `source` to `dest`,
+/// given as address and prefix length. A prefix length of zero matches all addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub source: (IpAddr, u8),
+    pub dest: (IpAddr, u8),
+    pub table: u32,
+    pub priority: u32,
+}
+
+fn rule_message(ty: u16, flags: u16, rule: &Rule) -> Message {
+    // struct fib_rule_hdr
+    let mut header = [0u8; 12];
+    header[0] = family(rule.source.0);
+    header[1] = rule.dest.1;
+    header[2] = rule.source.1;
+    header[4] = table_id(rule.table);
+    header[7] = FR_ACT_TO_TBL;
+    let mut message = Message::new(ty, flags, &header);
+    if rule.dest.1 > 0 {
+        message.add_addr(FRA_DST, rule.dest.0);
+    }
+    if rule.source.1 > 0 {
+        message.add_addr(FRA_SRC, rule.source.0);
+    }
+    message.add_attribute(FRA_PRIORITY, &rule.priority.to_ne_bytes());
+    message.add_attribute(FRA_TABLE, &rule.table.to_ne_bytes());
+    message
+}
+
+/// Adds a routing rule.
+pub fn add_rule(rule: &Rule) -> io::Result<()> {
+    let flags = NLM_F_CREATE | NLM_F_EXCL;
+    request(&rule_message(RTM_NEWRULE, flags, rule))
+}
+
+/// Removes a routing rule.
+pub fn remove_rule(rule: &Rule) -> io::Result<()> {
+    request(&rule_message(RTM_DELRULE, 0, rule))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // the expected messages are spelled out in little endian byte order
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn encodes_addresses() {
+        let addr = Ipv4Addr::new(10, 0, 0, 2).into();
+        let bytes = addr_message(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, 3, addr, 24).encode(1);
+        #[rustfmt::skip]
+        let expected = [
+            // nlmsghdr: length, RTM_NEWADDR, NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL |
+            // NLM_F_CREATE, sequence number, port id
+            40, 0, 0, 0, 20, 0, 0x05, 0x06, 1, 0, 0, 0, 0, 0, 0, 0,
+            // ifaddrmsg: AF_INET, prefix length, IFA_F_NODAD, scope, interface index
+            2, 24, 2, 0, 3, 0, 0, 0,
+            // IFA_LOCAL and IFA_ADDRESS
+            8, 0, 2, 0, 10, 0, 0, 2,
+            8, 0, 1, 0, 10, 0, 0, 2,
+        ];
+        assert_eq!(bytes, expected);
+
+        let addr = "fd00::2".parse::<Ipv6Addr>().unwrap();
+        let bytes = addr_message(RTM_DELADDR, 0, 3, addr.into(), 64).encode(1);
+        assert_eq!(bytes.len(), 16 + 8 + 2 * 20);
+        assert_eq!(&bytes[4..6], &[21, 0]);
+        assert_eq!(&bytes[16..24], &[10, 64, 2, 0, 3, 0, 0, 0]);
+        assert_eq!(&bytes[24..28], &[20, 0, 2, 0]);
+        assert_eq!(&bytes[28..44], &addr.octets());
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn encodes_routes() {
+        let dest = Ipv4Addr::new(10, 0, 1, 0).into();
+        let gateway = Some(Ipv4Addr::new(10, 0, 0, 1).into());
+        let message = route_message(RTM_NEWROUTE, 0, RT_TABLE_MAIN, 3, dest, 24, gateway, 5);
+        #[rustfmt::skip]
+        let expected = [
+            // nlmsghdr: length, RTM_NEWROUTE, NLM_F_REQUEST | NLM_F_ACK, sequence number,
+            // port id
+            68, 0, 0, 0, 24, 0, 0x05, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+            // rtmsg: AF_INET, destination length, source length, tos, RT_TABLE_MAIN,
+            // RTPROT_STATIC, RT_SCOPE_UNIVERSE, RTN_UNICAST, flags
+            2, 24, 0, 0, 254, 4, 0, 1, 0, 0, 0, 0,
+            // RTA_DST, RTA_GATEWAY, RTA_OIF, RTA_PRIORITY and RTA_TABLE
+            8, 0, 1, 0, 10, 0, 1, 0,
+            8, 0, 5, 0, 10, 0, 0, 1,
+            8, 0, 4, 0, 3, 0, 0, 0,
+            8, 0, 6, 0, 5, 0, 0, 0,
+            8, 0, 15, 0, 254, 0, 0, 0,
+        ];
+        assert_eq!(message.encode(2), expected);
+
+        // default routes have no destination, routes without a gateway are link scoped, and
+        // tables above 255 only fit into the attribute
+        let dest = Ipv4Addr::UNSPECIFIED.into();
+        let bytes = route_message(RTM_DELROUTE, 0, 1000, 3, dest, 0, None, 1).encode(2);
+        assert_eq!(bytes.len(), 16 + 12 + 3 * 8);
+        assert_eq!(&bytes[16..24], &[2, 0, 0, 0, 0, 4, 253, 1]);
+        assert_eq!(&bytes[28..32], &[8, 0, 4, 0]);
+        assert_eq!(&bytes[44..52], &[8, 0, 15, 0, 0xe8, 0x03, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn pads_attributes() {
+        let mut message = Message::new(RTM_NEWLINK, 0, &[0; 16]);
+        message.add_attribute(IFLA_ADDRESS, &[2, 0, 0, 0, 0, 1]);
+        message.add_attribute(IFLA_MTU, &1500u32.to_ne_bytes());
+        let bytes = message.encode(3);
+        #[rustfmt::skip]
+        let attributes = [
+            // the length excludes the padding to a multiple of four bytes
+            10, 0, 1, 0, 2, 0, 0, 0, 0, 1, 0, 0,
+            8, 0, 4, 0, 0xdc, 0x05, 0, 0,
+        ];
+        assert_eq!(&bytes[..4], &[52, 0, 0, 0]);
+        assert_eq!(&bytes[32..], &attributes);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn encodes_rules() {
+        let rule = Rule {
+            source: (Ipv4Addr::new(10, 0, 1, 0).into(), 24),
+            dest: (Ipv4Addr::UNSPECIFIED.into(), 0),
+            table: 1000,
+            priority: 100,
+        };
+        let bytes = rule_message(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, &rule).encode(7);
+        let mut expected = vec![];
+        // struct nlmsghdr
+        expected.extend_from_slice(&52u32.to_ne_bytes());
+        expected.extend_from_slice(&RTM_NEWRULE.to_ne_bytes());
+        expected.extend_from_slice(&0x605u16.to_ne_bytes());
+        expected.extend_from_slice(&7u32.to_ne_bytes());
+        expected.extend_from_slice(&0u32.to_ne_bytes());
+        // struct fib_rule_hdr: AF_INET, no destination, /24 source, table in an attribute
+        expected.extend_from_slice(&[2, 0, 24, 0, 0, 0, 0, FR_ACT_TO_TBL, 0, 0, 0, 0]);
+        expected.extend_from_slice(&8u16.to_ne_bytes());
+        expected.extend_from_slice(&FRA_SRC.to_ne_bytes());
+        expected.extend_from_slice(&[10, 0, 1, 0]);
+        expected.extend_from_slice(&8u16.to_ne_bytes());
+        expected.extend_from_slice(&FRA_PRIORITY.to_ne_bytes());
+        expected.extend_from_slice(&100u32.to_ne_bytes());
+        expected.extend_from_slice(&8u16.to_ne_bytes());
+        expected.extend_from_slice(&FRA_TABLE.to_ne_bytes());
+        expected.extend_from_slice(&1000u32.to_ne_bytes());
+        assert_eq!(bytes, expected);
+    }
+}
@@ -0,0 +1,127 @@
+//! Per-machine `/etc/resolv.conf` and `/etc/hosts` in a private mount namespace.
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Name resolution configuration of a machine. The files are bind mounted over the host's in a
+/// private mount namespace of the machine, so they are only visible to the machine's process.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Resolver {
+    /// Name servers written to `/etc/resolv.conf`, at most three are used by the libc resolver.
+    pub nameservers: Vec<IpAddr>,
+    /// Search domains of unqualified names.
+    pub search: Vec<String>,
+    /// Entries of `/etc/hosts` in addition to the entries of localhost and the machine's
+    /// hostname.
+    pub hosts: Vec<(IpAddr, String)>,
+}
+
+impl Resolver {
+    fn resolv_conf(&self) -> String {
+        let mut conf = String::new();
+        for nameserver in &self.nameservers {
+            conf.push_str(&format!("nameserver {}\n", nameserver));
+        }
+        if !self.search.is_empty() {
+            conf.push_str(&format!("search {}\n", self.search.join(" ")));
+        }
+        conf
+    }
+
+    fn hosts(&self, hostname: &str) -> String {
+        let mut hosts = format!(
+            "127.0.0.1 localhost\n::1 localhost\n127.0.1.1 {}\n",
+            hostname
+        );
+        for (addr, name) in &self.hosts {
+            hosts.push_str(&format!("{} {}\n", addr, name));
+        }
+        hosts
+    }
+
+    /// Moves the calling thread into a private mount namespace and mounts the files, `hostname`
+    /// resolves to a loopback address. Processes spawned by the thread inherit the namespace.
+    pub(crate) fn mount(&self, hostname: &str) -> io::Result<()> {
+        unsafe {
+            errno!(libc::unshare(libc::CLONE_NEWNS))?;
+            // keep the mounts from propagating to the host
+            errno!(libc::mount(
+                ptr::null(),
+                b"/\0".as_ptr() as *const libc::c_char,
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            ))?;
+        }
+        bind_file("resolv.conf", &self.resolv_conf())?;
+        bind_file("hosts", &self.hosts(hostname))?;
+        Ok(())
+    }
+}
+
+/// Writes `contents` to a temporary file and bind mounts it over `/etc/<name>`. The mount keeps
+/// the file alive after it is removed.
+fn bind_file(name: &str, contents: &str) -> io::Result<()> {
+    let (source, mut file) = create_temp_file(name)?;
+    let res = file.write_all(contents.as_bytes());
+    drop(file);
+    if let Err(err) = res {
+        fs::remove_file(&source).ok();
+        return Err(err);
+    }
+    let target = Path::new("/etc").join(name);
+    let res = unsafe {
+        let source = CString::new(source.as_os_str().as_bytes())?;
+        let target = CString::new(target.as_os_str().as_bytes())?;
+        errno!(libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND,
+            ptr::null(),
+        ))
+    };
+    fs::remove_file(&source)?;
+    res.map(drop)
+}
+
+/// Creates a file with a random name in the temporary directory. The file must not exist yet, so
+/// a symlink planted at the name is never followed.
+fn create_temp_file(name: &str) -> io::Result<(PathBuf, File)> {
+    loop {
+        let file_name = format!("netsim-embed-{:016x}-{}", rand::random::<u64>(), name);
+        let path = std::env::temp_dir().join(file_name);
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(&path);
+        match res {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_unique_temp_files() {
+        let (a, _) = create_temp_file("hosts").unwrap();
+        let (b, _) = create_temp_file("hosts").unwrap();
+        assert_ne!(a, b);
+        for path in [a, b] {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with("netsim-embed-") && name.ends_with("-hosts"));
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Network sysctls, which are private to the network namespace of a machine.
+use std::fs;
+use std::io;
+
+const TCP_CONGESTION_CONTROL: &str = "net/ipv4/tcp_congestion_control";
+const IP_LOCAL_PORT_RANGE: &str = "net/ipv4/ip_local_port_range";
+const TCP_ECN: &str = "net/ipv4/tcp_ecn";
+const TCP_RMEM: &str = "net/ipv4/tcp_rmem";
+const TCP_WMEM: &str = "net/ipv4/tcp_wmem";
+const IP_FORWARD: &str = "net/ipv4/ip_forward";
+const IPV6_FORWARDING: &str = "net/ipv6/conf/all/forwarding";
+
+/// Use of explicit congestion notification by TCP (`net.ipv4.tcp_ecn`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpEcn {
+    Disabled,
+    /// ECN is requested by outgoing connections and accepted on incoming connections.
+    Enabled,
+    /// ECN is only used if incoming connections request it, the kernel's default.
+    Passive,
+}
+
+/// Network sysctls of a machine, fields which are `None` keep their current value.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Sysctls {
+    /// `net.ipv4.tcp_congestion_control`, eg. `cubic` or `bbr`. The algorithm must be built
+    /// into the kernel or loaded as module.
+    pub tcp_congestion_control: Option<String>,
+    /// `net.ipv4.ip_local_port_range`, the range ephemeral ports are chosen from.
+    pub ip_local_port_range: Option<(u16, u16)>,
+    pub tcp_ecn: Option<TcpEcn>,
+    /// `net.ipv4.tcp_rmem`, the minimum, default and maximum receive buffer size in bytes.
+    pub tcp_rmem: Option<(u32, u32, u32)>,
+    /// `net.ipv4.tcp_wmem`, the minimum, default and maximum send buffer size in bytes.
+    pub tcp_wmem: Option<(u32, u32, u32)>,
+    /// `net.ipv4.ip_forward`, whether the machine routes IPv4 packets between its interfaces.
+    pub ip_forward: Option<bool>,
+    /// `net.ipv6.conf.all.forwarding`, whether the machine routes IPv6 packets between its
+    /// interfaces.
+    pub ipv6_forwarding: Option<bool>,
+}
+
+impl Sysctls {
+    /// Writes the sysctls of the calling thread's network namespace.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(algorithm) = &self.tcp_congestion_control {
+            write(TCP_CONGESTION_CONTROL, algorithm)?;
+        }
+        if let Some((low, high)) = self.ip_local_port_range {
+            write(IP_LOCAL_PORT_RANGE, &format!("{} {}", low, high))?;
+        }
+        if let Some(ecn) = self.tcp_ecn {
+            let value = match ecn {
+                TcpEcn::Disabled => "0",
+                TcpEcn::Enabled => "1",
+                TcpEcn::Passive => "2",
+            };
+            write(TCP_ECN, value)?;
+        }
+        if let Some((min, default, max)) = self.tcp_rmem {
+            write(TCP_RMEM, &format!("{} {} {}", min, default, max))?;
+        }
+        if let Some((min, default, max)) = self.tcp_wmem {
+            write(TCP_WMEM, &format!("{} {} {}", min, default, max))?;
+        }
+        if let Some(enabled) = self.ip_forward {
+            write(IP_FORWARD, if enabled { "1" } else { "0" })?;
+        }
+        if let Some(enabled) = self.ipv6_forwarding {
+            write(IPV6_FORWARDING, if enabled { "1" } else { "0" })?;
+        }
+        Ok(())
+    }
+
+    /// Reads the sysctls of the calling thread's network namespace, sysctls the kernel doesn't
+    /// provide are `None`.
+    pub(crate) fn read() -> io::Result<Self> {
+        let ecn = read(TCP_ECN)?.map(|value| match value.as_str() {
+            "0" => TcpEcn::Disabled,
+            "1" => TcpEcn::Enabled,
+            _ => TcpEcn::Passive,
+        });
+        Ok(Self {
+            tcp_congestion_control: read(TCP_CONGESTION_CONTROL)?,
+            ip_local_port_range: read(IP_LOCAL_PORT_RANGE)?
+                .map(|value| parse_numbers(&value, 2).map(|n| (n[0], n[1])))
+                .transpose()?,
+            tcp_ecn: ecn,
+            tcp_rmem: read(TCP_RMEM)?
+                .map(|value| parse_numbers(&value, 3).map(|n| (n[0], n[1], n[2])))
+                .transpose()?,
+            tcp_wmem: read(TCP_WMEM)?
+                .map(|value| parse_numbers(&value, 3).map(|n| (n[0], n[1], n[2])))
+                .transpose()?,
+            ip_forward: read(IP_FORWARD)?.map(|value| value != "0"),
+            ipv6_forwarding: read(IPV6_FORWARDING)?.map(|value| value != "0"),
+        })
+    }
+}
+
+fn write(key: &str, value: &str) -> io::Result<()> {
+    fs::write(format!("/proc/sys/{}", key), value)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", key.replace('/', "."), err)))
+}
+
+fn read(key: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(format!("/proc/sys/{}", key)) {
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses a sysctl consisting of `len` whitespace separated numbers.
+fn parse_numbers<T: std::str::FromStr>(value: &str, len: usize) -> io::Result<Vec<T>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse().map_err(|_| io::ErrorKind::InvalidData.into()))
+        .collect::<io::Result<Vec<T>>>()?;
+    if numbers.len() != len {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(
+            parse_numbers::<u16>("32768\t60999", 2).unwrap(),
+            [32768, 60999]
+        );
+        assert_eq!(
+            parse_numbers::<u32>(" 4096 131072  6291456 ", 3).unwrap(),
+            [4096, 131072, 6291456]
+        );
+        for (value, len) in [
+            ("32768", 2),
+            ("1 2 3", 2),
+            ("1 x", 2),
+            ("70000 1", 2),
+            ("", 1),
+        ] {
+            let err = parse_numbers::<u16>(value, len).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reads_sysctls() {
+        let sysctls = Sysctls::read().unwrap();
+        let (low, high) = sysctls.ip_local_port_range.unwrap();
+        assert!(low <= high);
+        let (min, default, max) = sysctls.tcp_rmem.unwrap();
+        assert!(min <= default && default <= max);
+        assert!(sysctls.tcp_congestion_control.is_some());
+        assert!(sysctls.tcp_ecn.is_some());
+        assert_eq!(read("net/ipv4/netsim_embed_missing").unwrap(), None);
+    }
+}
@@ -0,0 +1,614 @@
+use futures::channel::mpsc;
+use futures::future::Future;
+use futures::stream::Stream;
+use netsim_embed_core::{Icmp6, IcmpMessage, Ipv6Range, Packet6, Plug, Protocol};
+use std::collections::HashSet;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// How an IPv6 firewall treats inbound packets that are not replies to outbound traffic and do
+/// not match a pinhole.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum InboundPolicy {
+    /// Drop unsolicited inbound packets like a typical home router (RFC 6092).
+    #[default]
+    Block,
+    /// Forward all inbound packets, the firewall is open.
+    Allow,
+}
+
+#[derive(Debug)]
+enum FirewallCtrl {
+    SetInboundPolicy(InboundPolicy),
+    SetRestrictEndpoints(bool),
+    OpenPinhole(Protocol, SocketAddrV6),
+    ClosePinhole(Protocol, SocketAddrV6),
+    Flush,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    outbound: AtomicUsize,
+    inbound: AtomicUsize,
+    blocked: AtomicUsize,
+    invalid: AtomicUsize,
+    hop_limit_expired: AtomicUsize,
+    misdirected: AtomicUsize,
+}
+
+fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A stateful IPv6 firewall routing between a customer prefix and the public network without
+/// translating addresses.
+///
+/// Outbound packets create filter state which lets replies through. By default filtering is
+/// address dependent, so any port of a remote host the local endpoint sent to may reply.
+///
+/// ICMPv6 echo requests create filter state for their replies like UDP and TCP packets, and
+/// inbound ICMPv6 errors pass if the packet they quote matches filter state (RFC 6092 section
+/// 3.2). Other ICMPv6 messages are dropped.
+#[derive(Debug)]
+pub struct Ipv6Firewall {
+    public_plug: Plug,
+    private_plug: Plug,
+    subnet: Ipv6Range,
+    policy: InboundPolicy,
+    restrict_endpoints: bool,
+    udp_state: HashSet<(SocketAddrV6, SocketAddrV6)>,
+    tcp_state: HashSet<(SocketAddrV6, SocketAddrV6)>,
+    /// Local addresses with the echo identifier as port and the remote addresses pinged.
+    echo_state: HashSet<(SocketAddrV6, SocketAddrV6)>,
+    pinholes: HashSet<(Protocol, SocketAddrV6)>,
+    ctrl_tx: mpsc::UnboundedSender<FirewallCtrl>,
+    ctrl_rx: mpsc::UnboundedReceiver<FirewallCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl Ipv6Firewall {
+    pub fn new(public_plug: Plug, private_plug: Plug, subnet: Ipv6Range) -> Self {
+        let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
+        Self {
+            public_plug,
+            private_plug,
+            subnet,
+            policy: InboundPolicy::default(),
+            restrict_endpoints: false,
+            udp_state: Default::default(),
+            tcp_state: Default::default(),
+            echo_state: Default::default(),
+            pinholes: Default::default(),
+            ctrl_tx,
+            ctrl_rx,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns a handle to reconfigure the firewall after it was spawned.
+    pub fn handle(&self) -> FirewallHandle {
+        FirewallHandle {
+            ctrl: self.ctrl_tx.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    pub fn set_inbound_policy(&mut self, policy: InboundPolicy) {
+        self.policy = policy;
+    }
+
+    /// Only let replies through from the exact remote port the local endpoint sent to.
+    pub fn set_restrict_endpoints(&mut self, restrict_endpoints: bool) {
+        self.restrict_endpoints = restrict_endpoints;
+    }
+
+    /// Allow unsolicited inbound packets to `local_addr`.
+    pub fn open_pinhole(&mut self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.pinholes.insert((protocol, local_addr));
+    }
+
+    pub fn close_pinhole(&mut self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.pinholes.remove(&(protocol, local_addr));
+    }
+
+    /// Forget all filter state like a rebooting router. Pinholes are kept.
+    pub fn flush(&mut self) {
+        self.udp_state.clear();
+        self.tcp_state.clear();
+        self.echo_state.clear();
+    }
+
+    fn state_mut(&mut self, protocol: Protocol) -> &mut HashSet<(SocketAddrV6, SocketAddrV6)> {
+        match protocol {
+            Protocol::Udp => &mut self.udp_state,
+            Protocol::Tcp => &mut self.tcp_state,
+        }
+    }
+
+    fn remote_key(&self, remote: SocketAddrV6) -> SocketAddrV6 {
+        if self.restrict_endpoints {
+            remote
+        } else {
+            SocketAddrV6::new(*remote.ip(), 0, 0, 0)
+        }
+    }
+
+    fn permits_inbound(
+        &self,
+        protocol: Protocol,
+        remote: SocketAddrV6,
+        local: SocketAddrV6,
+    ) -> bool {
+        if self.policy == InboundPolicy::Allow || self.pinholes.contains(&(protocol, local)) {
+            return true;
+        }
+        let state = match protocol {
+            Protocol::Udp => &self.udp_state,
+            Protocol::Tcp => &self.tcp_state,
+        };
+        state.contains(&(local, self.remote_key(remote)))
+    }
+
+    fn process_ctrl(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(ctrl)) = Pin::new(&mut self.ctrl_rx).poll_next(cx) {
+            log::debug!("firewall {:?}: CTRL {:?}", self.subnet, ctrl);
+            match ctrl {
+                FirewallCtrl::SetInboundPolicy(policy) => self.set_inbound_policy(policy),
+                FirewallCtrl::SetRestrictEndpoints(restrict_endpoints) => {
+                    self.set_restrict_endpoints(restrict_endpoints)
+                }
+                FirewallCtrl::OpenPinhole(protocol, local_addr) => {
+                    self.open_pinhole(protocol, local_addr)
+                }
+                FirewallCtrl::ClosePinhole(protocol, local_addr) => {
+                    self.close_pinhole(protocol, local_addr)
+                }
+                FirewallCtrl::Flush => self.flush(),
+            }
+        }
+    }
+
+    fn process_outgoing(&mut self, cx: &mut Context) -> bool {
+        loop {
+            match self.private_plug.poll_incoming(cx) {
+                Poll::Pending => return false,
+                Poll::Ready(None) => return true,
+                Poll::Ready(Some(mut bytes)) => {
+                    if Icmp6::is_icmpv6(&bytes) {
+                        self.process_outgoing_icmp(&bytes);
+                        continue;
+                    }
+                    let mut packet = if let Some(packet) = Packet6::new(&mut bytes) {
+                        packet
+                    } else {
+                        log::info!("firewall {:?}: dropping invalid packet", self.subnet);
+                        inc(&self.counters.invalid);
+                        continue;
+                    };
+                    let source_addr = packet.get_source();
+                    let dest_addr = packet.get_destination();
+                    if !self.subnet.contains(*source_addr.ip()) {
+                        log::info!(
+                            "firewall {:?}: dropping outbound packet from {} outside the subnet",
+                            self.subnet,
+                            source_addr,
+                        );
+                        inc(&self.counters.misdirected);
+                        continue;
+                    }
+                    match packet.get_hop_limit().checked_sub(1) {
+                        Some(hop_limit) if hop_limit > 0 => packet.set_hop_limit(hop_limit),
+                        _ => {
+                            log::info!(
+                                "firewall {:?}: dropping packet with hop limit zero",
+                                self.subnet
+                            );
+                            inc(&self.counters.hop_limit_expired);
+                            continue;
+                        }
+                    }
+                    let key = (source_addr, self.remote_key(dest_addr));
+                    self.state_mut(packet.protocol()).insert(key);
+                    log::trace!(
+                        "firewall {:?}: outbound packet {} => {}",
+                        self.subnet,
+                        source_addr,
+                        dest_addr,
+                    );
+                    inc(&self.counters.outbound);
+                    self.public_plug.unbounded_send(bytes);
+                }
+            }
+        }
+    }
+
+    fn process_incoming(&mut self, cx: &mut Context) -> bool {
+        loop {
+            match self.public_plug.poll_incoming(cx) {
+                Poll::Pending => return false,
+                Poll::Ready(None) => return true,
+                Poll::Ready(Some(mut bytes)) => {
+                    if Icmp6::is_icmpv6(&bytes) {
+                        self.process_incoming_icmp(&bytes);
+                        continue;
+                    }
+                    let mut packet = if let Some(packet) = Packet6::new(&mut bytes) {
+                        packet
+                    } else {
+                        log::info!("firewall {:?}: dropping invalid packet", self.subnet);
+                        inc(&self.counters.invalid);
+                        continue;
+                    };
+                    let source_addr = packet.get_source();
+                    let dest_addr = packet.get_destination();
+                    if !self.subnet.contains(*dest_addr.ip()) {
+                        log::info!(
+                            "firewall {:?}: dropping inbound packet to {} outside the subnet",
+                            self.subnet,
+                            dest_addr,
+                        );
+                        inc(&self.counters.misdirected);
+                        continue;
+                    }
+                    match packet.get_hop_limit().checked_sub(1) {
+                        Some(hop_limit) if hop_limit > 0 => packet.set_hop_limit(hop_limit),
+                        _ => {
+                            log::info!(
+                                "firewall {:?}: dropping packet with hop limit zero",
+                                self.subnet
+                            );
+                            inc(&self.counters.hop_limit_expired);
+                            continue;
+                        }
+                    }
+                    if !self.permits_inbound(packet.protocol(), source_addr, dest_addr) {
+                        log::debug!(
+                            "firewall {:?}: blocking unsolicited packet {} => {}",
+                            self.subnet,
+                            source_addr,
+                            dest_addr,
+                        );
+                        inc(&self.counters.blocked);
+                        continue;
+                    }
+                    log::trace!(
+                        "firewall {:?}: inbound packet {} => {}",
+                        self.subnet,
+                        source_addr,
+                        dest_addr,
+                    );
+                    inc(&self.counters.inbound);
+                    self.private_plug.unbounded_send(bytes);
+                }
+            }
+        }
+    }
+    /// Parses an ICMPv6 packet and decrements its hop limit, returns `None` if it is dropped.
+    fn parse_icmp(&self, bytes: &[u8]) -> Option<Icmp6> {
+        let mut icmp = if let Some(icmp) = Icmp6::parse(bytes) {
+            icmp
+        } else {
+            log::info!(
+                "firewall {:?}: dropping unsupported icmpv6 packet",
+                self.subnet
+            );
+            inc(&self.counters.invalid);
+            return None;
+        };
+        match icmp.hop_limit.checked_sub(1) {
+            Some(hop_limit) if hop_limit > 0 => icmp.hop_limit = hop_limit,
+            _ => {
+                log::info!(
+                    "firewall {:?}: dropping packet with hop limit zero",
+                    self.subnet
+                );
+                inc(&self.counters.hop_limit_expired);
+                return None;
+            }
+        }
+        Some(icmp)
+    }
+
+    fn process_outgoing_icmp(&mut self, bytes: &[u8]) {
+        let icmp = if let Some(icmp) = self.parse_icmp(bytes) {
+            icmp
+        } else {
+            return;
+        };
+        if !self.subnet.contains(icmp.source) {
+            log::info!(
+                "firewall {:?}: dropping outbound packet from {} outside the subnet",
+                self.subnet,
+                icmp.source,
+            );
+            inc(&self.counters.misdirected);
+            return;
+        }
+        if let IcmpMessage::EchoRequest { id, .. } = &icmp.message {
+            let local = SocketAddrV6::new(icmp.source, *id, 0, 0);
+            let remote = SocketAddrV6::new(icmp.dest, 0, 0, 0);
+            self.echo_state.insert((local, remote));
+        }
+        log::trace!(
+            "firewall {:?}: outbound icmpv6 packet {} => {}",
+            self.subnet,
+            icmp.source,
+            icmp.dest,
+        );
+        inc(&self.counters.outbound);
+        self.public_plug.unbounded_send(icmp.to_bytes());
+    }
+
+    fn permits_inbound_icmp(&self, icmp: &Icmp6) -> bool {
+        if self.policy == InboundPolicy::Allow {
+            return true;
+        }
+        match &icmp.message {
+            IcmpMessage::EchoRequest { .. } => false,
+            IcmpMessage::EchoReply { id, .. } => {
+                let local = SocketAddrV6::new(icmp.dest, *id, 0, 0);
+                let remote = SocketAddrV6::new(icmp.source, 0, 0, 0);
+                self.echo_state.contains(&(local, remote))
+            }
+            message => {
+                // errors quote an outbound packet, which must match the filter state
+                let original = message.original().unwrap();
+                match (original.source, original.dest) {
+                    (SocketAddr::V6(local), SocketAddr::V6(remote)) => {
+                        let state = match original.protocol {
+                            Protocol::Udp => &self.udp_state,
+                            Protocol::Tcp => &self.tcp_state,
+                        };
+                        state.contains(&(local, self.remote_key(remote)))
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    fn process_incoming_icmp(&mut self, bytes: &[u8]) {
+        let icmp = if let Some(icmp) = self.parse_icmp(bytes) {
+            icmp
+        } else {
+            return;
+        };
+        if !self.subnet.contains(icmp.dest) {
+            log::info!(
+                "firewall {:?}: dropping inbound packet to {} outside the subnet",
+                self.subnet,
+                icmp.dest,
+            );
+            inc(&self.counters.misdirected);
+            return;
+        }
+        if !self.permits_inbound_icmp(&icmp) {
+            log::debug!(
+                "firewall {:?}: blocking unsolicited icmpv6 packet {} => {}",
+                self.subnet,
+                icmp.source,
+                icmp.dest,
+            );
+            inc(&self.counters.blocked);
+            return;
+        }
+        log::trace!(
+            "firewall {:?}: inbound icmpv6 packet {} => {}",
+            self.subnet,
+            icmp.source,
+            icmp.dest,
+        );
+        inc(&self.counters.inbound);
+        self.private_plug.unbounded_send(icmp.to_bytes());
+    }
+}
+
+impl Future for Ipv6Firewall {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.process_ctrl(cx);
+        let private_unplugged = self.process_outgoing(cx);
+        let public_unplugged = self.process_incoming(cx);
+
+        if private_unplugged && public_unplugged {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Handle to inspect and reconfigure a running `Ipv6Firewall`.
+#[derive(Clone, Debug)]
+pub struct FirewallHandle {
+    ctrl: mpsc::UnboundedSender<FirewallCtrl>,
+    counters: Arc<Counters>,
+}
+
+impl FirewallHandle {
+    pub fn set_inbound_policy(&self, policy: InboundPolicy) {
+        self.send(FirewallCtrl::SetInboundPolicy(policy));
+    }
+
+    /// Enable/disable port-restricted filtering.
+    pub fn set_restrict_endpoints(&self, restrict_endpoints: bool) {
+        self.send(FirewallCtrl::SetRestrictEndpoints(restrict_endpoints));
+    }
+
+    /// Allow unsolicited inbound packets to `local_addr`.
+    pub fn open_pinhole(&self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.send(FirewallCtrl::OpenPinhole(protocol, local_addr));
+    }
+
+    pub fn close_pinhole(&self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.send(FirewallCtrl::ClosePinhole(protocol, local_addr));
+    }
+
+    /// Forget all filter state like a rebooting router. Pinholes are kept.
+    pub fn flush(&self) {
+        self.send(FirewallCtrl::Flush);
+    }
+
+    fn send(&self, ctrl: FirewallCtrl) {
+        self.ctrl.unbounded_send(ctrl).ok();
+    }
+
+    /// Number of outbound packets forwarded to the public network.
+    pub fn outbound(&self) -> usize {
+        self.counters.outbound.load(Ordering::Relaxed)
+    }
+
+    /// Number of inbound packets forwarded to the private network.
+    pub fn inbound(&self) -> usize {
+        self.counters.inbound.load(Ordering::Relaxed)
+    }
+
+    /// Number of unsolicited inbound packets that were dropped.
+    pub fn blocked(&self) -> usize {
+        self.counters.blocked.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because they could not be parsed.
+    pub fn invalid(&self) -> usize {
+        self.counters.invalid.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because their hop limit reached zero.
+    pub fn hop_limit_expired(&self) -> usize {
+        self.counters.hop_limit_expired.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because they did not originate from or were not addressed to
+    /// the private subnet.
+    pub fn misdirected(&self) -> usize {
+        self.counters.misdirected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::{wire, Original, Unreachable};
+    use std::net::Ipv6Addr;
+
+    fn udp(source: SocketAddrV6, dest: SocketAddrV6) -> Vec<u8> {
+        Packet6::build_udp(source, dest, b"ping")
+    }
+
+    #[async_std::test]
+    async fn blocks_unsolicited_inbound_packets() {
+        let (mut public, fw_public) = wire();
+        let (fw_private, mut private) = wire();
+        let subnet = Ipv6Range::unique_local_subnet(1);
+        let mut firewall = Ipv6Firewall::new(fw_public, fw_private, subnet);
+        let local = SocketAddrV6::new(subnet.address_for(0), 3000, 0, 0);
+        let remote = SocketAddrV6::new("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 4000, 0, 0);
+        let pinhole = SocketAddrV6::new(subnet.address_for(1), 5000, 0, 0);
+        firewall.open_pinhole(Protocol::Udp, pinhole);
+        let handle = firewall.handle();
+        async_std::task::spawn(firewall);
+
+        public.unbounded_send(udp(remote, local));
+        public.unbounded_send(udp(remote, pinhole));
+        let bytes = private.incoming().await.unwrap();
+        assert_eq!(
+            Packet6::new(&mut bytes.clone()).unwrap().get_destination(),
+            pinhole
+        );
+
+        private.unbounded_send(udp(local, remote));
+        public.incoming().await.unwrap();
+        let reply = SocketAddrV6::new(*remote.ip(), 4001, 0, 0);
+        public.unbounded_send(udp(reply, local));
+        let bytes = private.incoming().await.unwrap();
+        assert_eq!(
+            Packet6::new(&mut bytes.clone()).unwrap().get_source(),
+            reply
+        );
+        assert_eq!(handle.blocked(), 1);
+        assert_eq!(handle.inbound(), 2);
+    }
+
+    fn echo(source: Ipv6Addr, dest: Ipv6Addr, id: u16, request: bool) -> Vec<u8> {
+        let (seq, data) = (1, b"ping".to_vec());
+        let message = if request {
+            IcmpMessage::EchoRequest { id, seq, data }
+        } else {
+            IcmpMessage::EchoReply { id, seq, data }
+        };
+        let icmp = Icmp6 {
+            source,
+            dest,
+            hop_limit: 64,
+            message,
+        };
+        icmp.to_bytes()
+    }
+
+    fn port_unreachable(source: SocketAddrV6, dest: SocketAddrV6) -> Vec<u8> {
+        let original = Original {
+            protocol: Protocol::Udp,
+            source: source.into(),
+            dest: dest.into(),
+            ttl: 63,
+            rest: vec![0; 4],
+        };
+        let icmp = Icmp6 {
+            source: *dest.ip(),
+            dest: *source.ip(),
+            hop_limit: 64,
+            message: IcmpMessage::Unreachable {
+                reason: Unreachable::Port,
+                original,
+            },
+        };
+        icmp.to_bytes()
+    }
+
+    #[async_std::test]
+    async fn filters_icmpv6() {
+        let (mut public, fw_public) = wire();
+        let (fw_private, mut private) = wire();
+        let subnet = Ipv6Range::unique_local_subnet(1);
+        let firewall = Ipv6Firewall::new(fw_public, fw_private, subnet);
+        let handle = firewall.handle();
+        async_std::task::spawn(firewall);
+        let local = SocketAddrV6::new(subnet.address_for(0), 3000, 0, 0);
+        let remote = SocketAddrV6::new("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 4000, 0, 0);
+        let stranger = SocketAddrV6::new("2001:db8::2".parse::<Ipv6Addr>().unwrap(), 4000, 0, 0);
+
+        private.unbounded_send(echo(*local.ip(), *remote.ip(), 7, true));
+        let request = Icmp6::parse(&public.incoming().await.unwrap()).unwrap();
+        assert_eq!(request.hop_limit, 63);
+
+        // inbound echo requests and replies to other requests are dropped
+        public.unbounded_send(echo(*remote.ip(), *local.ip(), 7, true));
+        public.unbounded_send(echo(*remote.ip(), *local.ip(), 8, false));
+        public.unbounded_send(echo(*stranger.ip(), *local.ip(), 7, false));
+        public.unbounded_send(echo(*remote.ip(), *local.ip(), 7, false));
+        let reply = Icmp6::parse(&private.incoming().await.unwrap()).unwrap();
+        assert_eq!(reply.source, *remote.ip());
+        assert!(matches!(
+            reply.message,
+            IcmpMessage::EchoReply { id: 7, .. }
+        ));
+        assert_eq!(handle.blocked(), 3);
+
+        // errors pass if they are about outbound traffic
+        private.unbounded_send(udp(local, remote));
+        public.incoming().await.unwrap();
+        public.unbounded_send(port_unreachable(
+            SocketAddrV6::new(*local.ip(), 3001, 0, 0),
+            remote,
+        ));
+        public.unbounded_send(port_unreachable(local, remote));
+        let error = Icmp6::parse(&private.incoming().await.unwrap()).unwrap();
+        assert_eq!(error.message.original().unwrap().source, local.into());
+        assert_eq!(handle.blocked(), 4);
+        assert_eq!(handle.inbound(), 2);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::nat::HairpinMode;
+use futures::channel::{mpsc, oneshot};
+use netsim_embed_core::Protocol;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A translation entry of a NAT.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct NatMapping {
+    pub protocol: Protocol,
+    /// Address of the endpoint in the private network.
+    pub local_addr: SocketAddrV4,
+    /// Address the endpoint is visible as on the public network.
+    pub external_addr: SocketAddrV4,
+    /// The remote endpoint the mapping is restricted to, if the NAT is symmetric.
+    pub remote_addr: Option<SocketAddrV4>,
+}
+
+#[derive(Debug)]
+pub(crate) enum NatCtrl {
+    Mappings(Protocol, oneshot::Sender<Vec<NatMapping>>),
+    Blacklisted(oneshot::Sender<Vec<SocketAddrV4>>),
+    ForwardPort(Protocol, u16, SocketAddrV4),
+    RemoveForward(Protocol, u16),
+    SetHairPinning(bool),
+    SetHairpinMode(HairpinMode),
+    SetSymmetric(bool),
+    SetRestrictEndpoints(bool),
+    SetBlacklistUnrecognizedAddrs(bool),
+    Flush,
+    Renumber(Vec<Ipv4Addr>),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub outbound: AtomicUsize,
+    pub inbound: AtomicUsize,
+    pub hairpinned: AtomicUsize,
+    pub hairpin_dropped: AtomicUsize,
+    pub invalid: AtomicUsize,
+    pub ttl_expired: AtomicUsize,
+    pub misdirected: AtomicUsize,
+    pub unmapped: AtomicUsize,
+    pub blacklisted: AtomicUsize,
+    pub exhausted: AtomicUsize,
+}
+
+impl Counters {
+    pub fn inc(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handle to inspect and reconfigure a running `Ipv4Nat`.
+#[derive(Clone, Debug)]
+pub struct NatHandle {
+    pub(crate) ctrl: mpsc::UnboundedSender<NatCtrl>,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl NatHandle {
+    /// Returns the current mappings for `protocol`, including forwarded ports, or `None` if the
+    /// NAT has stopped.
+    pub async fn mappings(&self, protocol: Protocol) -> Option<Vec<NatMapping>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(NatCtrl::Mappings(protocol, tx));
+        rx.await.ok()
+    }
+
+    /// Returns the remote addresses the NAT blocks all traffic from, or `None` if the NAT has
+    /// stopped.
+    pub async fn blacklisted_addrs(&self) -> Option<Vec<SocketAddrV4>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(NatCtrl::Blacklisted(tx));
+        rx.await.ok()
+    }
+
+    /// Manually forward a port.
+    pub fn forward_port(&self, port: u16, local_addr: SocketAddrV4, protocol: Protocol) {
+        self.send(NatCtrl::ForwardPort(protocol, port, local_addr));
+    }
+
+    /// Remove a manually forwarded port.
+    pub fn remove_forward(&self, port: u16, protocol: Protocol) {
+        self.send(NatCtrl::RemoveForward(protocol, port));
+    }
+
+    /// Enable/disable hair-pinning.
+    pub fn set_hair_pinning(&self, hair_pinning: bool) {
+        self.send(NatCtrl::SetHairPinning(hair_pinning));
+    }
+
+    /// Set how hair-pinned packets are translated.
+    pub fn set_hairpin_mode(&self, hairpin_mode: HairpinMode) {
+        self.send(NatCtrl::SetHairpinMode(hairpin_mode));
+    }
+
+    /// Enable/disable symmetric mappings, dropping existing symmetric mappings.
+    pub fn set_symmetric(&self, symmetric: bool) {
+        self.send(NatCtrl::SetSymmetric(symmetric));
+    }
+
+    /// Enable/disable port-restricted filtering, forgetting previously allowed endpoints.
+    pub fn set_restrict_endpoints(&self, restrict_endpoints: bool) {
+        self.send(NatCtrl::SetRestrictEndpoints(restrict_endpoints));
+    }
+
+    /// Enable/disable blacklisting of addresses sending unrecognized traffic.
+    pub fn set_blacklist_unrecognized_addrs(&self, blacklist_unrecognized_addrs: bool) {
+        self.send(NatCtrl::SetBlacklistUnrecognizedAddrs(
+            blacklist_unrecognized_addrs,
+        ));
+    }
+
+    /// Forget all mappings and blacklisted addresses like a rebooting router. Forwarded ports
+    /// are kept.
+    pub fn reboot(&self) {
+        self.send(NatCtrl::Flush);
+    }
+
+    /// Replace the public addresses of the NAT and flush all mappings. The first address
+    /// becomes the primary address used for forwarded ports.
+    ///
+    /// # Panics
+    ///
+    /// If `addrs` is empty.
+    pub fn renumber(&self, addrs: Vec<Ipv4Addr>) {
+        assert!(!addrs.is_empty());
+        self.send(NatCtrl::Renumber(addrs));
+    }
+
+    fn send(&self, ctrl: NatCtrl) {
+        self.ctrl.unbounded_send(ctrl).ok();
+    }
+
+    /// Number of outbound packets that were translated and sent to the public network.
+    pub fn num_outbound(&self) -> usize {
+        self.counters.outbound.load(Ordering::Relaxed)
+    }
+
+    /// Number of inbound packets that were translated and sent to the private network.
+    pub fn num_inbound(&self) -> usize {
+        self.counters.inbound.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets that were hair-pinned back into the private network.
+    pub fn num_hairpinned(&self) -> usize {
+        self.counters.hairpinned.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets to a public address of the NAT dropped by `HairpinMode::Drop`.
+    pub fn num_hairpin_dropped(&self) -> usize {
+        self.counters.hairpin_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because they could not be parsed.
+    pub fn num_invalid(&self) -> usize {
+        self.counters.invalid.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because their ttl reached zero.
+    pub fn num_ttl_expired(&self) -> usize {
+        self.counters.ttl_expired.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped because they did not originate from the private subnet or were
+    /// not addressed to a public address of the NAT.
+    pub fn num_misdirected(&self) -> usize {
+        self.counters.misdirected.load(Ordering::Relaxed)
+    }
+
+    /// Number of inbound packets dropped because there was no matching mapping.
+    pub fn num_unmapped(&self) -> usize {
+        self.counters.unmapped.load(Ordering::Relaxed)
+    }
+
+    /// Number of inbound packets dropped because their source address is blacklisted.
+    pub fn num_blacklisted(&self) -> usize {
+        self.counters.blacklisted.load(Ordering::Relaxed)
+    }
+
+    /// Number of outbound packets dropped because no external port was left for a new mapping.
+    pub fn num_exhausted(&self) -> usize {
+        self.counters.exhausted.load(Ordering::Relaxed)
+    }
+}
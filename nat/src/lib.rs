@@ -1,6 +1,17 @@
+mod firewall;
+mod handle;
 mod nat;
+mod nat64;
 mod port_allocator;
 mod port_map;
+mod port_mapping;
 
-pub use nat::Ipv4Nat;
-pub use port_allocator::{PortAllocator, RandomPortAllocator, SequentialPortAllocator};
+pub use firewall::{FirewallHandle, InboundPolicy, Ipv6Firewall};
+pub use handle::{NatHandle, NatMapping};
+pub use nat::{HairpinMode, Ipv4Nat, Pooling};
+pub use nat64::{Nat64, Nat64Handle};
+pub use netsim_embed_core::{embed_ipv4_addr, extract_ipv4_addr};
+pub use port_allocator::{
+    ContiguousPortAllocator, ParityPortAllocator, PortAllocation, PortAllocator,
+    PreservingPortAllocator, RandomPortAllocator, RangePortAllocator, SequentialPortAllocator,
+};
@@ -1,11 +1,44 @@
-use crate::port_allocator::PortAllocator;
+use crate::handle::{Counters, NatCtrl, NatHandle};
+use crate::port_allocator::{PortAllocation, PortAllocator};
 use crate::port_map::PortMap;
+use crate::port_mapping::{self, Request};
+use async_io::Timer;
+use futures::channel::mpsc;
 use futures::future::Future;
+use futures::stream::Stream;
 use netsim_embed_core::{Ipv4Range, Packet, Plug, Protocol};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// How a NAT with several public addresses picks the external address of a new mapping
+/// (RFC 4787 section 4.1).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Pooling {
+    /// All mappings of an internal host use the same external address.
+    #[default]
+    Paired,
+    /// Every mapping may use any external address of the pool.
+    Arbitrary,
+}
+
+/// How a NAT treats packets from the private network addressed to one of its public addresses.
+/// `PreserveSource` and `Translate` only apply if hair-pinning is enabled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HairpinMode {
+    /// Rewrite the destination only, the receiver sees the private source address.
+    #[default]
+    PreserveSource,
+    /// Rewrite source and destination to their external counterparts, so the receiver sees the
+    /// sender's mapped address (RFC 4787 section 6).
+    Translate,
+    /// Drop the packets, emulating a NAT with broken hair-pinning, whether hair-pinning is
+    /// enabled or not.
+    Drop,
+}
 
 /// An Ipv4 NAT.
 #[derive(Debug)]
@@ -13,12 +46,23 @@ pub struct Ipv4Nat {
     private_plug: Plug,
     public_plug: Plug,
     public_ip: Ipv4Addr,
+    address_pool: Vec<Ipv4Addr>,
+    pooling: Pooling,
+    paired_addrs: HashMap<Ipv4Addr, Ipv4Addr>,
     subnet: Ipv4Range,
     hair_pinning: bool,
+    hairpin_mode: HairpinMode,
     udp_map: PortMap,
     tcp_map: PortMap,
     blacklist_unrecognized_addrs: bool,
     blacklisted_addrs: HashSet<SocketAddrV4>,
+    port_mapping_addr: Option<Ipv4Addr>,
+    port_mappings: HashMap<(Protocol, SocketAddrV4), Instant>,
+    expiry_timer: Timer,
+    epoch: Instant,
+    ctrl_tx: mpsc::UnboundedSender<NatCtrl>,
+    ctrl_rx: mpsc::UnboundedReceiver<NatCtrl>,
+    counters: Arc<Counters>,
 }
 
 impl Ipv4Nat {
@@ -28,16 +72,36 @@ impl Ipv4Nat {
         public_ip: Ipv4Addr,
         subnet: Ipv4Range,
     ) -> Self {
+        let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
         Self {
             private_plug,
             public_plug,
             public_ip,
+            address_pool: vec![public_ip],
+            pooling: Pooling::default(),
+            paired_addrs: Default::default(),
             subnet,
             hair_pinning: false,
+            hairpin_mode: HairpinMode::default(),
             udp_map: Default::default(),
             tcp_map: Default::default(),
             blacklist_unrecognized_addrs: false,
             blacklisted_addrs: Default::default(),
+            port_mapping_addr: None,
+            port_mappings: Default::default(),
+            expiry_timer: Timer::never(),
+            epoch: Instant::now(),
+            ctrl_tx,
+            ctrl_rx,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns a handle to inspect the NAT once it is running.
+    pub fn handle(&self) -> NatHandle {
+        NatHandle {
+            ctrl: self.ctrl_tx.clone(),
+            counters: Arc::clone(&self.counters),
         }
     }
 
@@ -47,17 +111,72 @@ impl Ipv4Nat {
         self.tcp_map.set_port_allocator(port_allocator);
     }
 
+    /// Set the port allocation behaviour.
+    pub fn set_port_allocation(&mut self, port_allocation: &PortAllocation) {
+        self.udp_map.set_port_allocator(port_allocation.build());
+        self.tcp_map.set_port_allocator(port_allocation.build());
+    }
+
+    /// Add further public addresses to the NAT, turning it into a carrier-grade NAT which
+    /// assigns external addresses from the pool according to `pooling`.
+    pub fn set_address_pool(&mut self, addrs: Vec<Ipv4Addr>, pooling: Pooling) {
+        let public_ip = self.public_ip;
+        self.address_pool = vec![public_ip];
+        self.address_pool
+            .extend(addrs.into_iter().filter(|addr| *addr != public_ip));
+        self.pooling = pooling;
+        self.paired_addrs.clear();
+    }
+
     /// Enable/disable hair-pinning.
     pub fn set_hair_pinning(&mut self, hair_pinning: bool) {
         self.hair_pinning = hair_pinning;
     }
 
+    /// Set how hair-pinned packets are translated.
+    pub fn set_hairpin_mode(&mut self, hairpin_mode: HairpinMode) {
+        self.hairpin_mode = hairpin_mode;
+    }
+
+    /// Answer NAT-PMP and PCP requests sent to `addr` in the private network, installing the
+    /// requested mappings.
+    pub fn set_port_mapping(&mut self, addr: Option<Ipv4Addr>) {
+        self.port_mapping_addr = addr;
+    }
+
     /// Manually forward a port.
     pub fn forward_port(&mut self, port: u16, local_addr: SocketAddrV4, protocol: Protocol) {
-        match protocol {
-            Protocol::Udp => self.udp_map.forward_port(port, local_addr),
-            Protocol::Tcp => self.tcp_map.forward_port(port, local_addr),
-        }
+        let external_addr = SocketAddrV4::new(self.public_ip, port);
+        self.map_mut(protocol)
+            .forward_port(external_addr, local_addr);
+    }
+
+    /// Remove a manually forwarded port.
+    pub fn remove_forward(&mut self, port: u16, protocol: Protocol) {
+        let external_addr = SocketAddrV4::new(self.public_ip, port);
+        self.map_mut(protocol).remove_forward(external_addr);
+    }
+
+    /// Forget all mappings and blacklisted addresses. Forwarded ports are kept.
+    pub fn flush(&mut self) {
+        self.udp_map.flush(self.public_ip);
+        self.tcp_map.flush(self.public_ip);
+        self.paired_addrs.clear();
+        self.blacklisted_addrs.clear();
+        self.port_mappings.clear();
+        self.epoch = Instant::now();
+    }
+
+    /// Replace the public addresses of the NAT and flush all mappings.
+    ///
+    /// # Panics
+    ///
+    /// If `addrs` is empty.
+    pub fn renumber(&mut self, addrs: Vec<Ipv4Addr>) {
+        assert!(!addrs.is_empty(), "a NAT needs at least one public address");
+        self.public_ip = addrs[0];
+        self.address_pool = addrs;
+        self.flush();
     }
 
     /// Causes the NAT to permanently block all traffic from an address A if it recieves
@@ -82,6 +201,200 @@ impl Ipv4Nat {
 }
 
 impl Ipv4Nat {
+    fn map_mut(&mut self, protocol: Protocol) -> &mut PortMap {
+        match protocol {
+            Protocol::Udp => &mut self.udp_map,
+            Protocol::Tcp => &mut self.tcp_map,
+        }
+    }
+
+    fn process_ctrl(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(ctrl)) = Pin::new(&mut self.ctrl_rx).poll_next(cx) {
+            log::debug!("nat {}: CTRL {:?}", self.public_ip, ctrl);
+            match ctrl {
+                NatCtrl::Mappings(protocol, tx) => {
+                    let map = match protocol {
+                        Protocol::Udp => &self.udp_map,
+                        Protocol::Tcp => &self.tcp_map,
+                    };
+                    tx.send(map.mappings(protocol)).ok();
+                }
+                NatCtrl::Blacklisted(tx) => {
+                    tx.send(self.blacklisted_addrs.iter().copied().collect())
+                        .ok();
+                }
+                NatCtrl::ForwardPort(protocol, port, local_addr) => {
+                    self.forward_port(port, local_addr, protocol)
+                }
+                NatCtrl::RemoveForward(protocol, port) => self.remove_forward(port, protocol),
+                NatCtrl::SetHairPinning(hair_pinning) => self.set_hair_pinning(hair_pinning),
+                NatCtrl::SetHairpinMode(hairpin_mode) => self.set_hairpin_mode(hairpin_mode),
+                NatCtrl::SetSymmetric(symmetric) => self.set_symmetric(symmetric),
+                NatCtrl::SetRestrictEndpoints(restrict_endpoints) => {
+                    self.set_restrict_endpoints(restrict_endpoints)
+                }
+                NatCtrl::SetBlacklistUnrecognizedAddrs(blacklist_unrecognized_addrs) => {
+                    self.set_blacklist_unrecognized_addrs(blacklist_unrecognized_addrs)
+                }
+                NatCtrl::Flush => self.flush(),
+                NatCtrl::Renumber(addrs) => self.renumber(addrs),
+            }
+        }
+    }
+
+    fn handle_port_mapping(&mut self, local_ip: Ipv4Addr, request: Request) -> Vec<u8> {
+        let epoch = self.epoch.elapsed().as_secs() as u32;
+        let (protocol, internal_port, external_port, lifetime) = match request {
+            Request::PmpAddress => {
+                let public_ip = pool_addr(
+                    &self.address_pool,
+                    self.pooling,
+                    &mut self.paired_addrs,
+                    local_ip,
+                );
+                return port_mapping::pmp_address_response(epoch, public_ip);
+            }
+            Request::PcpAnnounce => return port_mapping::pcp_announce_response(epoch),
+            Request::Invalid(response) => return response,
+            // deletes all mappings of the client, see RFC 6886 section 3.4
+            Request::PmpMap {
+                protocol,
+                internal_port: 0,
+                lifetime: 0,
+                ..
+            } => {
+                self.remove_port_mappings(protocol, local_ip);
+                log::debug!(
+                    "nat {}: removed {:?} port mappings of {}",
+                    self.public_ip,
+                    protocol,
+                    local_ip,
+                );
+                return port_mapping::pmp_map_response(protocol, epoch, 0, 0, 0);
+            }
+            Request::PmpMap {
+                protocol,
+                internal_port,
+                external_port,
+                lifetime,
+            }
+            | Request::PcpMap {
+                protocol,
+                internal_port,
+                external_port,
+                lifetime,
+                ..
+            } => (protocol, internal_port, external_port, lifetime),
+        };
+        let local_addr = SocketAddrV4::new(local_ip, internal_port);
+        let external_addr = if lifetime == 0 {
+            if self.port_mappings.remove(&(protocol, local_addr)).is_some() {
+                self.map_mut(protocol).unmap(local_addr);
+            }
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+        } else {
+            let (pool, pooling, paired_addrs) =
+                (&self.address_pool, self.pooling, &mut self.paired_addrs);
+            let map = match protocol {
+                Protocol::Udp => &mut self.udp_map,
+                Protocol::Tcp => &mut self.tcp_map,
+            };
+            let external_addr = match map.map_explicit(local_addr, external_port, || {
+                pool_addr(pool, pooling, paired_addrs, local_ip)
+            }) {
+                Some(addr) => addr,
+                None => {
+                    log::debug!(
+                        "nat {}: no port left for port mapping of {:?} {}",
+                        self.public_ip,
+                        protocol,
+                        local_addr,
+                    );
+                    return match request {
+                        Request::PcpMap { nonce, .. } => port_mapping::pcp_map_no_resources(
+                            nonce,
+                            protocol,
+                            epoch,
+                            internal_port,
+                        ),
+                        _ => port_mapping::pmp_map_out_of_resources(protocol, epoch, internal_port),
+                    };
+                }
+            };
+            let expires = Instant::now() + Duration::from_secs(lifetime.into());
+            self.port_mappings.insert((protocol, local_addr), expires);
+            external_addr
+        };
+        log::debug!(
+            "nat {}: port mapping {:?} {} => {} for {}s",
+            self.public_ip,
+            protocol,
+            local_addr,
+            external_addr,
+            lifetime,
+        );
+        match request {
+            Request::PcpMap { nonce, .. } => port_mapping::pcp_map_response(
+                nonce,
+                protocol,
+                epoch,
+                internal_port,
+                external_addr.port(),
+                *external_addr.ip(),
+                lifetime,
+            ),
+            _ => port_mapping::pmp_map_response(
+                protocol,
+                epoch,
+                internal_port,
+                external_addr.port(),
+                lifetime,
+            ),
+        }
+    }
+
+    /// Removes the NAT-PMP/PCP mappings of `protocol` requested by `local_ip`.
+    fn remove_port_mappings(&mut self, protocol: Protocol, local_ip: Ipv4Addr) {
+        let removed = self
+            .port_mappings
+            .keys()
+            .filter(|(p, local_addr)| *p == protocol && *local_addr.ip() == local_ip)
+            .map(|(_, local_addr)| *local_addr)
+            .collect::<Vec<_>>();
+        for local_addr in removed {
+            self.port_mappings.remove(&(protocol, local_addr));
+            self.map_mut(protocol).unmap(local_addr);
+        }
+    }
+
+    fn expire_port_mappings(&mut self) {
+        if self.port_mappings.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let expired = self
+            .port_mappings
+            .iter()
+            .filter(|(_, expires)| **expires <= now)
+            .map(|(mapping, _)| *mapping)
+            .collect::<Vec<_>>();
+        for (protocol, local_addr) in expired {
+            self.port_mappings.remove(&(protocol, local_addr));
+            self.map_mut(protocol).unmap(local_addr);
+        }
+    }
+
+    /// Arms the timer for the next expiring NAT-PMP/PCP mapping.
+    fn poll_expiry(&mut self, cx: &mut Context) {
+        while let Some(next) = self.port_mappings.values().min().copied() {
+            self.expiry_timer.set_at(next);
+            if Pin::new(&mut self.expiry_timer).poll(cx).is_pending() {
+                return;
+            }
+            self.expire_port_mappings();
+        }
+    }
+
     fn process_outgoing(&mut self, cx: &mut Context) -> bool {
         loop {
             match self.private_plug.poll_incoming(cx) {
@@ -92,6 +405,7 @@ impl Ipv4Nat {
                         packet
                     } else {
                         log::info!("nat {}: dropping invalid outbound packet", self.public_ip);
+                        Counters::inc(&self.counters.invalid);
                         continue;
                     };
                     let source_addr = packet.get_source();
@@ -103,6 +417,7 @@ impl Ipv4Nat {
                             self.public_ip,
                             source_addr.ip(),
                         );
+                        Counters::inc(&self.counters.misdirected);
                         continue;
                     }
 
@@ -113,25 +428,68 @@ impl Ipv4Nat {
                                 "nat {} dropping outbound packet with ttl zero.",
                                 self.public_ip,
                             );
+                            Counters::inc(&self.counters.ttl_expired);
                             continue;
                         }
                     };
                     packet.set_ttl(next_ttl);
 
+                    if self.port_mapping_addr == Some(*dest_addr.ip()) {
+                        if packet.protocol() == Protocol::Udp
+                            && dest_addr.port() == port_mapping::SERVER_PORT
+                        {
+                            let epoch = self.epoch.elapsed().as_secs() as u32;
+                            if let Some(request) =
+                                Request::parse(packet.payload(), *source_addr.ip(), epoch)
+                            {
+                                let response = self.handle_port_mapping(*source_addr.ip(), request);
+                                self.private_plug.unbounded_send(Packet::build_udp(
+                                    dest_addr,
+                                    source_addr,
+                                    &response,
+                                ));
+                            }
+                        }
+                        continue;
+                    }
+
+                    let to_pool = self.address_pool.contains(dest_addr.ip());
+                    if to_pool && self.hairpin_mode == HairpinMode::Drop {
+                        log::debug!(
+                            "nat {}: dropping hair-pinned packet from {} to {}.",
+                            self.public_ip,
+                            source_addr,
+                            dest_addr,
+                        );
+                        Counters::inc(&self.counters.hairpin_dropped);
+                        continue;
+                    }
+                    let hairpin = self.hair_pinning && to_pool;
+
+                    let (pool, pooling, paired_addrs) =
+                        (&self.address_pool, self.pooling, &mut self.paired_addrs);
                     let map = match packet.protocol() {
                         Protocol::Udp => &mut self.udp_map,
                         Protocol::Tcp => &mut self.tcp_map,
                     };
 
-                    let external_source_addr =
-                        SocketAddrV4::new(self.public_ip, map.map_port(dest_addr, source_addr));
+                    let external_source_addr = if let Some(addr) =
+                        map.map_port(dest_addr, source_addr, || {
+                            pool_addr(pool, pooling, paired_addrs, *source_addr.ip())
+                        }) {
+                        addr
+                    } else {
+                        Counters::inc(&self.counters.exhausted);
+                        continue;
+                    };
 
-                    if self.hair_pinning && dest_addr.ip() == &self.public_ip {
+                    if hairpin {
                         let private_dest_addr = if let Some(addr) =
-                            map.get_inbound_addr(external_source_addr, dest_addr.port())
+                            map.get_inbound_addr(external_source_addr, dest_addr)
                         {
                             addr
                         } else {
+                            Counters::inc(&self.counters.unmapped);
                             continue;
                         };
                         packet.set_destination(private_dest_addr);
@@ -141,7 +499,17 @@ impl Ipv4Nat {
                             dest_addr,
                             private_dest_addr,
                         );
+                        if self.hairpin_mode == HairpinMode::Translate {
+                            packet.set_source(external_source_addr);
+                            log::trace!(
+                                "nat {}: rewrote hair-pinned packet source address: {} => {}",
+                                self.public_ip,
+                                source_addr,
+                                external_source_addr,
+                            );
+                        }
                         packet.set_checksum();
+                        Counters::inc(&self.counters.hairpinned);
                         self.private_plug.unbounded_send(bytes);
                     } else {
                         packet.set_source(external_source_addr);
@@ -152,6 +520,7 @@ impl Ipv4Nat {
                             external_source_addr,
                         );
                         packet.set_checksum();
+                        Counters::inc(&self.counters.outbound);
                         self.public_plug.unbounded_send(bytes);
                     }
                 }
@@ -169,16 +538,19 @@ impl Ipv4Nat {
                         packet
                     } else {
                         log::info!("nat {}: dropping invalid inbound packet.", self.public_ip);
+                        Counters::inc(&self.counters.invalid);
                         continue;
                     };
                     let source_addr = packet.get_source();
                     let dest_addr = packet.get_destination();
 
-                    if dest_addr.ip() != &self.public_ip {
+                    if !self.address_pool.contains(dest_addr.ip()) {
                         log::info!(
-                            "nat {} dropping inbound packet not directed at our public ip.",
+                            "nat {} dropping inbound packet to {} which is not in our address pool.",
                             self.public_ip,
+                            dest_addr,
                         );
+                        Counters::inc(&self.counters.misdirected);
                         continue;
                     }
 
@@ -189,6 +561,7 @@ impl Ipv4Nat {
                                 "nat {} dropping inbound packet with ttl zero.",
                                 self.public_ip,
                             );
+                            Counters::inc(&self.counters.ttl_expired);
                             continue;
                         }
                     };
@@ -200,6 +573,7 @@ impl Ipv4Nat {
                             self.public_ip,
                             source_addr
                         );
+                        Counters::inc(&self.counters.blacklisted);
                         continue;
                     }
 
@@ -208,9 +582,7 @@ impl Ipv4Nat {
                         Protocol::Tcp => &mut self.tcp_map,
                     };
 
-                    if let Some(private_dest_addr) =
-                        map.get_inbound_addr(source_addr, dest_addr.port())
-                    {
+                    if let Some(private_dest_addr) = map.get_inbound_addr(source_addr, dest_addr) {
                         packet.set_destination(private_dest_addr);
                         log::trace!(
                             "nat {}: rewrote inbound packet destination address: {} => {}.",
@@ -219,6 +591,7 @@ impl Ipv4Nat {
                             private_dest_addr,
                         );
                         packet.set_checksum();
+                        Counters::inc(&self.counters.inbound);
                         self.private_plug.unbounded_send(bytes);
                     } else if self.blacklist_unrecognized_addrs {
                         log::info!(
@@ -226,6 +599,7 @@ impl Ipv4Nat {
                             self.public_ip,
                             source_addr,
                         );
+                        Counters::inc(&self.counters.unmapped);
                         self.blacklisted_addrs.insert(source_addr);
                     } else {
                         log::info!(
@@ -234,6 +608,7 @@ impl Ipv4Nat {
                             dest_addr,
                         );
                         log::info!("{:?}", map);
+                        Counters::inc(&self.counters.unmapped);
                     }
                 }
             }
@@ -241,17 +616,335 @@ impl Ipv4Nat {
     }
 }
 
+/// Picks the external address of a new mapping for `local_ip`.
+fn pool_addr(
+    pool: &[Ipv4Addr],
+    pooling: Pooling,
+    paired_addrs: &mut HashMap<Ipv4Addr, Ipv4Addr>,
+    local_ip: Ipv4Addr,
+) -> Ipv4Addr {
+    match pooling {
+        Pooling::Paired => {
+            let next = pool[paired_addrs.len() % pool.len()];
+            *paired_addrs.entry(local_ip).or_insert(next)
+        }
+        Pooling::Arbitrary => pool[rand::random::<usize>() % pool.len()],
+    }
+}
+
 impl Future for Ipv4Nat {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.expire_port_mappings();
+        self.process_ctrl(cx);
         let private_unplugged = self.process_outgoing(cx);
         let public_unplugged = self.process_incoming(cx);
 
         if private_unplugged && public_unplugged {
             return Poll::Ready(());
         }
+        self.poll_expiry(cx);
 
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::NatMapping;
+    use netsim_embed_core::wire;
+
+    fn udp(source: SocketAddrV4, dest: SocketAddrV4) -> Vec<u8> {
+        Packet::build_udp(source, dest, b"ping")
+    }
+
+    fn source(mut bytes: Vec<u8>) -> SocketAddrV4 {
+        Packet::new(&mut bytes).unwrap().get_source()
+    }
+
+    struct Setup {
+        public: Plug,
+        private: Plug,
+        subnet: Ipv4Range,
+        remote: SocketAddrV4,
+        task: async_std::task::JoinHandle<()>,
+    }
+
+    impl Setup {
+        fn new(configure: impl FnOnce(&mut Ipv4Nat)) -> (Self, NatHandle) {
+            let (public, nat_public) = wire();
+            let (nat_private, private) = wire();
+            let subnet = Ipv4Range::local_subnet_192(0);
+            let mut nat = Ipv4Nat::new(nat_public, nat_private, Ipv4Addr::new(1, 0, 0, 1), subnet);
+            configure(&mut nat);
+            let handle = nat.handle();
+            let task = async_std::task::spawn(nat);
+            let remote = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+            let setup = Self {
+                public,
+                private,
+                subnet,
+                remote,
+                task,
+            };
+            (setup, handle)
+        }
+
+        fn local(&self, device: u32, port: u16) -> SocketAddrV4 {
+            SocketAddrV4::new(self.subnet.address_for(device), port)
+        }
+
+        /// Sends a packet from `local` to the remote host and returns its external source.
+        async fn send_out(&mut self, local: SocketAddrV4) -> SocketAddrV4 {
+            self.private.unbounded_send(udp(local, self.remote));
+            source(self.public.incoming().await.unwrap())
+        }
+
+        /// Sends a NAT-PMP UDP mapping request from `local`, returns the result code and the
+        /// external port.
+        async fn pmp_map(&mut self, local: SocketAddrV4, lifetime: u32) -> (u16, u16) {
+            self.pmp_request(local, local.port(), lifetime).await
+        }
+
+        /// Sends a NAT-PMP UDP mapping request for `internal_port` from `client`.
+        async fn pmp_request(
+            &mut self,
+            client: SocketAddrV4,
+            internal_port: u16,
+            lifetime: u32,
+        ) -> (u16, u16) {
+            let server = SocketAddrV4::new(self.subnet.gateway_addr(), port_mapping::SERVER_PORT);
+            let mut request = vec![0, 1, 0, 0];
+            request.extend_from_slice(&internal_port.to_be_bytes());
+            request.extend_from_slice(&[0, 0]);
+            request.extend_from_slice(&lifetime.to_be_bytes());
+            self.private
+                .unbounded_send(Packet::build_udp(client, server, &request));
+            let mut bytes = self.private.incoming().await.unwrap();
+            let response = Packet::new(&mut bytes).unwrap().payload().to_vec();
+            (
+                u16::from_be_bytes([response[2], response[3]]),
+                u16::from_be_bytes([response[10], response[11]]),
+            )
+        }
+    }
+
+    fn pool() -> Vec<Ipv4Addr> {
+        vec![Ipv4Addr::new(1, 0, 0, 2), Ipv4Addr::new(1, 0, 0, 3)]
+    }
+
+    #[async_std::test]
+    async fn paired_pooling_keeps_hosts_on_one_address() {
+        let (mut setup, _handle) = Setup::new(|nat| nat.set_address_pool(pool(), Pooling::Paired));
+        let a1 = setup.send_out(setup.local(0, 3000)).await;
+        let b = setup.send_out(setup.local(1, 3000)).await;
+        let a2 = setup.send_out(setup.local(0, 3001)).await;
+        assert_eq!(a1.ip(), a2.ip());
+        assert_ne!(a1.ip(), b.ip());
+        assert_ne!(a1.port(), a2.port());
+    }
+
+    #[async_std::test]
+    async fn arbitrary_pooling_uses_the_whole_pool() {
+        let (mut setup, _handle) =
+            Setup::new(|nat| nat.set_address_pool(pool(), Pooling::Arbitrary));
+        let mut ips = HashSet::new();
+        for port in 3000..3032 {
+            let local = setup.local(0, port);
+            let addr = setup.send_out(local).await;
+            // existing mappings keep their address
+            assert_eq!(setup.send_out(local).await, addr);
+            ips.insert(*addr.ip());
+        }
+        assert_eq!(ips.len(), 3);
+    }
+
+    #[async_std::test]
+    async fn handle_reports_mappings_and_counters() {
+        let (mut setup, handle) = Setup::new(|nat| nat.set_blacklist_unrecognized_addrs(true));
+        let local = setup.local(0, 3000);
+        let external = setup.send_out(local).await;
+        let mapping = NatMapping {
+            protocol: Protocol::Udp,
+            local_addr: local,
+            external_addr: external,
+            remote_addr: None,
+        };
+        assert_eq!(handle.mappings(Protocol::Udp).await, Some(vec![mapping]));
+        assert_eq!(handle.mappings(Protocol::Tcp).await, Some(vec![]));
+
+        let stranger = SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53);
+        let unmapped = SocketAddrV4::new(*external.ip(), external.port() + 1);
+        setup.public.unbounded_send(udp(stranger, unmapped));
+        setup.public.unbounded_send(udp(setup.remote, external));
+        setup.private.incoming().await.unwrap();
+        assert_eq!(handle.blacklisted_addrs().await, Some(vec![stranger]));
+        assert_eq!(handle.num_outbound(), 1);
+        assert_eq!(handle.num_inbound(), 1);
+        assert_eq!(handle.num_unmapped(), 1);
+
+        drop((setup.public, setup.private));
+        setup.task.await;
+        assert_eq!(handle.mappings(Protocol::Udp).await, None);
+        assert_eq!(handle.blacklisted_addrs().await, None);
+    }
+
+    fn dest(mut bytes: Vec<u8>) -> SocketAddrV4 {
+        Packet::new(&mut bytes).unwrap().get_destination()
+    }
+
+    #[async_std::test]
+    async fn handle_manages_forwarded_ports() {
+        let (mut setup, handle) = Setup::new(|_| {});
+        let server = setup.local(1, 8000);
+        handle.forward_port(7000, server, Protocol::Udp);
+        let forwarded = SocketAddrV4::new(Ipv4Addr::new(1, 0, 0, 1), 7000);
+        setup.public.unbounded_send(udp(setup.remote, forwarded));
+        assert_eq!(dest(setup.private.incoming().await.unwrap()), server);
+        let mapping = |external_addr| NatMapping {
+            protocol: Protocol::Udp,
+            local_addr: server,
+            external_addr,
+            remote_addr: None,
+        };
+
+        // a reboot forgets the mapping of the client but keeps the forwarded port
+        setup.send_out(setup.local(0, 3000)).await;
+        assert_eq!(handle.mappings(Protocol::Udp).await.unwrap().len(), 2);
+        handle.reboot();
+        assert_eq!(
+            handle.mappings(Protocol::Udp).await,
+            Some(vec![mapping(forwarded)])
+        );
+
+        // forwarded ports move to the new primary address
+        let renumbered = SocketAddrV4::new(Ipv4Addr::new(1, 0, 0, 9), 7000);
+        handle.renumber(vec![*renumbered.ip()]);
+        assert_eq!(
+            handle.mappings(Protocol::Udp).await,
+            Some(vec![mapping(renumbered)])
+        );
+        setup.public.unbounded_send(udp(setup.remote, forwarded));
+        setup.public.unbounded_send(udp(setup.remote, renumbered));
+        assert_eq!(dest(setup.private.incoming().await.unwrap()), server);
+        assert_eq!(handle.num_misdirected(), 1);
+
+        handle.remove_forward(7000, Protocol::Udp);
+        assert_eq!(handle.mappings(Protocol::Udp).await, Some(vec![]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn renumber_requires_an_address() {
+        let (public, private) = (wire().0, wire().0);
+        let mut nat = Ipv4Nat::new(
+            public,
+            private,
+            Ipv4Addr::new(1, 0, 0, 1),
+            Ipv4Range::global(),
+        );
+        nat.renumber(vec![]);
+    }
+
+    #[async_std::test]
+    async fn port_mappings_expire() {
+        let (mut setup, handle) = Setup::new(|nat| {
+            nat.set_restrict_endpoints(true);
+            nat.set_port_mapping(Some(Ipv4Range::local_subnet_192(0).gateway_addr()));
+        });
+        let implicit = setup.local(0, 3000);
+        let external = setup.send_out(implicit).await;
+        assert_eq!(setup.pmp_map(implicit, 1).await, (0, external.port()));
+        let (result, port) = setup.pmp_map(setup.local(0, 4000), 1).await;
+        assert_eq!(result, 0);
+        assert_ne!(port, external.port());
+        assert_eq!(handle.mappings(Protocol::Udp).await.unwrap().len(), 2);
+
+        // the renewed mapping accepts packets from anyone until it expires
+        let stranger = SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53);
+        setup.public.unbounded_send(udp(stranger, external));
+        assert_eq!(dest(setup.private.incoming().await.unwrap()), implicit);
+
+        // the requested mapping is removed, the renewed one is port-restricted again
+        async_std::task::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(handle.mappings(Protocol::Udp).await.unwrap().len(), 1);
+        setup.public.unbounded_send(udp(stranger, external));
+        setup.public.unbounded_send(udp(setup.remote, external));
+        assert_eq!(dest(setup.private.incoming().await.unwrap()), implicit);
+        assert_eq!(handle.num_unmapped(), 1);
+    }
+
+    #[async_std::test]
+    async fn port_mappings_are_deleted_all_at_once() {
+        let (mut setup, handle) = Setup::new(|nat| {
+            nat.set_port_mapping(Some(Ipv4Range::local_subnet_192(0).gateway_addr()));
+        });
+        for local in [
+            setup.local(0, 4000),
+            setup.local(0, 4001),
+            setup.local(1, 4000),
+        ] {
+            assert_eq!(setup.pmp_map(local, 60).await.0, 0);
+        }
+        assert_eq!(handle.mappings(Protocol::Udp).await.unwrap().len(), 3);
+
+        // internal port 0 and lifetime 0 delete the client's mappings only
+        assert_eq!(setup.pmp_request(setup.local(0, 5351), 0, 0).await, (0, 0));
+        let mappings = handle.mappings(Protocol::Udp).await.unwrap();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].local_addr, setup.local(1, 4000));
+    }
+
+    #[async_std::test]
+    async fn port_mapping_fails_when_ports_are_exhausted() {
+        let (mut setup, _handle) = Setup::new(|nat| {
+            nat.set_port_allocator(crate::RangePortAllocator::new(5000..=5000));
+            nat.set_port_mapping(Some(Ipv4Range::local_subnet_192(0).gateway_addr()));
+        });
+        assert_eq!(setup.pmp_map(setup.local(0, 4000), 60).await, (0, 5000));
+        // out of resources
+        assert_eq!(setup.pmp_map(setup.local(0, 4001), 60).await, (4, 0));
+    }
+
+    #[async_std::test]
+    async fn hairpin_modes() {
+        for (hair_pinning, mode) in [
+            (true, HairpinMode::PreserveSource),
+            (true, HairpinMode::Translate),
+            (true, HairpinMode::Drop),
+            (false, HairpinMode::Drop),
+        ] {
+            let (mut setup, handle) = Setup::new(|nat| {
+                nat.set_hair_pinning(hair_pinning);
+                nat.set_hairpin_mode(mode);
+            });
+            let server = setup.local(1, 8000);
+            let server_external = setup.send_out(server).await;
+            let client = setup.local(0, 3000);
+            let client_external = setup.send_out(client).await;
+
+            setup.private.unbounded_send(udp(client, server_external));
+            // sent after the hair-pinned packet, so it shows up first on the public side if
+            // the hair-pinned packet was dropped
+            setup.private.unbounded_send(udp(client, setup.remote));
+            if mode == HairpinMode::Drop {
+                assert_eq!(dest(setup.public.incoming().await.unwrap()), setup.remote);
+                assert_eq!(handle.num_hairpin_dropped(), 1);
+                assert_eq!(handle.num_hairpinned(), 0);
+                continue;
+            }
+            let mut bytes = setup.private.incoming().await.unwrap();
+            let packet = Packet::new(&mut bytes).unwrap();
+            assert_eq!(packet.get_destination(), server);
+            let expected_source = if mode == HairpinMode::Translate {
+                client_external
+            } else {
+                client
+            };
+            assert_eq!(packet.get_source(), expected_source);
+            assert_eq!(handle.num_hairpinned(), 1);
+        }
+    }
+}
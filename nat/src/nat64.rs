@@ -0,0 +1,815 @@
+use async_io::Timer;
+use futures::channel::mpsc;
+use futures::future::Future;
+use futures::stream::Stream;
+use netsim_embed_core::{
+    embed_ipv4_addr, extract_ipv4_addr, is_nat64_prefix, Icmp, Icmp6, IcmpMessage, Ipv6Range,
+    Packet, Packet6, Plug, Protocol,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// External ports and echo identifiers of the mappings.
+const PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Idle timeouts of mappings, the defaults of RFC 6146 section 4.
+const UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60 + 4 * 60);
+const ECHO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often idle mappings are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+enum Nat64Ctrl {
+    Flush,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    exhausted: AtomicUsize,
+}
+
+fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+struct Mapping {
+    local: SocketAddrV6,
+    remotes: HashSet<Ipv4Addr>,
+    last_used: Instant,
+}
+
+#[derive(Debug)]
+struct Mappings {
+    map_out: HashMap<SocketAddrV6, u16>,
+    map_in: HashMap<u16, Mapping>,
+    next_port: u16,
+    timeout: Duration,
+}
+
+impl Mappings {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            map_out: Default::default(),
+            map_in: Default::default(),
+            next_port: *PORTS.start(),
+            timeout,
+        }
+    }
+
+    /// Returns the external port of `local`, the mapping is endpoint independent. Returns
+    /// `None` if all ports are in use.
+    fn map_port(&mut self, local: SocketAddrV6, remote: Ipv4Addr) -> Option<u16> {
+        let port = if let Some(port) = self.map_out.get(&local) {
+            *port
+        } else {
+            let num_ports = usize::from(PORTS.end() - PORTS.start()) + 1;
+            let port = (0..num_ports).find_map(|_| {
+                let port = self.next_port;
+                self.next_port = match port.checked_add(1) {
+                    Some(next) if PORTS.contains(&next) => next,
+                    _ => *PORTS.start(),
+                };
+                (!self.map_in.contains_key(&port)).then_some(port)
+            })?;
+            self.map_out.insert(local, port);
+            let mapping = Mapping {
+                local,
+                remotes: HashSet::new(),
+                last_used: Instant::now(),
+            };
+            self.map_in.insert(port, mapping);
+            port
+        };
+        let mapping = self.map_in.get_mut(&port).unwrap();
+        mapping.remotes.insert(remote);
+        mapping.last_used = Instant::now();
+        Some(port)
+    }
+
+    /// Removes the mappings which weren't used by outbound packets within the timeout.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let map_out = &mut self.map_out;
+        self.map_in.retain(|_, mapping| {
+            let keep = now.duration_since(mapping.last_used) < timeout;
+            if !keep {
+                map_out.remove(&mapping.local);
+            }
+            keep
+        });
+    }
+
+    fn clear(&mut self) {
+        self.map_out.clear();
+        self.map_in.clear();
+    }
+
+    /// Returns the external port of `local` without creating a mapping.
+    fn get_outbound_port(&self, local: SocketAddrV6) -> Option<u16> {
+        self.map_out.get(&local).copied()
+    }
+
+    /// Returns the local address for inbound packets from `remote`, filtering is address
+    /// dependent.
+    fn get_inbound_addr(&self, remote: Ipv4Addr, port: u16) -> Option<SocketAddrV6> {
+        let mapping = self.map_in.get(&port)?;
+        if mapping.remotes.contains(&remote) {
+            Some(mapping.local)
+        } else {
+            None
+        }
+    }
+}
+
+/// A stateful NAT64 (RFC 6146) translating UDP and TCP traffic from an IPv6 network to IPv4
+/// addresses embedded in a prefix. ICMP echo messages and errors are translated as well
+/// (RFC 7915), so that ping and path MTU discovery work across it.
+///
+/// Mappings expire after the idle timeouts of RFC 6146. Packets which would need a new mapping
+/// while all ports are in use are dropped.
+#[derive(Debug)]
+pub struct Nat64 {
+    ipv6_plug: Plug,
+    ipv4_plug: Plug,
+    public_ip: Ipv4Addr,
+    prefix: Ipv6Range,
+    udp_map: Mappings,
+    tcp_map: Mappings,
+    /// Echo identifiers are mapped like ports (RFC 6146 section 3.5.3).
+    echo_map: Mappings,
+    expiry_timer: Timer,
+    ctrl_tx: mpsc::UnboundedSender<Nat64Ctrl>,
+    ctrl_rx: mpsc::UnboundedReceiver<Nat64Ctrl>,
+    counters: Arc<Counters>,
+}
+
+impl Nat64 {
+    /// Creates a NAT64 translating destinations in `prefix` to IPv4 addresses and sending
+    /// translated packets from `public_ip`.
+    ///
+    /// # Panics
+    ///
+    /// If the prefix length is not one of 32, 40, 48, 56, 64 or 96.
+    pub fn new(ipv4_plug: Plug, ipv6_plug: Plug, public_ip: Ipv4Addr, prefix: Ipv6Range) -> Self {
+        assert!(is_nat64_prefix(prefix));
+        let (ctrl_tx, ctrl_rx) = mpsc::unbounded();
+        Self {
+            ipv6_plug,
+            ipv4_plug,
+            public_ip,
+            prefix,
+            udp_map: Mappings::new(UDP_TIMEOUT),
+            tcp_map: Mappings::new(TCP_TIMEOUT),
+            echo_map: Mappings::new(ECHO_TIMEOUT),
+            expiry_timer: Timer::interval(EXPIRY_INTERVAL),
+            ctrl_tx,
+            ctrl_rx,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns a handle to flush the NAT64 and read its counters after it was spawned.
+    pub fn handle(&self) -> Nat64Handle {
+        Nat64Handle {
+            ctrl: self.ctrl_tx.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Removes all mappings like a rebooting gateway.
+    pub fn flush(&mut self) {
+        self.udp_map.clear();
+        self.tcp_map.clear();
+        self.echo_map.clear();
+    }
+
+    fn process_ctrl(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(ctrl)) = Pin::new(&mut self.ctrl_rx).poll_next(cx) {
+            log::debug!("nat64 {}: CTRL {:?}", self.public_ip, ctrl);
+            match ctrl {
+                Nat64Ctrl::Flush => self.flush(),
+            }
+        }
+    }
+
+    fn poll_expiry(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(now)) = Pin::new(&mut self.expiry_timer).poll_next(cx) {
+            self.udp_map.expire(now);
+            self.tcp_map.expire(now);
+            self.echo_map.expire(now);
+        }
+    }
+
+    fn map_mut(&mut self, protocol: Protocol) -> &mut Mappings {
+        match protocol {
+            Protocol::Udp => &mut self.udp_map,
+            Protocol::Tcp => &mut self.tcp_map,
+        }
+    }
+
+    fn process_outgoing(&mut self, cx: &mut Context) -> bool {
+        loop {
+            match self.ipv6_plug.poll_incoming(cx) {
+                Poll::Pending => return false,
+                Poll::Ready(None) => return true,
+                Poll::Ready(Some(mut bytes)) => {
+                    if Icmp6::is_icmpv6(&bytes) {
+                        if let Some(bytes) = self.translate_outbound_icmp(&bytes) {
+                            self.ipv4_plug.unbounded_send(bytes);
+                        }
+                        continue;
+                    }
+                    let mut packet = if let Some(packet) = Packet6::new(&mut bytes) {
+                        packet
+                    } else {
+                        log::info!("nat64 {}: dropping untranslatable packet", self.public_ip);
+                        continue;
+                    };
+                    let source_addr = packet.get_source();
+                    let dest_addr = packet.get_destination();
+                    let dest_ip = if let Some(ip) = extract_ipv4_addr(self.prefix, *dest_addr.ip())
+                    {
+                        ip
+                    } else {
+                        log::debug!(
+                            "nat64 {}: dropping packet to {} outside of {:?}",
+                            self.public_ip,
+                            dest_addr,
+                            self.prefix,
+                        );
+                        continue;
+                    };
+                    match packet.get_hop_limit().checked_sub(1) {
+                        Some(hop_limit) if hop_limit > 0 => packet.set_hop_limit(hop_limit),
+                        _ => {
+                            log::info!(
+                                "nat64 {}: dropping packet with hop limit zero",
+                                self.public_ip
+                            );
+                            continue;
+                        }
+                    }
+                    let port = if let Some(port) = self
+                        .map_mut(packet.protocol())
+                        .map_port(source_addr, dest_ip)
+                    {
+                        port
+                    } else {
+                        log::info!(
+                            "nat64 {}: dropping packet from {}, all ports are in use",
+                            self.public_ip,
+                            source_addr,
+                        );
+                        inc(&self.counters.exhausted);
+                        continue;
+                    };
+                    let source = SocketAddrV4::new(self.public_ip, port);
+                    let dest = SocketAddrV4::new(dest_ip, dest_addr.port());
+                    log::trace!(
+                        "nat64 {}: translated outbound packet {} => {} to {} => {}",
+                        self.public_ip,
+                        source_addr,
+                        dest_addr,
+                        source,
+                        dest,
+                    );
+                    self.ipv4_plug.unbounded_send(packet.to_ipv4(source, dest));
+                }
+            }
+        }
+    }
+
+    fn translate_outbound_icmp(&mut self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let icmp = if let Some(icmp) = Icmp6::parse(bytes) {
+            icmp
+        } else {
+            log::debug!(
+                "nat64 {}: dropping untranslatable icmpv6 packet",
+                self.public_ip
+            );
+            return None;
+        };
+        let dest = if let Some(ip) = extract_ipv4_addr(self.prefix, icmp.dest) {
+            ip
+        } else {
+            log::debug!(
+                "nat64 {}: dropping icmpv6 packet to {} outside of {:?}",
+                self.public_ip,
+                icmp.dest,
+                self.prefix,
+            );
+            return None;
+        };
+        let ttl = match icmp.hop_limit.checked_sub(1) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => {
+                log::info!(
+                    "nat64 {}: dropping packet with hop limit zero",
+                    self.public_ip
+                );
+                return None;
+            }
+        };
+        let message = match icmp.message {
+            IcmpMessage::EchoRequest { id, seq, data } => {
+                let local = SocketAddrV6::new(icmp.source, id, 0, 0);
+                let id = if let Some(id) = self.echo_map.map_port(local, dest) {
+                    id
+                } else {
+                    log::info!(
+                        "nat64 {}: dropping echo request from {}, all identifiers are in use",
+                        self.public_ip,
+                        icmp.source,
+                    );
+                    inc(&self.counters.exhausted);
+                    return None;
+                };
+                IcmpMessage::EchoRequest { id, seq, data }
+            }
+            IcmpMessage::EchoReply { .. } => {
+                log::debug!(
+                    "nat64 {}: dropping echo reply to {}, echo requests aren't translated inbound",
+                    self.public_ip,
+                    dest,
+                );
+                return None;
+            }
+            mut message => {
+                // errors are about packets which were translated inbound, from a remote address
+                // in the prefix to a local address
+                let original = message.original_mut().unwrap();
+                let (remote, local) = match (original.source, original.dest) {
+                    (SocketAddr::V6(remote), SocketAddr::V6(local)) => (remote, local),
+                    _ => return None,
+                };
+                let remote_ip = extract_ipv4_addr(self.prefix, *remote.ip());
+                let port = self.map_mut(original.protocol).get_outbound_port(local);
+                let (remote_ip, port) = if let (Some(ip), Some(port)) = (remote_ip, port) {
+                    (ip, port)
+                } else {
+                    log::debug!(
+                        "nat64 {}: dropping icmpv6 error about unmapped packet {} => {}",
+                        self.public_ip,
+                        remote,
+                        local,
+                    );
+                    return None;
+                };
+                original.source = SocketAddrV4::new(remote_ip, remote.port()).into();
+                original.dest = SocketAddrV4::new(self.public_ip, port).into();
+                if let IcmpMessage::PacketTooBig { mtu, .. } = &mut message {
+                    *mtu = mtu.saturating_sub(20);
+                }
+                message
+            }
+        };
+        log::trace!(
+            "nat64 {}: translated outbound icmpv6 packet {} => {} to {} => {}",
+            self.public_ip,
+            icmp.source,
+            icmp.dest,
+            self.public_ip,
+            dest,
+        );
+        let icmp = Icmp {
+            source: self.public_ip,
+            dest,
+            ttl,
+            message,
+        };
+        Some(icmp.to_bytes())
+    }
+
+    fn process_incoming(&mut self, cx: &mut Context) -> bool {
+        loop {
+            match self.ipv4_plug.poll_incoming(cx) {
+                Poll::Pending => return false,
+                Poll::Ready(None) => return true,
+                Poll::Ready(Some(mut bytes)) => {
+                    if let Some(icmp) = Icmp::parse(&bytes) {
+                        if let Some(bytes) = self.translate_inbound_icmp(icmp) {
+                            self.ipv6_plug.unbounded_send(bytes);
+                        }
+                        continue;
+                    }
+                    let mut packet = if let Some(packet) = Packet::new(&mut bytes) {
+                        packet
+                    } else {
+                        log::info!("nat64 {}: dropping untranslatable packet", self.public_ip);
+                        continue;
+                    };
+                    let source_addr = packet.get_source();
+                    let dest_addr = packet.get_destination();
+                    if *dest_addr.ip() != self.public_ip {
+                        log::info!(
+                            "nat64 {}: dropping inbound packet not directed at our public ip",
+                            self.public_ip,
+                        );
+                        continue;
+                    }
+                    match packet.get_ttl().checked_sub(1) {
+                        Some(ttl) if ttl > 0 => packet.set_ttl(ttl),
+                        _ => {
+                            log::info!("nat64 {}: dropping packet with ttl zero", self.public_ip);
+                            continue;
+                        }
+                    }
+                    let local_addr = if let Some(addr) = self
+                        .map_mut(packet.protocol())
+                        .get_inbound_addr(*source_addr.ip(), dest_addr.port())
+                    {
+                        addr
+                    } else {
+                        log::debug!(
+                            "nat64 {}: dropping inbound packet from {} for unmapped port {}",
+                            self.public_ip,
+                            source_addr,
+                            dest_addr.port(),
+                        );
+                        continue;
+                    };
+                    let source = SocketAddrV6::new(
+                        embed_ipv4_addr(self.prefix, *source_addr.ip()),
+                        source_addr.port(),
+                        0,
+                        0,
+                    );
+                    log::trace!(
+                        "nat64 {}: translated inbound packet {} => {} to {} => {}",
+                        self.public_ip,
+                        source_addr,
+                        dest_addr,
+                        source,
+                        local_addr,
+                    );
+                    self.ipv6_plug
+                        .unbounded_send(packet.to_ipv6(source, local_addr));
+                }
+            }
+        }
+    }
+
+    fn translate_inbound_icmp(&mut self, icmp: Icmp) -> Option<Vec<u8>> {
+        if icmp.dest != self.public_ip {
+            log::info!(
+                "nat64 {}: dropping inbound packet not directed at our public ip",
+                self.public_ip,
+            );
+            return None;
+        }
+        let hop_limit = match icmp.ttl.checked_sub(1) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => {
+                log::info!("nat64 {}: dropping packet with ttl zero", self.public_ip);
+                return None;
+            }
+        };
+        let (dest, message) = match icmp.message {
+            IcmpMessage::EchoReply { id, seq, data } => {
+                let local = if let Some(local) = self.echo_map.get_inbound_addr(icmp.source, id) {
+                    local
+                } else {
+                    log::debug!(
+                        "nat64 {}: dropping echo reply from {} for unmapped id {}",
+                        self.public_ip,
+                        icmp.source,
+                        id,
+                    );
+                    return None;
+                };
+                let message = IcmpMessage::EchoReply {
+                    id: local.port(),
+                    seq,
+                    data,
+                };
+                (*local.ip(), message)
+            }
+            IcmpMessage::EchoRequest { .. } => {
+                log::debug!(
+                    "nat64 {}: dropping echo request from {}",
+                    self.public_ip,
+                    icmp.source,
+                );
+                return None;
+            }
+            mut message => {
+                // errors are about packets which were translated outbound, from our public ip to
+                // a remote address
+                let original = message.original_mut().unwrap();
+                let (public, remote) = match (original.source, original.dest) {
+                    (SocketAddr::V4(public), SocketAddr::V4(remote)) => (public, remote),
+                    _ => return None,
+                };
+                let local = if *public.ip() == self.public_ip {
+                    self.map_mut(original.protocol)
+                        .get_inbound_addr(*remote.ip(), public.port())
+                } else {
+                    None
+                };
+                let local = if let Some(local) = local {
+                    local
+                } else {
+                    log::debug!(
+                        "nat64 {}: dropping icmp error about unmapped packet {} => {}",
+                        self.public_ip,
+                        public,
+                        remote,
+                    );
+                    return None;
+                };
+                let remote_ip = embed_ipv4_addr(self.prefix, *remote.ip());
+                original.source = local.into();
+                original.dest = SocketAddrV6::new(remote_ip, remote.port(), 0, 0).into();
+                if let IcmpMessage::PacketTooBig { mtu, .. } = &mut message {
+                    *mtu = mtu.saturating_add(20);
+                }
+                (*local.ip(), message)
+            }
+        };
+        let source = embed_ipv4_addr(self.prefix, icmp.source);
+        log::trace!(
+            "nat64 {}: translated inbound icmp packet {} => {} to {} => {}",
+            self.public_ip,
+            icmp.source,
+            icmp.dest,
+            source,
+            dest,
+        );
+        let icmp = Icmp6 {
+            source,
+            dest,
+            hop_limit,
+            message,
+        };
+        Some(icmp.to_bytes())
+    }
+}
+
+impl Future for Nat64 {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.process_ctrl(cx);
+        self.poll_expiry(cx);
+        let ipv6_unplugged = self.process_outgoing(cx);
+        let ipv4_unplugged = self.process_incoming(cx);
+
+        if ipv6_unplugged && ipv4_unplugged {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Handle to a running `Nat64`.
+#[derive(Clone, Debug)]
+pub struct Nat64Handle {
+    ctrl: mpsc::UnboundedSender<Nat64Ctrl>,
+    counters: Arc<Counters>,
+}
+
+impl Nat64Handle {
+    /// Removes all mappings like a rebooting gateway.
+    pub fn flush(&self) {
+        self.ctrl.unbounded_send(Nat64Ctrl::Flush).ok();
+    }
+
+    /// Number of packets dropped because all ports or echo identifiers were in use.
+    pub fn num_exhausted(&self) -> usize {
+        self.counters.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use netsim_embed_core::{wire, Original, Unreachable};
+    use std::net::Ipv6Addr;
+
+    struct Setup {
+        ipv4: Plug,
+        ipv6: Plug,
+        public_ip: Ipv4Addr,
+        local_ip: Ipv6Addr,
+        prefix: Ipv6Range,
+        handle: Nat64Handle,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let (ipv4, nat_ipv4) = wire();
+            let (nat_ipv6, ipv6) = wire();
+            let public_ip = Ipv4Addr::new(1, 0, 0, 1);
+            let prefix = Ipv6Range::nat64_well_known_prefix();
+            let nat = Nat64::new(nat_ipv4, nat_ipv6, public_ip, prefix);
+            let handle = nat.handle();
+            async_std::task::spawn(nat);
+            Self {
+                ipv4,
+                ipv6,
+                public_ip,
+                local_ip: "fd00::2".parse().unwrap(),
+                prefix,
+                handle,
+            }
+        }
+
+        fn embed(&self, addr: SocketAddrV4) -> SocketAddrV6 {
+            SocketAddrV6::new(embed_ipv4_addr(self.prefix, *addr.ip()), addr.port(), 0, 0)
+        }
+    }
+
+    fn echo_request(id: u16, data: &[u8]) -> IcmpMessage {
+        IcmpMessage::EchoRequest {
+            id,
+            seq: 1,
+            data: data.to_vec(),
+        }
+    }
+
+    fn echo_reply(id: u16, data: &[u8]) -> IcmpMessage {
+        IcmpMessage::EchoReply {
+            id,
+            seq: 1,
+            data: data.to_vec(),
+        }
+    }
+
+    #[async_std::test]
+    async fn translates_echo_messages() {
+        let mut setup = Setup::new();
+        let remote = Ipv4Addr::new(8, 8, 8, 8);
+        let request = Icmp6 {
+            source: setup.local_ip,
+            dest: embed_ipv4_addr(setup.prefix, remote),
+            hop_limit: 64,
+            message: echo_request(7, b"ping"),
+        };
+        setup.ipv6.unbounded_send(request.to_bytes());
+        let request = Icmp::parse(&setup.ipv4.incoming().await.unwrap()).unwrap();
+        assert_eq!(request.source, setup.public_ip);
+        assert_eq!(request.dest, remote);
+        assert_eq!(request.ttl, 63);
+        let id = match request.message {
+            IcmpMessage::EchoRequest { id, .. } => id,
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        // neither inbound echo requests nor replies from other hosts are translated
+        let public_ip = setup.public_ip;
+        let inbound = |source, message| {
+            Icmp {
+                source,
+                dest: public_ip,
+                ttl: 64,
+                message,
+            }
+            .to_bytes()
+        };
+        setup
+            .ipv4
+            .unbounded_send(inbound(remote, echo_request(id, b"ping")));
+        setup
+            .ipv4
+            .unbounded_send(inbound(Ipv4Addr::new(8, 8, 4, 4), echo_reply(id, b"pong")));
+        setup
+            .ipv4
+            .unbounded_send(inbound(remote, echo_reply(id, b"pong")));
+        let reply = Icmp6::parse(&setup.ipv6.incoming().await.unwrap()).unwrap();
+        let expected = Icmp6 {
+            source: embed_ipv4_addr(setup.prefix, remote),
+            dest: setup.local_ip,
+            hop_limit: 63,
+            message: echo_reply(7, b"pong"),
+        };
+        assert_eq!(reply, expected);
+    }
+
+    #[async_std::test]
+    async fn translates_icmp_errors() {
+        let mut setup = Setup::new();
+        let local = SocketAddrV6::new(setup.local_ip, 5000, 0, 0);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+        let query = Packet6::build_udp(local, setup.embed(remote), b"query");
+        setup.ipv6.unbounded_send(query);
+        let mut bytes = setup.ipv4.incoming().await.unwrap();
+        let public = Packet::new(&mut bytes).unwrap().get_source();
+
+        // a router on the path reports that the query is too big for the next hop
+        let router = Ipv4Addr::new(9, 9, 9, 9);
+        let original = Original {
+            protocol: Protocol::Udp,
+            source: public.into(),
+            dest: remote.into(),
+            ttl: 63,
+            rest: vec![0; 4],
+        };
+        let error = Icmp {
+            source: router,
+            dest: setup.public_ip,
+            ttl: 64,
+            message: IcmpMessage::PacketTooBig {
+                mtu: 1400,
+                original,
+            },
+        };
+        setup.ipv4.unbounded_send(error.to_bytes());
+        let error = Icmp6::parse(&setup.ipv6.incoming().await.unwrap()).unwrap();
+        assert_eq!(error.source, embed_ipv4_addr(setup.prefix, router));
+        assert_eq!(error.dest, setup.local_ip);
+        match error.message {
+            IcmpMessage::PacketTooBig {
+                mtu: 1420,
+                original,
+            } => {
+                assert_eq!(original.source, local.into());
+                assert_eq!(original.dest, setup.embed(remote).into());
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        // the local host reports that nothing listens on the port of a reply
+        let reply = Packet::build_udp(remote, public, b"reply");
+        setup.ipv4.unbounded_send(reply);
+        let mut bytes = setup.ipv6.incoming().await.unwrap();
+        let reply = Packet6::new(&mut bytes).unwrap();
+        let original = Original {
+            protocol: Protocol::Udp,
+            source: reply.get_source().into(),
+            dest: reply.get_destination().into(),
+            ttl: 63,
+            rest: vec![0; 4],
+        };
+        let error = Icmp6 {
+            source: setup.local_ip,
+            dest: *reply.get_source().ip(),
+            hop_limit: 64,
+            message: IcmpMessage::Unreachable {
+                reason: Unreachable::Port,
+                original,
+            },
+        };
+        setup.ipv6.unbounded_send(error.to_bytes());
+        let error = Icmp::parse(&setup.ipv4.incoming().await.unwrap()).unwrap();
+        assert_eq!(error.source, setup.public_ip);
+        assert_eq!(error.dest, *remote.ip());
+        match error.message {
+            IcmpMessage::Unreachable {
+                reason: Unreachable::Port,
+                original,
+            } => {
+                assert_eq!(original.source, remote.into());
+                assert_eq!(original.dest, public.into());
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[async_std::test]
+    async fn drops_packets_when_ports_are_exhausted() {
+        let mut setup = Setup::new();
+        let remote = setup.embed(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53));
+        let send = |setup: &mut Setup, port| {
+            let local = SocketAddrV6::new(setup.local_ip, port, 0, 0);
+            let packet = Packet6::build_udp(local, remote, b"query");
+            setup.ipv6.unbounded_send(packet);
+        };
+        let mut ports = HashSet::new();
+        for port in 0..=(PORTS.end() - PORTS.start()) {
+            send(&mut setup, 1000 + port);
+            let mut bytes = setup.ipv4.incoming().await.unwrap();
+            ports.insert(Packet::new(&mut bytes).unwrap().get_source().port());
+        }
+        assert_eq!(ports.len(), PORTS.len());
+        assert!(ports.iter().all(|port| PORTS.contains(port)));
+
+        // a new local endpoint gets no port while mapped endpoints keep theirs
+        send(&mut setup, 999);
+        send(&mut setup, 1000);
+        let mut bytes = setup.ipv4.incoming().await.unwrap();
+        let source = Packet::new(&mut bytes).unwrap().get_source();
+        assert_eq!(source.port(), *PORTS.start());
+        assert_eq!(setup.handle.num_exhausted(), 1);
+
+        // packets the NAT64 received before it processed the flush are still dropped
+        setup.handle.flush();
+        loop {
+            send(&mut setup, 999);
+            let incoming = setup.ipv4.incoming();
+            if timeout(Duration::from_millis(100), incoming).await.is_ok() {
+                break;
+            }
+        }
+    }
+}
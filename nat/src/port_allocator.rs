@@ -1,8 +1,38 @@
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
 use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
 
 pub trait PortAllocator: std::fmt::Debug + Send {
     fn next_port(&mut self, local_endpoint: SocketAddrV4) -> u16;
+
+    /// Called when the port returned by the last call to `next_port` is already mapped. The
+    /// next call for the same endpoint must not return it again.
+    fn port_in_use(&mut self, _local_endpoint: SocketAddrV4, _port: u16) {}
+
+    /// Range of the ports returned by `next_port`.
+    fn range(&self) -> RangeInclusive<u16> {
+        1..=u16::MAX
+    }
+}
+
+/// Number of ports in a range.
+pub(crate) fn num_ports(range: &RangeInclusive<u16>) -> usize {
+    (*range.end() as usize + 1).saturating_sub(*range.start() as usize)
+}
+
+impl<T: PortAllocator + ?Sized> PortAllocator for Box<T> {
+    fn next_port(&mut self, local_endpoint: SocketAddrV4) -> u16 {
+        (**self).next_port(local_endpoint)
+    }
+
+    fn port_in_use(&mut self, local_endpoint: SocketAddrV4, port: u16) {
+        (**self).port_in_use(local_endpoint, port)
+    }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        (**self).range()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -39,6 +69,10 @@ impl PortAllocator for SequentialPortAllocator {
             }
         }
     }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        49152..=u16::MAX
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -53,4 +87,331 @@ impl PortAllocator for RandomPortAllocator {
             }
         }
     }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        1000..=u16::MAX
+    }
+}
+
+/// Hands out ports sequentially from a configurable range of external ports, wrapping around
+/// at the end of the range.
+#[derive(Clone, Debug)]
+pub struct RangePortAllocator {
+    range: RangeInclusive<u16>,
+    next_port: u16,
+}
+
+impl RangePortAllocator {
+    /// Creates an allocator for the given range.
+    ///
+    /// # Panics
+    ///
+    /// If the range is empty.
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        assert!(!range.is_empty());
+        Self {
+            next_port: *range.start(),
+            range,
+        }
+    }
+}
+
+impl Default for RangePortAllocator {
+    fn default() -> Self {
+        Self::new(49152..=65535)
+    }
+}
+
+impl PortAllocator for RangePortAllocator {
+    fn next_port(&mut self, _local_endpoint: SocketAddrV4) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == *self.range.end() {
+            *self.range.start()
+        } else {
+            port + 1
+        };
+        port
+    }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        self.range.clone()
+    }
+}
+
+/// Tries to use the internal source port as the external port and falls back to the wrapped
+/// allocator if it is already mapped or outside of the wrapped allocator's range (port
+/// preservation, RFC 4787 section 4.2.1).
+#[derive(Clone, Debug)]
+pub struct PreservingPortAllocator<T> {
+    inner: T,
+    rejected: Option<SocketAddrV4>,
+    /// Whether the last port returned was the internal port.
+    preserved: bool,
+}
+
+impl<T: PortAllocator> PreservingPortAllocator<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            rejected: None,
+            preserved: false,
+        }
+    }
+}
+
+impl<T: PortAllocator> PortAllocator for PreservingPortAllocator<T> {
+    fn next_port(&mut self, local_endpoint: SocketAddrV4) -> u16 {
+        if self.rejected != Some(local_endpoint) {
+            self.rejected = None;
+            self.preserved = self.inner.range().contains(&local_endpoint.port());
+            if self.preserved {
+                return local_endpoint.port();
+            }
+        }
+        self.preserved = false;
+        self.inner.next_port(local_endpoint)
+    }
+
+    fn port_in_use(&mut self, local_endpoint: SocketAddrV4, port: u16) {
+        if self.preserved {
+            self.rejected = Some(local_endpoint);
+        } else {
+            self.inner.port_in_use(local_endpoint, port);
+        }
+    }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        self.inner.range()
+    }
+}
+
+/// Only hands out external ports with the same parity as the internal source port (port parity
+/// preservation, RFC 4787 section 4.2.2). Parity is given up if the wrapped allocator didn't
+/// return a port with the right parity within one cycle of its range.
+#[derive(Clone, Debug)]
+pub struct ParityPortAllocator<T> {
+    inner: T,
+}
+
+impl<T: PortAllocator> ParityPortAllocator<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: PortAllocator> PortAllocator for ParityPortAllocator<T> {
+    fn next_port(&mut self, local_endpoint: SocketAddrV4) -> u16 {
+        let mut port = self.inner.next_port(local_endpoint);
+        for _ in 1..num_ports(&self.inner.range()) {
+            if port % 2 == local_endpoint.port() % 2 {
+                break;
+            }
+            port = self.inner.next_port(local_endpoint);
+        }
+        port
+    }
+
+    fn port_in_use(&mut self, local_endpoint: SocketAddrV4, port: u16) {
+        self.inner.port_in_use(local_endpoint, port);
+    }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        self.inner.range()
+    }
+}
+
+/// Maps contiguous internal ports of a host to contiguous external ports if possible (port
+/// contiguity, RFC 4787 section 4.2.3), e.g. for RTP/RTCP port pairs.
+#[derive(Clone, Debug)]
+pub struct ContiguousPortAllocator<T> {
+    inner: T,
+    last: Option<(SocketAddrV4, u16)>,
+    rejected: bool,
+    /// Contiguous ports handed out which the wrapped allocator didn't return yet, they are
+    /// skipped when it does.
+    reserved: HashSet<u16>,
+}
+
+impl<T: PortAllocator> ContiguousPortAllocator<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            last: None,
+            rejected: false,
+            reserved: HashSet::new(),
+        }
+    }
+
+    fn next_inner_port(&mut self, local_endpoint: SocketAddrV4) -> u16 {
+        let mut port = self.inner.next_port(local_endpoint);
+        for _ in 1..num_ports(&self.inner.range()) {
+            if !self.reserved.remove(&port) {
+                break;
+            }
+            port = self.inner.next_port(local_endpoint);
+        }
+        port
+    }
+}
+
+impl<T: PortAllocator> PortAllocator for ContiguousPortAllocator<T> {
+    fn next_port(&mut self, local_endpoint: SocketAddrV4) -> u16 {
+        let contiguous = self.last.and_then(|(last_endpoint, last_port)| {
+            if self.rejected
+                || last_endpoint.ip() != local_endpoint.ip()
+                || last_endpoint.port().checked_add(1) != Some(local_endpoint.port())
+            {
+                return None;
+            }
+            last_port
+                .checked_add(1)
+                .filter(|port| self.inner.range().contains(port) && !self.reserved.contains(port))
+        });
+        self.rejected = false;
+        let port = if let Some(port) = contiguous {
+            self.reserved.insert(port);
+            port
+        } else {
+            self.next_inner_port(local_endpoint)
+        };
+        self.last = Some((local_endpoint, port));
+        port
+    }
+
+    fn port_in_use(&mut self, local_endpoint: SocketAddrV4, port: u16) {
+        self.rejected = true;
+        self.inner.port_in_use(local_endpoint, port);
+    }
+
+    fn range(&self) -> RangeInclusive<u16> {
+        self.inner.range()
+    }
+}
+
+/// Port assignment behaviour of a NAT.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PortAllocation {
+    /// Reuse the internal source port as external port if it is available.
+    pub preserve: bool,
+    /// Keep the parity of the internal source port.
+    pub parity: bool,
+    /// Map contiguous internal ports to contiguous external ports.
+    pub contiguous: bool,
+    /// Range of external ports to allocate from. Defaults to the behaviour of
+    /// `SequentialPortAllocator` if `None`.
+    pub range: Option<RangeInclusive<u16>>,
+}
+
+impl PortAllocation {
+    /// Creates a port allocator implementing the configured behaviour.
+    pub fn build(&self) -> Box<dyn PortAllocator> {
+        let mut allocator: Box<dyn PortAllocator> = match &self.range {
+            Some(range) => Box::new(RangePortAllocator::new(range.clone())),
+            None => Box::<SequentialPortAllocator>::default(),
+        };
+        if self.parity {
+            allocator = Box::new(ParityPortAllocator::new(allocator));
+        }
+        if self.contiguous {
+            allocator = Box::new(ContiguousPortAllocator::new(allocator));
+        }
+        if self.preserve {
+            allocator = Box::new(PreservingPortAllocator::new(allocator));
+        }
+        allocator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new("192.168.0.2".parse().unwrap(), port)
+    }
+
+    /// Maps a port like `PortMap` does, asking again while the returned port is mapped.
+    fn map(allocator: &mut dyn PortAllocator, mapped: &mut HashSet<u16>, port: u16) -> Option<u16> {
+        for _ in 0..num_ports(&allocator.range()) {
+            let external = allocator.next_port(endpoint(port));
+            if mapped.insert(external) {
+                return Some(external);
+            }
+            allocator.port_in_use(endpoint(port), external);
+        }
+        None
+    }
+
+    #[test]
+    fn preserving_falls_back_when_in_use() {
+        let mut allocator = PreservingPortAllocator::new(RangePortAllocator::new(2000..=5000));
+        assert_eq!(allocator.next_port(endpoint(4000)), 4000);
+        allocator.port_in_use(endpoint(4000), 4000);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2000);
+        assert_eq!(allocator.next_port(endpoint(4001)), 4001);
+    }
+
+    #[test]
+    fn preserving_stays_in_range() {
+        let mut allocator = PreservingPortAllocator::new(RangePortAllocator::new(2000..=2001));
+        assert_eq!(allocator.next_port(endpoint(2001)), 2001);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2000);
+        allocator.port_in_use(endpoint(4000), 2000);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2001);
+    }
+
+    #[test]
+    fn parity_is_preserved() {
+        let mut allocator = ParityPortAllocator::new(RangePortAllocator::new(2000..=2010));
+        assert_eq!(allocator.next_port(endpoint(4001)), 2001);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2002);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2004);
+    }
+
+    #[test]
+    fn parity_is_given_up_after_a_cycle() {
+        let mut allocator = ParityPortAllocator::new(RangePortAllocator::new(5000..=5000));
+        assert_eq!(allocator.next_port(endpoint(4001)), 5000);
+        let mut allocator = ParityPortAllocator::new(RangePortAllocator::new(5000..=5002));
+        assert_eq!(allocator.next_port(endpoint(4001)), 5001);
+        assert_eq!(allocator.next_port(endpoint(4001)), 5001);
+    }
+
+    #[test]
+    fn contiguous_ports_stay_contiguous() {
+        let mut allocator = ContiguousPortAllocator::new(RangePortAllocator::new(2000..=2010));
+        assert_eq!(allocator.next_port(endpoint(5000)), 2000);
+        assert_eq!(allocator.next_port(endpoint(4000)), 2001);
+        assert_eq!(allocator.next_port(endpoint(4001)), 2002);
+    }
+
+    #[test]
+    fn range_wraps_around() {
+        let mut allocator = RangePortAllocator::new(2000..=2001);
+        assert_eq!(allocator.next_port(endpoint(1)), 2000);
+        assert_eq!(allocator.next_port(endpoint(1)), 2001);
+        assert_eq!(allocator.next_port(endpoint(1)), 2000);
+    }
+
+    #[test]
+    fn contiguous_ports_are_not_handed_out_again() {
+        let mut allocator = ContiguousPortAllocator::new(RangePortAllocator::new(2000..=2010));
+        assert_eq!(allocator.next_port(endpoint(5000)), 2000);
+        assert_eq!(allocator.next_port(endpoint(5001)), 2001);
+        // the inner allocator would return 2001 next
+        assert_eq!(allocator.next_port(endpoint(4000)), 2002);
+        assert_eq!(allocator.next_port(endpoint(6000)), 2003);
+    }
+
+    #[test]
+    fn contiguous_ports_stay_in_range() {
+        let mut allocator = ContiguousPortAllocator::new(RangePortAllocator::new(2000..=2002));
+        let mut mapped = HashSet::new();
+        assert_eq!(map(&mut allocator, &mut mapped, 5000), Some(2000));
+        assert_eq!(map(&mut allocator, &mut mapped, 5001), Some(2001));
+        assert_eq!(map(&mut allocator, &mut mapped, 5002), Some(2002));
+        // 2003 is out of range and the ports handed out are never returned again
+        assert_eq!(map(&mut allocator, &mut mapped, 5003), None);
+        assert_eq!(map(&mut allocator, &mut mapped, 4000), None);
+    }
 }
@@ -1,12 +1,18 @@
-use crate::port_allocator::{PortAllocator, SequentialPortAllocator};
+use crate::handle::NatMapping;
+use crate::port_allocator::{num_ports, PortAllocator, SequentialPortAllocator};
+use netsim_embed_core::Protocol;
 use std::collections::hash_map::{Entry, HashMap};
-use std::net::SocketAddrV4;
+use std::net::{Ipv4Addr, SocketAddrV4};
 
 #[derive(Debug)]
 pub struct PortMap {
-    map_out: HashMap<SocketAddrV4, u16>,
-    map_in: HashMap<u16, SocketAddrV4>,
-    allowed_endpoints: Option<HashMap<u16, SocketAddrV4>>,
+    forwarded: HashMap<SocketAddrV4, SocketAddrV4>,
+    /// External addresses accepting traffic from any remote address, mapped to whether the
+    /// mapping was created by `map_explicit`.
+    explicit: HashMap<SocketAddrV4, bool>,
+    map_out: HashMap<SocketAddrV4, SocketAddrV4>,
+    map_in: HashMap<SocketAddrV4, SocketAddrV4>,
+    allowed_endpoints: Option<HashMap<SocketAddrV4, SocketAddrV4>>,
     symmetric_map: Option<SymmetricMap>,
     port_allocator: Box<dyn PortAllocator>,
 }
@@ -15,6 +21,8 @@ pub struct PortMap {
 impl Default for PortMap {
     fn default() -> Self {
         Self {
+            forwarded: Default::default(),
+            explicit: Default::default(),
             map_out: Default::default(),
             map_in: Default::default(),
             allowed_endpoints: Default::default(),
@@ -26,14 +34,92 @@ impl Default for PortMap {
 
 #[derive(Debug, Default)]
 pub struct SymmetricMap {
-    map_out: HashMap<(SocketAddrV4, SocketAddrV4), u16>,
-    map_in: HashMap<u16, (SocketAddrV4, SocketAddrV4)>,
+    map_out: HashMap<(SocketAddrV4, SocketAddrV4), SocketAddrV4>,
+    map_in: HashMap<SocketAddrV4, (SocketAddrV4, SocketAddrV4)>,
 }
 
 impl PortMap {
-    pub fn forward_port(&mut self, port: u16, local_addr: SocketAddrV4) {
-        self.map_out.insert(local_addr, port);
-        self.map_in.insert(port, local_addr);
+    pub fn forward_port(&mut self, external_addr: SocketAddrV4, local_addr: SocketAddrV4) {
+        self.forwarded.insert(external_addr, local_addr);
+        self.map_out.insert(local_addr, external_addr);
+        self.map_in.insert(external_addr, local_addr);
+    }
+
+    pub fn remove_forward(&mut self, external_addr: SocketAddrV4) {
+        if let Some(local_addr) = self.forwarded.remove(&external_addr) {
+            self.map_in.remove(&external_addr);
+            if self.map_out.get(&local_addr) == Some(&external_addr) {
+                self.map_out.remove(&local_addr);
+            }
+        }
+    }
+
+    /// Removes all mappings except for forwarded ports, which are moved to `public_ip`.
+    pub fn flush(&mut self, public_ip: Ipv4Addr) {
+        self.explicit.clear();
+        self.map_out.clear();
+        self.map_in.clear();
+        if let Some(ref mut allowed_endpoints) = self.allowed_endpoints {
+            allowed_endpoints.clear();
+        }
+        if let Some(ref mut symmetric_map) = self.symmetric_map {
+            *symmetric_map = Default::default();
+        }
+        for (external_addr, local_addr) in std::mem::take(&mut self.forwarded) {
+            self.forward_port(
+                SocketAddrV4::new(public_ip, external_addr.port()),
+                local_addr,
+            );
+        }
+    }
+
+    /// Creates a mapping for `local_addr` accepting traffic from any remote address, preferring
+    /// `port` as external port on the address returned by `public_ip`. An existing mapping is
+    /// returned and accepts traffic from any remote address until `unmap` is called. Returns
+    /// `None` if all ports of the port allocator are in use.
+    pub fn map_explicit(
+        &mut self,
+        local_addr: SocketAddrV4,
+        port: u16,
+        public_ip: impl FnOnce() -> Ipv4Addr,
+    ) -> Option<SocketAddrV4> {
+        if let Some(addr) = self.map_out.get(&local_addr) {
+            self.explicit.entry(*addr).or_insert(false);
+            return Some(*addr);
+        }
+        let public_ip = public_ip();
+        let map_in = &self.map_in;
+        let symmetric_map = &self.symmetric_map;
+        let in_use = |addr: &SocketAddrV4| {
+            map_in.contains_key(addr)
+                || symmetric_map
+                    .as_ref()
+                    .map(|map| map.map_in.contains_key(addr))
+                    .unwrap_or(false)
+        };
+        let preferred = SocketAddrV4::new(public_ip, port);
+        let addr = if port != 0 && !in_use(&preferred) {
+            preferred
+        } else {
+            allocate_port(&mut *self.port_allocator, local_addr, public_ip, in_use)?
+        };
+        self.explicit.insert(addr, true);
+        self.map_out.insert(local_addr, addr);
+        self.map_in.insert(addr, local_addr);
+        Some(addr)
+    }
+
+    /// Reverts `map_explicit` for `local_addr`. The mapping is removed if `map_explicit` created
+    /// it, an existing mapping only stops accepting traffic from any remote address. Forwarded
+    /// ports are kept.
+    pub fn unmap(&mut self, local_addr: SocketAddrV4) {
+        if let Some(addr) = self.map_out.get(&local_addr).copied() {
+            if self.explicit.remove(&addr) != Some(true) || self.forwarded.contains_key(&addr) {
+                return;
+            }
+            self.map_out.remove(&local_addr);
+            self.map_in.remove(&addr);
+        }
     }
 
     pub fn set_port_allocator<T: PortAllocator + 'static>(&mut self, port_allocator: T) {
@@ -56,12 +142,41 @@ impl PortMap {
         }
     }
 
-    pub fn get_inbound_addr(&self, remote_addr: SocketAddrV4, port: u16) -> Option<SocketAddrV4> {
+    pub fn mappings(&self, protocol: Protocol) -> Vec<NatMapping> {
+        let mut mappings = self
+            .map_in
+            .iter()
+            .map(|(external_addr, local_addr)| NatMapping {
+                protocol,
+                local_addr: *local_addr,
+                external_addr: *external_addr,
+                remote_addr: None,
+            })
+            .collect::<Vec<_>>();
+        if let Some(ref symmetric_map) = self.symmetric_map {
+            mappings.extend(symmetric_map.map_in.iter().map(
+                |(external_addr, (local_addr, remote_addr))| NatMapping {
+                    protocol,
+                    local_addr: *local_addr,
+                    external_addr: *external_addr,
+                    remote_addr: Some(*remote_addr),
+                },
+            ));
+        }
+        mappings
+    }
+
+    pub fn get_inbound_addr(
+        &self,
+        remote_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
         if let Some(ref allowed_endpoints) = self.allowed_endpoints {
-            if !allowed_endpoints
-                .get(&port)
-                .map(|allowed| *allowed == remote_addr)
-                .unwrap_or(false)
+            if !self.explicit.contains_key(&external_addr)
+                && !allowed_endpoints
+                    .get(&external_addr)
+                    .map(|allowed| *allowed == remote_addr)
+                    .unwrap_or(false)
             {
                 log::trace!(
                     "NAT dropping packet from restricted address {}. allowed endpoints: {:?}",
@@ -71,11 +186,11 @@ impl PortMap {
                 return None;
             }
         }
-        if let Some(addr) = self.map_in.get(&port) {
+        if let Some(addr) = self.map_in.get(&external_addr) {
             return Some(*addr);
         }
         if let Some(ref symmetric_map) = self.symmetric_map {
-            if let Some(&(addr, allowed_remote_addr)) = symmetric_map.map_in.get(&port) {
+            if let Some(&(addr, allowed_remote_addr)) = symmetric_map.map_in.get(&external_addr) {
                 if allowed_remote_addr == remote_addr {
                     return Some(addr);
                 }
@@ -84,50 +199,108 @@ impl PortMap {
         None
     }
 
-    pub fn map_port(&mut self, remote_addr: SocketAddrV4, source_addr: SocketAddrV4) -> u16 {
-        let port = match self.map_out.entry(source_addr) {
+    /// Returns the external address for packets from `source_addr` to `remote_addr`, creating a
+    /// new mapping on the address returned by `public_ip` if there is none yet. Returns `None`
+    /// if all ports of the port allocator are in use.
+    pub fn map_port(
+        &mut self,
+        remote_addr: SocketAddrV4,
+        source_addr: SocketAddrV4,
+        public_ip: impl FnOnce() -> Ipv4Addr,
+    ) -> Option<SocketAddrV4> {
+        let addr = match self.map_out.entry(source_addr) {
             Entry::Occupied(oe) => *oe.get(),
             Entry::Vacant(ve) => {
                 if let Some(ref mut symmetric_map) = self.symmetric_map {
                     match symmetric_map.map_out.entry((source_addr, remote_addr)) {
                         Entry::Occupied(oe) => *oe.get(),
                         Entry::Vacant(ve) => {
-                            let port = loop {
-                                let port = self.port_allocator.next_port(source_addr);
-                                if self.map_in.contains_key(&port) {
-                                    continue;
-                                }
-                                if symmetric_map.map_in.contains_key(&port) {
-                                    continue;
-                                }
-                                break port;
-                            };
-
-                            ve.insert(port);
+                            let map_in = &self.map_in;
+                            let symmetric_map_in = &symmetric_map.map_in;
+                            let addr = allocate_port(
+                                &mut *self.port_allocator,
+                                source_addr,
+                                public_ip(),
+                                |addr| {
+                                    map_in.contains_key(addr) || symmetric_map_in.contains_key(addr)
+                                },
+                            )?;
+
+                            ve.insert(addr);
                             symmetric_map
                                 .map_in
-                                .insert(port, (source_addr, remote_addr));
-                            port
+                                .insert(addr, (source_addr, remote_addr));
+                            addr
                         }
                     }
                 } else {
-                    let port = loop {
-                        let port = self.port_allocator.next_port(source_addr);
-                        if self.map_in.contains_key(&port) {
-                            continue;
-                        }
-                        break port;
-                    };
+                    let map_in = &self.map_in;
+                    let addr = allocate_port(
+                        &mut *self.port_allocator,
+                        source_addr,
+                        public_ip(),
+                        |addr| map_in.contains_key(addr),
+                    )?;
 
-                    ve.insert(port);
-                    self.map_in.insert(port, source_addr);
-                    port
+                    ve.insert(addr);
+                    self.map_in.insert(addr, source_addr);
+                    addr
                 }
             }
         };
         if let Some(ref mut allowed_endpoints) = self.allowed_endpoints {
-            allowed_endpoints.insert(port, remote_addr);
+            allowed_endpoints.insert(addr, remote_addr);
+        }
+        Some(addr)
+    }
+}
+
+/// Asks `allocator` for a port of `public_ip` which isn't `in_use`, gives up after as many
+/// attempts as the allocator has ports.
+fn allocate_port(
+    allocator: &mut dyn PortAllocator,
+    source_addr: SocketAddrV4,
+    public_ip: Ipv4Addr,
+    in_use: impl Fn(&SocketAddrV4) -> bool,
+) -> Option<SocketAddrV4> {
+    for _ in 0..num_ports(&allocator.range()) {
+        let port = allocator.next_port(source_addr);
+        let addr = SocketAddrV4::new(public_ip, port);
+        if !in_use(&addr) {
+            return Some(addr);
+        }
+        allocator.port_in_use(source_addr, port);
+    }
+    log::debug!("nat {}: no port left for {}", public_ip, source_addr);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port_allocator::RangePortAllocator;
+
+    fn local(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), port)
+    }
+
+    fn remote(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), port)
+    }
+
+    #[test]
+    fn map_port_gives_up_when_exhausted() {
+        let public_ip = Ipv4Addr::new(1, 2, 3, 4);
+        for symmetric in [false, true] {
+            let mut map = PortMap::default();
+            map.set_symmetric(symmetric);
+            map.set_port_allocator(RangePortAllocator::new(5000..=5001));
+            let first = map.map_port(remote(1), local(1), || public_ip);
+            assert_eq!(first, Some(SocketAddrV4::new(public_ip, 5000)));
+            let second = map.map_port(remote(1), local(2), || public_ip);
+            assert_eq!(second, Some(SocketAddrV4::new(public_ip, 5001)));
+            assert_eq!(map.map_port(remote(1), local(3), || public_ip), None);
+            assert_eq!(map.map_port(remote(1), local(1), || public_ip), first);
         }
-        port
     }
 }
@@ -0,0 +1,265 @@
+//! Wire format of the NAT-PMP (RFC 6886) and PCP (RFC 6887) port mapping protocols.
+use netsim_embed_core::Protocol;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// UDP port NAT-PMP and PCP servers listen on.
+pub const SERVER_PORT: u16 = 5351;
+
+const PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+const PMP_OP_ADDRESS: u8 = 0;
+const PMP_OP_MAP_UDP: u8 = 1;
+const PMP_OP_MAP_TCP: u8 = 2;
+
+const PCP_OP_ANNOUNCE: u8 = 0;
+const PCP_OP_MAP: u8 = 1;
+
+const PMP_OUT_OF_RESOURCES: u16 = 4;
+const PMP_UNSUPPORTED_OPCODE: u16 = 5;
+
+const PCP_UNSUPP_VERSION: u8 = 1;
+const PCP_MALFORMED_REQUEST: u8 = 3;
+const PCP_UNSUPP_OPCODE: u8 = 4;
+const PCP_NO_RESOURCES: u8 = 8;
+const PCP_UNSUPP_PROTOCOL: u8 = 9;
+const PCP_ADDRESS_MISMATCH: u8 = 12;
+
+/// A port mapping request received from a client.
+#[derive(Debug)]
+pub enum Request {
+    /// NAT-PMP request for the public address.
+    PmpAddress,
+    /// NAT-PMP mapping request, a lifetime of zero deletes the mapping.
+    PmpMap {
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+    /// PCP announce request.
+    PcpAnnounce,
+    /// PCP MAP request, a lifetime of zero deletes the mapping.
+    PcpMap {
+        nonce: [u8; 12],
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+    /// A request that is answered with an error.
+    Invalid(Vec<u8>),
+}
+
+impl Request {
+    /// Parses a request sent from `source`. Returns `None` for messages which must be ignored.
+    pub fn parse(bytes: &[u8], source: Ipv4Addr, epoch: u32) -> Option<Self> {
+        match *bytes.first()? {
+            PMP_VERSION => Self::parse_pmp(bytes, epoch),
+            PCP_VERSION => Some(Self::parse_pcp(bytes, source, epoch)),
+            _ => {
+                let mut response = pcp_header(bytes.get(1)? & 0x7f, PCP_UNSUPP_VERSION, 0, epoch);
+                response[0] = PCP_VERSION;
+                Some(Self::Invalid(response))
+            }
+        }
+    }
+
+    fn parse_pmp(bytes: &[u8], epoch: u32) -> Option<Self> {
+        let opcode = *bytes.get(1)?;
+        if opcode & 0x80 != 0 {
+            return None;
+        }
+        let protocol = match opcode {
+            PMP_OP_ADDRESS => return Some(Self::PmpAddress),
+            PMP_OP_MAP_UDP => Protocol::Udp,
+            PMP_OP_MAP_TCP => Protocol::Tcp,
+            _ => {
+                let mut response = Vec::with_capacity(8);
+                response.extend_from_slice(&[PMP_VERSION, 0x80 | opcode]);
+                response.extend_from_slice(&PMP_UNSUPPORTED_OPCODE.to_be_bytes());
+                response.extend_from_slice(&epoch.to_be_bytes());
+                return Some(Self::Invalid(response));
+            }
+        };
+        if bytes.len() < 12 {
+            return None;
+        }
+        Some(Self::PmpMap {
+            protocol,
+            internal_port: u16::from_be_bytes([bytes[4], bytes[5]]),
+            external_port: u16::from_be_bytes([bytes[6], bytes[7]]),
+            lifetime: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        })
+    }
+
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn parse_pcp(bytes: &[u8], source: Ipv4Addr, epoch: u32) -> Self {
+        let opcode = bytes.get(1).copied().unwrap_or_default() & 0x7f;
+        let error = |result| Self::Invalid(pcp_header(opcode, result, 0, epoch));
+        if bytes.len() < 24 || bytes.len() % 4 != 0 || bytes[1] & 0x80 != 0 {
+            return error(PCP_MALFORMED_REQUEST);
+        }
+        let mut client_ip = [0; 16];
+        client_ip.copy_from_slice(&bytes[8..24]);
+        if Ipv6Addr::from(client_ip) != source.to_ipv6_mapped() {
+            return error(PCP_ADDRESS_MISMATCH);
+        }
+        let lifetime = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        match opcode {
+            PCP_OP_ANNOUNCE => Self::PcpAnnounce,
+            PCP_OP_MAP => {
+                if bytes.len() < 60 {
+                    return error(PCP_MALFORMED_REQUEST);
+                }
+                let mut nonce = [0; 12];
+                nonce.copy_from_slice(&bytes[24..36]);
+                let protocol = match bytes[36] {
+                    6 => Protocol::Tcp,
+                    17 => Protocol::Udp,
+                    _ => {
+                        let mut response = pcp_header(opcode, PCP_UNSUPP_PROTOCOL, 0, epoch);
+                        response.extend_from_slice(&bytes[24..60]);
+                        return Self::Invalid(response);
+                    }
+                };
+                Self::PcpMap {
+                    nonce,
+                    protocol,
+                    internal_port: u16::from_be_bytes([bytes[40], bytes[41]]),
+                    external_port: u16::from_be_bytes([bytes[42], bytes[43]]),
+                    lifetime,
+                }
+            }
+            _ => error(PCP_UNSUPP_OPCODE),
+        }
+    }
+}
+
+/// Encodes a successful NAT-PMP public address response.
+pub fn pmp_address_response(epoch: u32, public_ip: Ipv4Addr) -> Vec<u8> {
+    let mut response = Vec::with_capacity(12);
+    response.extend_from_slice(&[PMP_VERSION, 0x80 | PMP_OP_ADDRESS, 0, 0]);
+    response.extend_from_slice(&epoch.to_be_bytes());
+    response.extend_from_slice(&public_ip.octets());
+    response
+}
+
+/// Encodes a successful NAT-PMP mapping response.
+pub fn pmp_map_response(
+    protocol: Protocol,
+    epoch: u32,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Vec<u8> {
+    let opcode = match protocol {
+        Protocol::Udp => PMP_OP_MAP_UDP,
+        Protocol::Tcp => PMP_OP_MAP_TCP,
+    };
+    let mut response = Vec::with_capacity(16);
+    response.extend_from_slice(&[PMP_VERSION, 0x80 | opcode, 0, 0]);
+    response.extend_from_slice(&epoch.to_be_bytes());
+    response.extend_from_slice(&internal_port.to_be_bytes());
+    response.extend_from_slice(&external_port.to_be_bytes());
+    response.extend_from_slice(&lifetime.to_be_bytes());
+    response
+}
+
+/// Encodes a NAT-PMP mapping response telling the client that no external port is left.
+pub fn pmp_map_out_of_resources(protocol: Protocol, epoch: u32, internal_port: u16) -> Vec<u8> {
+    let mut response = pmp_map_response(protocol, epoch, internal_port, 0, 0);
+    response[2..4].copy_from_slice(&PMP_OUT_OF_RESOURCES.to_be_bytes());
+    response
+}
+
+/// Encodes a successful PCP announce response.
+pub fn pcp_announce_response(epoch: u32) -> Vec<u8> {
+    pcp_header(PCP_OP_ANNOUNCE, 0, 0, epoch)
+}
+
+/// Encodes a successful PCP MAP response.
+pub fn pcp_map_response(
+    nonce: [u8; 12],
+    protocol: Protocol,
+    epoch: u32,
+    internal_port: u16,
+    external_port: u16,
+    external_ip: Ipv4Addr,
+    lifetime: u32,
+) -> Vec<u8> {
+    let mut response = pcp_header(PCP_OP_MAP, 0, lifetime, epoch);
+    response.extend_from_slice(&nonce);
+    response.push(match protocol {
+        Protocol::Tcp => 6,
+        Protocol::Udp => 17,
+    });
+    response.extend_from_slice(&[0; 3]);
+    response.extend_from_slice(&internal_port.to_be_bytes());
+    response.extend_from_slice(&external_port.to_be_bytes());
+    response.extend_from_slice(&external_ip.to_ipv6_mapped().octets());
+    response
+}
+
+/// Encodes a PCP MAP response telling the client that no external port is left.
+pub fn pcp_map_no_resources(
+    nonce: [u8; 12],
+    protocol: Protocol,
+    epoch: u32,
+    internal_port: u16,
+) -> Vec<u8> {
+    let mut response = pcp_map_response(
+        nonce,
+        protocol,
+        epoch,
+        internal_port,
+        0,
+        Ipv4Addr::UNSPECIFIED,
+        0,
+    );
+    response[3] = PCP_NO_RESOURCES;
+    response
+}
+
+fn pcp_header(opcode: u8, result: u8, lifetime: u32, epoch: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(60);
+    header.extend_from_slice(&[PCP_VERSION, 0x80 | opcode, 0, result]);
+    header.extend_from_slice(&lifetime.to_be_bytes());
+    header.extend_from_slice(&epoch.to_be_bytes());
+    header.extend_from_slice(&[0; 12]);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pmp_map_request() {
+        let request = [0, 1, 0, 0, 0x1f, 0x90, 0x1f, 0x91, 0, 0, 0x0e, 0x10];
+        match Request::parse(&request, Ipv4Addr::LOCALHOST, 0) {
+            Some(Request::PmpMap {
+                protocol: Protocol::Udp,
+                internal_port: 8080,
+                external_port: 8081,
+                lifetime: 3600,
+            }) => {}
+            req => panic!("unexpected request {:?}", req),
+        }
+    }
+
+    #[test]
+    fn rejects_pcp_address_mismatch() {
+        let mut request = vec![PCP_VERSION, PCP_OP_MAP, 0, 0, 0, 0, 0x0e, 0x10];
+        request.extend_from_slice(&Ipv4Addr::new(10, 0, 0, 3).to_ipv6_mapped().octets());
+        request.extend_from_slice(&[0; 36]);
+        match Request::parse(&request, Ipv4Addr::new(10, 0, 0, 2), 7) {
+            Some(Request::Invalid(response)) => {
+                assert_eq!(response[1], 0x80 | PCP_OP_MAP);
+                assert_eq!(response[3], PCP_ADDRESS_MISMATCH);
+            }
+            req => panic!("unexpected request {:?}", req),
+        }
+    }
+}
@@ -4,9 +4,10 @@ use futures::{
     stream::{FuturesUnordered, StreamExt},
 };
 use libpacket::ipv4::Ipv4Packet;
-use netsim_embed_core::{Ipv4Route, Plug};
+use libpacket::ipv6::Ipv6Packet;
+use netsim_embed_core::{Ipv4Range, Ipv4Route, Ipv6Route, Plug};
 use std::{
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc, Mutex,
@@ -14,11 +15,16 @@ use std::{
     task::Poll,
 };
 
+mod switch;
+
+pub use switch::{EthernetSwitch, MacAddr, VlanMode, DEFAULT_VLAN, MAX_VLAN};
+
 #[derive(Debug)]
 #[allow(clippy::enum_variant_names)]
 enum RouterCtrl {
-    AddRoute(usize, Plug, Vec<Ipv4Route>),
+    AddRoute(usize, Plug, Vec<Ipv4Route>, Vec<Ipv6Route>),
     RemoveRoute(usize, oneshot::Sender<Option<Plug>>),
+    SetRoutes(usize, Vec<Ipv4Route>, Vec<Ipv6Route>),
     EnableRoute(usize),
     DisableRoute(usize),
 }
@@ -26,7 +32,7 @@ enum RouterCtrl {
 #[derive(Debug)]
 pub struct Ipv4Router {
     #[allow(unused)]
-    addr: Ipv4Addr,
+    addr: Option<Ipv4Addr>,
     ctrl: mpsc::UnboundedSender<RouterCtrl>,
     counters: Arc<Counters>,
 }
@@ -55,6 +61,15 @@ impl std::fmt::Debug for Counters {
 
 impl Ipv4Router {
     pub fn new(addr: Ipv4Addr) -> Self {
+        Self::spawn(Some(addr))
+    }
+
+    /// Creates a router without an IPv4 address for IPv6-only networks.
+    pub fn ipv6_only() -> Self {
+        Self::spawn(None)
+    }
+
+    fn spawn(addr: Option<Ipv4Addr>) -> Self {
         let (tx, rx) = mpsc::unbounded();
         let counters = Arc::new(Counters::default());
         router(addr, Arc::clone(&counters), rx);
@@ -86,8 +101,24 @@ impl Ipv4Router {
     }
 
     pub fn add_connection(&self, id: usize, plug: Plug, routes: Vec<Ipv4Route>) {
+        self.add_dual_stack_connection(id, plug, routes, vec![]);
+    }
+
+    /// Adds a connection which IPv6 packets are routed to.
+    pub fn add_ipv6_connection(&self, id: usize, plug: Plug, routes: Vec<Ipv6Route>) {
+        self.add_dual_stack_connection(id, plug, vec![], routes);
+    }
+
+    /// Adds a connection which both IPv4 and IPv6 packets are routed to.
+    pub fn add_dual_stack_connection(
+        &self,
+        id: usize,
+        plug: Plug,
+        routes: Vec<Ipv4Route>,
+        routes6: Vec<Ipv6Route>,
+    ) {
         self.ctrl
-            .unbounded_send(RouterCtrl::AddRoute(id, plug, routes))
+            .unbounded_send(RouterCtrl::AddRoute(id, plug, routes, routes6))
             .ok();
     }
 
@@ -99,6 +130,13 @@ impl Ipv4Router {
         rx.await.unwrap()
     }
 
+    /// Replaces the routes of a connection, eg. when a machine gets another address.
+    pub fn set_routes(&self, id: usize, routes: Vec<Ipv4Route>, routes6: Vec<Ipv6Route>) {
+        self.ctrl
+            .unbounded_send(RouterCtrl::SetRoutes(id, routes, routes6))
+            .ok();
+    }
+
     pub fn enable_route(&self, id: usize) {
         self.ctrl
             .unbounded_send(RouterCtrl::EnableRoute(id))
@@ -112,51 +150,86 @@ impl Ipv4Router {
     }
 }
 
-fn router(addr: Ipv4Addr, counters: Arc<Counters>, mut ctrl: mpsc::UnboundedReceiver<RouterCtrl>) {
+#[derive(Debug)]
+struct Connection {
+    id: usize,
+    plug: Plug,
+    routes: Vec<Ipv4Route>,
+    routes6: Vec<Ipv6Route>,
+    enabled: bool,
+}
+
+impl Connection {
+    fn routes_to(&self, dest: IpAddr) -> bool {
+        match dest {
+            IpAddr::V4(dest) => self.routes.iter().any(|route| {
+                route.dest().contains(dest) || dest.is_broadcast() || dest.is_multicast()
+            }),
+            IpAddr::V6(dest) => self
+                .routes6
+                .iter()
+                .any(|route| route.dest().contains(dest) || dest.is_multicast()),
+        }
+    }
+}
+
+fn router(
+    addr: Option<Ipv4Addr>,
+    counters: Arc<Counters>,
+    mut ctrl: mpsc::UnboundedReceiver<RouterCtrl>,
+) {
+    let name = addr
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "(ipv6-only)".to_string());
     async_global_executor::spawn(async move {
         let mut conns = vec![];
         loop {
             futures::select! {
                 ctrl = ctrl.next() => match ctrl {
-                    Some(RouterCtrl::AddRoute(id, plug, routes)) => {
-                        conns.push((id, plug, routes, true));
+                    Some(RouterCtrl::AddRoute(id, plug, routes, routes6)) => {
+                        conns.push(Connection { id, plug, routes, routes6, enabled: true });
                     }
                     Some(RouterCtrl::RemoveRoute(id, ch)) => {
-                        let plug = if let Some(idx) = conns.iter().position(|(id2, _, _, _)| *id2 == id) {
-                            let (_, plug, _, _) = conns.swap_remove(idx);
-                            Some(plug)
-                        } else {
-                            None
-                        };
+                        let plug = conns
+                            .iter()
+                            .position(|conn| conn.id == id)
+                            .map(|idx| conns.swap_remove(idx).plug);
                         ch.send(plug).ok();
                     }
+                    Some(RouterCtrl::SetRoutes(id, routes, routes6)) => {
+                        if let Some(conn) = conns.iter_mut().find(|conn| conn.id == id) {
+                            conn.routes = routes;
+                            conn.routes6 = routes6;
+                        }
+                    }
                     Some(RouterCtrl::EnableRoute(id)) => {
-                        if let Some((_, _, _, en)) = conns.iter_mut().find(|(id2, _, _, _)| id == *id2) {
-                            *en = true;
+                        if let Some(conn) = conns.iter_mut().find(|conn| conn.id == id) {
+                            conn.enabled = true;
                         }
                     }
                     Some(RouterCtrl::DisableRoute(id)) => {
-                        if let Some((_, _, _, en)) = conns.iter_mut().find(|(id2, _, _, _)| id == *id2) {
-                            *en = false;
+                        if let Some(conn) = conns.iter_mut().find(|conn| conn.id == id) {
+                            conn.enabled = false;
                         }
                     }
                     None => break,
                 },
                 incoming = incoming(&mut conns).fuse() => match incoming {
-                    (_, Some(packet)) => forward_packet(addr, &counters, &mut conns, packet),
+                    (_, Some(packet)) => forward_packet(addr, &name, &counters, &mut conns, packet),
                     (i, None) => { conns.swap_remove(i); }
                 }
             }
         }
-    }).detach()
+    })
+    .detach()
 }
 
-async fn incoming(conns: &mut [(usize, Plug, Vec<Ipv4Route>, bool)]) -> (usize, Option<Vec<u8>>) {
+async fn incoming(conns: &mut [Connection]) -> (usize, Option<Vec<u8>>) {
     let mut futures = conns
         .iter_mut()
         .enumerate()
-        .filter(|(_, (_, _, _, en))| *en)
-        .map(|(i, (_, plug, _, _))| async move { (i, plug.incoming().await) })
+        .filter(|(_, conn)| conn.enabled)
+        .map(|(i, conn)| async move { (i, conn.plug.incoming().await) })
         .collect::<FuturesUnordered<_>>();
     if futures.is_empty() {
         poll_fn(|_| Poll::Pending).await
@@ -165,57 +238,123 @@ async fn incoming(conns: &mut [(usize, Plug, Vec<Ipv4Route>, bool)]) -> (usize,
     }
 }
 
+fn parse_addrs(bytes: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match bytes.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new(bytes)?;
+            Some((packet.get_source().into(), packet.get_destination().into()))
+        }
+        6 => {
+            let packet = Ipv6Packet::new(bytes)?;
+            Some((packet.get_source().into(), packet.get_destination().into()))
+        }
+        _ => None,
+    }
+}
+
 fn forward_packet(
-    addr: Ipv4Addr,
+    addr: Option<Ipv4Addr>,
+    name: &str,
     counters: &Counters,
-    conns: &mut [(usize, Plug, Vec<Ipv4Route>, bool)],
+    conns: &mut [Connection],
     bytes: Vec<u8>,
 ) {
     let count = counters.filter.lock().unwrap().iter().all(|f| f(&bytes));
-    let packet = if let Some(packet) = Ipv4Packet::new(&bytes) {
-        packet
+    let (src, dest) = if let Some(addrs) = parse_addrs(&bytes) {
+        addrs
     } else {
         if count {
             counters.invalid.fetch_add(1, Ordering::Relaxed);
         }
-        log::info!("router {}: dropping invalid ipv4 packet", addr);
+        log::info!("router {}: dropping invalid ip packet", name);
         return;
     };
-    let dest = packet.get_destination();
-    if dest == addr {
-        log::info!("router {}: dropping packet addressed to me", addr);
+    // packets addressed to the router are only forwarded to connections with a host route for
+    // its address, e.g. to the NAT-PMP/PCP server of a NAT which clients reach on their default
+    // gateway
+    let own_range = addr.map(Ipv4Range::from);
+    let has_own_route = |conn: &Connection| {
+        conn.routes
+            .iter()
+            .any(|route| Some(route.dest()) == own_range)
+    };
+    let to_me = addr.map(IpAddr::V4) == Some(dest);
+    if to_me && !conns.iter().any(has_own_route) {
+        log::info!("router {}: dropping packet addressed to me", name);
         return;
     }
     let mut forwarded = false;
-    for (_, tx, routes, en) in conns {
-        for route in routes {
-            if route.dest().contains(dest) || dest.is_broadcast() || dest.is_multicast() {
-                if !*en {
-                    if count {
-                        counters.disabled.fetch_add(1, Ordering::Relaxed);
-                    }
-                    log::trace!("router {}: route {:?} disabled", addr, route);
-                } else {
-                    if count {
-                        counters.forwarded.fetch_add(1, Ordering::Relaxed);
-                    }
-                    log::trace!("router {}: routing packet on route {:?}", addr, route,);
-                    tx.unbounded_send(bytes.clone());
-                    forwarded = true;
-                }
+    for conn in conns {
+        let routed = if to_me {
+            has_own_route(conn)
+        } else {
+            conn.routes_to(dest)
+        };
+        if !routed {
+            continue;
+        }
+        if !conn.enabled {
+            if count {
+                counters.disabled.fetch_add(1, Ordering::Relaxed);
+            }
+            log::trace!("router {}: connection {} disabled", name, conn.id);
+        } else {
+            if count {
+                counters.forwarded.fetch_add(1, Ordering::Relaxed);
             }
+            log::trace!("router {}: routing packet on connection {}", name, conn.id);
+            conn.plug.unbounded_send(bytes.clone());
+            forwarded = true;
         }
     }
     if !forwarded {
-        let src = packet.get_source();
         if count {
             counters.unroutable.fetch_add(1, Ordering::Relaxed);
         }
         log::debug!(
             "router {}: dropping unroutable packet from {} to {}",
-            addr,
+            name,
             src,
             dest
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::{wire, Packet};
+    use std::net::SocketAddrV4;
+
+    #[test]
+    fn forwards_packets_to_me_only_on_host_routes() {
+        async_global_executor::block_on(async {
+            let addr = Ipv4Addr::new(10, 0, 0, 1);
+            let router = Ipv4Router::new(addr);
+            let (mut client, plug) = wire();
+            router.add_connection(0, plug, vec![Ipv4Addr::new(10, 0, 0, 2).into()]);
+            let (mut upstream, plug) = wire();
+            router.add_connection(1, plug, vec![Ipv4Range::global().into()]);
+            // the connections were added once the router answered
+            router.remove_connection(3).await;
+            let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5350);
+            let to_me = Packet::build_udp(source, SocketAddrV4::new(addr, 5351), b"me");
+            let remote = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+            let to_remote = Packet::build_udp(source, remote, b"remote");
+
+            // the default route doesn't cover the router's own address
+            client.unbounded_send(to_me.clone());
+            client.unbounded_send(to_remote.clone());
+            assert_eq!(upstream.incoming().await, Some(to_remote.clone()));
+
+            let (mut server, plug) = wire();
+            router.add_connection(2, plug, vec![addr.into()]);
+            router.remove_connection(3).await;
+            client.unbounded_send(to_me.clone());
+            client.unbounded_send(to_remote.clone());
+            assert_eq!(server.incoming().await, Some(to_me));
+            assert_eq!(upstream.incoming().await, Some(to_remote));
+            assert_eq!(router.forwarded(), 3);
+        });
+    }
+}
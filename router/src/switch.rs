@@ -0,0 +1,529 @@
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{poll_fn, FutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
+use netsim_embed_core::Plug;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+
+/// Length of an ethernet header without VLAN tags.
+const ETH_HDR_LEN: usize = 14;
+
+/// Length of an 802.1Q tag.
+const VLAN_TAG_LEN: usize = 4;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// VLAN of ports which are added without a VLAN mode.
+pub const DEFAULT_VLAN: u16 = 1;
+
+/// Highest valid VLAN id, ids 0 and 4095 are reserved.
+pub const MAX_VLAN: u16 = 4094;
+
+pub type MacAddr = [u8; 6];
+
+/// 802.1Q VLAN membership of a switch port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VlanMode {
+    /// Frames are sent and received untagged and belong to the VLAN, tagged frames are dropped.
+    Access(u16),
+    /// Frames of the allowed VLANs are sent and received tagged, untagged frames belong to the
+    /// native VLAN if there is one.
+    Trunk {
+        native: Option<u16>,
+        allowed: Vec<u16>,
+    },
+}
+
+impl Default for VlanMode {
+    fn default() -> Self {
+        Self::Access(DEFAULT_VLAN)
+    }
+}
+
+impl VlanMode {
+    /// Returns whether all VLANs of the mode are in `1..=MAX_VLAN`.
+    pub fn is_valid(&self) -> bool {
+        let valid = |vlan: &u16| (1..=MAX_VLAN).contains(vlan);
+        match self {
+            Self::Access(vlan) => valid(vlan),
+            Self::Trunk { native, allowed } => native.iter().chain(allowed).all(valid),
+        }
+    }
+
+    /// Returns the VLAN a frame received on the port belongs to, `None` if the port doesn't
+    /// carry it.
+    fn ingress(&self, tag: Option<u16>) -> Option<u16> {
+        match (self, tag) {
+            (Self::Access(vlan), None) => Some(*vlan),
+            (Self::Access(_), Some(_)) => None,
+            (Self::Trunk { native, .. }, None) => *native,
+            (Self::Trunk { allowed, .. }, Some(vlan)) => allowed.contains(&vlan).then_some(vlan),
+        }
+    }
+
+    /// Returns whether frames of a VLAN are sent on the port, and if they are tagged.
+    fn egress(&self, vlan: u16) -> Option<bool> {
+        match self {
+            Self::Access(access) => (*access == vlan).then_some(false),
+            Self::Trunk { native, .. } if *native == Some(vlan) => Some(false),
+            Self::Trunk { allowed, .. } => allowed.contains(&vlan).then_some(true),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SwitchCtrl {
+    AddPort(usize, Plug, VlanMode),
+    RemovePort(usize, oneshot::Sender<Option<Plug>>),
+    SetVlanMode(usize, VlanMode),
+    EnablePort(usize),
+    DisablePort(usize),
+    MacTable(oneshot::Sender<Vec<(u16, MacAddr, usize)>>),
+}
+
+/// An ethernet switch which learns the MAC addresses behind its ports and floods broadcast,
+/// multicast and unknown unicast frames to all other ports. Ports are grouped into isolated
+/// broadcast domains by 802.1Q VLANs.
+#[derive(Debug)]
+pub struct EthernetSwitch {
+    ctrl: mpsc::UnboundedSender<SwitchCtrl>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicUsize,
+    flooded: AtomicUsize,
+    invalid: AtomicUsize,
+    disabled: AtomicUsize,
+    filtered: AtomicUsize,
+}
+
+impl Default for EthernetSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EthernetSwitch {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let counters = Arc::new(Counters::default());
+        switch(Arc::clone(&counters), rx);
+        Self { ctrl: tx, counters }
+    }
+
+    /// Number of frames sent to the single port their destination was learned on.
+    pub fn forwarded(&self) -> usize {
+        self.counters.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames sent to all ports.
+    pub fn flooded(&self) -> usize {
+        self.counters.flooded.load(Ordering::Relaxed)
+    }
+
+    pub fn invalid(&self) -> usize {
+        self.counters.invalid.load(Ordering::Relaxed)
+    }
+
+    pub fn disabled(&self) -> usize {
+        self.counters.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped because their port doesn't carry their VLAN.
+    pub fn filtered(&self) -> usize {
+        self.counters.filtered.load(Ordering::Relaxed)
+    }
+
+    /// Adds an access port of the default VLAN.
+    pub fn add_port(&self, id: usize, plug: Plug) {
+        self.add_vlan_port(id, plug, VlanMode::default());
+    }
+
+    /// Adds a port carrying the VLANs of `mode`.
+    ///
+    /// # Panics
+    ///
+    /// If a VLAN id is outside of `1..=MAX_VLAN`.
+    pub fn add_vlan_port(&self, id: usize, plug: Plug, mode: VlanMode) {
+        assert!(mode.is_valid(), "invalid VLAN id in {:?}", mode);
+        self.ctrl
+            .unbounded_send(SwitchCtrl::AddPort(id, plug, mode))
+            .ok();
+    }
+
+    /// Changes the VLANs of a port, MAC addresses learned on it are forgotten.
+    ///
+    /// # Panics
+    ///
+    /// If a VLAN id is outside of `1..=MAX_VLAN`.
+    pub fn set_vlan_mode(&self, id: usize, mode: VlanMode) {
+        assert!(mode.is_valid(), "invalid VLAN id in {:?}", mode);
+        self.ctrl
+            .unbounded_send(SwitchCtrl::SetVlanMode(id, mode))
+            .ok();
+    }
+
+    /// Removes a port and forgets the MAC addresses learned on it.
+    pub async fn remove_port(&self, id: usize) -> Option<Plug> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .unbounded_send(SwitchCtrl::RemovePort(id, tx))
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    pub fn enable_port(&self, id: usize) {
+        self.ctrl
+            .unbounded_send(SwitchCtrl::EnablePort(id))
+            .unwrap();
+    }
+
+    pub fn disable_port(&self, id: usize) {
+        self.ctrl
+            .unbounded_send(SwitchCtrl::DisablePort(id))
+            .unwrap();
+    }
+
+    /// Returns the learned MAC addresses with their VLAN and the port they were learned on.
+    pub async fn mac_table(&self) -> Vec<(u16, MacAddr, usize)> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl.unbounded_send(SwitchCtrl::MacTable(tx)).unwrap();
+        rx.await.unwrap()
+    }
+}
+
+#[derive(Debug)]
+struct Port {
+    id: usize,
+    plug: Plug,
+    vlan: VlanMode,
+    enabled: bool,
+}
+
+fn switch(counters: Arc<Counters>, mut ctrl: mpsc::UnboundedReceiver<SwitchCtrl>) {
+    async_global_executor::spawn(async move {
+        let mut ports = vec![];
+        let mut macs = HashMap::new();
+        loop {
+            futures::select! {
+                ctrl = ctrl.next() => match ctrl {
+                    Some(SwitchCtrl::AddPort(id, plug, vlan)) => {
+                        ports.push(Port { id, plug, vlan, enabled: true });
+                    }
+                    Some(SwitchCtrl::RemovePort(id, ch)) => {
+                        macs.retain(|_, port| *port != id);
+                        let plug = ports
+                            .iter()
+                            .position(|port| port.id == id)
+                            .map(|idx| ports.swap_remove(idx).plug);
+                        ch.send(plug).ok();
+                    }
+                    Some(SwitchCtrl::SetVlanMode(id, vlan)) => {
+                        if let Some(port) = ports.iter_mut().find(|port| port.id == id) {
+                            port.vlan = vlan;
+                            macs.retain(|_, port| *port != id);
+                        }
+                    }
+                    Some(SwitchCtrl::EnablePort(id)) => {
+                        if let Some(port) = ports.iter_mut().find(|port| port.id == id) {
+                            port.enabled = true;
+                        }
+                    }
+                    Some(SwitchCtrl::DisablePort(id)) => {
+                        if let Some(port) = ports.iter_mut().find(|port| port.id == id) {
+                            port.enabled = false;
+                        }
+                    }
+                    Some(SwitchCtrl::MacTable(ch)) => {
+                        let table = macs
+                            .iter()
+                            .map(|((vlan, mac), port)| (*vlan, *mac, *port))
+                            .collect();
+                        ch.send(table).ok();
+                    }
+                    None => break,
+                },
+                incoming = incoming(&mut ports).fuse() => match incoming {
+                    (i, Some(frame)) => {
+                        let id = ports[i].id;
+                        forward_frame(&counters, &mut macs, &mut ports, id, frame);
+                    }
+                    (i, None) => {
+                        let id = ports.swap_remove(i).id;
+                        macs.retain(|_, port| *port != id);
+                    }
+                }
+            }
+        }
+    })
+    .detach()
+}
+
+async fn incoming(ports: &mut [Port]) -> (usize, Option<Vec<u8>>) {
+    let mut futures = ports
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, port)| port.enabled)
+        .map(|(i, port)| async move { (i, port.plug.incoming().await) })
+        .collect::<FuturesUnordered<_>>();
+    if futures.is_empty() {
+        poll_fn(|_| Poll::Pending).await
+    } else {
+        futures.next().await.unwrap()
+    }
+}
+
+fn is_group_addr(addr: &MacAddr) -> bool {
+    addr[0] & 1 == 1
+}
+
+/// Returns the VLAN id of a tagged frame.
+fn vlan_tag(frame: &[u8]) -> Option<u16> {
+    if u16::from_be_bytes([frame[12], frame[13]]) == ETHERTYPE_VLAN {
+        Some(u16::from_be_bytes([frame[14], frame[15]]) & 0x0fff)
+    } else {
+        None
+    }
+}
+
+fn tag(frame: &[u8], vlan: u16) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(frame.len() + VLAN_TAG_LEN);
+    tagged.extend_from_slice(&frame[..12]);
+    tagged.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    tagged.extend_from_slice(&vlan.to_be_bytes());
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
+
+/// Sends an untagged frame of a VLAN on a port if the port carries the VLAN.
+fn send_frame(port: &mut Port, vlan: u16, frame: &[u8]) -> bool {
+    match port.vlan.egress(vlan) {
+        Some(false) => port.plug.unbounded_send(frame.to_vec()),
+        Some(true) => port.plug.unbounded_send(tag(frame, vlan)),
+        None => return false,
+    }
+    true
+}
+
+fn forward_frame(
+    counters: &Counters,
+    macs: &mut HashMap<(u16, MacAddr), usize>,
+    ports: &mut [Port],
+    ingress: usize,
+    mut frame: Vec<u8>,
+) {
+    if frame.len() < ETH_HDR_LEN {
+        counters.invalid.fetch_add(1, Ordering::Relaxed);
+        log::info!("switch: dropping invalid frame on port {}", ingress);
+        return;
+    }
+    let tag = vlan_tag(&frame);
+    if tag.is_some() {
+        if frame.len() < ETH_HDR_LEN + VLAN_TAG_LEN {
+            counters.invalid.fetch_add(1, Ordering::Relaxed);
+            log::info!("switch: dropping invalid frame on port {}", ingress);
+            return;
+        }
+        frame.drain(12..12 + VLAN_TAG_LEN);
+    }
+    let port = ports.iter().find(|port| port.id == ingress).unwrap();
+    let vlan = if let Some(vlan) = port.vlan.ingress(tag) {
+        vlan
+    } else {
+        counters.filtered.fetch_add(1, Ordering::Relaxed);
+        log::debug!("switch: port {} doesn't carry vlan {:?}", ingress, tag);
+        return;
+    };
+    let mut dest = [0; 6];
+    dest.copy_from_slice(&frame[..6]);
+    let mut src = [0; 6];
+    src.copy_from_slice(&frame[6..12]);
+    if is_group_addr(&src) {
+        counters.invalid.fetch_add(1, Ordering::Relaxed);
+        log::info!("switch: dropping frame with group source address");
+        return;
+    }
+    macs.insert((vlan, src), ingress);
+    let egress = if is_group_addr(&dest) {
+        None
+    } else {
+        macs.get(&(vlan, dest)).copied()
+    };
+    match egress {
+        Some(egress) if egress == ingress => {
+            log::trace!("switch: dropping frame for port {} it came from", ingress);
+        }
+        Some(egress) => {
+            let port = ports.iter_mut().find(|port| port.id == egress).unwrap();
+            if !port.enabled {
+                counters.disabled.fetch_add(1, Ordering::Relaxed);
+                log::trace!("switch: port {} disabled", egress);
+            } else if send_frame(port, vlan, &frame) {
+                counters.forwarded.fetch_add(1, Ordering::Relaxed);
+                log::trace!("switch: forwarding frame on port {}", egress);
+            } else {
+                counters.filtered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        None => {
+            counters.flooded.fetch_add(1, Ordering::Relaxed);
+            log::trace!(
+                "switch: flooding frame from port {} on vlan {}",
+                ingress,
+                vlan
+            );
+            for port in ports {
+                if port.id != ingress && port.enabled {
+                    send_frame(port, vlan, &frame);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::wire;
+
+    fn frame(dest: MacAddr, src: MacAddr) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame
+    }
+
+    #[test]
+    fn learns_and_floods() {
+        async_global_executor::block_on(async {
+            let switch = EthernetSwitch::new();
+            let mut plugs = vec![];
+            for id in 0..3 {
+                let (a, b) = wire();
+                switch.add_port(id, b);
+                plugs.push(a);
+            }
+            // the ports are added once the switch answered
+            switch.mac_table().await;
+            let macs = [[2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2], [2, 0, 0, 0, 0, 3]];
+
+            // unknown destination is flooded to the other ports
+            plugs[0].unbounded_send(frame(macs[1], macs[0]));
+            assert_eq!(plugs[1].incoming().await, Some(frame(macs[1], macs[0])));
+            assert_eq!(plugs[2].incoming().await, Some(frame(macs[1], macs[0])));
+
+            // the source address was learned
+            plugs[1].unbounded_send(frame(macs[0], macs[1]));
+            assert_eq!(plugs[0].incoming().await, Some(frame(macs[0], macs[1])));
+            plugs[2].unbounded_send(frame([0xff; 6], macs[2]));
+            assert_eq!(plugs[0].incoming().await, Some(frame([0xff; 6], macs[2])));
+            assert_eq!(plugs[1].incoming().await, Some(frame([0xff; 6], macs[2])));
+
+            let mut table = switch.mac_table().await;
+            table.sort();
+            assert_eq!(
+                table,
+                vec![(1, macs[0], 0), (1, macs[1], 1), (1, macs[2], 2)]
+            );
+            assert_eq!(switch.forwarded(), 1);
+            assert_eq!(switch.flooded(), 2);
+
+            switch.remove_port(2).await.unwrap();
+            assert_eq!(switch.mac_table().await.len(), 2);
+        });
+    }
+
+    #[test]
+    fn validates_vlan_ids() {
+        assert!(VlanMode::Access(1).is_valid());
+        assert!(VlanMode::Access(MAX_VLAN).is_valid());
+        assert!(!VlanMode::Access(0).is_valid());
+        assert!(!VlanMode::Access(4095).is_valid());
+        // would leak into the priority bits of the tag
+        assert!(!VlanMode::Access(4097).is_valid());
+        let trunk = |native, allowed| VlanMode::Trunk { native, allowed };
+        assert!(trunk(Some(1), vec![10, 20]).is_valid());
+        assert!(!trunk(Some(4095), vec![10]).is_valid());
+        assert!(!trunk(None, vec![10, 4097]).is_valid());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid VLAN id")]
+    fn rejects_invalid_vlan_ids() {
+        let switch = EthernetSwitch::new();
+        switch.add_vlan_port(0, wire().0, VlanMode::Access(4097));
+    }
+
+    #[test]
+    fn isolates_vlans() {
+        async_global_executor::block_on(async {
+            let switch = EthernetSwitch::new();
+            let trunk = VlanMode::Trunk {
+                native: None,
+                allowed: vec![10, 20],
+            };
+            let modes = [
+                VlanMode::Access(10),
+                VlanMode::Access(20),
+                VlanMode::Access(10),
+                trunk,
+            ];
+            let mut plugs = vec![];
+            for (id, mode) in modes.iter().enumerate() {
+                let (a, b) = wire();
+                switch.add_vlan_port(id, b, mode.clone());
+                plugs.push(a);
+            }
+            switch.mac_table().await;
+            let macs = [[2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]];
+
+            // broadcasts stay in their vlan and are tagged on the trunk
+            plugs[0].unbounded_send(frame([0xff; 6], macs[0]));
+            assert_eq!(plugs[2].incoming().await, Some(frame([0xff; 6], macs[0])));
+            assert_eq!(
+                plugs[3].incoming().await,
+                Some(tag(&frame([0xff; 6], macs[0]), 10))
+            );
+            plugs[1].unbounded_send(frame([0xff; 6], macs[1]));
+            assert_eq!(
+                plugs[3].incoming().await,
+                Some(tag(&frame([0xff; 6], macs[1]), 20))
+            );
+
+            // tagged frames from the trunk are untagged on access ports
+            plugs[3].unbounded_send(tag(&frame(macs[1], macs[0]), 20));
+            assert_eq!(plugs[1].incoming().await, Some(frame(macs[1], macs[0])));
+
+            // untagged frames aren't carried by a trunk without native vlan
+            plugs[3].unbounded_send(frame([0xff; 6], macs[1]));
+            plugs[3].unbounded_send(tag(&frame([0xff; 6], macs[1]), 10));
+            assert_eq!(plugs[0].incoming().await, Some(frame([0xff; 6], macs[1])));
+            assert_eq!(plugs[2].incoming().await, Some(frame([0xff; 6], macs[1])));
+            assert_eq!(switch.filtered(), 1);
+            assert_eq!(switch.flooded(), 3);
+            assert_eq!(switch.forwarded(), 1);
+
+            let mut table = switch.mac_table().await;
+            table.sort();
+            assert_eq!(
+                table,
+                vec![
+                    (10, macs[0], 0),
+                    (10, macs[1], 3),
+                    (20, macs[0], 3),
+                    (20, macs[1], 1)
+                ]
+            );
+        });
+    }
+}
@@ -1,14 +1,30 @@
 use async_process::Command;
+use futures::channel::mpsc;
 use futures::prelude::*;
 use netsim_embed_core::*;
-pub use netsim_embed_core::{DelayBuffer, Ipv4Range, Protocol};
-pub use netsim_embed_machine::{unshare_user, Machine, MachineId, Namespace};
+pub use netsim_embed_core::{DelayBuffer, Ipv4Range, Ipv4Route, Ipv6Range, Ipv6Route, Protocol};
+pub use netsim_embed_dhcp::DhcpLease;
+use netsim_embed_dhcp::{DhcpHandle, DhcpServer};
+use netsim_embed_dns::{DnsHandle, DnsServer};
+#[cfg(feature = "json")]
+pub use netsim_embed_machine::JsonChannel;
+pub use netsim_embed_machine::{
+    unshare_user, IfaceMode, Ipv4Rule, Ipv6Rule, Machine, MachineConfig, MachineId, Namespace,
+    Offload, Resolver, Sysctls, TcpEcn, MAIN_TABLE,
+};
 use netsim_embed_nat::*;
-pub use netsim_embed_router::Filter;
+pub use netsim_embed_nat::{
+    embed_ipv4_addr, extract_ipv4_addr, HairpinMode, InboundPolicy, NatMapping, Pooling,
+    PortAllocation,
+};
 use netsim_embed_router::*;
+pub use netsim_embed_router::{Filter, MacAddr, VlanMode, DEFAULT_VLAN, MAX_VLAN};
+use netsim_embed_stun::{StunServer, TurnServer};
 use std::fmt::Display;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
+use std::time::Duration;
 
 pub fn run<F>(f: F)
 where
@@ -33,10 +49,54 @@ impl NetworkId {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct NatId(usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FirewallId(usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DhcpId(usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DnsId(usize);
+
+/// Connection ids of machine interfaces, the first interface uses the machine index.
+fn iface_id(machine: MachineId, iface: usize) -> usize {
+    machine.0 + iface * 3 * u16::MAX as usize
+}
+
+/// Returns the machine interface of a connection id.
+fn port_iface(id: usize) -> Option<(MachineId, usize)> {
+    let stride = 3 * u16::MAX as usize;
+    let machine = id % stride;
+    if machine < u16::MAX as usize {
+        Some((MachineId(machine), id / stride))
+    } else {
+        None
+    }
+}
+
+/// Connection ids of in-process servers attached to networks.
+fn service_id(n: usize) -> usize {
+    n + 2 * u16::MAX as usize
+}
+
+/// Locally administered MAC address of an in-process server attached to a switched network,
+/// which doesn't collide with the addresses of machine interfaces.
+fn service_mac(n: usize) -> MacAddr {
+    [0x02, 0xff, 0, 0, (n >> 8) as u8, n as u8]
+}
+
 pub struct Netsim<C, E> {
     machines: Vec<Machine<C, E>>,
-    plugs: Vec<Connector>,
+    plugs: Vec<Vec<Connector>>,
     networks: Vec<Network>,
+    nats: Vec<Nat>,
+    firewalls: Vec<Firewall>,
+    dhcp_servers: Vec<Dhcp>,
+    dns_servers: Vec<Dns>,
+    services: usize,
 }
 
 impl<C, E> Default for Netsim<C, E> {
@@ -45,6 +105,11 @@ impl<C, E> Default for Netsim<C, E> {
             machines: Default::default(),
             plugs: Default::default(),
             networks: Default::default(),
+            nats: Default::default(),
+            firewalls: Default::default(),
+            dhcp_servers: Default::default(),
+            dns_servers: Default::default(),
+            services: 0,
         }
     }
 }
@@ -55,29 +120,13 @@ where
     E: FromStr + Display + Send + 'static,
     E::Err: std::fmt::Debug + Display + Send + Sync,
 {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub fn machine(&mut self, id: MachineId) -> &mut Machine<C, E> {
-        &mut self.machines[id.0]
-    }
-
-    pub fn machines(&self) -> &[Machine<C, E>] {
-        &self.machines
-    }
-
-    pub fn machines_mut(&mut self) -> &mut [Machine<C, E>] {
-        &mut self.machines
-    }
-
     #[cfg(feature = "ipc")]
     pub async fn spawn<M: MachineFn>(
         &mut self,
         _machine: M,
         arg: M::Arg,
         delay: Option<DelayBuffer>,
-    ) -> MachineId {
+    ) -> Result<MachineId> {
         use ipc_channel::ipc;
         let id = M::id();
         let (server, server_name) = ipc::IpcOneShotServer::<ipc::IpcSender<M::Arg>>::new().unwrap();
@@ -87,31 +136,162 @@ where
             &format!("{id}"),
             &server_name,
         ]);
-        let machine = self.spawn_machine(command, delay).await;
+        let machine = self.spawn_machine(command, delay).await?;
         let (_, ipc) = async_global_executor::spawn_blocking(|| server.accept())
             .await
             .unwrap();
         ipc.send(arg)
             .expect("Failed sending argument to child process");
-        machine
+        Ok(machine)
     }
 
     pub async fn spawn_machine(
         &mut self,
         command: Command,
         delay: Option<DelayBuffer>,
-    ) -> MachineId {
+    ) -> Result<MachineId> {
+        self.spawn_machine_with_config(command, delay, MachineConfig::default())
+            .await
+    }
+
+    /// Spawns a machine configured by `config`, eg. with TAP interfaces which are plugged into
+    /// switched networks.
+    pub async fn spawn_machine_with_config(
+        &mut self,
+        command: Command,
+        delay: Option<DelayBuffer>,
+        config: MachineConfig,
+    ) -> Result<MachineId> {
+        config.validate()?;
+        let (id, plug) = self.wire_machine(delay);
+        let machine = Machine::with_config(id, plug, command, config).await;
+        self.push_machine(machine)
+    }
+}
+
+impl<C, E> Netsim<C, E>
+where
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    pub async fn spawn_task<F, T>(
+        &mut self,
+        task: F,
+        delay: Option<DelayBuffer>,
+    ) -> Result<MachineId>
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<C>, mpsc::UnboundedSender<E>) -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.spawn_task_with_config(task, delay, MachineConfig::default())
+            .await
+    }
+
+    /// Spawns a machine which runs the future returned by `task` in its network namespace
+    /// instead of a process, see `Machine::with_task`.
+    pub async fn spawn_task_with_config<F, T>(
+        &mut self,
+        task: F,
+        delay: Option<DelayBuffer>,
+        config: MachineConfig,
+    ) -> Result<MachineId>
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<C>, mpsc::UnboundedSender<E>) -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        config.validate()?;
+        let (id, plug) = self.wire_machine(delay);
+        let machine = Machine::with_task(id, plug, config, task).await;
+        self.push_machine(machine)
+    }
+}
+
+#[cfg(feature = "json")]
+impl<C, E> Netsim<C, E>
+where
+    C: serde::Serialize + Send + 'static,
+    E: serde::de::DeserializeOwned + Send + 'static,
+{
+    pub async fn spawn_json_machine(
+        &mut self,
+        command: Command,
+        delay: Option<DelayBuffer>,
+    ) -> Result<MachineId> {
+        self.spawn_json_machine_with_config(command, delay, MachineConfig::default())
+            .await
+    }
+
+    /// Spawns a machine whose process exchanges commands and events as JSON lines with a
+    /// `JsonChannel`, see `Machine::with_json`.
+    pub async fn spawn_json_machine_with_config(
+        &mut self,
+        command: Command,
+        delay: Option<DelayBuffer>,
+        config: MachineConfig,
+    ) -> Result<MachineId> {
+        config.validate()?;
+        let (id, plug) = self.wire_machine(delay);
+        let machine = Machine::with_json(id, plug, command, config).await;
+        self.push_machine(machine)
+    }
+}
+
+impl<C, E> Netsim<C, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn machine(&mut self, id: MachineId) -> &mut Machine<C, E> {
+        &mut self.machines[id.0]
+    }
+
+    pub fn machines(&self) -> &[Machine<C, E>] {
+        &self.machines
+    }
+
+    pub fn machines_mut(&mut self) -> &mut [Machine<C, E>] {
+        &mut self.machines
+    }
+
+    /// Wires up the first interface of the next machine, which must be pushed to `machines`
+    /// before another machine is spawned.
+    fn wire_machine(&mut self, delay: Option<DelayBuffer>) -> (MachineId, Plug) {
         let (plug_a, plug_b) = wire();
         let plug_b = if let Some(delay) = delay {
             delay.spawn(plug_b)
         } else {
             plug_b
         };
-        let id = MachineId(self.machines.len());
-        let machine = Machine::new(id, plug_b, command).await;
-        self.machines.push(machine);
-        self.plugs.push(Connector::Unplugged(plug_a));
-        id
+        self.plugs.push(vec![Connector::Unplugged(plug_a)]);
+        (MachineId(self.machines.len()), plug_b)
+    }
+
+    /// Adds a machine wired with `wire_machine`, or unwires it if it failed to start.
+    fn push_machine(&mut self, machine: Result<Machine<C, E>>) -> Result<MachineId> {
+        match machine {
+            Ok(machine) => {
+                self.machines.push(machine);
+                Ok(MachineId(self.machines.len() - 1))
+            }
+            Err(err) => {
+                self.plugs.pop();
+                Err(err)
+            }
+        }
+    }
+
+    /// Adds another interface to a machine and returns its index. The interface can be plugged
+    /// into a network with `plug_iface`, independently of the machine's other interfaces.
+    pub async fn add_iface(&mut self, machine: MachineId, delay: Option<DelayBuffer>) -> usize {
+        let (plug_a, plug_b) = wire();
+        let plug_b = if let Some(delay) = delay {
+            delay.spawn(plug_b)
+        } else {
+            plug_b
+        };
+        let iface = self.machines[machine.0].add_iface(plug_b).await;
+        self.plugs[machine.0].push(Connector::Unplugged(plug_a));
+        iface
     }
 
     pub fn network(&self, id: NetworkId) -> &Network {
@@ -124,34 +304,420 @@ where
 
     pub fn spawn_network(&mut self, range: Ipv4Range) -> NetworkId {
         let id = NetworkId(self.networks.len());
-        self.networks.push(Network::new(id, range));
+        self.networks.push(Network::new(id, Some(range), None));
+        id
+    }
+
+    /// Spawns a network carrying both IPv4 and IPv6.
+    pub fn spawn_dual_stack_network(&mut self, range: Ipv4Range, range6: Ipv6Range) -> NetworkId {
+        let id = NetworkId(self.networks.len());
+        self.networks
+            .push(Network::new(id, Some(range), Some(range6)));
+        id
+    }
+
+    /// Spawns an IPv6-only network.
+    pub fn spawn_ipv6_network(&mut self, range: Ipv6Range) -> NetworkId {
+        let id = NetworkId(self.networks.len());
+        self.networks.push(Network::new(id, None, Some(range)));
+        id
+    }
+
+    /// Spawns a network which is an ethernet switch, machines with TAP interfaces plugged into it
+    /// share a broadcast domain. Addresses are assigned from `range` when machines are plugged
+    /// in.
+    ///
+    /// Switched networks are not connected to routed networks, routes, NATs and servers require
+    /// routed networks.
+    pub fn spawn_switch(&mut self, range: Ipv4Range) -> NetworkId {
+        let id = NetworkId(self.networks.len());
+        self.networks.push(Network::switched(id, Some(range), None));
+        id
+    }
+
+    /// Spawns a switched network carrying both IPv4 and IPv6.
+    pub fn spawn_dual_stack_switch(&mut self, range: Ipv4Range, range6: Ipv6Range) -> NetworkId {
+        let id = NetworkId(self.networks.len());
+        self.networks
+            .push(Network::switched(id, Some(range), Some(range6)));
+        id
+    }
+
+    pub fn nat(&self, id: NatId) -> &Nat {
+        &self.nats[id.0]
+    }
+
+    pub fn nats(&self) -> &[Nat] {
+        &self.nats
+    }
+
+    pub fn firewall(&self, id: FirewallId) -> &Firewall {
+        &self.firewalls[id.0]
+    }
+
+    pub fn firewalls(&self) -> &[Firewall] {
+        &self.firewalls
+    }
+
+    pub fn dhcp_server(&self, id: DhcpId) -> &Dhcp {
+        &self.dhcp_servers[id.0]
+    }
+
+    pub fn dhcp_servers(&self) -> &[Dhcp] {
+        &self.dhcp_servers
+    }
+
+    pub fn dns_server(&self, id: DnsId) -> &Dns {
+        &self.dns_servers[id.0]
+    }
+
+    pub fn dns_servers(&self) -> &[Dns] {
+        &self.dns_servers
+    }
+
+    /// Assigns new public addresses from the public network to a NAT and flushes its mappings,
+    /// like an ISP renumbering a customer.
+    pub async fn renumber_nat(&mut self, id: NatId) -> Vec<Ipv4Addr> {
+        let nat = &mut self.nats[id.0];
+        let public_net = &mut self.networks[nat.public_net.0];
+        let addrs = (0..nat.addrs.len())
+            .map(|_| public_net.unique_addr())
+            .collect::<Vec<_>>();
+        let routes = addrs
+            .iter()
+            .map(|addr| Ipv4Range::new(*addr, 32).into())
+            .collect();
+        if let Some(plug) = public_net
+            .router()
+            .remove_connection(nat.private_net.id())
+            .await
+        {
+            public_net
+                .router()
+                .add_connection(nat.private_net.id(), plug, routes);
+        }
+        nat.handle.renumber(addrs.clone());
+        nat.addrs = addrs.clone();
+        addrs
+    }
+
+    /// Attaches an in-process STUN server to a network and returns its address. If
+    /// `behaviour_discovery` is set, the server answers on a second address and port to support
+    /// RFC 5780 NAT behaviour discovery.
+    pub fn add_stun_server(
+        &mut self,
+        net: NetworkId,
+        addr: Option<Ipv4Addr>,
+        behaviour_discovery: bool,
+    ) -> SocketAddrV4 {
+        let (plug, server_plug) = wire();
+        let net = &mut self.networks[net.0];
+        let addr = SocketAddrV4::new(addr.unwrap_or_else(|| net.unique_addr()), 3478);
+        let mut server = StunServer::new(server_plug, addr);
+        let mut routes = vec![(*addr.ip()).into()];
+        if behaviour_discovery {
+            let alternate = SocketAddrV4::new(net.unique_addr(), 3479);
+            server
+                .set_alternate(Some(alternate))
+                .expect("unique addresses differ");
+            routes.push((*alternate.ip()).into());
+        }
+        async_global_executor::spawn(server).detach();
+        net.router()
+            .add_connection(service_id(self.services), plug, routes);
+        self.services += 1;
+        addr
+    }
+
+    /// Attaches an in-process TURN server to a network and returns its address. Relayed
+    /// transport addresses are allocated on the same IP address.
+    pub fn add_turn_server(&mut self, net: NetworkId, addr: Option<Ipv4Addr>) -> SocketAddrV4 {
+        let (plug, server_plug) = wire();
+        let net = &mut self.networks[net.0];
+        let addr = SocketAddrV4::new(addr.unwrap_or_else(|| net.unique_addr()), 3478);
+        async_global_executor::spawn(TurnServer::new(server_plug, addr)).detach();
+        net.router()
+            .add_connection(service_id(self.services), plug, vec![(*addr.ip()).into()]);
+        self.services += 1;
+        addr
+    }
+
+    /// Attaches an in-process authoritative DNS server to a routed network and returns its id.
+    /// Machines use it if it is one of the name servers of their `MachineConfig::resolver`.
+    ///
+    /// # Panics
+    ///
+    /// If the network is switched.
+    pub fn add_dns_server(&mut self, net: NetworkId, addr: Option<Ipv4Addr>) -> DnsId {
+        let (plug, server_plug) = wire();
+        let network = &mut self.networks[net.0];
+        let addr = SocketAddrV4::new(addr.unwrap_or_else(|| network.unique_addr()), 53);
+        let server = DnsServer::new(server_plug, addr);
+        let id = DnsId(self.dns_servers.len());
+        self.dns_servers.push(Dns {
+            id,
+            addr,
+            handle: server.handle(),
+        });
+        async_global_executor::spawn(server).detach();
+        network
+            .router()
+            .add_connection(service_id(self.services), plug, vec![(*addr.ip()).into()]);
+        self.services += 1;
         id
     }
 
+    /// Registers the addresses of all interfaces of a machine under `name` with a DNS server.
+    /// Addresses assigned later on are not registered.
+    pub fn register_machine(&self, dns: DnsId, machine: MachineId, name: &str) {
+        let machine = &self.machines[machine.0];
+        for iface in 0..machine.num_ifaces() {
+            for (addr, _) in machine.iface_addrs(iface) {
+                self.dns_servers[dns.0].add_record(name, addr);
+            }
+        }
+    }
+
+    /// Attaches an in-process DHCPv4 server to a switched network. It leases a pool of
+    /// `config.pool_size` addresses allocated from the network's range, so leased addresses don't
+    /// collide with the addresses of machines plugged in with `Addressing::Auto`. Machines
+    /// running a DHCP client are plugged in with `Addressing::None`.
+    ///
+    /// # Panics
+    ///
+    /// If the network is routed or has no IPv4 range.
+    pub fn add_dhcp_server(&mut self, net: NetworkId, config: DhcpConfig) -> DhcpId {
+        let (plug, server_plug) = wire();
+        let network = &mut self.networks[net.0];
+        let range = network.range.expect("DHCP requires an IPv4 network");
+        let addr = network.unique_addr();
+        let pool = (0..config.pool_size)
+            .map(|_| network.unique_addr())
+            .collect();
+        let mut server =
+            DhcpServer::new(server_plug, addr, service_mac(self.services), range, pool);
+        server.set_lease_time(config.lease_time);
+        server.set_router(config.router);
+        server.set_dns_servers(config.dns_servers);
+        let id = DhcpId(self.dhcp_servers.len());
+        self.dhcp_servers.push(Dhcp {
+            id,
+            net,
+            addr,
+            handle: server.handle(),
+        });
+        async_global_executor::spawn(server).detach();
+        network.switch().add_port(service_id(self.services), plug);
+        self.services += 1;
+        id
+    }
+
+    /// Plugs a machine into a network and assigns it an address of each family the network
+    /// carries.
     pub async fn plug(&mut self, machine: MachineId, net: NetworkId, addr: Option<Ipv4Addr>) {
-        if let Connector::Plugged(_) = self.plugs[machine.0] {
-            log::debug!("Unplugging {}", machine);
-            self.unplug(machine).await
+        let addr = addr.map(Addressing::Static).unwrap_or_default();
+        self.plug_dual_stack(machine, net, addr, Addressing::Auto)
+            .await
+    }
+
+    /// Plugs a machine into a network and only assigns it an IPv6 address.
+    pub async fn plug_ipv6(&mut self, machine: MachineId, net: NetworkId, addr: Option<Ipv6Addr>) {
+        let addr = addr.map(Addressing::Static).unwrap_or_default();
+        self.plug_dual_stack(machine, net, Addressing::None, addr)
+            .await
+    }
+
+    /// Plugs a machine into a network and assigns it the IPv4 and IPv6 addresses described by
+    /// `addr` and `addr6`. Addresses of a family the machine doesn't get are removed.
+    ///
+    /// # Panics
+    ///
+    /// If a static address is given for a family the network doesn't carry.
+    pub async fn plug_dual_stack(
+        &mut self,
+        machine: MachineId,
+        net: NetworkId,
+        addr: Addressing<Ipv4Addr>,
+        addr6: Addressing<Ipv6Addr>,
+    ) {
+        self.plug_iface(machine, 0, net, addr, addr6).await
+    }
+
+    /// Plugs an interface of a machine into a network, like `plug_dual_stack` does for the
+    /// first interface.
+    ///
+    /// # Panics
+    ///
+    /// If a static address is given for a family the network doesn't carry, or if a TUN
+    /// interface is plugged into a switched network or a TAP interface into a routed network.
+    pub async fn plug_iface(
+        &mut self,
+        machine: MachineId,
+        iface: usize,
+        net: NetworkId,
+        addr: Addressing<Ipv4Addr>,
+        addr6: Addressing<Ipv6Addr>,
+    ) {
+        let mode = self.machines[machine.0].iface_mode(iface);
+        if self.networks[net.0].is_switched() != (mode == IfaceMode::Tap) {
+            panic!(
+                "{} iface {} is a {:?} interface, TAP interfaces are plugged into switched networks",
+                machine, iface, mode
+            );
         }
-        let plug = std::mem::replace(&mut self.plugs[machine.0], Connector::Plugged(net));
+        if let Connector::Plugged(_) = self.plugs[machine.0][iface] {
+            log::debug!("Unplugging {} iface {}", machine, iface);
+            self.unplug_iface(machine, iface).await
+        }
+        let plug = std::mem::replace(&mut self.plugs[machine.0][iface], Connector::Plugged(net));
         if let Connector::Unplugged(plug) = plug {
             let net = &mut self.networks[net.0];
-            let addr = addr.unwrap_or_else(|| net.unique_addr());
-            let mask = net.range.netmask_prefix_length();
-            net.router
-                .add_connection(machine.0, plug, vec![addr.into()]);
-            log::debug!("Setting {}'s address to {}/{}", machine, addr, mask);
-            self.machines[machine.0].set_addr(addr, mask).await;
+            let addr = match (addr, net.range) {
+                (Addressing::None, _) | (Addressing::Auto, None) => None,
+                (Addressing::Auto, Some(range)) => {
+                    Some((net.unique_addr(), range.netmask_prefix_length()))
+                }
+                (Addressing::Static(addr), Some(range)) => {
+                    Some((addr, range.netmask_prefix_length()))
+                }
+                (Addressing::Static(_), None) => panic!("network has no IPv4 range"),
+            };
+            let addr6 = match (addr6, net.range6) {
+                (Addressing::None, _) | (Addressing::Auto, None) => None,
+                (Addressing::Auto, Some(range)) => {
+                    Some((net.unique_ipv6_addr(), range.netmask_prefix_length()))
+                }
+                (Addressing::Static(addr), Some(range)) => {
+                    Some((addr, range.netmask_prefix_length()))
+                }
+                (Addressing::Static(_), None) => panic!("network has no IPv6 range"),
+            };
+            match &net.fabric {
+                Fabric::Router(router) => router.add_dual_stack_connection(
+                    iface_id(machine, iface),
+                    plug,
+                    addr.iter().map(|(addr, _)| (*addr).into()).collect(),
+                    addr6.iter().map(|(addr, _)| (*addr).into()).collect(),
+                ),
+                Fabric::Switch(switch) => switch.add_port(iface_id(machine, iface), plug),
+            }
+            let machine = &mut self.machines[machine.0];
+            if let Some((addr, mask)) = addr {
+                log::debug!(
+                    "Setting {}'s address on iface {} to {}/{}",
+                    machine.id(),
+                    iface,
+                    addr,
+                    mask
+                );
+                machine.set_iface_addr(iface, addr, mask).await;
+            } else {
+                machine.clear_iface_addr(iface).await;
+            }
+            if let Some((addr, prefix_len)) = addr6 {
+                log::debug!(
+                    "Setting {}'s address on iface {} to {}/{}",
+                    machine.id(),
+                    iface,
+                    addr,
+                    prefix_len
+                );
+                machine.set_iface_ipv6_addr(iface, addr, prefix_len).await;
+            } else {
+                machine.clear_iface_ipv6_addr(iface).await;
+            }
+            // secondary addresses belong to the previous network
+            machine.clear_secondary_addrs(iface).await.ok();
+        }
+    }
+
+    /// Adds a secondary address to a plugged interface of a machine and routes it to the
+    /// machine. The prefix length is taken from the network's range.
+    ///
+    /// # Panics
+    ///
+    /// If the interface is not plugged into a network or the network doesn't carry the address
+    /// family.
+    pub async fn add_addr(
+        &mut self,
+        machine: MachineId,
+        iface: usize,
+        addr: IpAddr,
+    ) -> std::io::Result<()> {
+        let net = self.plugged_net(machine, iface);
+        let net = &self.networks[net.0];
+        let prefix_len = match addr {
+            IpAddr::V4(_) => net
+                .range
+                .expect("network has no IPv4 range")
+                .netmask_prefix_length(),
+            IpAddr::V6(_) => net
+                .range6
+                .expect("network has no IPv6 range")
+                .netmask_prefix_length(),
+        };
+        self.machines[machine.0]
+            .add_iface_addr(iface, addr, prefix_len)
+            .await?;
+        self.update_routes(machine, iface);
+        Ok(())
+    }
+
+    /// Removes an address from a plugged interface of a machine and stops routing it.
+    ///
+    /// # Panics
+    ///
+    /// If the interface is not plugged into a network.
+    pub async fn remove_addr(
+        &mut self,
+        machine: MachineId,
+        iface: usize,
+        addr: IpAddr,
+    ) -> std::io::Result<()> {
+        self.plugged_net(machine, iface);
+        self.machines[machine.0]
+            .remove_iface_addr(iface, addr)
+            .await?;
+        self.update_routes(machine, iface);
+        Ok(())
+    }
+
+    fn plugged_net(&self, machine: MachineId, iface: usize) -> NetworkId {
+        match self.plugs[machine.0][iface] {
+            Connector::Plugged(net) => net,
+            _ => panic!("{} iface {} is not plugged into a network", machine, iface),
+        }
+    }
+
+    /// Routes all addresses of a machine's interface to it.
+    fn update_routes(&self, machine: MachineId, iface: usize) {
+        let net = self.plugged_net(machine, iface);
+        let mut routes = vec![];
+        let mut routes6 = vec![];
+        for (addr, _) in self.machines[machine.0].iface_addrs(iface) {
+            match addr {
+                IpAddr::V4(addr) => routes.push(addr.into()),
+                IpAddr::V6(addr) => routes6.push(addr.into()),
+            }
+        }
+        if let Fabric::Router(router) = &self.networks[net.0].fabric {
+            router.set_routes(iface_id(machine, iface), routes, routes6);
         }
     }
 
     pub async fn unplug(&mut self, machine: MachineId) {
-        if let Connector::Plugged(net) = self.plugs[machine.0] {
-            self.plugs[machine.0] = if let Some(plug) = self.networks[net.0]
-                .router
-                .remove_connection(machine.0)
-                .await
-            {
+        self.unplug_iface(machine, 0).await
+    }
+
+    /// Unplugs an interface of a machine from its network, the interface keeps its addresses.
+    pub async fn unplug_iface(&mut self, machine: MachineId, iface: usize) {
+        if let Connector::Plugged(net) = self.plugs[machine.0][iface] {
+            let id = iface_id(machine, iface);
+            let plug = match &self.networks[net.0].fabric {
+                Fabric::Router(router) => router.remove_connection(id).await,
+                Fabric::Switch(switch) => switch.remove_port(id).await,
+            };
+            self.plugs[machine.0][iface] = if let Some(plug) = plug {
                 Connector::Unplugged(plug)
             } else {
                 Connector::Shutdown
@@ -161,24 +727,68 @@ where
 
     pub fn add_route(&mut self, net_a: NetworkId, net_b: NetworkId) {
         let (plug_a, plug_b) = wire();
-        let range_a = self.networks[net_a.0].range;
-        let range_b = self.networks[net_b.0].range;
-        self.networks[net_a.0]
-            .router
-            .add_connection(net_b.id(), plug_b, vec![range_b.into()]);
-        self.networks[net_b.0]
-            .router
-            .add_connection(net_a.id(), plug_a, vec![range_a.into()]);
+        let network_a = &self.networks[net_a.0];
+        let network_b = &self.networks[net_b.0];
+        network_a.router().add_dual_stack_connection(
+            net_b.id(),
+            plug_b,
+            network_b.range.into_iter().map(Into::into).collect(),
+            network_b.range6.into_iter().map(Into::into).collect(),
+        );
+        network_b.router().add_dual_stack_connection(
+            net_a.id(),
+            plug_a,
+            network_a.range.into_iter().map(Into::into).collect(),
+            network_a.range6.into_iter().map(Into::into).collect(),
+        );
     }
 
+    /// Enables a route or trunk between two networks.
     pub fn enable_route(&mut self, net_a: NetworkId, net_b: NetworkId) {
-        self.networks[net_a.0].router.enable_route(net_b.id());
-        self.networks[net_b.0].router.enable_route(net_a.id());
+        self.networks[net_a.0].set_link_enabled(net_b.id(), true);
+        self.networks[net_b.0].set_link_enabled(net_a.id(), true);
     }
 
+    /// Disables a route or trunk between two networks, like pulling the cable.
     pub fn disable_route(&mut self, net_a: NetworkId, net_b: NetworkId) {
-        self.networks[net_a.0].router.disable_route(net_b.id());
-        self.networks[net_b.0].router.disable_route(net_a.id());
+        self.networks[net_a.0].set_link_enabled(net_b.id(), false);
+        self.networks[net_b.0].set_link_enabled(net_a.id(), false);
+    }
+
+    /// Connects two switched networks with a trunk carrying the VLANs tagged, so the VLANs span
+    /// both switches.
+    ///
+    /// # Panics
+    ///
+    /// If either network is routed or a VLAN id is outside of `1..=MAX_VLAN`.
+    pub fn add_trunk(&mut self, net_a: NetworkId, net_b: NetworkId, vlans: Vec<u16>) {
+        let mode = VlanMode::Trunk {
+            native: None,
+            allowed: vlans,
+        };
+        assert!(mode.is_valid(), "invalid VLAN id in {:?}", mode);
+        let (plug_a, plug_b) = wire();
+        self.networks[net_a.0]
+            .switch()
+            .add_vlan_port(net_b.id(), plug_b, mode.clone());
+        self.networks[net_b.0]
+            .switch()
+            .add_vlan_port(net_a.id(), plug_a, mode);
+    }
+
+    /// Sets the VLANs of the switch port a machine's interface is plugged into. Interfaces are
+    /// plugged into access ports of `DEFAULT_VLAN`, a trunk lets the machine tag frames itself,
+    /// eg. with a VLAN interface.
+    ///
+    /// # Panics
+    ///
+    /// If the interface is not plugged into a switched network or a VLAN id is outside of
+    /// `1..=MAX_VLAN`.
+    pub fn set_vlan(&mut self, machine: MachineId, iface: usize, mode: VlanMode) {
+        let net = self.plugged_net(machine, iface);
+        self.networks[net.0]
+            .switch()
+            .set_vlan_mode(iface_id(machine, iface), mode);
     }
 
     pub fn add_nat_route(
@@ -186,48 +796,173 @@ where
         config: NatConfig,
         public_net: NetworkId,
         private_net: NetworkId,
-    ) {
+    ) -> NatId {
         let (public, nat_public) = wire();
         let (nat_private, private) = wire();
-        let nat_addr = self.networks[public_net.0].unique_addr();
-        let nat_range = self.networks[private_net.0].range;
+        let nat_addrs = (0..config.address_pool_size.max(1))
+            .map(|_| self.networks[public_net.0].unique_addr())
+            .collect::<Vec<_>>();
+        let nat_addr = nat_addrs[0];
+        let nat_range = self.networks[private_net.0]
+            .range
+            .expect("NAT requires an IPv4 private network");
         let mut nat = Ipv4Nat::new(nat_public, nat_private, nat_addr, nat_range);
+        let routes = nat_addrs
+            .iter()
+            .map(|addr| Ipv4Range::new(*addr, 32).into())
+            .collect();
+        nat.set_address_pool(nat_addrs.clone(), config.pooling);
         nat.set_hair_pinning(config.hair_pinning);
+        nat.set_hairpin_mode(config.hairpin_mode);
         nat.set_symmetric(config.symmetric);
         nat.set_blacklist_unrecognized_addrs(config.blacklist_unrecognized_addrs);
         nat.set_restrict_endpoints(config.restrict_endpoints);
+        nat.set_port_allocation(&config.port_allocation);
+        let mut private_routes = vec![Ipv4Range::global().into()];
+        if config.port_mapping {
+            let gateway = nat_range.gateway_addr();
+            nat.set_port_mapping(Some(gateway));
+            private_routes.push(gateway.into());
+        }
         for (protocol, port, local_addr) in config.forward_ports {
             nat.forward_port(port, local_addr, protocol);
         }
+        let id = NatId(self.nats.len());
+        self.nats.push(Nat {
+            id,
+            public_net,
+            private_net,
+            addrs: nat_addrs,
+            handle: nat.handle(),
+        });
         async_global_executor::spawn(nat).detach();
-        self.networks[public_net.0].router.add_connection(
+        self.networks[public_net.0]
+            .router()
+            .add_connection(private_net.id(), public, routes);
+        self.networks[private_net.0].router().add_connection(
+            public_net.id(),
+            private,
+            private_routes,
+        );
+        id
+    }
+
+    /// Connects an IPv6 network to an IPv4 network through a NAT64 which translates packets to
+    /// IPv4 addresses embedded in `prefix`, eg. `Ipv6Range::nat64_well_known_prefix()`. Returns
+    /// the public address of the NAT64. A DNS server with `Dns::set_dns64_prefix` set to the same
+    /// prefix gives the IPv6 machines addresses for IPv4-only hosts.
+    pub fn add_nat64_route(
+        &mut self,
+        prefix: Ipv6Range,
+        public_net: NetworkId,
+        private_net: NetworkId,
+    ) -> Ipv4Addr {
+        let (public, nat_public) = wire();
+        let (nat_private, private) = wire();
+        let nat_addr = self.networks[public_net.0].unique_addr();
+        let nat = Nat64::new(nat_public, nat_private, nat_addr, prefix);
+        async_global_executor::spawn(nat).detach();
+        self.networks[public_net.0].router().add_connection(
             private_net.id(),
             public,
-            vec![Ipv4Range::new(nat_addr, 32).into()],
+            vec![nat_addr.into()],
         );
-        self.networks[private_net.0].router.add_connection(
+        self.networks[private_net.0].router().add_ipv6_connection(
             public_net.id(),
             private,
-            vec![Ipv4Range::global().into()],
+            vec![prefix.into()],
         );
+        nat_addr
+    }
+
+    /// Connects the IPv6 prefix of `private_net` to `public_net` through a stateful firewall
+    /// which routes without translating addresses, like an IPv6 home router.
+    pub fn add_firewall_route(
+        &mut self,
+        config: FirewallConfig,
+        public_net: NetworkId,
+        private_net: NetworkId,
+    ) -> FirewallId {
+        let (public, fw_public) = wire();
+        let (fw_private, private) = wire();
+        let subnet = self.networks[private_net.0]
+            .range6
+            .expect("firewall requires an IPv6 private network");
+        let mut firewall = Ipv6Firewall::new(fw_public, fw_private, subnet);
+        firewall.set_inbound_policy(config.inbound_policy);
+        firewall.set_restrict_endpoints(config.restrict_endpoints);
+        for (protocol, local_addr) in config.pinholes {
+            firewall.open_pinhole(protocol, local_addr);
+        }
+        let id = FirewallId(self.firewalls.len());
+        self.firewalls.push(Firewall {
+            id,
+            handle: firewall.handle(),
+        });
+        async_global_executor::spawn(firewall).detach();
+        self.networks[public_net.0].router().add_ipv6_connection(
+            private_net.id(),
+            public,
+            vec![subnet.into()],
+        );
+        self.networks[private_net.0].router().add_ipv6_connection(
+            public_net.id(),
+            private,
+            vec![Ipv6Range::global().into()],
+        );
+        id
     }
 }
 
+/// How a machine is addressed in one address family when it is plugged into a network.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Addressing<A> {
+    /// No address of this family is assigned.
+    None,
+    /// A unique address is allocated from the network's range, if the network carries the family.
+    #[default]
+    Auto,
+    /// The given address is assigned.
+    Static(A),
+}
+
+/// Forwards packets between the connections of a network.
+#[derive(Debug)]
+enum Fabric {
+    Router(Ipv4Router),
+    Switch(EthernetSwitch),
+}
+
 #[derive(Debug)]
 pub struct Network {
     id: NetworkId,
-    range: Ipv4Range,
-    router: Ipv4Router,
+    range: Option<Ipv4Range>,
+    range6: Option<Ipv6Range>,
+    fabric: Fabric,
     device: u32,
 }
 
 impl Network {
-    fn new(id: NetworkId, range: Ipv4Range) -> Self {
-        let router = Ipv4Router::new(range.gateway_addr());
+    fn new(id: NetworkId, range: Option<Ipv4Range>, range6: Option<Ipv6Range>) -> Self {
+        let router = match range {
+            Some(range) => Ipv4Router::new(range.gateway_addr()),
+            None => Ipv4Router::ipv6_only(),
+        };
         Self {
             id,
             range,
-            router,
+            range6,
+            fabric: Fabric::Router(router),
+            device: 0,
+        }
+    }
+
+    fn switched(id: NetworkId, range: Option<Ipv4Range>, range6: Option<Ipv6Range>) -> Self {
+        Self {
+            id,
+            range,
+            range6,
+            fabric: Fabric::Switch(EthernetSwitch::new()),
             device: 0,
         }
     }
@@ -236,44 +971,468 @@ impl Network {
         self.id
     }
 
+    /// Returns the IPv4 range of the network.
+    ///
+    /// # Panics
+    ///
+    /// If the network is IPv6-only.
     pub fn range(&self) -> Ipv4Range {
+        self.range.expect("network has no IPv4 range")
+    }
+
+    /// Returns the IPv4 range of the network, `None` for IPv6-only networks.
+    pub fn ipv4_range(&self) -> Option<Ipv4Range> {
         self.range
     }
 
+    /// Returns the IPv6 range of the network, `None` for IPv4-only networks.
+    pub fn ipv6_range(&self) -> Option<Ipv6Range> {
+        self.range6
+    }
+
+    /// Returns whether the network is an ethernet switch.
+    pub fn is_switched(&self) -> bool {
+        matches!(self.fabric, Fabric::Switch(_))
+    }
+
+    fn router(&self) -> &Ipv4Router {
+        match &self.fabric {
+            Fabric::Router(router) => router,
+            Fabric::Switch(_) => panic!("{:?} is a switched network", self.id),
+        }
+    }
+
+    /// Sets a filter selecting the packets which are counted by a routed network.
+    ///
+    /// # Panics
+    ///
+    /// If the network is switched.
     pub fn set_count_filter(&self, filter: Option<Filter>) {
-        self.router.set_filter(filter);
+        self.router().set_filter(filter);
     }
 
     pub fn num_forwarded(&self) -> usize {
-        self.router.forwarded()
+        match &self.fabric {
+            Fabric::Router(router) => router.forwarded(),
+            Fabric::Switch(switch) => switch.forwarded(),
+        }
     }
 
     pub fn num_invalid(&self) -> usize {
-        self.router.invalid()
+        match &self.fabric {
+            Fabric::Router(router) => router.invalid(),
+            Fabric::Switch(switch) => switch.invalid(),
+        }
     }
 
     pub fn num_disabled(&self) -> usize {
-        self.router.disabled()
+        match &self.fabric {
+            Fabric::Router(router) => router.disabled(),
+            Fabric::Switch(switch) => switch.disabled(),
+        }
     }
 
     pub fn num_unroutable(&self) -> usize {
-        self.router.unroutable()
+        match &self.fabric {
+            Fabric::Router(router) => router.unroutable(),
+            Fabric::Switch(_) => 0,
+        }
+    }
+
+    /// Returns the number of frames a switched network flooded to all ports.
+    pub fn num_flooded(&self) -> usize {
+        match &self.fabric {
+            Fabric::Router(_) => 0,
+            Fabric::Switch(switch) => switch.flooded(),
+        }
+    }
+
+    /// Returns the number of frames a switched network dropped because their port doesn't carry
+    /// their VLAN.
+    pub fn num_filtered(&self) -> usize {
+        match &self.fabric {
+            Fabric::Router(_) => 0,
+            Fabric::Switch(switch) => switch.filtered(),
+        }
+    }
+
+    /// Returns the MAC addresses a switched network learned on machine interfaces with their
+    /// VLAN and the machine interface they were learned on. Addresses learned on trunks are
+    /// omitted.
+    pub async fn mac_table(&self) -> Vec<(u16, MacAddr, MachineId, usize)> {
+        match &self.fabric {
+            Fabric::Router(_) => vec![],
+            Fabric::Switch(switch) => switch
+                .mac_table()
+                .await
+                .into_iter()
+                .filter_map(|(vlan, mac, port)| {
+                    let (machine, iface) = port_iface(port)?;
+                    Some((vlan, mac, machine, iface))
+                })
+                .collect(),
+        }
+    }
+
+    fn switch(&self) -> &EthernetSwitch {
+        match &self.fabric {
+            Fabric::Router(_) => panic!("{:?} is a routed network", self.id),
+            Fabric::Switch(switch) => switch,
+        }
+    }
+
+    /// Enables/disables the connection to another network, a route or a trunk.
+    fn set_link_enabled(&self, id: usize, enabled: bool) {
+        match (&self.fabric, enabled) {
+            (Fabric::Router(router), true) => router.enable_route(id),
+            (Fabric::Router(router), false) => router.disable_route(id),
+            (Fabric::Switch(switch), true) => switch.enable_port(id),
+            (Fabric::Switch(switch), false) => switch.disable_port(id),
+        }
     }
 
     pub fn unique_addr(&mut self) -> Ipv4Addr {
-        let addr = self.range.address_for(self.device);
+        let range = self.range.expect("network has no IPv4 range");
+        let addr = range.address_for(self.device);
         self.device += 1;
         addr
     }
+
+    pub fn unique_ipv6_addr(&mut self) -> Ipv6Addr {
+        let range = self.range6.expect("network has no IPv6 range");
+        let addr = range.address_for(self.device);
+        self.device += 1;
+        addr
+    }
+}
+
+#[derive(Debug)]
+pub struct Nat {
+    id: NatId,
+    public_net: NetworkId,
+    private_net: NetworkId,
+    addrs: Vec<Ipv4Addr>,
+    handle: NatHandle,
+}
+
+impl Nat {
+    pub fn id(&self) -> NatId {
+        self.id
+    }
+
+    /// Returns the public addresses of the NAT.
+    pub fn public_addrs(&self) -> &[Ipv4Addr] {
+        &self.addrs
+    }
+
+    /// Returns the current mappings for `protocol`, or `None` if the NAT has stopped.
+    pub async fn mappings(&self, protocol: Protocol) -> Option<Vec<NatMapping>> {
+        self.handle.mappings(protocol).await
+    }
+
+    /// Returns the blacklisted remote addresses, or `None` if the NAT has stopped.
+    pub async fn blacklisted_addrs(&self) -> Option<Vec<SocketAddrV4>> {
+        self.handle.blacklisted_addrs().await
+    }
+
+    pub fn forward_port(&self, port: u16, local_addr: SocketAddrV4, protocol: Protocol) {
+        self.handle.forward_port(port, local_addr, protocol);
+    }
+
+    pub fn remove_forward(&self, port: u16, protocol: Protocol) {
+        self.handle.remove_forward(port, protocol);
+    }
+
+    pub fn set_hair_pinning(&self, hair_pinning: bool) {
+        self.handle.set_hair_pinning(hair_pinning);
+    }
+
+    pub fn set_hairpin_mode(&self, hairpin_mode: HairpinMode) {
+        self.handle.set_hairpin_mode(hairpin_mode);
+    }
+
+    pub fn set_symmetric(&self, symmetric: bool) {
+        self.handle.set_symmetric(symmetric);
+    }
+
+    pub fn set_restrict_endpoints(&self, restrict_endpoints: bool) {
+        self.handle.set_restrict_endpoints(restrict_endpoints);
+    }
+
+    pub fn set_blacklist_unrecognized_addrs(&self, blacklist_unrecognized_addrs: bool) {
+        self.handle
+            .set_blacklist_unrecognized_addrs(blacklist_unrecognized_addrs);
+    }
+
+    /// Simulates a reboot of the NAT, which forgets all mappings except forwarded ports.
+    pub fn reboot(&self) {
+        self.handle.reboot();
+    }
+
+    pub fn num_outbound(&self) -> usize {
+        self.handle.num_outbound()
+    }
+
+    pub fn num_inbound(&self) -> usize {
+        self.handle.num_inbound()
+    }
+
+    pub fn num_hairpinned(&self) -> usize {
+        self.handle.num_hairpinned()
+    }
+
+    pub fn num_hairpin_dropped(&self) -> usize {
+        self.handle.num_hairpin_dropped()
+    }
+
+    pub fn num_invalid(&self) -> usize {
+        self.handle.num_invalid()
+    }
+
+    pub fn num_ttl_expired(&self) -> usize {
+        self.handle.num_ttl_expired()
+    }
+
+    pub fn num_misdirected(&self) -> usize {
+        self.handle.num_misdirected()
+    }
+
+    pub fn num_unmapped(&self) -> usize {
+        self.handle.num_unmapped()
+    }
+
+    pub fn num_blacklisted(&self) -> usize {
+        self.handle.num_blacklisted()
+    }
+
+    pub fn num_exhausted(&self) -> usize {
+        self.handle.num_exhausted()
+    }
+}
+
+#[derive(Debug)]
+pub struct Firewall {
+    id: FirewallId,
+    handle: FirewallHandle,
+}
+
+impl Firewall {
+    pub fn id(&self) -> FirewallId {
+        self.id
+    }
+
+    pub fn set_inbound_policy(&self, policy: InboundPolicy) {
+        self.handle.set_inbound_policy(policy);
+    }
+
+    pub fn set_restrict_endpoints(&self, restrict_endpoints: bool) {
+        self.handle.set_restrict_endpoints(restrict_endpoints);
+    }
+
+    /// Allow unsolicited inbound packets to `local_addr`.
+    pub fn open_pinhole(&self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.handle.open_pinhole(protocol, local_addr);
+    }
+
+    pub fn close_pinhole(&self, protocol: Protocol, local_addr: SocketAddrV6) {
+        self.handle.close_pinhole(protocol, local_addr);
+    }
+
+    /// Simulates a reboot of the router, which forgets all filter state except pinholes.
+    pub fn reboot(&self) {
+        self.handle.flush();
+    }
+
+    pub fn num_outbound(&self) -> usize {
+        self.handle.outbound()
+    }
+
+    pub fn num_inbound(&self) -> usize {
+        self.handle.inbound()
+    }
+
+    pub fn num_blocked(&self) -> usize {
+        self.handle.blocked()
+    }
+
+    pub fn num_invalid(&self) -> usize {
+        self.handle.invalid()
+    }
+
+    pub fn num_hop_limit_expired(&self) -> usize {
+        self.handle.hop_limit_expired()
+    }
+
+    pub fn num_misdirected(&self) -> usize {
+        self.handle.misdirected()
+    }
+}
+
+#[derive(Debug)]
+pub struct Dhcp {
+    id: DhcpId,
+    net: NetworkId,
+    addr: Ipv4Addr,
+    handle: DhcpHandle,
+}
+
+impl Dhcp {
+    pub fn id(&self) -> DhcpId {
+        self.id
+    }
+
+    /// Returns the network the server is attached to.
+    pub fn network(&self) -> NetworkId {
+        self.net
+    }
+
+    /// Returns the address of the server.
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    /// Returns the active leases.
+    pub async fn leases(&self) -> Vec<DhcpLease> {
+        self.handle.leases().await
+    }
+
+    /// Forgets the lease of the client with hardware address `mac`, eg. a machine interface's
+    /// `iface_mac_addr`. Its next renewal is refused and it is offered a different address.
+    pub fn revoke(&self, mac: MacAddr) {
+        self.handle.revoke(mac);
+    }
+
+    /// Sets the lease time of new leases and renewals.
+    pub fn set_lease_time(&self, lease_time: Duration) {
+        self.handle.set_lease_time(lease_time);
+    }
+
+    pub fn num_offered(&self) -> usize {
+        self.handle.offered()
+    }
+
+    pub fn num_acked(&self) -> usize {
+        self.handle.acked()
+    }
+
+    pub fn num_nacked(&self) -> usize {
+        self.handle.nacked()
+    }
+
+    pub fn num_released(&self) -> usize {
+        self.handle.released()
+    }
+
+    pub fn num_invalid(&self) -> usize {
+        self.handle.invalid()
+    }
+}
+
+#[derive(Debug)]
+pub struct Dns {
+    id: DnsId,
+    addr: SocketAddrV4,
+    handle: DnsHandle,
+}
+
+impl Dns {
+    pub fn id(&self) -> DnsId {
+        self.id
+    }
+
+    /// Returns the address of the server.
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// Adds an `A` or `AAAA` record, names are case insensitive.
+    pub fn add_record(&self, name: &str, addr: IpAddr) {
+        self.handle.add_record(name, addr);
+    }
+
+    /// Removes all records of a name, queries for it are answered with `NXDOMAIN`.
+    pub fn remove_records(&self, name: &str) {
+        self.handle.remove_records(name);
+    }
+
+    /// Synthesizes `AAAA` records in the prefix of a NAT64 for names with only `A` records, eg.
+    /// the prefix passed to `Netsim::add_nat64_route`. `None` disables DNS64.
+    ///
+    /// # Panics
+    ///
+    /// If the prefix length is not one of 32, 40, 48, 56, 64 or 96.
+    pub fn set_dns64_prefix(&self, prefix: Option<Ipv6Range>) {
+        self.handle.set_dns64_prefix(prefix);
+    }
+
+    /// Returns all records sorted by name.
+    pub async fn records(&self) -> Vec<(String, IpAddr)> {
+        self.handle.records().await
+    }
+
+    pub fn num_queries(&self) -> usize {
+        self.handle.queries()
+    }
+
+    pub fn num_nxdomain(&self) -> usize {
+        self.handle.nxdomain()
+    }
+
+    pub fn num_synthesized(&self) -> usize {
+        self.handle.synthesized()
+    }
+
+    pub fn num_invalid(&self) -> usize {
+        self.handle.invalid()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DhcpConfig {
+    /// Number of addresses the server leases.
+    pub pool_size: usize,
+    pub lease_time: Duration,
+    /// Default gateway announced to clients.
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl Default for DhcpConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: 32,
+            lease_time: Duration::from_secs(3600),
+            router: None,
+            dns_servers: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FirewallConfig {
+    pub inbound_policy: InboundPolicy,
+    /// Only let replies through from the exact remote port a local endpoint sent to.
+    pub restrict_endpoints: bool,
+    pub pinholes: Vec<(Protocol, SocketAddrV6)>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct NatConfig {
     pub hair_pinning: bool,
+    /// How hair-pinned packets are translated if `hair_pinning` is enabled. `HairpinMode::Drop`
+    /// drops them even if it isn't.
+    pub hairpin_mode: HairpinMode,
     pub symmetric: bool,
     pub blacklist_unrecognized_addrs: bool,
     pub restrict_endpoints: bool,
     pub forward_ports: Vec<(Protocol, u16, SocketAddrV4)>,
+    pub port_allocation: PortAllocation,
+    /// Number of public addresses allocated for the NAT, more than one makes it a carrier-grade
+    /// NAT.
+    pub address_pool_size: usize,
+    pub pooling: Pooling,
+    /// Answer NAT-PMP and PCP requests on the gateway address of the private network.
+    pub port_mapping: bool,
 }
 
 #[cfg(feature = "ipc")]
@@ -0,0 +1,6 @@
+pub mod message;
+mod server;
+mod turn;
+
+pub use server::{AlternateError, StunServer};
+pub use turn::TurnServer;
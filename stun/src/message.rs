@@ -0,0 +1,221 @@
+//! Encoding and decoding of STUN messages (RFC 5389).
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_RESPONSE: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+pub const MAPPED_ADDRESS: u16 = 0x0001;
+pub const CHANGE_REQUEST: u16 = 0x0003;
+pub const ERROR_CODE: u16 = 0x0009;
+pub const UNKNOWN_ATTRIBUTES: u16 = 0x000a;
+pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const SOFTWARE: u16 = 0x8022;
+pub const RESPONSE_ORIGIN: u16 = 0x802b;
+pub const OTHER_ADDRESS: u16 = 0x802c;
+
+/// `CHANGE-REQUEST` flag asking for a response from the alternate address.
+pub const CHANGE_IP: u32 = 0x04;
+/// `CHANGE-REQUEST` flag asking for a response from the alternate port.
+pub const CHANGE_PORT: u32 = 0x02;
+
+const FAMILY_IPV4: u8 = 0x01;
+
+/// A STUN message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    ty: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    /// Creates a message without attributes.
+    pub fn new(ty: u16, transaction_id: [u8; 12]) -> Self {
+        Self {
+            ty,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Parses a message, returns `None` if `bytes` is not a STUN message.
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 20 || bytes[0] & 0xc0 != 0 {
+            return None;
+        }
+        let ty = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        let cookie = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if cookie != MAGIC_COOKIE || len % 4 != 0 || bytes.len() < 20 + len {
+            return None;
+        }
+        let mut transaction_id = [0; 12];
+        transaction_id.copy_from_slice(&bytes[8..20]);
+        let mut attributes = Vec::new();
+        let mut rest = &bytes[20..20 + len];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return None;
+            }
+            let attr_ty = u16::from_be_bytes([rest[0], rest[1]]);
+            let attr_len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let padded_len = (attr_len + 3) & !3;
+            if rest.len() < 4 + padded_len {
+                return None;
+            }
+            attributes.push((attr_ty, rest[4..4 + attr_len].to_vec()));
+            rest = &rest[4 + padded_len..];
+        }
+        Some(Self {
+            ty,
+            transaction_id,
+            attributes,
+        })
+    }
+
+    /// Returns the message type, ie. the combination of class and method.
+    pub fn ty(&self) -> u16 {
+        self.ty
+    }
+
+    pub fn transaction_id(&self) -> [u8; 12] {
+        self.transaction_id
+    }
+
+    /// Returns the value of the first attribute of type `ty`.
+    pub fn attribute(&self, ty: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(attr_ty, _)| *attr_ty == ty)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Returns the value of a 32-bit integer attribute.
+    pub fn u32_attribute(&self, ty: u16) -> Option<u32> {
+        let value = self.attribute(ty)?;
+        if value.len() < 4 {
+            return None;
+        }
+        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    /// Returns the value of an address attribute like `MAPPED-ADDRESS`.
+    pub fn address(&self, ty: u16) -> Option<SocketAddrV4> {
+        let value = self.attribute(ty)?;
+        if value.len() < 8 || value[1] != FAMILY_IPV4 {
+            return None;
+        }
+        let port = u16::from_be_bytes([value[2], value[3]]);
+        Some(SocketAddrV4::new(
+            Ipv4Addr::new(value[4], value[5], value[6], value[7]),
+            port,
+        ))
+    }
+
+    /// Returns the value of an XOR encoded address attribute.
+    pub fn xor_address(&self, ty: u16) -> Option<SocketAddrV4> {
+        decode_xor_address(self.attribute(ty)?)
+    }
+
+    /// Returns the values of all XOR encoded address attributes of type `ty`.
+    pub fn xor_addresses(&self, ty: u16) -> Vec<SocketAddrV4> {
+        self.attributes
+            .iter()
+            .filter(|(attr_ty, _)| *attr_ty == ty)
+            .filter_map(|(_, value)| decode_xor_address(value))
+            .collect()
+    }
+
+    pub fn add_attribute(&mut self, ty: u16, value: Vec<u8>) {
+        self.attributes.push((ty, value));
+    }
+
+    pub fn add_u32_attribute(&mut self, ty: u16, value: u32) {
+        self.add_attribute(ty, value.to_be_bytes().to_vec());
+    }
+
+    /// Adds an address attribute like `MAPPED-ADDRESS`.
+    pub fn add_address(&mut self, ty: u16, addr: SocketAddrV4) {
+        let mut value = vec![0, FAMILY_IPV4];
+        value.extend_from_slice(&addr.port().to_be_bytes());
+        value.extend_from_slice(&addr.ip().octets());
+        self.add_attribute(ty, value);
+    }
+
+    /// Adds an XOR encoded address attribute like `XOR-MAPPED-ADDRESS`.
+    pub fn add_xor_address(&mut self, ty: u16, addr: SocketAddrV4) {
+        let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip = u32::from(*addr.ip()) ^ MAGIC_COOKIE;
+        let mut value = vec![0, FAMILY_IPV4];
+        value.extend_from_slice(&port.to_be_bytes());
+        value.extend_from_slice(&ip.to_be_bytes());
+        self.add_attribute(ty, value);
+    }
+
+    /// Adds an `ERROR-CODE` attribute.
+    pub fn add_error(&mut self, code: u16, reason: &str) {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.add_attribute(ERROR_CODE, value);
+    }
+
+    /// Returns the code of the `ERROR-CODE` attribute.
+    pub fn error_code(&self) -> Option<u16> {
+        let value = self.attribute(ERROR_CODE)?;
+        if value.len() < 4 {
+            return None;
+        }
+        Some(u16::from(value[2] & 0x07) * 100 + u16::from(value[3]))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(128);
+        bytes.extend_from_slice(&self.ty.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        bytes.extend_from_slice(&self.transaction_id);
+        for (ty, value) in &self.attributes {
+            bytes.extend_from_slice(&ty.to_be_bytes());
+            bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(value);
+            bytes.resize((bytes.len() + 3) & !3, 0);
+        }
+        let len = (bytes.len() - 20) as u16;
+        bytes[2..4].copy_from_slice(&len.to_be_bytes());
+        bytes
+    }
+}
+
+fn decode_xor_address(value: &[u8]) -> Option<SocketAddrV4> {
+    if value.len() < 8 || value[1] != FAMILY_IPV4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = u32::from_be_bytes([value[4], value[5], value[6], value[7]]) ^ MAGIC_COOKIE;
+    Some(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xor_address_roundtrip() {
+        let addr = "1.2.3.4:5678".parse().unwrap();
+        let mut msg = Message::new(BINDING_RESPONSE, [7; 12]);
+        msg.add_xor_address(XOR_MAPPED_ADDRESS, addr);
+        msg.add_address(MAPPED_ADDRESS, addr);
+        msg.add_attribute(SOFTWARE, b"abc".to_vec());
+        msg.add_error(420, "Unknown Attribute");
+        let msg = Message::parse(&msg.encode()).unwrap();
+        assert_eq!(msg.xor_address(XOR_MAPPED_ADDRESS), Some(addr));
+        assert_eq!(msg.address(MAPPED_ADDRESS), Some(addr));
+        assert_eq!(msg.error_code(), Some(420));
+        assert_eq!(msg.attribute(SOFTWARE), Some(&b"abc"[..]));
+    }
+}
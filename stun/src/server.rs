@@ -0,0 +1,315 @@
+use crate::message::*;
+use futures::future::Future;
+use netsim_embed_core::{Packet, Plug, Protocol};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+/// Error returned by `StunServer::set_alternate`.
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum AlternateError {
+    /// The alternate address must be on a different IP address.
+    #[error("alternate address has the primary ip {0}")]
+    SameIp(Ipv4Addr),
+    /// The alternate address must use a different port.
+    #[error("alternate address has the primary port {0}")]
+    SamePort(u16),
+}
+
+/// An in-process STUN server answering binding requests (RFC 5389). With an alternate address it
+/// also supports NAT behaviour discovery (RFC 5780).
+#[derive(Debug)]
+pub struct StunServer {
+    plug: Plug,
+    addr: SocketAddrV4,
+    alternate: Option<SocketAddrV4>,
+}
+
+impl StunServer {
+    pub fn new(plug: Plug, addr: SocketAddrV4) -> Self {
+        Self {
+            plug,
+            addr,
+            alternate: None,
+        }
+    }
+
+    /// Answer on a second IP address and port, which are reported in `OTHER-ADDRESS` and used
+    /// to honour `CHANGE-REQUEST`. Both must differ from the primary address.
+    pub fn set_alternate(&mut self, alternate: Option<SocketAddrV4>) -> Result<(), AlternateError> {
+        if let Some(alternate) = alternate {
+            if alternate.ip() == self.addr.ip() {
+                return Err(AlternateError::SameIp(*alternate.ip()));
+            }
+            if alternate.port() == self.addr.port() {
+                return Err(AlternateError::SamePort(alternate.port()));
+            }
+        }
+        self.alternate = alternate;
+        Ok(())
+    }
+
+    fn other_ip(&self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        let alternate = self.alternate?;
+        Some(if ip == *self.addr.ip() {
+            *alternate.ip()
+        } else {
+            *self.addr.ip()
+        })
+    }
+
+    fn other_port(&self, port: u16) -> Option<u16> {
+        let alternate = self.alternate?;
+        Some(if port == self.addr.port() {
+            alternate.port()
+        } else {
+            self.addr.port()
+        })
+    }
+
+    fn serves(&self, addr: SocketAddrV4) -> bool {
+        let ips = [Some(*self.addr.ip()), self.alternate.map(|addr| *addr.ip())];
+        let ports = [
+            Some(self.addr.port()),
+            self.alternate.map(|addr| addr.port()),
+        ];
+        ips.contains(&Some(*addr.ip())) && ports.contains(&Some(addr.port()))
+    }
+
+    /// Returns the address to respond from and the response.
+    fn handle(
+        &self,
+        source: SocketAddrV4,
+        dest: SocketAddrV4,
+        request: &Message,
+    ) -> (SocketAddrV4, Message) {
+        let change = request.u32_attribute(CHANGE_REQUEST).unwrap_or_default();
+        if change != 0 && self.alternate.is_none() {
+            let mut response = Message::new(BINDING_ERROR, request.transaction_id());
+            response.add_error(420, "Unknown Attribute");
+            response.add_attribute(UNKNOWN_ATTRIBUTES, CHANGE_REQUEST.to_be_bytes().to_vec());
+            return (dest, response);
+        }
+        let mut origin = dest;
+        if change & CHANGE_IP != 0 {
+            origin.set_ip(self.other_ip(*dest.ip()).unwrap());
+        }
+        if change & CHANGE_PORT != 0 {
+            origin.set_port(self.other_port(dest.port()).unwrap());
+        }
+        let mut response = Message::new(BINDING_RESPONSE, request.transaction_id());
+        response.add_xor_address(XOR_MAPPED_ADDRESS, source);
+        response.add_address(MAPPED_ADDRESS, source);
+        response.add_address(RESPONSE_ORIGIN, origin);
+        if let (Some(ip), Some(port)) = (self.other_ip(*dest.ip()), self.other_port(dest.port())) {
+            response.add_address(OTHER_ADDRESS, SocketAddrV4::new(ip, port));
+        }
+        response.add_attribute(SOFTWARE, b"netsim-embed".to_vec());
+        (origin, response)
+    }
+}
+
+impl Future for StunServer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            let mut bytes = match self.plug.poll_incoming(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Ready(Some(bytes)) => bytes,
+            };
+            let packet = match Packet::new(&mut bytes) {
+                Some(packet) if packet.protocol() == Protocol::Udp => packet,
+                _ => {
+                    log::debug!("stun {}: dropping non-udp packet", self.addr);
+                    continue;
+                }
+            };
+            let source = packet.get_source();
+            let dest = packet.get_destination();
+            if !self.serves(dest) {
+                log::debug!("stun {}: dropping packet to {}", self.addr, dest);
+                continue;
+            }
+            let request = match Message::parse(packet.payload()) {
+                Some(request) if request.ty() == BINDING_REQUEST => request,
+                _ => {
+                    log::debug!(
+                        "stun {}: dropping invalid request from {}",
+                        self.addr,
+                        source
+                    );
+                    continue;
+                }
+            };
+            let (origin, response) = self.handle(source, dest, &request);
+            log::trace!("stun {}: answering {} from {}", self.addr, source, origin);
+            let response = Packet::build_udp(origin, source, &response.encode());
+            self.plug.unbounded_send(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::{wire, Ipv4Range};
+    use netsim_embed_nat::Ipv4Nat;
+
+    fn primary() -> SocketAddrV4 {
+        "1.0.0.1:3478".parse().unwrap()
+    }
+
+    fn alternate() -> SocketAddrV4 {
+        "1.0.0.2:3479".parse().unwrap()
+    }
+
+    fn request(change: u32) -> Message {
+        let mut request = Message::new(BINDING_REQUEST, [change as u8; 12]);
+        if change != 0 {
+            request.add_u32_attribute(CHANGE_REQUEST, change);
+        }
+        request
+    }
+
+    #[test]
+    fn rejects_alternate_sharing_the_primary_ip_or_port() {
+        let mut server = StunServer::new(wire().0, primary());
+        let same_ip = SocketAddrV4::new(*primary().ip(), 3479);
+        assert_eq!(
+            server.set_alternate(Some(same_ip)),
+            Err(AlternateError::SameIp(*primary().ip()))
+        );
+        let same_port = SocketAddrV4::new(*alternate().ip(), 3478);
+        assert_eq!(
+            server.set_alternate(Some(same_port)),
+            Err(AlternateError::SamePort(3478))
+        );
+        assert_eq!(server.alternate, None);
+        assert_eq!(server.set_alternate(Some(alternate())), Ok(()));
+    }
+
+    #[test]
+    fn answers_change_requests() {
+        let mut server = StunServer::new(wire().0, primary());
+        let client = "8.8.8.8:5000".parse().unwrap();
+
+        // without an alternate address change requests are unknown
+        let (_, response) = server.handle(client, primary(), &request(CHANGE_PORT));
+        assert_eq!(response.ty(), BINDING_ERROR);
+        assert_eq!(response.error_code(), Some(420));
+        let (origin, response) = server.handle(client, primary(), &request(0));
+        assert_eq!(origin, primary());
+        assert_eq!(response.address(OTHER_ADDRESS), None);
+
+        server.set_alternate(Some(alternate())).unwrap();
+        let cases = [
+            (primary(), 0, primary()),
+            (primary(), CHANGE_PORT, "1.0.0.1:3479".parse().unwrap()),
+            (primary(), CHANGE_IP, "1.0.0.2:3478".parse().unwrap()),
+            (primary(), CHANGE_IP | CHANGE_PORT, alternate()),
+            (alternate(), 0, alternate()),
+            (alternate(), CHANGE_IP | CHANGE_PORT, primary()),
+        ];
+        for (dest, change, expected_origin) in cases {
+            let request = request(change);
+            let (origin, response) = server.handle(client, dest, &request);
+            assert_eq!(origin, expected_origin);
+            assert_eq!(response.ty(), BINDING_RESPONSE);
+            assert_eq!(response.transaction_id(), request.transaction_id());
+            assert_eq!(response.xor_address(XOR_MAPPED_ADDRESS), Some(client));
+            assert_eq!(response.address(RESPONSE_ORIGIN), Some(origin));
+            let other = if dest == primary() {
+                alternate()
+            } else {
+                primary()
+            };
+            assert_eq!(response.address(OTHER_ADDRESS), Some(other));
+        }
+    }
+
+    /// Result of the RFC 5780 mapping and filtering behaviour tests.
+    #[derive(Debug, Eq, PartialEq)]
+    struct Behaviour {
+        endpoint_independent_mapping: bool,
+        endpoint_independent_filtering: bool,
+    }
+
+    fn send(plug: &mut Plug, local: SocketAddrV4, dest: SocketAddrV4, request: &Message) {
+        plug.unbounded_send(Packet::build_udp(local, dest, &request.encode()));
+    }
+
+    async fn recv(plug: &mut Plug) -> Message {
+        let mut bytes = plug.incoming().await.unwrap();
+        let packet = Packet::new(&mut bytes).unwrap();
+        Message::parse(packet.payload()).unwrap()
+    }
+
+    /// Runs NAT behaviour discovery behind a NAT whose public side is attached to a STUN server
+    /// with an alternate address.
+    async fn discover(configure: impl FnOnce(&mut Ipv4Nat)) -> Behaviour {
+        let (nat_public, server_plug) = wire();
+        let (mut private, nat_private) = wire();
+        let subnet = Ipv4Range::local_subnet_192(0);
+        let mut nat = Ipv4Nat::new(nat_public, nat_private, "2.0.0.1".parse().unwrap(), subnet);
+        configure(&mut nat);
+        async_std::task::spawn(nat);
+        let mut server = StunServer::new(server_plug, primary());
+        server.set_alternate(Some(alternate())).unwrap();
+        async_std::task::spawn(server);
+
+        let local = SocketAddrV4::new(subnet.address_for(0), 5000);
+
+        // test I and II: is the mapping the same for a different server address?
+        send(&mut private, local, primary(), &request(0));
+        let response = recv(&mut private).await;
+        let mapped = response.xor_address(XOR_MAPPED_ADDRESS).unwrap();
+        let other = response.address(OTHER_ADDRESS).unwrap();
+        let dest = SocketAddrV4::new(*other.ip(), primary().port());
+        send(&mut private, local, dest, &request(0));
+        let response = recv(&mut private).await;
+        let endpoint_independent_mapping = response.xor_address(XOR_MAPPED_ADDRESS) == Some(mapped);
+
+        // filtering test II: does the response from the other address pass? If it is dropped,
+        // the response to the following request arrives first.
+        let change = request(CHANGE_IP | CHANGE_PORT);
+        send(&mut private, local, primary(), &change);
+        send(&mut private, local, primary(), &request(0));
+        let response = recv(&mut private).await;
+        let endpoint_independent_filtering = response.transaction_id() == change.transaction_id();
+        Behaviour {
+            endpoint_independent_mapping,
+            endpoint_independent_filtering,
+        }
+    }
+
+    #[async_std::test]
+    async fn detects_full_cone_nat() {
+        let behaviour = discover(|_| {}).await;
+        assert_eq!(
+            behaviour,
+            Behaviour {
+                endpoint_independent_mapping: true,
+                endpoint_independent_filtering: true,
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn detects_symmetric_nat() {
+        let behaviour = discover(|nat| {
+            nat.set_symmetric(true);
+            nat.set_restrict_endpoints(true);
+        })
+        .await;
+        assert_eq!(
+            behaviour,
+            Behaviour {
+                endpoint_independent_mapping: false,
+                endpoint_independent_filtering: false,
+            }
+        );
+    }
+}
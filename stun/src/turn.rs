@@ -0,0 +1,486 @@
+use crate::message::*;
+use futures::future::Future;
+use netsim_embed_core::{Packet, Plug, Protocol};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+const ALLOCATE: u16 = 0x0003;
+const REFRESH: u16 = 0x0004;
+const SEND_INDICATION: u16 = 0x0016;
+const DATA_INDICATION: u16 = 0x0017;
+const CREATE_PERMISSION: u16 = 0x0008;
+const CHANNEL_BIND: u16 = 0x0009;
+
+const SUCCESS: u16 = 0x0100;
+const ERROR: u16 = 0x0110;
+
+const CHANNEL_NUMBER: u16 = 0x000c;
+const LIFETIME: u16 = 0x000d;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA: u16 = 0x0013;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+
+const UDP_TRANSPORT: u8 = 17;
+
+const DEFAULT_LIFETIME: u32 = 600;
+const MAX_LIFETIME: u32 = 3600;
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Allocation {
+    relay: SocketAddrV4,
+    expires: Instant,
+    permissions: HashMap<Ipv4Addr, Instant>,
+    channels: HashMap<u16, (SocketAddrV4, Instant)>,
+}
+
+impl Allocation {
+    fn permitted(&self, peer: Ipv4Addr, now: Instant) -> bool {
+        self.permissions
+            .get(&peer)
+            .map(|expires| *expires > now)
+            .unwrap_or(false)
+    }
+
+    fn channel_for(&self, peer: SocketAddrV4, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (addr, expires))| *addr == peer && *expires > now)
+            .map(|(channel, _)| *channel)
+    }
+}
+
+/// A minimal in-process TURN server (RFC 5766) relaying UDP without authentication.
+///
+/// Relayed transport addresses are allocated on the server's IP address.
+#[derive(Debug)]
+pub struct TurnServer {
+    plug: Plug,
+    addr: SocketAddrV4,
+    allocations: HashMap<SocketAddrV4, Allocation>,
+    relays: HashMap<u16, SocketAddrV4>,
+    relay_ports: RangeInclusive<u16>,
+    next_port: u16,
+    next_transaction: u64,
+}
+
+impl TurnServer {
+    pub fn new(plug: Plug, addr: SocketAddrV4) -> Self {
+        Self {
+            plug,
+            addr,
+            allocations: Default::default(),
+            relays: Default::default(),
+            relay_ports: 49152..=u16::MAX,
+            next_port: 49152,
+            next_transaction: 0,
+        }
+    }
+
+    /// Sets the ports relayed transport addresses are allocated from, allocations fail with
+    /// 508 Insufficient Capacity once all of them are in use. Defaults to `49152..=65535`.
+    ///
+    /// # Panics
+    ///
+    /// If the range is empty.
+    pub fn set_relay_ports(&mut self, relay_ports: RangeInclusive<u16>) {
+        assert!(!relay_ports.is_empty());
+        self.next_port = *relay_ports.start();
+        self.relay_ports = relay_ports;
+    }
+
+    fn send(&mut self, source: SocketAddrV4, dest: SocketAddrV4, payload: &[u8]) {
+        self.plug
+            .unbounded_send(Packet::build_udp(source, dest, payload));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let relays = &mut self.relays;
+        self.allocations.retain(|_, allocation| {
+            if allocation.expires <= now {
+                relays.remove(&allocation.relay.port());
+                return false;
+            }
+            allocation.permissions.retain(|_, expires| *expires > now);
+            allocation.channels.retain(|_, (_, expires)| *expires > now);
+            true
+        });
+    }
+
+    fn allocate_port(&mut self) -> Option<u16> {
+        for _ in self.relay_ports.clone() {
+            let port = self.next_port;
+            self.next_port = if port >= *self.relay_ports.end() {
+                *self.relay_ports.start()
+            } else {
+                port + 1
+            };
+            if port != self.addr.port() && !self.relays.contains_key(&port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn handle_client(&mut self, client: SocketAddrV4, payload: &[u8]) {
+        let now = Instant::now();
+        if let Some(&first) = payload.first() {
+            if (0x40..0x80).contains(&first) && payload.len() >= 4 {
+                let channel = u16::from_be_bytes([payload[0], payload[1]]);
+                let len = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+                let data = &payload[4..(4 + len).min(payload.len())];
+                let relay = self.allocations.get(&client).and_then(|allocation| {
+                    let (peer, expires) = allocation.channels.get(&channel)?;
+                    (*expires > now).then_some((allocation.relay, *peer))
+                });
+                match relay {
+                    Some((relay, peer)) => self.send(relay, peer, data),
+                    None => log::debug!("turn {}: unbound channel {:x}", self.addr, channel),
+                }
+                return;
+            }
+        }
+        let request = if let Some(request) = Message::parse(payload) {
+            request
+        } else {
+            log::debug!(
+                "turn {}: dropping invalid message from {}",
+                self.addr,
+                client
+            );
+            return;
+        };
+        if request.ty() == SEND_INDICATION {
+            let relay = self.allocations.get(&client).and_then(|allocation| {
+                let peer = request.xor_address(XOR_PEER_ADDRESS)?;
+                allocation
+                    .permitted(*peer.ip(), now)
+                    .then_some((allocation.relay, peer))
+            });
+            match (relay, request.attribute(DATA)) {
+                (Some((relay, peer)), Some(data)) => self.send(relay, peer, data),
+                _ => log::debug!("turn {}: dropping send indication", self.addr),
+            }
+            return;
+        }
+        let response = match self.handle_request(client, &request, now) {
+            Ok(response) => response,
+            Err((code, reason)) => {
+                let mut response = Message::new(request.ty() | ERROR, request.transaction_id());
+                response.add_error(code, reason);
+                response
+            }
+        };
+        self.send(self.addr, client, &response.encode());
+    }
+
+    fn handle_request(
+        &mut self,
+        client: SocketAddrV4,
+        request: &Message,
+        now: Instant,
+    ) -> Result<Message, (u16, &'static str)> {
+        let mut response = Message::new(request.ty() | SUCCESS, request.transaction_id());
+        match request.ty() {
+            BINDING_REQUEST => {
+                response.add_xor_address(XOR_MAPPED_ADDRESS, client);
+            }
+            ALLOCATE => {
+                if self.allocations.contains_key(&client) {
+                    return Err((437, "Allocation Mismatch"));
+                }
+                match request.attribute(REQUESTED_TRANSPORT) {
+                    Some(transport) if transport.first() == Some(&UDP_TRANSPORT) => {}
+                    Some(_) => return Err((442, "Unsupported Transport Protocol")),
+                    None => return Err((400, "Bad Request")),
+                }
+                let lifetime = lifetime(request);
+                let port = self.allocate_port().ok_or((508, "Insufficient Capacity"))?;
+                let relay = SocketAddrV4::new(*self.addr.ip(), port);
+                self.relays.insert(relay.port(), client);
+                self.allocations.insert(
+                    client,
+                    Allocation {
+                        relay,
+                        expires: now + Duration::from_secs(lifetime.into()),
+                        permissions: Default::default(),
+                        channels: Default::default(),
+                    },
+                );
+                log::debug!("turn {}: allocated {} for {}", self.addr, relay, client);
+                response.add_xor_address(XOR_RELAYED_ADDRESS, relay);
+                response.add_u32_attribute(LIFETIME, lifetime);
+                response.add_xor_address(XOR_MAPPED_ADDRESS, client);
+            }
+            REFRESH => {
+                let lifetime = lifetime(request);
+                let allocation = self
+                    .allocations
+                    .get_mut(&client)
+                    .ok_or((437, "Allocation Mismatch"))?;
+                if lifetime == 0 {
+                    let relay = allocation.relay;
+                    self.allocations.remove(&client);
+                    self.relays.remove(&relay.port());
+                } else {
+                    allocation.expires = now + Duration::from_secs(lifetime.into());
+                }
+                response.add_u32_attribute(LIFETIME, lifetime);
+            }
+            CREATE_PERMISSION => {
+                let peers = request.xor_addresses(XOR_PEER_ADDRESS);
+                if peers.is_empty() {
+                    return Err((400, "Bad Request"));
+                }
+                let allocation = self
+                    .allocations
+                    .get_mut(&client)
+                    .ok_or((437, "Allocation Mismatch"))?;
+                for peer in peers {
+                    allocation
+                        .permissions
+                        .insert(*peer.ip(), now + PERMISSION_LIFETIME);
+                }
+            }
+            CHANNEL_BIND => {
+                let channel = request
+                    .attribute(CHANNEL_NUMBER)
+                    .filter(|value| value.len() >= 2)
+                    .map(|value| u16::from_be_bytes([value[0], value[1]]))
+                    .filter(|channel| (0x4000..0x8000).contains(channel))
+                    .ok_or((400, "Bad Request"))?;
+                let peer = request
+                    .xor_address(XOR_PEER_ADDRESS)
+                    .ok_or((400, "Bad Request"))?;
+                let allocation = self
+                    .allocations
+                    .get_mut(&client)
+                    .ok_or((437, "Allocation Mismatch"))?;
+                let conflict = match allocation.channels.get(&channel) {
+                    Some((bound, _)) => *bound != peer,
+                    None => allocation.channel_for(peer, now).is_some(),
+                };
+                if conflict {
+                    return Err((400, "Bad Request"));
+                }
+                allocation
+                    .channels
+                    .insert(channel, (peer, now + CHANNEL_LIFETIME));
+                allocation
+                    .permissions
+                    .insert(*peer.ip(), now + PERMISSION_LIFETIME);
+            }
+            _ => return Err((400, "Bad Request")),
+        }
+        Ok(response)
+    }
+
+    fn handle_peer(&mut self, peer: SocketAddrV4, relay: SocketAddrV4, payload: &[u8]) {
+        let now = Instant::now();
+        let client = match self.relays.get(&relay.port()) {
+            Some(client) => *client,
+            None => return,
+        };
+        self.next_transaction += 1;
+        let transaction = self.next_transaction;
+        let allocation = &self.allocations[&client];
+        if !allocation.permitted(*peer.ip(), now) {
+            log::debug!(
+                "turn {}: no permission for {} on {}",
+                self.addr,
+                peer,
+                relay
+            );
+            return;
+        }
+        let message = if let Some(channel) = allocation.channel_for(peer, now) {
+            let mut message = Vec::with_capacity(4 + payload.len());
+            message.extend_from_slice(&channel.to_be_bytes());
+            message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            message.extend_from_slice(payload);
+            message
+        } else {
+            let mut indication = Message::new(DATA_INDICATION, transaction_id(transaction));
+            indication.add_xor_address(XOR_PEER_ADDRESS, peer);
+            indication.add_attribute(DATA, payload.to_vec());
+            indication.encode()
+        };
+        self.send(self.addr, client, &message);
+    }
+}
+
+fn lifetime(request: &Message) -> u32 {
+    request
+        .u32_attribute(LIFETIME)
+        .unwrap_or(DEFAULT_LIFETIME)
+        .min(MAX_LIFETIME)
+}
+
+fn transaction_id(n: u64) -> [u8; 12] {
+    let mut id = [0; 12];
+    id[4..].copy_from_slice(&n.to_be_bytes());
+    id
+}
+
+impl Future for TurnServer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            let mut bytes = match self.plug.poll_incoming(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Ready(Some(bytes)) => bytes,
+            };
+            let packet = match Packet::new(&mut bytes) {
+                Some(packet) if packet.protocol() == Protocol::Udp => packet,
+                _ => continue,
+            };
+            let source = packet.get_source();
+            let dest = packet.get_destination();
+            self.expire(Instant::now());
+            if dest == self.addr {
+                self.handle_client(source, packet.payload());
+            } else if dest.ip() == self.addr.ip() {
+                self.handle_peer(source, dest, packet.payload());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netsim_embed_core::wire;
+
+    fn server() -> SocketAddrV4 {
+        "1.0.0.1:3478".parse().unwrap()
+    }
+
+    fn client() -> SocketAddrV4 {
+        "8.8.8.8:5000".parse().unwrap()
+    }
+
+    fn peer() -> SocketAddrV4 {
+        "9.9.9.9:6000".parse().unwrap()
+    }
+
+    fn allocate(id: u8) -> Message {
+        let mut request = Message::new(ALLOCATE, [id; 12]);
+        request.add_attribute(REQUESTED_TRANSPORT, vec![UDP_TRANSPORT, 0, 0, 0]);
+        request
+    }
+
+    fn channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+        let mut message = channel.to_be_bytes().to_vec();
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    /// Returns the source, destination and payload of the next packet sent by the server.
+    async fn recv(plug: &mut Plug) -> (SocketAddrV4, SocketAddrV4, Vec<u8>) {
+        let mut bytes = plug.incoming().await.unwrap();
+        let packet = Packet::new(&mut bytes).unwrap();
+        (
+            packet.get_source(),
+            packet.get_destination(),
+            packet.payload().to_vec(),
+        )
+    }
+
+    async fn request(plug: &mut Plug, client: SocketAddrV4, request: &Message) -> Message {
+        plug.unbounded_send(Packet::build_udp(client, server(), &request.encode()));
+        let (source, dest, payload) = recv(plug).await;
+        assert_eq!((source, dest), (server(), client));
+        let response = Message::parse(&payload).unwrap();
+        assert_eq!(response.transaction_id(), request.transaction_id());
+        response
+    }
+
+    #[async_std::test]
+    async fn relays_data() {
+        let (mut plug, server_plug) = wire();
+        async_std::task::spawn(TurnServer::new(server_plug, server()));
+
+        let response = request(&mut plug, client(), &Message::new(ALLOCATE, [0; 12])).await;
+        assert_eq!(response.ty(), ALLOCATE | ERROR);
+        assert_eq!(response.error_code(), Some(400));
+        let response = request(&mut plug, client(), &allocate(1)).await;
+        assert_eq!(response.ty(), ALLOCATE | SUCCESS);
+        assert_eq!(response.xor_address(XOR_MAPPED_ADDRESS), Some(client()));
+        assert_eq!(response.u32_attribute(LIFETIME), Some(DEFAULT_LIFETIME));
+        let relay = response.xor_address(XOR_RELAYED_ADDRESS).unwrap();
+        assert_eq!(relay.ip(), server().ip());
+        let response = request(&mut plug, client(), &allocate(2)).await;
+        assert_eq!(response.error_code(), Some(437));
+
+        // send indications are dropped without a permission
+        let mut send = Message::new(SEND_INDICATION, [3; 12]);
+        send.add_xor_address(XOR_PEER_ADDRESS, peer());
+        send.add_attribute(DATA, b"multi\nline".to_vec());
+        plug.unbounded_send(Packet::build_udp(client(), server(), &send.encode()));
+        let mut permission = Message::new(CREATE_PERMISSION, [4; 12]);
+        permission.add_xor_address(XOR_PEER_ADDRESS, peer());
+        let response = request(&mut plug, client(), &permission).await;
+        assert_eq!(response.ty(), CREATE_PERMISSION | SUCCESS);
+        plug.unbounded_send(Packet::build_udp(client(), server(), &send.encode()));
+        assert_eq!(
+            recv(&mut plug).await,
+            (relay, peer(), b"multi\nline".to_vec())
+        );
+
+        // peer data is delivered in data indications until a channel is bound
+        plug.unbounded_send(Packet::build_udp(peer(), relay, b"pong"));
+        let (_, dest, payload) = recv(&mut plug).await;
+        assert_eq!(dest, client());
+        let indication = Message::parse(&payload).unwrap();
+        assert_eq!(indication.ty(), DATA_INDICATION);
+        assert_eq!(indication.xor_address(XOR_PEER_ADDRESS), Some(peer()));
+        assert_eq!(indication.attribute(DATA), Some(&b"pong"[..]));
+
+        let mut bind = Message::new(CHANNEL_BIND, [5; 12]);
+        bind.add_attribute(CHANNEL_NUMBER, vec![0x40, 0x00, 0, 0]);
+        bind.add_xor_address(XOR_PEER_ADDRESS, peer());
+        let response = request(&mut plug, client(), &bind).await;
+        assert_eq!(response.ty(), CHANNEL_BIND | SUCCESS);
+        plug.unbounded_send(Packet::build_udp(peer(), relay, b"pong"));
+        let expected = (server(), client(), channel_data(0x4000, b"pong"));
+        assert_eq!(recv(&mut plug).await, expected);
+        let data = channel_data(0x4000, b"ping");
+        plug.unbounded_send(Packet::build_udp(client(), server(), &data));
+        assert_eq!(recv(&mut plug).await, (relay, peer(), b"ping".to_vec()));
+
+        // a refresh with lifetime zero deletes the allocation
+        let mut refresh = Message::new(REFRESH, [6; 12]);
+        refresh.add_u32_attribute(LIFETIME, 0);
+        let response = request(&mut plug, client(), &refresh).await;
+        assert_eq!(response.ty(), REFRESH | SUCCESS);
+        plug.unbounded_send(Packet::build_udp(peer(), relay, b"pong"));
+        let response = request(&mut plug, client(), &refresh).await;
+        assert_eq!(response.error_code(), Some(437));
+    }
+
+    #[async_std::test]
+    async fn fails_when_relay_ports_are_exhausted() {
+        let (mut plug, server_plug) = wire();
+        let mut turn = TurnServer::new(server_plug, server());
+        turn.set_relay_ports(50000..=50001);
+        async_std::task::spawn(turn);
+
+        for (id, port) in [(1, 5001), (2, 5002)] {
+            let client = SocketAddrV4::new(*client().ip(), port);
+            let response = request(&mut plug, client, &allocate(id)).await;
+            assert_eq!(response.ty(), ALLOCATE | SUCCESS);
+        }
+        let response = request(&mut plug, client(), &allocate(3)).await;
+        assert_eq!(response.ty(), ALLOCATE | ERROR);
+        assert_eq!(response.error_code(), Some(508));
+    }
+}
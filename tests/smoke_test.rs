@@ -1,4 +1,6 @@
+use async_process::Command;
 use ipc_channel::ipc::{IpcReceiver, IpcSender};
+use std::ffi::CStr;
 
 #[netsim_embed::machine]
 fn send_one(sender: IpcSender<usize>) {
@@ -15,7 +17,7 @@ fn add((left, right, sender): (IpcReceiver<usize>, IpcReceiver<usize>, IpcSender
 fn can_send_one() {
     let mut s = netsim_embed::Netsim::<String, String>::new();
     let (sender, receiver) = ipc_channel::ipc::channel().unwrap();
-    let _ = async_std::task::block_on(s.spawn(send_one, sender, None));
+    async_std::task::block_on(s.spawn(send_one, sender, None)).unwrap();
     assert_eq!(1, receiver.recv().unwrap());
 }
 
@@ -24,19 +26,94 @@ fn one_plus_one_makes_two() {
         let mut s = netsim_embed::Netsim::<String, String>::new();
 
         let (sender1, receiver1) = ipc_channel::ipc::channel::<usize>().unwrap();
-        let _ = s.spawn(send_one, sender1, None).await;
+        s.spawn(send_one, sender1, None).await.unwrap();
 
         let (sender2, receiver2) = ipc_channel::ipc::channel::<usize>().unwrap();
-        let _ = s.spawn(send_one, sender2, None).await;
+        s.spawn(send_one, sender2, None).await.unwrap();
 
         let (sender3, receiver3) = ipc_channel::ipc::channel::<usize>().unwrap();
-        let _ = s.spawn(add, (receiver1, receiver2, sender3), None).await;
+        s.spawn(add, (receiver1, receiver2, sender3), None)
+            .await
+            .unwrap();
 
         assert_eq!(2, receiver3.recv().unwrap());
     })
 }
 
+/// Returns the hostname of the calling thread.
+fn hostname() -> String {
+    let mut name = [0 as libc::c_char; 65];
+    assert_eq!(
+        unsafe { libc::gethostname(name.as_mut_ptr(), name.len()) },
+        0
+    );
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    name.to_str().unwrap().to_owned()
+}
+
+fn sets_hostname() {
+    async_std::task::block_on(async {
+        let mut s = netsim_embed::Netsim::<String, String>::new();
+        let hostname = "h".repeat(64);
+        let config = netsim_embed::MachineConfig {
+            hostname: Some(hostname.clone()),
+            ..Default::default()
+        };
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo \"<$(hostname) $(uname -n)\""]);
+        let machine = s
+            .spawn_machine_with_config(command, None, config)
+            .await
+            .unwrap();
+        let names = s.machine(machine).recv().await.unwrap();
+        assert_eq!(names, format!("<{} {}", hostname, hostname));
+        // the host's hostname is untouched
+        assert_ne!(self::hostname(), hostname);
+    })
+}
+
+fn rejects_long_hostnames() {
+    async_std::task::block_on(async {
+        let mut s = netsim_embed::Netsim::<String, String>::new();
+        let config = netsim_embed::MachineConfig {
+            hostname: Some("h".repeat(65)),
+            ..Default::default()
+        };
+        let err = s
+            .spawn_machine_with_config(Command::new("true"), None, config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    })
+}
+
+fn reports_start_up_errors() {
+    async_std::task::block_on(async {
+        let mut s = netsim_embed::Netsim::<String, String>::new();
+        let config = netsim_embed::MachineConfig {
+            sysctls: netsim_embed::Sysctls {
+                tcp_congestion_control: Some("nonexistent".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(s
+            .spawn_machine_with_config(Command::new("true"), None, config)
+            .await
+            .is_err());
+        // the failed machine isn't kept around
+        let machine = s.spawn_machine(Command::new("true"), None).await.unwrap();
+        assert_eq!(machine, netsim_embed::MachineId(0));
+    })
+}
+
 fn main() {
     netsim_embed::declare_machines!(send_one, add);
-    netsim_embed::run_tests!(can_send_one, one_plus_one_makes_two);
+    netsim_embed::run_tests!(
+        can_send_one,
+        one_plus_one_makes_two,
+        sets_hostname,
+        rejects_long_hostnames,
+        reports_start_up_errors
+    );
 }